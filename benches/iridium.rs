@@ -12,7 +12,7 @@ mod arithmetic {
     fn execute_add(c: &mut Criterion) {
         let clos = || {
             let mut test_vm = VM::get_test_vm();
-            test_vm.program = vec![1, 0, 1, 2];
+            test_vm.add_bytes(vec![1, 0, 1, 2]);
             test_vm.run_once();
         };
 
@@ -22,7 +22,7 @@ mod arithmetic {
     fn execute_sub(c: &mut Criterion) {
         let clos = || {
             let mut test_vm = VM::get_test_vm();
-            test_vm.program = vec![2, 1, 0, 2];
+            test_vm.add_bytes(vec![2, 1, 0, 2]);
             test_vm.run_once();
         };
 
@@ -32,7 +32,7 @@ mod arithmetic {
     fn execute_mul(c: &mut Criterion) {
         let clos = || {
             let mut test_vm = VM::get_test_vm();
-            test_vm.program = vec![3, 0, 1, 2];
+            test_vm.add_bytes(vec![3, 0, 1, 2]);
             test_vm.run_once();
         };
 
@@ -42,7 +42,7 @@ mod arithmetic {
     fn execute_div(c: &mut Criterion) {
         let clos = || {
             let mut test_vm = VM::get_test_vm();
-            test_vm.program = vec![4, 1, 0, 2];
+            test_vm.add_bytes(vec![4, 1, 0, 2]);
             test_vm.run_once();
         };
 
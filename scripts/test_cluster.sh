@@ -12,6 +12,6 @@ tmux send-keys -t iridium:server "!start_cluster" C-m
 sleep 3
 tmux send-keys -t iridium:client1 "!join_cluster localhost 2254" C-m
 sleep 3
-tmux send-keys -t iridium:client2 "!join_cluster localhost 2254" C-m
+tmux send-keys -t iridium:client2 "!join_cluster localhost 2255" C-m
 sleep 3
 tmux attach -t iridium
\ No newline at end of file
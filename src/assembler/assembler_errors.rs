@@ -10,6 +10,9 @@ pub enum AssemblerError {
     NonOpcodeInOpcodeField,
     InsufficientSections,
     ParseError { error: String },
+    InvalidOperands { instruction: u32, mnemonic: String },
+    UnknownLabel { name: String },
+    LabelOutOfRange { instruction: u32, name: String },
 }
 
 impl fmt::Display for AssemblerError {
@@ -30,6 +33,15 @@ impl fmt::Display for AssemblerError {
             AssemblerError::NonOpcodeInOpcodeField => f.write_str("An non-opcode was found in an opcode field"),
             AssemblerError::InsufficientSections => f.write_str("Less than two sections/segments were found in the code"),
             AssemblerError::ParseError { ref error } => f.write_str(&format!("There was an error parsing the code: {}", error)),
+            AssemblerError::InvalidOperands { instruction, ref mnemonic } => f.write_str(&format!(
+                "The operands given to {} are not valid for it. Instruction # was {}",
+                mnemonic, instruction
+            )),
+            AssemblerError::UnknownLabel { ref name } => f.write_str(&format!("The label @{} was used but never declared", name)),
+            AssemblerError::LabelOutOfRange { instruction, ref name } => f.write_str(&format!(
+                "The label @{} is too far away to be encoded in the instruction. Instruction # was {}",
+                name, instruction
+            )),
         }
     }
 }
@@ -44,6 +56,9 @@ impl Error for AssemblerError {
             AssemblerError::NonOpcodeInOpcodeField => "A non-opcode was found in an opcode field",
             AssemblerError::InsufficientSections => "Less than two sections/segments were found in the code",
             AssemblerError::ParseError { .. } => "There was an error parsing the code",
+            AssemblerError::InvalidOperands { .. } => "The operands are not valid for the instruction",
+            AssemblerError::UnknownLabel { .. } => "A label was used but never declared",
+            AssemblerError::LabelOutOfRange { .. } => "A label is too far away to be encoded in the instruction",
         }
     }
 }
@@ -1,16 +1,69 @@
-use assembler::Token;
 use nom::types::CompleteStr;
+use nom::IResult;
+
+use assembler::instruction_parsers::AssemblerInstruction;
+use assembler::Token;
+
+// The text of a comment, from the `;` to the end of the line
+named!(comment_text<CompleteStr, CompleteStr>,
+    preceded!(tag!(";"), take_while!(|c| c != '\n'))
+);
 
 /// Looks for a comment start
 /// Examples:
 named!(pub comment<CompleteStr, Token>,
     ws!(
         do_parse!(
-            tag!(";") >>
-            take_until!("\n") >>
+            text: comment_text >>
             (
-                Token::Comment
+                Token::Comment{text: text.trim().to_string()}
             )
         )
     )
 );
+
+/// Returns the text of each comment in `tokens`
+pub fn comment_texts(tokens: Vec<Token>) -> Vec<String> {
+    tokens
+        .into_iter()
+        .filter_map(|t| match t {
+            Token::Comment { text } => Some(text),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Runs `parser`, keeping the comments on the lines above what it parses and any comment at the end of
+/// its last line in the `AssemblerInstruction` it returns
+pub fn with_comments<F>(input: CompleteStr, parser: F) -> IResult<CompleteStr, AssemblerInstruction>
+where
+    F: Fn(CompleteStr) -> IResult<CompleteStr, AssemblerInstruction>,
+{
+    let (start, comments) = many0!(input, comment)?;
+    let (mut rest, mut instruction) = parser(start)?;
+    instruction.comments = comment_texts(comments);
+    // The parsers eat whitespace after what they match, which may include the end of the line. Only a
+    // comment before that belongs to this instruction; one after it belongs to the next.
+    let consumed = &start[..start.len() - rest.len()];
+    if !consumed[consumed.trim_end().len()..].contains('\n') {
+        let same_line = rest.trim_start_matches([' ', '\t']);
+        if let Ok((after, text)) = comment_text(CompleteStr(same_line)) {
+            instruction.inline_comment = Some(text.trim().to_string());
+            rest = CompleteStr(after.trim_start());
+        }
+    }
+    Ok((rest, instruction))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_comment() {
+        let result = comment(CompleteStr("; a comment\nhlt"));
+        assert_eq!(result, Ok((CompleteStr("hlt"), Token::Comment { text: "a comment".to_string() })));
+        // The last line of a file doesn't need a newline
+        assert!(comment(CompleteStr(";")).is_ok());
+    }
+}
@@ -1,9 +1,11 @@
+use assembler::comment_parsers::with_comments;
 use assembler::instruction_parsers::AssemblerInstruction;
 use assembler::label_parsers::label_declaration;
 use assembler::operand_parsers::operand;
 use assembler::Token;
 use nom::alpha1;
 use nom::types::CompleteStr;
+use nom::IResult;
 
 named!(directive_declaration<CompleteStr, Token>,
   do_parse!(
@@ -31,23 +33,18 @@ named!(directive_combined<CompleteStr, AssemblerInstruction>,
                     operand1: o1,
                     operand2: o2,
                     operand3: o3,
+                    comments: vec![],
+                    inline_comment: None,
                 }
             )
         )
     )
 );
 
-/// Will try to parse out any of the Directive forms
-named!(pub directive<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        ins: alt!(
-            directive_combined
-        ) >>
-        (
-            ins
-        )
-    )
-);
+/// Will try to parse out any of the Directive forms, along with the comments around it
+pub fn directive(input: CompleteStr) -> IResult<CompleteStr, AssemblerInstruction> {
+    with_comments(input, directive_combined)
+}
 
 mod tests {
     #![allow(unused_imports)]
@@ -79,6 +76,8 @@ mod tests {
             operand1: Some(Token::IrString { name: "Hello".to_string() }),
             operand2: None,
             operand3: None,
+            comments: vec![],
+            inline_comment: None,
         };
 
         assert_eq!(directive, correct_instruction);
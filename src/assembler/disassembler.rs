@@ -0,0 +1,129 @@
+//! Turns executables back into assembly, for looking at programs that were deployed without their source.
+//! Label names aren't kept in the bytecode, so jumps and `PRTS` show the offsets they were assembled with.
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use assembler::signatures::opcode_signature;
+use assembler::{is_pie, PIE_HEADER_LENGTH};
+use instruction::Opcode;
+
+/// Disassembles an executable. Each instruction is followed by a comment with its offset in the bytecode, which
+/// is what jumps to it refer to. Returns `None` if `bytecode` isn't an executable.
+pub fn disassemble(bytecode: &[u8]) -> Option<String> {
+    if !is_pie(bytecode) {
+        return None;
+    }
+    let natives_length = LittleEndian::read_u32(&bytecode[4..8]) as usize;
+    let ro_length = LittleEndian::read_u32(&bytecode[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + 4]) as usize;
+    let ro_start = PIE_HEADER_LENGTH + 4;
+    let natives_start = ro_start + ro_length;
+    let code_start = natives_start + natives_length;
+    if code_start > bytecode.len() {
+        return None;
+    }
+
+    let mut lines = vec![".data".to_string()];
+    lines.extend(ro_lines(&bytecode[ro_start..natives_start]));
+    lines.push(".code".to_string());
+    let natives = native_names(&bytecode[natives_start..code_start]);
+    if !natives.is_empty() {
+        lines.push(format!("; imports: {}", natives.join(", ")));
+    }
+    let mut offset = code_start;
+    while offset < bytecode.len() {
+        let end = usize::min(offset + 4, bytecode.len());
+        let text = instruction_text(&bytecode[offset..end], &natives);
+        lines.push(format!("{:<24} ; {}", text, offset));
+        offset = end;
+    }
+    Some(lines.join("\n") + "\n")
+}
+
+/// Describes the read-only section as comments, one per null-terminated string, with anything that isn't
+/// printable text shown as bytes
+fn ro_lines(ro: &[u8]) -> Vec<String> {
+    let mut lines = vec![];
+    let mut offset = 0;
+    while offset < ro.len() {
+        let end = match ro[offset..].iter().position(|b| *b == 0) {
+            Some(length) => offset + length + 1,
+            None => ro.len(),
+        };
+        let chunk = &ro[offset..end];
+        let text = &chunk[..chunk.len() - 1];
+        let is_string = chunk.len() > 1 && chunk[chunk.len() - 1] == 0 && text.iter().all(|b| *b >= 0x20 && *b < 0x7f);
+        if is_string {
+            lines.push(format!("; {}: '{}'", offset, String::from_utf8_lossy(text)));
+        } else {
+            let bytes: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            lines.push(format!("; {}: {}", offset, bytes.join(" ")));
+        }
+        offset = end;
+    }
+    lines
+}
+
+fn native_names(table: &[u8]) -> Vec<String> {
+    let mut names: Vec<String> = table.split(|b| *b == 0).map(|name| String::from_utf8_lossy(name).into_owned()).collect();
+    // The table ends with a null byte, which leaves an empty name after it
+    names.pop();
+    names
+}
+
+/// Renders one instruction, using the operand layout of its opcode
+fn instruction_text(bytes: &[u8], natives: &[String]) -> String {
+    let code = Opcode::from(bytes[0]);
+    if code == Opcode::IGL || bytes.len() < 4 {
+        let bytes: Vec<String> = bytes.iter().map(|b| format!("{:#04x}", b)).collect();
+        return format!("; {}", bytes.join(" "));
+    }
+    let mut text = format!("{:?}", code).to_lowercase();
+    let mut position = 1;
+    for operand in opcode_signature(code).operands {
+        if operand.starts_with('$') {
+            text.push_str(&format!(" ${}", bytes[position]));
+            position += 1;
+        } else {
+            let value = (u16::from(bytes[position]) << 8) | u16::from(bytes[position + 1]);
+            match natives.get(value as usize) {
+                Some(name) if code == Opcode::CALLN => text.push_str(&format!(" @{}", name)),
+                _ => text.push_str(&format!(" #{}", value)),
+            }
+            position += 2;
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::Assembler;
+
+    #[test]
+    fn test_disassemble() {
+        let program = Assembler::new()
+            .assemble(".data\nhi: .asciiz 'Hi'\n.code\nload $0 #500\nprts @hi\ncalln $1 @double\nadd $0 $1 $2\nhlt")
+            .unwrap();
+        let expected = "\
+.data
+; 0: 'Hi'
+.code
+; imports: double
+load $0 #500             ; 78
+prts #0                  ; 82
+calln $1 @double         ; 86
+add $0 $1 $2             ; 90
+hlt                      ; 94
+";
+        assert_eq!(disassemble(&program).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_not_an_executable() {
+        assert_eq!(disassemble(&[1, 2, 3]), None);
+        let mut program = Assembler::new().assemble(".data\n.code\nhlt").unwrap();
+        program.truncate(60);
+        assert_eq!(disassemble(&program), None);
+    }
+}
@@ -0,0 +1,162 @@
+//! Re-emits assembly source in a canonical layout, for `iridium fmt`. Labels get a column of their own,
+//! mnemonics are lowercase and padded so operands line up, comments are kept where they were, and there
+//! is one blank line between sections.
+
+use std::cmp;
+
+use nom::types::CompleteStr;
+
+use assembler::assembler_errors::AssemblerError;
+use assembler::instruction_parsers::AssemblerInstruction;
+use assembler::program_parsers::program;
+use assembler::Token;
+use instruction::Opcode;
+
+/// How far instructions are indented when there are no labels longer than this
+const MIN_INDENT: usize = 4;
+
+/// Formats `source`. Returns an error if any of it can't be parsed, since it couldn't be reproduced.
+pub fn format(source: &str) -> Result<String, AssemblerError> {
+    let (rest, program) = match program(CompleteStr(source)) {
+        Ok(result) => result,
+        Err(e) => return Err(AssemblerError::ParseError { error: e.to_string() }),
+    };
+    if !rest.trim().is_empty() {
+        let unparsed = rest.trim_start();
+        let line = source[..source.len() - unparsed.len()].matches('\n').count() + 1;
+        return Err(AssemblerError::ParseError {
+            error: format!("line {} could not be parsed: {}", line, unparsed.lines().next().unwrap_or("")),
+        });
+    }
+    // Unknown mnemonics are parsed as IGL, so formatting them would lose what was written
+    if let Some(idx) = program.instructions.iter().position(|i| i.opcode == Some(Token::Op { code: Opcode::IGL })) {
+        return Err(AssemblerError::ParseError {
+            error: format!("instruction #{} does not use a known opcode", idx),
+        });
+    }
+
+    let label_width = program
+        .instructions
+        .iter()
+        .filter_map(|i| i.get_label_name())
+        .map(|name| name.len() + 1)
+        .max()
+        .unwrap_or(0);
+    let indent = cmp::max(label_width + 1, MIN_INDENT);
+    let mnemonic_width = program
+        .instructions
+        .iter()
+        .filter(|i| !is_section(i))
+        .map(|i| mnemonic(i).len())
+        .max()
+        .unwrap_or(0);
+
+    let mut lines: Vec<String> = vec![];
+    for i in &program.instructions {
+        if is_section(i) {
+            if !lines.is_empty() {
+                lines.push(String::new());
+            }
+            lines.extend(i.comments.iter().map(|c| comment(0, c)));
+            lines.push(with_inline_comment(mnemonic(i), &i.inline_comment));
+            continue;
+        }
+        lines.extend(i.comments.iter().map(|c| comment(indent, c)));
+        let label = i.label.as_ref().map(|l| l.to_string()).unwrap_or_default();
+        let operands: Vec<String> = [&i.operand1, &i.operand2, &i.operand3]
+            .iter()
+            .cloned()
+            .flatten()
+            .map(|t| t.to_string())
+            .collect();
+        let line = format!(
+            "{:label_width$}{:mnemonic_width$} {}",
+            label,
+            mnemonic(i),
+            operands.join(" "),
+            label_width = indent,
+            mnemonic_width = mnemonic_width
+        );
+        lines.push(with_inline_comment(line.trim_end().to_string(), &i.inline_comment));
+    }
+    lines.extend(program.comments.iter().map(|c| comment(indent, c)));
+
+    let mut formatted = lines.join("\n");
+    formatted.push('\n');
+    Ok(formatted)
+}
+
+/// Whether the instruction is a bare `.data` or `.code`, which start at the beginning of the line
+fn is_section(i: &AssemblerInstruction) -> bool {
+    match i.get_directive_name() {
+        Some(name) => (name == "data" || name == "code") && !i.is_label() && !i.has_operands(),
+        None => false,
+    }
+}
+
+fn mnemonic(i: &AssemblerInstruction) -> String {
+    match (&i.opcode, &i.directive) {
+        (Some(Token::Op { code }), _) => format!("{:?}", code).to_lowercase(),
+        (Some(Token::PseudoOp { code }), _) => format!("{:?}", code).to_lowercase(),
+        (_, Some(directive)) => directive.to_string(),
+        _ => String::new(),
+    }
+}
+
+fn comment(indent: usize, text: &str) -> String {
+    let comment = if text.is_empty() { ";".to_string() } else { format!("; {}", text) };
+    format!("{:indent$}{}", "", comment, indent = indent)
+}
+
+fn with_inline_comment(line: String, text: &Option<String>) -> String {
+    match text {
+        Some(text) => format!("{} {}", line, comment(0, text)),
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::Assembler;
+
+    const MESSY: &str = "; Counts down from 10\n.data\ngreeting:   .asciiz 'Hello'\n.code\n  LOAD $0 #10 ;the counter\n\n\n; loop until zero\nloop: DEC $0\n   prts @greeting\n  mov $1 $0\nJMP @loop\n; done\n";
+
+    const FORMATTED: &str = "; Counts down from 10
+.data
+greeting: .asciiz 'Hello'
+
+.code
+          load    $0 #10 ; the counter
+          ; loop until zero
+loop:     dec     $0
+          prts    @greeting
+          mov     $1 $0
+          jmp     @loop
+          ; done
+";
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format(MESSY).unwrap(), FORMATTED);
+    }
+
+    #[test]
+    fn test_format_is_idempotent_and_keeps_meaning() {
+        assert_eq!(format(FORMATTED).unwrap(), FORMATTED);
+        let before = Assembler::new().assemble(MESSY).unwrap();
+        let after = Assembler::new().assemble(FORMATTED).unwrap();
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn test_format_without_labels() {
+        assert_eq!(format(".data\n.code\nhlt").unwrap(), ".data\n\n.code\n    hlt\n");
+    }
+
+    #[test]
+    fn test_format_errors() {
+        assert!(format(".data\n.code\nhlt\nload $0 $1 $2 $3\n").is_err());
+        assert!(format(".data\n.code\nfoo $1\n").is_err());
+    }
+}
@@ -2,18 +2,15 @@ use std::fmt;
 
 use byteorder::{LittleEndian, WriteBytesExt};
 use nom::types::CompleteStr;
+use nom::IResult;
 
-use assembler::comment_parsers::comment;
+use assembler::comment_parsers::with_comments;
 use assembler::label_parsers::label_declaration;
 use assembler::opcode_parsers::*;
 use assembler::operand_parsers::operand;
 use assembler::{SymbolTable, Token};
-use instruction;
 
-const MAX_I16: i32 = 32768;
-const MIN_I16: i32 = -32768;
-
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct AssemblerInstruction {
     pub opcode: Option<Token>,
     pub label: Option<Token>,
@@ -21,6 +18,10 @@ pub struct AssemblerInstruction {
     pub operand1: Option<Token>,
     pub operand2: Option<Token>,
     pub operand3: Option<Token>,
+    /// Comments on the lines above the instruction
+    pub comments: Vec<String>,
+    /// A comment at the end of the instruction's line
+    pub inline_comment: Option<String>,
 }
 
 impl AssemblerInstruction {
@@ -35,7 +36,7 @@ impl AssemblerInstruction {
                     }
                 },
                 _ => {
-                    println!("Non-opcode found in opcode field");
+                    error!("Non-opcode found in opcode field");
                 }
             }
         }
@@ -59,52 +60,10 @@ impl AssemblerInstruction {
         self.opcode.is_some()
     }
 
-    pub fn is_integer_needs_splitting(&self) -> bool {
-        if let Some(ref op) = self.opcode {
-            match op {
-                Token::Op { code } => match code {
-                    instruction::Opcode::LOAD => {
-                        if let Some(ref first_half) = self.operand2 {
-                            match first_half {
-                                Token::IntegerOperand { ref value } => {
-                                    if *value > MAX_I16 || *value < MIN_I16 {
-                                        return true;
-                                    }
-                                    return false;
-                                }
-                                _ => {
-                                    return false;
-                                }
-                            }
-                        }
-                        return true;
-                    }
-                    _ => {
-                        return false;
-                    }
-                },
-                _ => {
-                    return false;
-                }
-            }
-        }
-        false
-    }
-
     pub fn is_directive(&self) -> bool {
         self.directive.is_some()
     }
 
-    pub fn get_integer_value(&self) -> Option<i16> {
-        if let Some(ref operand) = self.operand2 {
-            match operand {
-                Token::IntegerOperand { ref value } => return Some(*value as i16),
-                _ => return None,
-            }
-        }
-        None
-    }
-
     pub fn get_register_number(&self) -> Option<u8> {
         match self.operand1 {
             Some(ref reg_token) => match reg_token {
@@ -168,6 +127,24 @@ impl AssemblerInstruction {
         }
     }
 
+    /// Returns the names of any labels used as operands
+    pub fn label_usages(&self) -> Vec<String> {
+        let mut results = vec![];
+        for operand in &[&self.operand1, &self.operand2, &self.operand3] {
+            if let Some(Token::LabelUsage { name }) = operand {
+                results.push(name.clone());
+            }
+        }
+        results
+    }
+
+    /// Renders the instruction back into assembly source, e.g. `test: LOAD $0 #100`
+    pub fn to_source(&self) -> String {
+        let tokens = [&self.label, &self.opcode, &self.directive, &self.operand1, &self.operand2, &self.operand3];
+        let parts: Vec<String> = tokens.iter().cloned().flatten().map(|t| t.to_string()).collect();
+        parts.join(" ")
+    }
+
     fn extract_operand(t: &Token, results: &mut Vec<u8>, symbols: &SymbolTable) {
         match t {
             Token::Register { reg_num } => {
@@ -208,13 +185,11 @@ impl fmt::Display for AssemblerInstruction {
 
 named!(instruction_combined<CompleteStr, AssemblerInstruction>,
     do_parse!(
-        opt!(comment) >>
         l: opt!(label_declaration) >>
         o: opcode >>
         o1: opt!(operand) >>
         o2: opt!(operand) >>
         o3: opt!(operand) >>
-        opt!(comment) >>
         (
             {
             AssemblerInstruction{
@@ -224,23 +199,18 @@ named!(instruction_combined<CompleteStr, AssemblerInstruction>,
                 operand1: o1,
                 operand2: o2,
                 operand3: o3,
+                comments: vec![],
+                inline_comment: None,
             }
             }
         )
     )
 );
 
-/// Will try to parse out any of the Instruction forms
-named!(pub instruction<CompleteStr, AssemblerInstruction>,
-    do_parse!(
-        ins: alt!(
-            instruction_combined
-        ) >>
-        (
-            ins
-        )
-    )
-);
+/// Will try to parse out any of the Instruction forms, along with the comments around it
+pub fn instruction(input: CompleteStr) -> IResult<CompleteStr, AssemblerInstruction> {
+    with_comments(input, instruction_combined)
+}
 
 #[cfg(test)]
 mod tests {
@@ -260,7 +230,9 @@ mod tests {
                     directive: None,
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::IntegerOperand { value: 100 }),
-                    operand3: None
+                    operand3: None,
+                    comments: vec![],
+                    inline_comment: None,
                 }
             ))
         );
@@ -279,7 +251,9 @@ mod tests {
                     directive: None,
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::LabelUsage { name: "test1".to_string() }),
-                    operand3: None
+                    operand3: None,
+                    comments: vec![],
+                    inline_comment: None,
                 }
             ))
         );
@@ -298,7 +272,9 @@ mod tests {
                     directive: None,
                     operand1: None,
                     operand2: None,
-                    operand3: None
+                    operand3: None,
+                    comments: vec![],
+                    inline_comment: None,
                 }
             ))
         );
@@ -318,6 +294,8 @@ mod tests {
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::Register { reg_num: 1 }),
                     operand3: Some(Token::Register { reg_num: 2 }),
+                    comments: vec![],
+                    inline_comment: None,
                 }
             ))
         );
@@ -325,7 +303,7 @@ mod tests {
 
     #[test]
     fn test_parse_instruction_with_comment_one() {
-        let result = instruction_combined(CompleteStr("; this is a test\nadd $0 $1 $2\n"));
+        let result = instruction(CompleteStr("; this is a test\n; and another\nadd $0 $1 $2\n"));
         assert_eq!(
             result,
             Ok((
@@ -337,6 +315,8 @@ mod tests {
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::Register { reg_num: 1 }),
                     operand3: Some(Token::Register { reg_num: 2 }),
+                    comments: vec!["this is a test".to_string(), "and another".to_string()],
+                    inline_comment: None,
                 }
             ))
         );
@@ -344,7 +324,7 @@ mod tests {
 
     #[test]
     fn test_parse_instruction_with_comment_two() {
-        let result = instruction_combined(CompleteStr("add $0 $1 $2 ; this is a test\n"));
+        let result = instruction(CompleteStr("add $0 $1 $2 ; this is a test\n"));
         assert_eq!(
             result,
             Ok((
@@ -356,11 +336,21 @@ mod tests {
                     operand1: Some(Token::Register { reg_num: 0 }),
                     operand2: Some(Token::Register { reg_num: 1 }),
                     operand3: Some(Token::Register { reg_num: 2 }),
+                    comments: vec![],
+                    inline_comment: Some("this is a test".to_string()),
                 }
             ))
         );
     }
 
+    #[test]
+    fn test_comment_on_next_line_is_not_inline() {
+        let (rest, i) = instruction(CompleteStr("hlt\n; about the next one\nnop\n")).unwrap();
+        assert_eq!(i.inline_comment, None);
+        let (_, next) = instruction(rest).unwrap();
+        assert_eq!(next.comments, vec!["about the next one".to_string()]);
+    }
+
     #[test]
     fn test_parse_cloop() {
         let result = instruction_combined(CompleteStr("cloop #10\n"));
@@ -374,7 +364,9 @@ mod tests {
                     directive: None,
                     operand1: Some(Token::IntegerOperand { value: 10 }),
                     operand2: None,
-                    operand3: None
+                    operand3: None,
+                    comments: vec![],
+                    inline_comment: None,
                 }
             ))
         );
@@ -393,7 +385,9 @@ mod tests {
                     directive: None,
                     operand1: Some(Token::LabelUsage { name: "test".to_string() }),
                     operand2: None,
-                    operand3: None
+                    operand3: None,
+                    comments: vec![],
+                    inline_comment: None,
                 }
             ))
         );
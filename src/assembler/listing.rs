@@ -0,0 +1,172 @@
+use std::fmt;
+
+use assembler::symbols::{Symbol, SymbolTable, SymbolType};
+
+/// One line of source and the bytes the assembler emitted for it
+#[derive(Debug, Clone)]
+pub struct ListingLine {
+    /// Byte offset of the instruction in the assembled output. Directives have no offset.
+    pub offset: Option<u32>,
+    pub bytes: Vec<u8>,
+    pub source: String,
+    /// Labels used by this line and the address each one resolved to
+    pub references: Vec<(String, Option<u32>)>,
+}
+
+/// A constant placed in the read-only section
+#[derive(Debug, Clone)]
+pub struct DataEntry {
+    pub name: String,
+    pub offset: u32,
+    pub size: u32,
+    pub symbol_type: SymbolType,
+}
+
+/// Records what the assembler actually emitted, so it can be compared against the source.
+/// The `Display` implementation renders it as a listing file.
+#[derive(Debug, Clone, Default)]
+pub struct Listing {
+    pub lines: Vec<ListingLine>,
+    pub data: Vec<DataEntry>,
+    pub symbols: Vec<Symbol>,
+}
+
+impl Listing {
+    pub fn new() -> Listing {
+        Listing {
+            lines: vec![],
+            data: vec![],
+            symbols: vec![],
+        }
+    }
+
+    pub fn add_line(&mut self, offset: Option<u32>, bytes: &[u8], source: String) {
+        self.lines.push(ListingLine {
+            offset,
+            bytes: bytes.to_vec(),
+            source,
+            references: vec![],
+        });
+    }
+
+    /// Attaches a resolved label to the most recently added line
+    pub fn add_label_reference(&mut self, name: &str, value: Option<u32>) {
+        if let Some(line) = self.lines.last_mut() {
+            line.references.push((name.to_string(), value));
+        }
+    }
+
+    pub fn add_data(&mut self, name: &str, offset: u32, size: u32, symbol_type: SymbolType) {
+        self.data.push(DataEntry {
+            name: name.to_string(),
+            offset,
+            size,
+            symbol_type,
+        });
+    }
+
+    /// Copies the symbol table into the listing, sorted by name
+    pub fn set_symbols(&mut self, symbols: &SymbolTable) {
+        self.symbols = symbols.symbols.clone();
+        self.symbols.sort_by(|a, b| a.name().cmp(b.name()));
+    }
+}
+
+fn format_address(value: Option<u32>) -> String {
+    match value {
+        Some(v) => format!("{:#06x}", v),
+        None => "unresolved".to_string(),
+    }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<8}{:<13}SOURCE", "OFFSET", "BYTES")?;
+        for line in &self.lines {
+            let offset = match line.offset {
+                Some(o) => format!("{:#06x}", o),
+                None => String::new(),
+            };
+            let bytes: Vec<String> = line.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            write!(f, "{:<8}{:<13}{}", offset, bytes.join(" "), line.source)?;
+            for (name, value) in &line.references {
+                write!(f, " ; @{} -> {}", name, format_address(*value))?;
+            }
+            writeln!(f)?;
+        }
+
+        let data_size: u32 = self.data.iter().map(|d| d.size).sum();
+        writeln!(f)?;
+        writeln!(f, "Data section ({} bytes)", data_size)?;
+        writeln!(f, "{:<8}{:<6}{:<9}NAME", "OFFSET", "SIZE", "TYPE")?;
+        for entry in &self.data {
+            writeln!(
+                f,
+                "{:<8}{:<6}{:<9}{}",
+                format!("{:#06x}", entry.offset),
+                entry.size,
+                entry.symbol_type.to_string(),
+                entry.name
+            )?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Symbol table")?;
+        writeln!(f, "{:<9}{:<11}NAME", "TYPE", "OFFSET")?;
+        for symbol in &self.symbols {
+            writeln!(
+                f,
+                "{:<9}{:<11}{}",
+                symbol.symbol_type().to_string(),
+                format_address(symbol.offset()),
+                symbol.name()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assembler::symbols::SymbolType;
+    use assembler::Assembler;
+
+    #[test]
+    fn test_listing_records_instructions_and_data() {
+        let mut asm = Assembler::new().with_listing();
+        let test_string = r"
+        .data
+        hello: .asciiz 'Hello'
+        .code
+        load $0 #100
+        test: inc $0
+        hlt
+        ";
+        assert!(asm.assemble(test_string).is_ok());
+        let listing = asm.listing().unwrap();
+        let emitted: Vec<_> = listing.lines.iter().filter(|l| l.offset.is_some()).collect();
+        assert_eq!(emitted.len(), 3);
+        assert_eq!(emitted[0].bytes, vec![0, 0, 0, 100]);
+        assert_eq!(emitted[1].offset.unwrap(), emitted[0].offset.unwrap() + 4);
+        assert_eq!(listing.data.len(), 1);
+        assert_eq!(listing.data[0].size, 6);
+        assert_eq!(listing.symbols[0].name(), "hello");
+        assert_eq!(*listing.symbols[0].symbol_type(), SymbolType::IrString);
+        assert_eq!(listing.symbols[1].name(), "test");
+    }
+
+    #[test]
+    fn test_listing_shows_split_load() {
+        let mut asm = Assembler::new().with_listing();
+        assert!(asm.assemble(".data\n.code\nload $0 #-50000\nhlt").is_ok());
+        let rendered = asm.listing().unwrap().to_string();
+        assert!(rendered.contains("LUI $0"));
+    }
+
+    #[test]
+    fn test_no_listing_by_default() {
+        let mut asm = Assembler::new();
+        assert!(asm.assemble(".data\n.code\nhlt").is_ok());
+        assert!(asm.listing().is_none());
+    }
+}
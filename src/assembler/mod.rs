@@ -1,32 +1,46 @@
 pub mod assembler_errors;
 pub mod comment_parsers;
 pub mod directive_parsers;
+pub mod disassembler;
+pub mod formatter;
 pub mod instruction_parsers;
 pub mod label_parsers;
+pub mod listing;
+pub mod object;
 pub mod opcode_parsers;
 pub mod operand_parsers;
+pub mod optimizer;
 pub mod program_parsers;
+pub mod pseudo_instructions;
 pub mod register_parsers;
+pub mod signatures;
 pub mod symbols;
 
+use std::fmt;
+
 use byteorder::{LittleEndian, WriteBytesExt};
 use nom::types::CompleteStr;
 
 use assembler::assembler_errors::AssemblerError;
 use assembler::instruction_parsers::AssemblerInstruction;
+use assembler::listing::Listing;
+use assembler::object::{ObjectFile, ObjectSymbol, Relocation, RelocationKind, Section};
 use assembler::program_parsers::{program, Program};
+use assembler::pseudo_instructions::PseudoOpcode;
 use assembler::symbols::{Symbol, SymbolTable, SymbolType};
 use instruction::Opcode;
 
 /// Magic number that begins every bytecode file prefix. These spell out EPIE in ASCII, if you were wondering.
 pub const PIE_HEADER_PREFIX: [u8; 4] = [0x45, 0x50, 0x49, 0x45];
 
-/// Constant that determines how long the header is. There are 60 zeros left after the prefix, for later usage if needed.
+/// Constant that determines how long the header is. After the prefix come 4 bytes giving the length of the
+/// native import table, and the rest is zeros, for later usage if needed.
 pub const PIE_HEADER_LENGTH: usize = 64;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Op { code: Opcode },
+    PseudoOp { code: PseudoOpcode },
     Register { reg_num: u8 },
     IntegerOperand { value: i32 },
     FloatOperand { value: f64 },
@@ -34,7 +48,24 @@ pub enum Token {
     LabelUsage { name: String },
     Directive { name: String },
     IrString { name: String },
-    Comment,
+    Comment { text: String },
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Op { code } => write!(f, "{:?}", code),
+            Token::PseudoOp { code } => write!(f, "{:?}", code),
+            Token::Register { reg_num } => write!(f, "${}", reg_num),
+            Token::IntegerOperand { value } => write!(f, "#{}", value),
+            Token::FloatOperand { value } => write!(f, "#{:?}", value),
+            Token::LabelDeclaration { ref name } => write!(f, "{}:", name),
+            Token::LabelUsage { ref name } => write!(f, "@{}", name),
+            Token::Directive { ref name } => write!(f, ".{}", name),
+            Token::IrString { ref name } => write!(f, "'{}'", name),
+            Token::Comment { ref text } => write!(f, "; {}", text),
+        }
+    }
 }
 
 #[derive(Debug, Default)]
@@ -55,16 +86,33 @@ pub struct Assembler {
     current_section: Option<AssemblerSection>,
     /// The current instruction the assembler is converting to bytecode
     current_instruction: u32,
+    /// How many bytes of executable code the instructions seen so far will take up
+    code_offset: u32,
     /// Any errors we find along the way. At the end, we'll present them to the user.
     errors: Vec<AssemblerError>,
     /// Scratch buffer
     buf: [u8; 4],
+    /// Human-readable listing of what was emitted, if one was requested with `with_listing`
+    listing: Option<Listing>,
+    /// If true, labels are left for the linker to resolve instead of being resolved against this program
+    relocatable: bool,
+    /// Labels declared with `.global`, which other modules may refer to
+    globals: Vec<String>,
+    /// Label operands the linker has to fill in, when assembling an object file
+    relocations: Vec<Relocation>,
+    /// Labels used but not declared, when assembling an object file
+    imports: Vec<String>,
+    /// If true, the peephole optimizer runs between the two phases
+    optimize: bool,
+    /// Native functions called with `CALLN`, in the order they appear in the import table
+    natives: Vec<String>,
 }
 
 impl Assembler {
     pub fn new() -> Assembler {
         Assembler {
             current_instruction: 0,
+            code_offset: 0,
             ro_offset: 0,
             ro: vec![],
             bytecode: vec![],
@@ -74,9 +122,33 @@ impl Assembler {
             symbols: SymbolTable::new(),
             current_section: None,
             buf: [0, 0, 0, 0],
+            listing: None,
+            relocatable: false,
+            globals: vec![],
+            relocations: vec![],
+            imports: vec![],
+            optimize: false,
+            natives: vec![],
         }
     }
 
+    /// Makes the assembler record a listing of every instruction and constant it emits
+    pub fn with_listing(mut self) -> Self {
+        self.listing = Some(Listing::new());
+        self
+    }
+
+    /// Makes the assembler run the peephole optimizer over the program before generating bytecode
+    pub fn with_optimizations(mut self) -> Self {
+        self.optimize = true;
+        self
+    }
+
+    /// Returns the listing of the last assembled program, if `with_listing` was used
+    pub fn listing(&self) -> Option<&Listing> {
+        self.listing.as_ref()
+    }
+
     pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
         match program(CompleteStr(raw)) {
             Ok((_remainder, mut program)) => {
@@ -88,6 +160,17 @@ impl Assembler {
                     return Err(self.errors.clone());
                 };
                 debug!("First parsing phase complete");
+                if self.optimize {
+                    optimizer::optimize(&mut program.instructions);
+                    // A native whose only `CALLN` was optimized away shouldn't be imported. The table comes
+                    // before the code, so this has to be settled before the labels are laid out again.
+                    self.natives.clear();
+                    for i in &program.instructions {
+                        self.process_native_usage(i);
+                    }
+                    self.relayout_labels(&program);
+                    debug!("Optimization complete");
+                }
                 debug!("Phase 1 program: {:#?}", program);
                 // Make sure that we have at least one data section and one code section
                 if self.sections.len() != 2 {
@@ -99,11 +182,18 @@ impl Assembler {
                 }
                 // Run the second pass, which translates opcodes and associated operands into the bytecode
                 let mut body = self.process_second_phase(&program);
+                if !self.errors.is_empty() {
+                    error!("Errors were found in the second parsing phase: {:?}", self.errors);
+                    return Err(self.errors.clone());
+                };
                 debug!("Phase 2 program: {:#?}", program);
                 // Get the header so we can smush it into the bytecode letter
                 let mut assembled_program = self.write_pie_header();
                 debug!("Length of header is: {}", assembled_program.len());
 
+                // The read-only section and the native import table go between the header and the executable code
+                assembled_program.extend_from_slice(&self.ro);
+                assembled_program.append(&mut natives_table(&self.natives));
                 // Merge the header with the populated body vector
                 assembled_program.append(&mut body);
                 debug!("Complete program is: {:#?}", assembled_program);
@@ -117,31 +207,47 @@ impl Assembler {
         }
     }
 
-    /// Runs the first pass of the two-pass assembling process. It looks for labels and puts them in the symbol table
-    fn process_first_phase(&mut self, p: &mut Program) {
-        info!("Beginning search for LOAD instructions that need to be split up");
-        let mut inserts_to_do = Vec::new();
-        for (idx, i) in p.instructions.iter_mut().enumerate() {
-            if i.is_integer_needs_splitting() {
-                let value = i.get_integer_value();
-                let _register = i.get_register_number();
-                let mut wtr = vec![];
-                let _ = wtr.write_i16::<LittleEndian>(value.unwrap());
-                i.operand2 = Some(Token::IntegerOperand { value: wtr[1].into() });
-                let new_instruction = AssemblerInstruction {
-                    opcode: Some(Token::Op { code: Opcode::LUI }),
-                    label: None,
-                    directive: None,
-                    operand1: i.operand1.clone(),
-                    operand2: Some(Token::IntegerOperand { value: wtr[0].into() }),
-                    operand3: None,
-                };
-                inserts_to_do.push((idx + 1, new_instruction));
+    /// Assembles a single module into a relocatable object file instead of an executable. Labels that
+    /// aren't declared in the module are recorded as imports, and every label operand is left for the
+    /// linker to fill in.
+    pub fn assemble_object(&mut self, raw: &str) -> Result<ObjectFile, Vec<AssemblerError>> {
+        self.relocatable = true;
+        let assembled_program = self.assemble(raw)?;
+        for name in &self.globals {
+            if !self.symbols.has_symbol(name) {
+                self.errors.push(AssemblerError::UnknownLabel { name: name.clone() });
             }
         }
-        for insert in inserts_to_do {
-            p.instructions.insert(insert.0, insert.1)
+        if !self.errors.is_empty() {
+            return Err(self.errors.clone());
+        }
+
+        let mut symbols = vec![];
+        for symbol in &self.symbols.symbols {
+            let section = match *symbol.symbol_type() {
+                SymbolType::Label => Section::Code,
+                SymbolType::Integer | SymbolType::IrString => Section::Data,
+            };
+            symbols.push(ObjectSymbol {
+                name: symbol.name().to_string(),
+                section,
+                offset: symbol.offset().unwrap_or(0),
+                exported: self.globals.iter().any(|g| g == symbol.name()),
+            });
         }
+        Ok(ObjectFile {
+            // `assemble` puts the header, read-only section and native import table in front of the code
+            code: assembled_program[PIE_HEADER_LENGTH + 4 + self.ro.len() + natives_table(&self.natives).len()..].to_vec(),
+            ro: self.ro.clone(),
+            symbols,
+            imports: self.imports.clone(),
+            relocations: self.relocations.clone(),
+        })
+    }
+
+    /// Runs the first pass of the two-pass assembling process. It looks for labels and puts them in the symbol table
+    fn process_first_phase(&mut self, p: &mut Program) {
+        self.expand_pseudo_instructions(p);
         info!("Beginning first parsing phase");
         // Iterate over every instruction, even though in the first phase we only care about labels and directives
         for i in &p.instructions {
@@ -153,7 +259,7 @@ impl Assembler {
                     debug!(
                         "Parsing label declaration in first phase: {:?} with offset {:?}",
                         i.get_label_name(),
-                        self.current_instruction * 4
+                        self.code_offset
                     );
                     self.process_label_declaration(&i);
                 } else {
@@ -169,12 +275,53 @@ impl Assembler {
                 self.process_directive(i);
             }
 
+            if i.is_opcode() {
+                self.process_native_usage(i);
+                self.code_offset += 4;
+            }
+
             // This is used to keep track of which instruction we hit an error on
             self.current_instruction += 1;
         }
+        // Labels were recorded relative to the start of the code, which comes after the read-only section.
+        // Now that we know how big that is, we can turn them into absolute offsets.
+        let code_start = self.code_start();
+        self.symbols.relocate_labels(code_start);
         self.phase = AssemblerPhase::Second;
     }
 
+    /// Replaces pseudo-instructions (and `LOAD`s of values that don't fit in 16 bits) with the real
+    /// instructions they stand for. This has to happen before any label offsets are calculated.
+    fn expand_pseudo_instructions(&mut self, p: &mut Program) {
+        info!("Expanding pseudo-instructions");
+        let mut expanded = Vec::with_capacity(p.instructions.len());
+        let mut callr_registers: Vec<u8> = vec![];
+        for (idx, i) in p.instructions.drain(..).enumerate() {
+            if let Some(Token::PseudoOp { code: PseudoOpcode::CALLR }) = i.opcode {
+                if let Some(reg_num) = i.get_register_number() {
+                    if !callr_registers.contains(&reg_num) {
+                        callr_registers.push(reg_num);
+                    }
+                }
+            }
+            match pseudo_instructions::expand(i) {
+                Ok(mut instructions) => expanded.append(&mut instructions),
+                Err(mnemonic) => {
+                    error!("Invalid operands for {} at instruction {}", mnemonic, idx);
+                    self.errors.push(AssemblerError::InvalidOperands {
+                        instruction: idx as u32,
+                        mnemonic,
+                    });
+                }
+            }
+        }
+        // `CALLR` calls through a stub per register, which we put after everything else in the code section
+        for reg_num in callr_registers {
+            expanded.push(pseudo_instructions::callr_stub(reg_num));
+        }
+        p.instructions = expanded;
+    }
+
     /// Runs the second pass of the assembler
     fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
         info!("Beginning second parsing phase");
@@ -185,17 +332,175 @@ impl Assembler {
         for i in &p.instructions {
             if i.is_directive() {
                 debug!("Found a directive in second phase {:?}, bypassing", i.directive);
+                if let Some(ref mut listing) = self.listing {
+                    listing.add_line(None, &[], i.to_source());
+                }
                 continue;
             }
             if i.is_opcode() {
-                let mut bytes = i.to_bytes(&self.symbols);
+                let offset = self.code_start() + program.len() as u32;
+                let mut bytes = self.resolve_labels(i, offset).to_bytes(&self.symbols);
+                if let Some(ref mut listing) = self.listing {
+                    listing.add_line(Some(offset), &bytes, i.to_source());
+                    for name in i.label_usages() {
+                        listing.add_label_reference(&name, self.symbols.symbol_value(&name));
+                    }
+                }
                 program.append(&mut bytes);
             }
             self.current_instruction += 1
         }
+        if let Some(ref mut listing) = self.listing {
+            listing.set_symbols(&self.symbols);
+        }
         program
     }
 
+    /// Recalculates the offsets of labels in the code after the optimizer has removed instructions
+    fn relayout_labels(&mut self, p: &Program) {
+        let code_start = self.code_start();
+        self.code_offset = 0;
+        for i in &p.instructions {
+            if let Some(name) = i.get_label_name() {
+                let is_code_label = self.symbols.symbols.iter().any(|s| s.name() == name && *s.symbol_type() == SymbolType::Label);
+                if is_code_label {
+                    self.symbols.set_symbol_offset(&name, code_start + self.code_offset);
+                }
+            }
+            if i.is_opcode() {
+                self.code_offset += 4;
+            }
+        }
+    }
+
+    /// Where the executable code starts in the assembled program. In an object file, everything is
+    /// relative to the start of the code.
+    fn code_start(&self) -> u32 {
+        if self.relocatable {
+            return 0;
+        }
+        (PIE_HEADER_LENGTH + 4 + self.ro.len() + natives_table(&self.natives).len()) as u32
+    }
+
+    /// Adds the native function named by a `CALLN` to the import table, if it isn't there already. This
+    /// has to happen in the first phase, since the size of the table decides where the code starts.
+    fn process_native_usage(&mut self, i: &AssemblerInstruction) {
+        if i.opcode != Some(Token::Op { code: Opcode::CALLN }) {
+            return;
+        }
+        if let Some(Token::LabelUsage { ref name }) = i.operand2 {
+            if !self.natives.contains(name) {
+                self.natives.push(name.clone());
+            }
+        }
+    }
+
+    /// Returns a copy of `i` with every `@label` operand replaced by the number the VM expects for it.
+    /// `JMP`, `JMPE`, `JMPF` and `JMPB` take a register, so when given a label they are switched to their
+    /// direct forms, which take an absolute address or, for `DJMPF` and `DJMPB`, a distance measured from
+    /// the start of the next instruction. The label given to `CALLN` names a native function, and is replaced
+    /// by its index in the import table.
+    fn resolve_labels(&mut self, i: &AssemblerInstruction, offset: u32) -> AssemblerInstruction {
+        let mut resolved = i.clone();
+        let code = match i.opcode {
+            Some(Token::Op { code }) => code,
+            _ => return resolved,
+        };
+        let code = match (code, &i.operand1) {
+            (Opcode::JMP, Some(Token::LabelUsage { .. })) => Opcode::DJMP,
+            (Opcode::JMPE, Some(Token::LabelUsage { .. })) => Opcode::DJMPE,
+            (Opcode::JMPF, Some(Token::LabelUsage { .. })) => Opcode::DJMPF,
+            (Opcode::JMPB, Some(Token::LabelUsage { .. })) => Opcode::DJMPB,
+            (code, _) => code,
+        };
+        resolved.opcode = Some(Token::Op { code });
+        let next_instruction = i64::from(offset) + 4;
+        // Tracks which byte of the instruction the operand is encoded at; the opcode is byte 0
+        let mut position = 1;
+        for operand in &mut [&mut resolved.operand1, &mut resolved.operand2, &mut resolved.operand3] {
+            let name = match operand {
+                Some(Token::LabelUsage { name }) => name.clone(),
+                Some(Token::Register { .. }) => {
+                    position += 1;
+                    continue;
+                }
+                Some(Token::IntegerOperand { .. }) => {
+                    position += 2;
+                    continue;
+                }
+                _ => continue,
+            };
+            if code == Opcode::CALLN {
+                if self.relocatable {
+                    // Each module has its own import table, so the linker has to renumber them
+                    self.relocations.push(Relocation {
+                        offset,
+                        position,
+                        symbol: name.clone(),
+                        kind: RelocationKind::Native,
+                    });
+                }
+                // Only the second operand was added to the import table in the first phase
+                match self.natives.iter().position(|n| *n == name) {
+                    Some(index) if position == 2 => **operand = Some(Token::IntegerOperand { value: index as i32 }),
+                    _ => {
+                        error!("CALLN takes a register and a native function, got {}", i);
+                        self.errors.push(AssemblerError::InvalidOperands {
+                            instruction: self.current_instruction,
+                            mnemonic: "CALLN".to_string(),
+                        });
+                    }
+                }
+                position += 2;
+                continue;
+            }
+            if self.relocatable {
+                let kind = match code {
+                    Opcode::DJMPF => RelocationKind::RelativeForward,
+                    Opcode::DJMPB => RelocationKind::RelativeBackward,
+                    _ => RelocationKind::Absolute,
+                };
+                if !self.symbols.has_symbol(&name) && !self.imports.contains(&name) {
+                    self.imports.push(name.clone());
+                }
+                self.relocations.push(Relocation {
+                    offset,
+                    position,
+                    symbol: name,
+                    kind,
+                });
+                **operand = Some(Token::IntegerOperand { value: 0 });
+                position += 2;
+                continue;
+            }
+            let target = match self.symbols.symbol_value(&name) {
+                Some(target) => i64::from(target),
+                None => {
+                    error!("No value found for label {:?}", name);
+                    self.errors.push(AssemblerError::UnknownLabel { name });
+                    continue;
+                }
+            };
+            let value = match code {
+                Opcode::DJMPF => target - next_instruction,
+                Opcode::DJMPB => next_instruction - target,
+                _ => target,
+            };
+            // Label operands are encoded in 16 unsigned bits
+            if value < 0 || value > i64::from(u16::MAX) {
+                error!("Label {:?} is out of range for {:?}: {}", name, code, value);
+                self.errors.push(AssemblerError::LabelOutOfRange {
+                    instruction: self.current_instruction,
+                    name,
+                });
+                continue;
+            }
+            **operand = Some(Token::IntegerOperand { value: value as i32 });
+            position += 2;
+        }
+        resolved
+    }
+
     fn process_label_declaration(&mut self, i: &AssemblerInstruction) {
         // Check if the label is None or String
         let name = match i.get_label_name() {
@@ -216,9 +521,10 @@ impl Assembler {
             return;
         }
 
-        // If we make it here, it isn't a symbol we've seen before, so stick it in the table
-        let symbol = Symbol::new_with_offset(name, SymbolType::Label, (self.current_instruction * 4) + 60);
-        debug!("Added new symbol to table: {:?} with offset {:?}", symbol, (self.current_instruction * 4) + 60);
+        // If we make it here, it isn't a symbol we've seen before, so stick it in the table. The offset is relative to the start of
+        // the code for now, and gets fixed up at the end of the first phase.
+        let symbol = Symbol::new_with_offset(name, SymbolType::Label, self.code_offset);
+        debug!("Added new symbol to table: {:?} with offset {:?}", symbol, self.code_offset);
         self.symbols.add_symbol(symbol);
     }
 
@@ -242,6 +548,9 @@ impl Assembler {
                 "integer" => {
                     self.handle_integer(i);
                 }
+                "global" => {
+                    self.handle_global(i);
+                }
                 _ => {
                     self.errors.push(AssemblerError::UnknownDirectiveFound {
                         directive: directive_name.clone(),
@@ -268,11 +577,18 @@ impl Assembler {
                 match i.get_label_name() {
                     Some(name) => {
                         self.symbols.set_symbol_offset(&name, self.ro_offset);
+                        self.symbols.set_symbol_type(&name, SymbolType::IrString);
+                        if let Some(ref mut listing) = self.listing {
+                            // The extra byte is the null terminator
+                            listing.add_data(&name, self.ro_offset, s.len() as u32 + 1, SymbolType::IrString);
+                        }
                     }
                     None => {
                         // This would be someone typing:
                         // .asciiz 'Hello'
-                        println!("Found a string constant with no associated label!");
+                        self.errors.push(AssemblerError::StringConstantDeclaredWithoutLabel {
+                            instruction: self.current_instruction,
+                        });
                         return;
                     }
                 };
@@ -286,8 +602,11 @@ impl Assembler {
                 self.ro_offset += 1;
             }
             None => {
-                // This just means someone typed `.asciiz` for some reason
-                println!("String constant following an .asciiz was empty");
+                // The operand wasn't a string, as in `.asciiz #5`
+                self.errors.push(AssemblerError::InvalidOperands {
+                    instruction: self.current_instruction,
+                    mnemonic: ".asciiz".to_string(),
+                });
             }
         }
     }
@@ -304,11 +623,17 @@ impl Assembler {
                 match i.get_label_name() {
                     Some(name) => {
                         self.symbols.set_symbol_offset(&name, self.ro_offset);
+                        self.symbols.set_symbol_type(&name, SymbolType::Integer);
+                        if let Some(ref mut listing) = self.listing {
+                            listing.add_data(&name, self.ro_offset, 4, SymbolType::Integer);
+                        }
                     }
                     None => {
                         // This would be someone typing:
-                        // .integer 50
-                        println!("Found a string constant with no associated label!");
+                        // .integer #50
+                        self.errors.push(AssemblerError::StringConstantDeclaredWithoutLabel {
+                            instruction: self.current_instruction,
+                        });
                         return;
                     }
                 };
@@ -321,19 +646,39 @@ impl Assembler {
                 }
             }
             None => {
-                // This just means someone typed `.asciiz` for some reason
-                println!("integer constant following an .integer was empty");
+                // The operand wasn't an integer, as in `.integer 'five'`
+                self.errors.push(AssemblerError::InvalidOperands {
+                    instruction: self.current_instruction,
+                    mnemonic: ".integer".to_string(),
+                });
             }
         }
     }
 
+    /// Handles marking a label as visible to other modules when linking:
+    /// .global @main
+    fn handle_global(&mut self, i: &AssemblerInstruction) {
+        if self.phase != AssemblerPhase::First {
+            return;
+        }
+        match i.operand1 {
+            Some(Token::LabelUsage { ref name }) => self.globals.push(name.clone()),
+            _ => self.errors.push(AssemblerError::InvalidOperands {
+                instruction: self.current_instruction,
+                mnemonic: ".global".to_string(),
+            }),
+        }
+    }
+
     /// Handles a declaration of a section header, such as:
     /// .code
     fn process_section_header(&mut self, header_name: &str) {
         let mut new_section: AssemblerSection = header_name.into();
         // Only specific section names are allowed
         if new_section == AssemblerSection::Unknown {
-            println!("Found an section header that is unknown: {:#?}", header_name);
+            self.errors.push(AssemblerError::UnknownDirectiveFound {
+                directive: header_name.to_string(),
+            });
             return;
         }
 
@@ -356,23 +701,46 @@ impl Assembler {
 
     /// Convenience function to write the executable header
     fn write_pie_header(&self) -> Vec<u8> {
-        let mut header = vec![];
-        for byte in &PIE_HEADER_PREFIX {
-            header.push(byte.clone());
-        }
+        pie_header(self.ro.len() as u32, natives_table(&self.natives).len() as u32)
+    }
+}
 
-        // Now pad the rest of the bytecode header
-        while header.len() < PIE_HEADER_LENGTH {
-            header.push(0 as u8);
-        }
+/// Whether `bytes` starts with an executable's header, as opposed to being source or an object file
+pub fn is_pie(bytes: &[u8]) -> bool {
+    bytes.len() >= PIE_HEADER_LENGTH + 4 && bytes[0..4] == PIE_HEADER_PREFIX
+}
+
+/// Builds the header of an executable whose read-only section is `ro_length` bytes long, and whose native
+/// import table is `natives_length` bytes long
+pub fn pie_header(ro_length: u32, natives_length: u32) -> Vec<u8> {
+    let mut header = vec![];
+    for byte in &PIE_HEADER_PREFIX {
+        header.push(*byte);
+    }
+    header.write_u32::<LittleEndian>(natives_length).unwrap();
+
+    // Now pad the rest of the bytecode header
+    while header.len() < PIE_HEADER_LENGTH {
+        header.push(0);
+    }
+
+    // Now we need to calculate the starting offset so that the VM knows where the RO section ends
+    let mut wtr: Vec<u8> = vec![];
+    wtr.write_u32::<LittleEndian>(ro_length).unwrap();
+    header.append(&mut wtr);
 
-        // Now we need to calculate the starting offset so that the VM knows where the RO section ends
-        let mut wtr: Vec<u8> = vec![];
-        wtr.write_u32::<LittleEndian>(self.ro.len() as u32).unwrap();
-        header.append(&mut wtr);
+    header
+}
 
-        header
+/// Encodes the names of the native functions a program imports, each followed by a null byte. The index of a
+/// name in the table is what `CALLN` is assembled with.
+pub fn natives_table(names: &[String]) -> Vec<u8> {
+    let mut table = vec![];
+    for name in names {
+        table.extend_from_slice(name.as_bytes());
+        table.push(0);
     }
+    table
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -434,7 +802,7 @@ mod tests {
         let mut vm = VM::new();
         assert_eq!(program.len(), 96);
         vm.add_bytes(program);
-        assert_eq!(vm.program.len(), 96);
+        assert_eq!(vm.program().len(), 96);
     }
 
     #[test]
@@ -539,4 +907,100 @@ mod tests {
         asm.process_first_phase(&mut p);
         assert_eq!(asm.errors.len(), 0);
     }
+
+    #[test]
+    /// Tests that labels point at the right place when there is data in the read-only section
+    fn test_labels_after_ro_data() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+        .data
+        hello: .asciiz 'Hello'
+        count: .integer #3
+        .code
+        load $0 #1
+        jmp @skip
+        load $0 #2
+        skip: hlt
+        ";
+        let program = asm.assemble(test_string).unwrap();
+        // Header, then 10 bytes of read-only data, then the code
+        assert_eq!(&program[68..74], b"Hello\0");
+        assert_eq!(asm.symbols.symbol_value("skip"), Some(68 + 10 + 12));
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(vm.registers()[0], 1);
+    }
+
+    #[test]
+    /// Tests that relative jumps to labels are encoded as distances from the next instruction
+    fn test_relative_jumps_to_labels() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+        .data
+        .code
+        jmpf @forward
+        back: load $1 #7
+        hlt
+        forward: load $0 #3
+        jmpb @back
+        ";
+        let program = asm.assemble(test_string).unwrap();
+        assert_eq!(&program[68..72], &[u8::from(Opcode::DJMPF), 0, 8, 0]);
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        assert_eq!(vm.registers()[0], 3);
+        assert_eq!(vm.registers()[1], 7);
+    }
+
+    #[test]
+    /// Tests that labels that can't be encoded are reported
+    fn test_bad_label_usages() {
+        let mut asm = Assembler::new();
+        assert!(asm.assemble(".data\n.code\njmp @nowhere\nhlt").is_err());
+        let mut asm = Assembler::new();
+        let result = asm.assemble(".data\n.code\nback: hlt\njmpf @back");
+        match result {
+            Err(errors) => match errors[0] {
+                AssemblerError::LabelOutOfRange { .. } => {}
+                _ => panic!("Expected a LabelOutOfRange error"),
+            },
+            Ok(_) => panic!("Expected a backwards jmpf to fail"),
+        }
+    }
+
+    #[test]
+    /// Tests that the native functions CALLN names end up in the import table, which comes before the code
+    fn test_native_imports() {
+        let mut asm = Assembler::new();
+        let test_string = r"
+        .data
+        .code
+        calln $0 @double
+        calln $1 @print
+        calln $0 @double
+        done: hlt
+        ";
+        let program = asm.assemble(test_string).unwrap();
+        assert_eq!(&program[4..8], &[13, 0, 0, 0]);
+        assert_eq!(&program[68..81], b"double\0print\0");
+        assert_eq!(&program[81..85], &[u8::from(Opcode::CALLN), 0, 0, 0]);
+        assert_eq!(&program[85..89], &[u8::from(Opcode::CALLN), 1, 0, 1]);
+        assert_eq!(&program[89..93], &[u8::from(Opcode::CALLN), 0, 0, 0]);
+        assert_eq!(asm.symbols.symbol_value("done"), Some(81 + 12));
+
+        let mut asm = Assembler::new();
+        assert!(asm.assemble(".data\n.code\ncalln @double\nhlt").is_err());
+    }
+
+    #[test]
+    /// Tests that a native only called from code the optimizer removes isn't imported
+    fn test_unreachable_native_not_imported() {
+        let mut asm = Assembler::new().with_optimizations();
+        let program = asm.assemble(".data\n.code\ncalln $0 @double\nhlt\ncalln $1 @print\n").unwrap();
+        assert_eq!(&program[4..8], &[7, 0, 0, 0]);
+        assert_eq!(&program[68..75], b"double\0");
+        assert_eq!(program.len(), 75 + 8);
+    }
 }
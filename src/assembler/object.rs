@@ -0,0 +1,104 @@
+//! Relocatable object files, which let modules be assembled separately and combined by the linker
+
+use bincode;
+
+/// Magic number that begins every object file. These spell out EOBJ in ASCII.
+pub const OBJECT_HEADER_PREFIX: [u8; 4] = [0x45, 0x4F, 0x42, 0x4A];
+
+/// Which section of a module a symbol lives in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Section {
+    Code,
+    Data,
+}
+
+/// A symbol defined by a module. Offsets are relative to the start of the symbol's section in this module.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ObjectSymbol {
+    pub name: String,
+    pub section: Section,
+    pub offset: u32,
+    /// Whether other modules may refer to it, i.e. it was declared with `.global`
+    pub exported: bool,
+}
+
+/// How the 16 bits at a relocation are filled in once the symbol's final address is known
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RelocationKind {
+    /// The symbol's address
+    Absolute,
+    /// The distance from the start of the next instruction forward to the symbol, as used by `DJMPF`
+    RelativeForward,
+    /// The distance from the start of the next instruction back to the symbol, as used by `DJMPB`
+    RelativeBackward,
+    /// The index of the native function named by the symbol in the program's import table, as used by `CALLN`
+    Native,
+}
+
+/// A label operand that has to be patched by the linker
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Relocation {
+    /// Offset of the instruction from the start of this module's code
+    pub offset: u32,
+    /// Byte within the instruction where the 16-bit operand starts
+    pub position: u8,
+    pub symbol: String,
+    pub kind: RelocationKind,
+}
+
+/// The output of assembling a single module without linking it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ObjectFile {
+    pub code: Vec<u8>,
+    pub ro: Vec<u8>,
+    pub symbols: Vec<ObjectSymbol>,
+    /// Symbols this module uses but does not define
+    pub imports: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl ObjectFile {
+    /// Serializes the object file, prefixed with `OBJECT_HEADER_PREFIX`
+    pub fn to_bytes(&self) -> bincode::Result<Vec<u8>> {
+        let mut results = OBJECT_HEADER_PREFIX.to_vec();
+        results.append(&mut bincode::serialize(self)?);
+        Ok(results)
+    }
+
+    /// Deserializes an object file. Returns `None` if the bytes aren't one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<ObjectFile> {
+        if bytes.len() < OBJECT_HEADER_PREFIX.len() || bytes[0..4] != OBJECT_HEADER_PREFIX {
+            return None;
+        }
+        bincode::deserialize(&bytes[4..]).ok()
+    }
+
+    /// Returns the symbol with the given name, if this module defines it
+    pub fn symbol(&self, name: &str) -> Option<&ObjectSymbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    pub fn exports(&self) -> Vec<&ObjectSymbol> {
+        self.symbols.iter().filter(|s| s.exported).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::Assembler;
+
+    #[test]
+    fn test_object_round_trip() {
+        let mut asm = Assembler::new();
+        let object = asm.assemble_object(".data\n.code\n.global @main\nmain: call @helper\nhlt").unwrap();
+        assert_eq!(object.imports, vec!["helper".to_string()]);
+        assert_eq!(object.exports().len(), 1);
+        assert_eq!(object.relocations.len(), 1);
+        assert_eq!(object.relocations[0].position, 1);
+        let bytes = object.to_bytes().unwrap();
+        assert_eq!(bytes[0..4], OBJECT_HEADER_PREFIX);
+        assert_eq!(ObjectFile::from_bytes(&bytes), Some(object));
+        assert_eq!(ObjectFile::from_bytes(&[0x45, 0x50, 0x49, 0x45]), None);
+    }
+}
@@ -1,16 +1,22 @@
+use assembler::pseudo_instructions::PseudoOpcode;
 use assembler::Token;
 use instruction::Opcode;
 use nom::types::CompleteStr;
 use nom::*;
 
 named!(pub opcode<CompleteStr, Token>,
-  do_parse!(
-      opcode: alpha1 >>
-      (
-        {
-            Token::Op{code: Opcode::from(opcode)}
-        }
-      )
+  ws!(
+    do_parse!(
+        opcode: alpha1 >>
+        (
+          {
+              match PseudoOpcode::from_mnemonic(opcode) {
+                  Some(code) => Token::PseudoOp{code},
+                  None => Token::Op{code: Opcode::from(opcode)},
+              }
+          }
+        )
+    )
   )
 );
 
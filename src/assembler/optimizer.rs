@@ -0,0 +1,347 @@
+//! Peephole optimizations over the list of `AssemblerInstruction`s. These run after pseudo-instructions
+//! have been expanded and before any bytecode is generated. Instructions with a label are treated as
+//! places execution can arrive from elsewhere, so no pattern is matched across one.
+
+use assembler::instruction_parsers::AssemblerInstruction;
+use assembler::Token;
+use instruction::Opcode;
+
+/// The most jumps we will follow when threading a jump to a jump, in case they form a loop
+const MAX_JUMP_THREADING_DEPTH: usize = 16;
+
+/// Runs every optimization over `instructions` until none of them change anything
+pub fn optimize(instructions: &mut Vec<AssemblerInstruction>) {
+    loop {
+        let mut changed = remove_nops(instructions);
+        changed |= remove_dead_code(instructions);
+        changed |= fold_constant_adds(instructions);
+        changed |= collapse_inc_dec(instructions);
+        changed |= remove_push_pop_pairs(instructions);
+        changed |= thread_jumps(instructions);
+        if !changed {
+            break;
+        }
+    }
+}
+
+fn opcode(i: &AssemblerInstruction) -> Option<Opcode> {
+    match i.opcode {
+        Some(Token::Op { code }) => Some(code),
+        _ => None,
+    }
+}
+
+fn register(t: &Option<Token>) -> Option<u8> {
+    match t {
+        Some(Token::Register { reg_num }) => Some(*reg_num),
+        _ => None,
+    }
+}
+
+fn integer(t: &Option<Token>) -> Option<i32> {
+    match t {
+        Some(Token::IntegerOperand { value }) => Some(*value),
+        _ => None,
+    }
+}
+
+fn label_usage(t: &Option<Token>) -> Option<&str> {
+    match t {
+        Some(Token::LabelUsage { name }) => Some(name),
+        _ => None,
+    }
+}
+
+/// Returns the register and value of a `LOAD $r #value`
+fn constant_load(i: &AssemblerInstruction) -> Option<(u8, i32)> {
+    if opcode(i) != Some(Opcode::LOAD) {
+        return None;
+    }
+    Some((register(&i.operand1)?, integer(&i.operand2)?))
+}
+
+/// Whether execution never falls through to the instruction after `i`
+fn is_unconditional_exit(i: &AssemblerInstruction) -> bool {
+    match opcode(i) {
+        Some(code) => matches!(
+            code,
+            Opcode::HLT | Opcode::EXIT | Opcode::RET | Opcode::IGL | Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::DJMP | Opcode::DJMPF | Opcode::DJMPB
+        ),
+        None => false,
+    }
+}
+
+/// Removes instruction `idx`, handing its label to the next instruction. Returns false if that isn't
+/// possible because there is no next instruction or it already has a label of its own.
+fn remove_instruction(instructions: &mut Vec<AssemblerInstruction>, idx: usize) -> bool {
+    if instructions[idx].is_label() {
+        match instructions.get(idx + 1) {
+            Some(next) if !next.is_label() && !next.is_directive() => {}
+            _ => return false,
+        }
+        instructions[idx + 1].label = instructions[idx].label.take();
+    }
+    instructions.remove(idx);
+    true
+}
+
+/// Removes the two instructions starting at `idx`, handing the label of the first to whatever follows
+/// them. Returns false, leaving both in place, if that isn't possible or the second has a label.
+fn remove_pair(instructions: &mut Vec<AssemblerInstruction>, idx: usize) -> bool {
+    if instructions[idx + 1].is_label() {
+        return false;
+    }
+    let second = instructions.remove(idx + 1);
+    if !remove_instruction(instructions, idx) {
+        instructions.insert(idx + 1, second);
+        return false;
+    }
+    true
+}
+
+fn remove_nops(instructions: &mut Vec<AssemblerInstruction>) -> bool {
+    let mut changed = false;
+    let mut idx = 0;
+    while idx < instructions.len() {
+        if opcode(&instructions[idx]) == Some(Opcode::NOP) && remove_instruction(instructions, idx) {
+            changed = true;
+            continue;
+        }
+        idx += 1;
+    }
+    changed
+}
+
+/// Removes instructions after a `HLT`, `RET` or unconditional jump that nothing can jump to
+fn remove_dead_code(instructions: &mut Vec<AssemblerInstruction>) -> bool {
+    let mut changed = false;
+    let mut idx = 0;
+    while idx < instructions.len() {
+        if is_unconditional_exit(&instructions[idx]) {
+            while let Some(next) = instructions.get(idx + 1) {
+                if next.is_label() || !next.is_opcode() {
+                    break;
+                }
+                instructions.remove(idx + 1);
+                changed = true;
+            }
+        }
+        idx += 1;
+    }
+    changed
+}
+
+/// `LOAD $a #x`, `LOAD $b #y`, `ADD $a $b $c` becomes `LOAD $c #x+y`, and a `LOAD` of $a or $b is
+/// dropped if the result overwrites it. `LOAD $a #x`, `ADD $a $a $c` is handled the same way.
+fn fold_constant_adds(instructions: &mut Vec<AssemblerInstruction>) -> bool {
+    let mut changed = false;
+    let mut idx = 0;
+    while idx + 1 < instructions.len() {
+        let (reg_a, x) = match constant_load(&instructions[idx]) {
+            Some(load) => load,
+            None => {
+                idx += 1;
+                continue;
+            }
+        };
+        // The second load is optional, for when the same register is added to itself
+        let (add_idx, second) = match constant_load(&instructions[idx + 1]) {
+            Some(load) if !instructions[idx + 1].is_label() && load.0 != reg_a => (idx + 2, Some(load)),
+            _ => (idx + 1, None),
+        };
+        let add = match instructions.get(add_idx) {
+            Some(add) if opcode(add) == Some(Opcode::ADD) && !add.is_label() => add,
+            _ => {
+                idx += 1;
+                continue;
+            }
+        };
+        let (reg_1, reg_2, reg_c) = match (register(&add.operand1), register(&add.operand2), register(&add.operand3)) {
+            (Some(r1), Some(r2), Some(r3)) => (r1, r2, r3),
+            _ => {
+                idx += 1;
+                continue;
+            }
+        };
+        let sum = match second {
+            Some((reg_b, y)) if (reg_1, reg_2) == (reg_a, reg_b) || (reg_1, reg_2) == (reg_b, reg_a) => i64::from(x) + i64::from(y),
+            None if reg_1 == reg_a && reg_2 == reg_a => 2 * i64::from(x),
+            _ => {
+                idx += 1;
+                continue;
+            }
+        };
+        // Only fold if the result still fits in a single `LOAD`
+        if sum < 0 || sum > i64::from(u16::MAX) {
+            idx += 1;
+            continue;
+        }
+        instructions[add_idx].opcode = Some(Token::Op { code: Opcode::LOAD });
+        instructions[add_idx].operand1 = Some(Token::Register { reg_num: reg_c });
+        instructions[add_idx].operand2 = Some(Token::IntegerOperand { value: sum as i32 });
+        instructions[add_idx].operand3 = None;
+        // Remove whichever loads the result overwrote, last first so the indexes stay valid
+        if let Some((reg_b, _)) = second {
+            if reg_b == reg_c {
+                instructions.remove(idx + 1);
+            }
+        }
+        if reg_a == reg_c {
+            remove_instruction(instructions, idx);
+        }
+        changed = true;
+        idx += 1;
+    }
+    changed
+}
+
+/// Cancels out `INC $r` and `DEC $r` that are next to each other
+fn collapse_inc_dec(instructions: &mut Vec<AssemblerInstruction>) -> bool {
+    let mut changed = false;
+    let mut idx = 0;
+    while idx + 1 < instructions.len() {
+        let (first, second) = (&instructions[idx], &instructions[idx + 1]);
+        let opposite = matches!(
+            (opcode(first), opcode(second)),
+            (Some(Opcode::INC), Some(Opcode::DEC)) | (Some(Opcode::DEC), Some(Opcode::INC))
+        );
+        if opposite && register(&first.operand1).is_some() && register(&first.operand1) == register(&second.operand1) && remove_pair(instructions, idx) {
+            changed = true;
+            // Step back, as the instructions on either side may now cancel out too
+            idx = idx.saturating_sub(1);
+            continue;
+        }
+        idx += 1;
+    }
+    changed
+}
+
+/// Removes `PUSH $r` immediately followed by `POP $r`
+fn remove_push_pop_pairs(instructions: &mut Vec<AssemblerInstruction>) -> bool {
+    let mut changed = false;
+    let mut idx = 0;
+    while idx + 1 < instructions.len() {
+        let (first, second) = (&instructions[idx], &instructions[idx + 1]);
+        if opcode(first) == Some(Opcode::PUSH)
+            && opcode(second) == Some(Opcode::POP)
+            && register(&first.operand1).is_some()
+            && register(&first.operand1) == register(&second.operand1)
+            && remove_pair(instructions, idx)
+        {
+            changed = true;
+            idx = idx.saturating_sub(1);
+            continue;
+        }
+        idx += 1;
+    }
+    changed
+}
+
+/// Follows a jump to an unconditional `JMP @label` and points the first jump at the final target.
+/// Relative jumps are left alone, as the final target could be in the wrong direction for them.
+fn thread_jumps(instructions: &mut [AssemblerInstruction]) -> bool {
+    let mut changed = false;
+    for idx in 0..instructions.len() {
+        match opcode(&instructions[idx]) {
+            Some(Opcode::JMP) | Some(Opcode::DJMP) | Some(Opcode::JMPE) | Some(Opcode::DJMPE) | Some(Opcode::LOOP) => {}
+            _ => continue,
+        }
+        let mut target = match label_usage(&instructions[idx].operand1) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let original = target.clone();
+        for _ in 0..MAX_JUMP_THREADING_DEPTH {
+            let next = instructions
+                .iter()
+                .find(|i| i.get_label_name().as_ref() == Some(&target))
+                .filter(|i| opcode(i) == Some(Opcode::JMP) || opcode(i) == Some(Opcode::DJMP))
+                .and_then(|i| label_usage(&i.operand1));
+            match next {
+                Some(name) if name != original => target = name.to_string(),
+                _ => break,
+            }
+        }
+        if target != original {
+            instructions[idx].operand1 = Some(Token::LabelUsage { name: target });
+            changed = true;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::program_parsers::program;
+    use assembler::Assembler;
+    use nom::types::CompleteStr;
+    use vm::VM;
+
+    fn optimized(code: &str) -> Vec<String> {
+        let (_, mut p) = program(CompleteStr(code)).unwrap();
+        optimize(&mut p.instructions);
+        p.instructions.iter().map(|i| i.to_source()).collect()
+    }
+
+    #[test]
+    fn test_remove_nops_keeps_labels() {
+        assert_eq!(optimized("nop\nstart: nop\ninc $0\nhlt\n"), vec!["start: INC $0", "HLT"]);
+    }
+
+    #[test]
+    fn test_remove_dead_code() {
+        assert_eq!(optimized("hlt\ninc $0\ndec $1\nnext: ret\ninc $2\n"), vec!["HLT", "next: RET"]);
+    }
+
+    #[test]
+    fn test_fold_constant_adds() {
+        assert_eq!(
+            optimized("load $0 #2\nload $1 #3\nadd $0 $1 $0\nhlt\n"),
+            vec!["LOAD $1 #3", "LOAD $0 #5", "HLT"]
+        );
+        assert_eq!(optimized("load $0 #2\nadd $0 $0 $4\nhlt\n"), vec!["LOAD $0 #2", "LOAD $4 #4", "HLT"]);
+        // A label on the ADD means it can be reached with other values in the registers
+        assert_eq!(optimized("load $0 #2\nx: add $0 $0 $0\nhlt\n").len(), 3);
+    }
+
+    #[test]
+    fn test_collapse_inc_dec_and_push_pop() {
+        assert_eq!(
+            optimized("inc $0\ninc $0\ndec $0\ndec $1\npush $2\npop $2\nhlt\n"),
+            vec!["INC $0", "DEC $1", "HLT"]
+        );
+    }
+
+    #[test]
+    fn test_thread_jumps() {
+        assert_eq!(optimized("jmp @a\na: jmp @b\nb: hlt\n"), vec!["JMP @b", "a: JMP @b", "b: HLT"]);
+        // Loops of jumps must not hang the optimizer
+        assert_eq!(optimized("a: jmp @b\nb: jmp @a\n").len(), 2);
+    }
+
+    #[test]
+    fn test_optimized_program_runs() {
+        let code = r"
+        .data
+        .code
+        load $0 #2
+        load $1 #3
+        add $0 $1 $2
+        nop
+        jmp @skip
+        inc $2
+        skip: push $2
+        pop $2
+        hlt
+        ";
+        let mut asm = Assembler::new().with_optimizations();
+        let optimized = asm.assemble(code).unwrap();
+        let unoptimized = Assembler::new().assemble(code).unwrap();
+        assert!(optimized.len() < unoptimized.len());
+        let mut vm = VM::new();
+        vm.add_bytes(optimized);
+        vm.run();
+        assert_eq!(vm.registers()[2], 5);
+    }
+}
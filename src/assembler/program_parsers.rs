@@ -1,3 +1,4 @@
+use assembler::comment_parsers::{comment, comment_texts};
 use assembler::directive_parsers::directive;
 use assembler::instruction_parsers::{instruction, AssemblerInstruction};
 use assembler::SymbolTable;
@@ -6,6 +7,8 @@ use nom::types::CompleteStr;
 #[derive(Debug, PartialEq)]
 pub struct Program {
     pub instructions: Vec<AssemblerInstruction>,
+    /// Comments after the last instruction
+    pub comments: Vec<String>,
 }
 
 impl Program {
@@ -21,9 +24,11 @@ impl Program {
 named!(pub program<CompleteStr, Program>,
     do_parse!(
         instructions: many1!(alt!(instruction | directive)) >>
+        comments: many0!(comment) >>
         (
             Program {
-                instructions
+                instructions,
+                comments: comment_texts(comments),
             }
         )
     )
@@ -69,7 +74,7 @@ mod tests {
         let result = test_assembler.assemble(".data\n.code\nload $0 #-50000");
         assert!(result.is_ok());
         let result = result.unwrap();
-        test_vm.program = result;
+        test_vm.add_bytes(result);
         test_vm.run();
     }
 
@@ -80,7 +85,7 @@ mod tests {
         let result = test_assembler.assemble(".data\n.code\ncloop #10");
         assert!(result.is_ok());
         let result = result.unwrap();
-        test_vm.program = result;
+        test_vm.add_bytes(result);
         test_vm.run();
     }
 }
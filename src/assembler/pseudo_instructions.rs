@@ -0,0 +1,280 @@
+//! Pseudo-instructions are convenience mnemonics that the assembler expands into one or more real
+//! opcodes during the first phase, before label offsets are calculated.
+
+use nom::types::CompleteStr;
+
+use assembler::instruction_parsers::AssemblerInstruction;
+use assembler::Token;
+use instruction::Opcode;
+
+/// Prefix for the names of the stubs the assembler appends for `CALLR`
+const CALLR_STUB_PREFIX: &str = "__callr";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PseudoOpcode {
+    /// `MOV $a $b` copies $b into $a
+    MOV,
+    /// `LI $r #value` loads any 32-bit value into $r
+    LI,
+    /// `BEQ $a $b @label` branches if $a == $b
+    BEQ,
+    /// `BLT $a $b @label` branches if $a < $b
+    BLT,
+    /// `BGT $a $b @label` branches if $a > $b
+    BGT,
+    /// `CALLR $r` calls the subroutine whose address is in $r
+    CALLR,
+    /// `NEG $r` negates $r
+    NEG,
+    /// `CLR $r` sets $r to 0
+    CLR,
+}
+
+impl PseudoOpcode {
+    /// Returns the pseudo-opcode for a mnemonic, if it is one
+    pub fn from_mnemonic(v: CompleteStr) -> Option<PseudoOpcode> {
+        match v.to_lowercase().as_ref() {
+            "mov" => Some(PseudoOpcode::MOV),
+            "li" => Some(PseudoOpcode::LI),
+            "beq" => Some(PseudoOpcode::BEQ),
+            "blt" => Some(PseudoOpcode::BLT),
+            "bgt" => Some(PseudoOpcode::BGT),
+            "callr" => Some(PseudoOpcode::CALLR),
+            "neg" => Some(PseudoOpcode::NEG),
+            "clr" => Some(PseudoOpcode::CLR),
+            _ => None,
+        }
+    }
+}
+
+fn op(code: Opcode, operand1: Option<Token>, operand2: Option<Token>, operand3: Option<Token>) -> AssemblerInstruction {
+    AssemblerInstruction {
+        opcode: Some(Token::Op { code }),
+        label: None,
+        directive: None,
+        operand1,
+        operand2,
+        operand3,
+        comments: vec![],
+        inline_comment: None,
+    }
+}
+
+fn register(reg_num: u8) -> Option<Token> {
+    Some(Token::Register { reg_num })
+}
+
+fn integer(value: i32) -> Option<Token> {
+    Some(Token::IntegerOperand { value })
+}
+
+fn is_register(t: &Option<Token>) -> bool {
+    matches!(t, Some(Token::Register { .. }))
+}
+
+fn is_label_usage(t: &Option<Token>) -> bool {
+    matches!(t, Some(Token::LabelUsage { .. }))
+}
+
+/// Builds the instructions that put an arbitrary 32-bit value into a register. `LOAD` only carries
+/// 16 bits, zero-extended, so anything else is loaded as the upper half and then shifted up by `LUI`.
+fn load_immediate(reg_num: u8, value: i32) -> Vec<AssemblerInstruction> {
+    if value >= 0 && value <= i32::from(u16::MAX) {
+        return vec![op(Opcode::LOAD, register(reg_num), integer(value), None)];
+    }
+    let upper = (value >> 16) & 0xFFFF;
+    let lower = value & 0xFFFF;
+    vec![
+        op(Opcode::LOAD, register(reg_num), integer(upper), None),
+        op(Opcode::LUI, register(reg_num), integer(lower), None),
+    ]
+}
+
+/// Returns the name of the stub `CALLR $reg_num` calls through
+pub fn callr_stub_name(reg_num: u8) -> String {
+    format!("{}{}", CALLR_STUB_PREFIX, reg_num)
+}
+
+/// Builds the stub that `CALLR` calls. `CALL` pushes the return address and jumps here, and this jumps
+/// on to the real subroutine, whose `RET` then returns straight to the caller.
+pub fn callr_stub(reg_num: u8) -> AssemblerInstruction {
+    let mut stub = op(Opcode::JMP, register(reg_num), None, None);
+    stub.label = Some(Token::LabelDeclaration {
+        name: callr_stub_name(reg_num),
+    });
+    stub
+}
+
+/// Expands `i` into the real instructions it stands for. Instructions that need no expansion are
+/// returned unchanged. Returns `Err` with the mnemonic if the operands don't fit the pseudo-instruction.
+pub fn expand(i: AssemblerInstruction) -> Result<Vec<AssemblerInstruction>, String> {
+    let label = i.label.clone();
+    let mut expanded = match i.opcode {
+        Some(Token::PseudoOp { code }) => expand_pseudo(code, &i)?,
+        Some(Token::Op { code: Opcode::LOAD }) => match (&i.operand1, &i.operand2) {
+            (Some(Token::Register { reg_num }), Some(Token::IntegerOperand { value })) => load_immediate(*reg_num, *value),
+            _ => vec![i],
+        },
+        _ => vec![i],
+    };
+    // Whatever label pointed at the pseudo-instruction now points at the first real one
+    if let Some(first) = expanded.first_mut() {
+        first.label = label;
+    }
+    Ok(expanded)
+}
+
+fn expand_pseudo(code: PseudoOpcode, i: &AssemblerInstruction) -> Result<Vec<AssemblerInstruction>, String> {
+    let invalid = || format!("{:?}", code);
+    match code {
+        PseudoOpcode::MOV => {
+            if !is_register(&i.operand1) || !is_register(&i.operand2) || i.operand3.is_some() {
+                return Err(invalid());
+            }
+            Ok(vec![op(Opcode::OR, i.operand2.clone(), i.operand2.clone(), i.operand1.clone())])
+        }
+        PseudoOpcode::LI => match (&i.operand1, &i.operand2, &i.operand3) {
+            (Some(Token::Register { reg_num }), Some(Token::IntegerOperand { value }), None) => Ok(load_immediate(*reg_num, *value)),
+            _ => Err(invalid()),
+        },
+        PseudoOpcode::BEQ | PseudoOpcode::BLT | PseudoOpcode::BGT => {
+            if !is_register(&i.operand1) || !is_register(&i.operand2) || !is_label_usage(&i.operand3) {
+                return Err(invalid());
+            }
+            let comparison = match code {
+                PseudoOpcode::BEQ => Opcode::EQ,
+                PseudoOpcode::BLT => Opcode::LT,
+                _ => Opcode::GT,
+            };
+            Ok(vec![
+                op(comparison, i.operand1.clone(), i.operand2.clone(), None),
+                op(Opcode::DJMPE, i.operand3.clone(), None, None),
+            ])
+        }
+        PseudoOpcode::CALLR => match (&i.operand1, &i.operand2) {
+            (Some(Token::Register { reg_num }), None) => Ok(vec![op(
+                Opcode::CALL,
+                Some(Token::LabelUsage {
+                    name: callr_stub_name(*reg_num),
+                }),
+                None,
+                None,
+            )]),
+            _ => Err(invalid()),
+        },
+        PseudoOpcode::NEG => {
+            if !is_register(&i.operand1) || i.operand2.is_some() {
+                return Err(invalid());
+            }
+            Ok(vec![
+                op(Opcode::NOT, i.operand1.clone(), i.operand1.clone(), None),
+                op(Opcode::INC, i.operand1.clone(), None, None),
+            ])
+        }
+        PseudoOpcode::CLR => {
+            if !is_register(&i.operand1) || i.operand2.is_some() {
+                return Err(invalid());
+            }
+            Ok(vec![op(Opcode::XOR, i.operand1.clone(), i.operand1.clone(), i.operand1.clone())])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::instruction_parsers::instruction;
+    use assembler::Assembler;
+    use vm::VM;
+
+    fn parse(s: &str) -> AssemblerInstruction {
+        instruction(CompleteStr(s)).unwrap().1
+    }
+
+    fn run(code: &str) -> VM {
+        let mut asm = Assembler::new();
+        let program = asm.assemble(code).unwrap();
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        vm.run();
+        vm
+    }
+
+    #[test]
+    fn test_parse_pseudo_opcode() {
+        let i = parse("mov $1 $2\n");
+        assert_eq!(i.opcode, Some(Token::PseudoOp { code: PseudoOpcode::MOV }));
+    }
+
+    #[test]
+    fn test_expand_keeps_label() {
+        let expanded = expand(parse("test: neg $1\n")).unwrap();
+        assert_eq!(expanded.len(), 2);
+        assert_eq!(expanded[0].get_label_name(), Some("test".to_string()));
+        assert!(expanded[1].label.is_none());
+    }
+
+    #[test]
+    fn test_expand_bad_operands() {
+        assert!(expand(parse("mov $1 #2\n")).is_err());
+        assert!(expand(parse("beq $1 $2 $3\n")).is_err());
+    }
+
+    #[test]
+    fn test_load_immediate() {
+        assert_eq!(load_immediate(0, 500).len(), 1);
+        assert_eq!(load_immediate(0, 65535).len(), 1);
+        assert_eq!(load_immediate(0, -1).len(), 2);
+        assert_eq!(load_immediate(0, 70000).len(), 2);
+    }
+
+    #[test]
+    fn test_li_and_mov() {
+        let vm = run(".data\n.code\nli $0 #-50000\nli $1 #123456789\nmov $2 $1\nhlt");
+        assert_eq!(vm.registers()[0], -50000);
+        assert_eq!(vm.registers()[1], 123456789);
+        assert_eq!(vm.registers()[2], 123456789);
+    }
+
+    #[test]
+    fn test_neg_and_clr() {
+        let vm = run(".data\n.code\nload $0 #42\nneg $0\nload $1 #7\nclr $1\nhlt");
+        assert_eq!(vm.registers()[0], -42);
+        assert_eq!(vm.registers()[1], 0);
+    }
+
+    #[test]
+    fn test_branches_and_jumps() {
+        let vm = run(r"
+        .data
+        .code
+        load $0 #5
+        load $1 #5
+        beq $0 $1 @equal
+        load $2 #1
+        hlt
+        equal: load $2 #2
+        blt $0 $1 @wrong
+        jmp @done
+        wrong: load $2 #3
+        done: hlt
+        ");
+        assert_eq!(vm.registers()[2], 2);
+    }
+
+    #[test]
+    fn test_callr() {
+        let vm = run(r"
+        .data
+        .code
+        load $5 @sub
+        callr $5
+        load $4 #1
+        hlt
+        sub: load $3 #9
+        ret
+        ");
+        assert_eq!(vm.registers()[3], 9);
+        assert_eq!(vm.registers()[4], 1);
+    }
+}
@@ -0,0 +1,167 @@
+//! Operand layouts and short descriptions of every mnemonic, used by the disassembler, REPL completion and the
+//! language server
+
+use assembler::pseudo_instructions::PseudoOpcode;
+use instruction::Opcode;
+
+/// The operands a mnemonic takes and what it does. Operands starting with `$` are registers, which take
+/// one byte, and ones starting with `#` or `@` are 16-bit numbers or labels, which take two.
+pub struct Signature {
+    pub operands: &'static [&'static str],
+    pub summary: &'static str,
+}
+
+fn signature(operands: &'static [&'static str], summary: &'static str) -> Signature {
+    Signature { operands, summary }
+}
+
+pub fn opcode_signature(code: Opcode) -> Signature {
+    match code {
+        Opcode::LOAD => signature(&["$reg", "#value"], "Loads a 16-bit number into the register, zero-extended"),
+        Opcode::ADD => signature(&["$a", "$b", "$result"], "Adds $a and $b and puts the result in $result"),
+        Opcode::SUB => signature(&["$a", "$b", "$result"], "Subtracts $b from $a and puts the result in $result"),
+        Opcode::MUL => signature(&["$a", "$b", "$result"], "Multiplies $a by $b and puts the result in $result"),
+        Opcode::DIV => signature(
+            &["$a", "$b", "$result"],
+            "Divides $a by $b, putting the quotient in $result and the remainder in the VM's remainder",
+        ),
+        Opcode::HLT => signature(&[], "Halts execution of the program"),
+        Opcode::JMP => signature(&["$target"], "Jumps to the address in the register. Given a label, assembles to DJMP."),
+        Opcode::JMPF => signature(
+            &["$distance"],
+            "Jumps forward by the number in the register. Given a label, assembles to DJMPF.",
+        ),
+        Opcode::JMPB => signature(
+            &["$distance"],
+            "Jumps backward by the number in the register. Given a label, assembles to DJMPB.",
+        ),
+        Opcode::EQ => signature(&["$a", "$b"], "Sets the equal flag if $a == $b"),
+        Opcode::NEQ => signature(&["$a", "$b"], "Sets the equal flag if $a != $b"),
+        Opcode::GTE => signature(&["$a", "$b"], "Sets the equal flag if $a >= $b"),
+        Opcode::LTE => signature(&["$a", "$b"], "Sets the equal flag if $a <= $b"),
+        Opcode::LT => signature(&["$a", "$b"], "Sets the equal flag if $a < $b"),
+        Opcode::GT => signature(&["$a", "$b"], "Sets the equal flag if $a > $b"),
+        Opcode::JMPE => signature(
+            &["$target"],
+            "Jumps to the address in the register if the equal flag is set. Given a label, assembles to DJMPE.",
+        ),
+        Opcode::NOP => signature(&[], "Does nothing"),
+        Opcode::ALOC => signature(&["$bytes"], "Grows the heap by the number of bytes in the register"),
+        Opcode::INC => signature(&["$reg"], "Adds 1 to the register"),
+        Opcode::DEC => signature(&["$reg"], "Subtracts 1 from the register"),
+        Opcode::DJMPE => signature(&["@target"], "Jumps to the address if the equal flag is set"),
+        Opcode::IGL => signature(&[], "Illegal instruction, which stops the VM"),
+        Opcode::PRTS => signature(&["@string"], "Prints the null-terminated string at this offset in the read-only section"),
+        Opcode::LOADF64 => signature(&["$freg", "#value"], "Loads a 16-bit number into the float register"),
+        Opcode::ADDF64 => signature(&["$fa", "$fb", "$fresult"], "Adds float registers $fa and $fb"),
+        Opcode::SUBF64 => signature(&["$fa", "$fb", "$fresult"], "Subtracts float register $fb from $fa"),
+        Opcode::MULF64 => signature(&["$fa", "$fb", "$fresult"], "Multiplies float registers $fa and $fb"),
+        Opcode::DIVF64 => signature(&["$fa", "$fb", "$fresult"], "Divides float register $fa by $fb"),
+        Opcode::EQF64 => signature(&["$fa", "$fb"], "Sets the equal flag if the float registers are equal"),
+        Opcode::NEQF64 => signature(&["$fa", "$fb"], "Sets the equal flag if the float registers are not equal"),
+        Opcode::GTF64 => signature(&["$fa", "$fb"], "Sets the equal flag if $fa > $fb"),
+        Opcode::GTEF64 => signature(&["$fa", "$fb"], "Sets the equal flag if $fa >= $fb"),
+        Opcode::LTF64 => signature(&["$fa", "$fb"], "Sets the equal flag if $fa < $fb"),
+        Opcode::LTEF64 => signature(&["$fa", "$fb"], "Sets the equal flag if $fa <= $fb"),
+        Opcode::SHL => signature(&["$reg", "$bits"], "Shifts the register left by the operand, or by 16 if it is 0"),
+        Opcode::SHR => signature(&["$reg", "$bits"], "Shifts the register right by the operand, or by 16 if it is 0"),
+        Opcode::AND => signature(&["$a", "$b", "$result"], "Bitwise AND of $a and $b"),
+        Opcode::OR => signature(&["$a", "$b", "$result"], "Bitwise OR of $a and $b"),
+        Opcode::XOR => signature(&["$a", "$b", "$result"], "Bitwise XOR of $a and $b"),
+        Opcode::NOT => signature(&["$a", "$result"], "Bitwise NOT of $a"),
+        Opcode::LUI => signature(&["$reg", "#value"], "Shifts the register left by 16 and puts the number in the lower 16 bits"),
+        Opcode::CLOOP => signature(&["#count"], "Sets the loop counter"),
+        Opcode::LOOP => signature(&["@target"], "Decrements the loop counter and jumps to the address while it isn't 0"),
+        Opcode::LOADM => signature(&["$result", "$offset"], "Loads 32 bits from the heap at the offset in $offset"),
+        Opcode::SETM => signature(&["$offset", "$value"], "Writes $value to the heap at the offset in $offset"),
+        Opcode::PUSH => signature(&["$reg"], "Pushes the register onto the stack"),
+        Opcode::POP => signature(&["$reg"], "Pops the top of the stack into the register"),
+        Opcode::CALL => signature(&["@target"], "Pushes the return address and jumps to the subroutine"),
+        Opcode::RET => signature(&[], "Returns from a subroutine"),
+        Opcode::DJMP => signature(&["@target"], "Jumps to the address"),
+        Opcode::DJMPF => signature(&["@target"], "Jumps forward by the distance from the next instruction"),
+        Opcode::DJMPB => signature(&["@target"], "Jumps backward by the distance from the next instruction"),
+        Opcode::CALLN => signature(
+            &["$first", "@native"],
+            "Calls a native function with arguments from the registers starting at $first, and writes its results back to them",
+        ),
+        Opcode::READ => signature(
+            &["$reg"],
+            "Reads a line of input as a number into the register. Sets the equal flag if one was read.",
+        ),
+        Opcode::SYSCALL => signature(
+            &["#number"],
+            "Makes a system call with arguments from $1, $2 and $3, putting the result in $0 (-1 on failure)",
+        ),
+        Opcode::EXIT => signature(&["$reg"], "Stops the program, with the value of the register as its exit code"),
+    }
+}
+
+pub fn pseudo_signature(code: PseudoOpcode) -> Signature {
+    match code {
+        PseudoOpcode::MOV => signature(&["$to", "$from"], "Copies $from into $to. Expands to OR $from $from $to."),
+        PseudoOpcode::LI => signature(&["$reg", "#value"], "Loads any 32-bit number. Expands to LOAD, or LOAD and LUI."),
+        PseudoOpcode::BEQ => signature(&["$a", "$b", "@target"], "Branches if $a == $b. Expands to EQ and DJMPE."),
+        PseudoOpcode::BLT => signature(&["$a", "$b", "@target"], "Branches if $a < $b. Expands to LT and DJMPE."),
+        PseudoOpcode::BGT => signature(&["$a", "$b", "@target"], "Branches if $a > $b. Expands to GT and DJMPE."),
+        PseudoOpcode::CALLR => signature(&["$target"], "Calls the subroutine at the address in the register"),
+        PseudoOpcode::NEG => signature(&["$reg"], "Negates the register. Expands to NOT and INC."),
+        PseudoOpcode::CLR => signature(&["$reg"], "Sets the register to 0. Expands to XOR."),
+    }
+}
+
+/// Every opcode that can be written in assembly
+pub fn opcodes() -> Vec<Opcode> {
+    (0..=u8::MAX).map(Opcode::from).filter(|code| *code != Opcode::IGL).collect()
+}
+
+pub fn pseudo_opcodes() -> Vec<PseudoOpcode> {
+    vec![
+        PseudoOpcode::MOV,
+        PseudoOpcode::LI,
+        PseudoOpcode::BEQ,
+        PseudoOpcode::BLT,
+        PseudoOpcode::BGT,
+        PseudoOpcode::CALLR,
+        PseudoOpcode::NEG,
+        PseudoOpcode::CLR,
+    ]
+}
+
+/// Renders the instruction's layout in bytecode, e.g. `[0x00] [reg] [value hi] [value lo]`
+pub fn encoding(code: Opcode, operands: &[&str]) -> String {
+    let mut bytes = vec![format!("[{:#04x}]", u8::from(code))];
+    for operand in operands {
+        let name = &operand[1..];
+        if operand.starts_with('$') {
+            bytes.push(format!("[{}]", name));
+        } else {
+            bytes.push(format!("[{} hi]", name));
+            bytes.push(format!("[{} lo]", name));
+        }
+    }
+    while bytes.len() < 4 {
+        bytes.push("[unused]".to_string());
+    }
+    bytes.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opcodes() {
+        let codes = opcodes();
+        assert!(codes.contains(&Opcode::LOAD));
+        assert!(codes.contains(&Opcode::DJMPB));
+        assert!(!codes.contains(&Opcode::IGL));
+    }
+
+    #[test]
+    fn test_encoding() {
+        let load = opcode_signature(Opcode::LOAD);
+        assert_eq!(encoding(Opcode::LOAD, load.operands), "[0x00] [reg] [value hi] [value lo]");
+        assert_eq!(encoding(Opcode::HLT, &[]), "[0x05] [unused] [unused] [unused]");
+    }
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Debug, Clone)]
 pub struct Symbol {
     name: String,
@@ -21,15 +23,37 @@ impl Symbol {
             offset: Some(offset),
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn offset(&self) -> Option<u32> {
+        self.offset
+    }
+
+    pub fn symbol_type(&self) -> &SymbolType {
+        &self.symbol_type
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SymbolType {
     Label,
     Integer,
     IrString,
 }
 
+impl fmt::Display for SymbolType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SymbolType::Label => f.write_str("label"),
+            SymbolType::Integer => f.write_str("integer"),
+            SymbolType::IrString => f.write_str("string"),
+        }
+    }
+}
+
 /// Holds all of the symbols
 #[derive(Debug, Clone, Default)]
 pub struct SymbolTable {
@@ -65,6 +89,25 @@ impl SymbolTable {
         false
     }
 
+    pub fn set_symbol_type(&mut self, s: &str, symbol_type: SymbolType) -> bool {
+        for symbol in &mut self.symbols {
+            if symbol.name == s {
+                symbol.symbol_type = symbol_type;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Adds `base` to the offset of every label, e.g. to go from code-relative to absolute offsets
+    pub fn relocate_labels(&mut self, base: u32) {
+        for symbol in &mut self.symbols {
+            if symbol.symbol_type == SymbolType::Label {
+                symbol.offset = symbol.offset.map(|o| o + base);
+            }
+        }
+    }
+
     pub fn symbol_value(&self, s: &str) -> Option<u32> {
         for symbol in &self.symbols {
             if symbol.name == s {
@@ -0,0 +1,21 @@
+extern crate env_logger;
+extern crate iridium;
+
+use std::io;
+
+use iridium::lsp::Server;
+
+/// Speaks the Language Server Protocol over stdin and stdout. Logging goes to stderr, so it can be turned
+/// on with `RUST_LOG` without confusing the client.
+fn main() {
+    env_logger::init();
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut server = Server::new();
+    if let Err(e) = server.run(&mut stdin.lock(), &mut stdout.lock()) {
+        eprintln!("Error talking to the client: {}", e);
+        std::process::exit(1);
+    }
+    // The protocol asks for a non-zero exit code if the client exits without shutting the server down first
+    std::process::exit(if server.is_shut_down() { 0 } else { 1 });
+}
@@ -1,8 +1,9 @@
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::path::Path;
 use std::sync::mpsc::Receiver;
 use std::thread;
+use std::time::Duration;
 
 extern crate byteorder;
 extern crate chrono;
@@ -18,33 +19,119 @@ extern crate serde_derive;
 extern crate uuid;
 
 use clap::App;
-use iridium::assembler::Assembler;
+use iridium::assembler::disassembler::disassemble;
+use iridium::assembler::formatter;
+use iridium::assembler::object::ObjectFile;
+use iridium::assembler::{is_pie, Assembler};
+use iridium::daemon::Daemon;
+use iridium::linker::Linker;
+use iridium::remote::auth::{Authenticator, UserStore, USERS_FILENAME};
+use iridium::remote::roles::{RoleConfig, ROLES_FILENAME};
+use iridium::remote::session::DEFAULT_IDLE_TIMEOUT;
+use iridium::repl::history::{History, HISTORY_FILENAME, USER_HISTORY_DIRNAME};
 use iridium::repl::REPL;
+use iridium::rpc;
+use iridium::runtime::runtime_errors::RuntimeError;
+use iridium::runtime::{Instance, Runtime};
+use iridium::tls::TlsConfig;
 use iridium::vm::VM;
 
 static NODE_ID_FILENAME: &'static str = ".node_id";
 static DEFAULT_NODE_LISTEN_PORT: &'static str = "2254";
 static DEFAULT_REMOTE_ACCESS_PORT: &'static str = "2244";
 fn main() {
-    env_logger::init();
     let mut _repl_receiver: Receiver<String>;
-    info!("Starting logging!");
     let yaml = clap::load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
-
     let daemon_mode = matches.value_of("DAEMON_MODE").unwrap_or("false");
+    // A daemon's logs are all there is to go on, so it logs more than usual unless told otherwise
+    if daemon_mode == "true" && std::env::var_os("RUST_LOG").is_none() {
+        std::env::set_var("RUST_LOG", "info");
+    }
+    env_logger::init();
+    info!("Starting logging!");
+
+    if let Some(asm_matches) = matches.subcommand_matches("asm") {
+        let input_file = asm_matches.value_of("INPUT_FILE").unwrap();
+        let object = asm_matches.is_present("OBJECT");
+        let output_file = match asm_matches.value_of("OUTPUT_FILE") {
+            Some(path) => path.to_string(),
+            None => {
+                let extension = if object { "iobj" } else { "pie" };
+                Path::new(input_file).with_extension(extension).to_string_lossy().into_owned()
+            }
+        };
+        let optimize = asm_matches.is_present("OPTIMIZE");
+        assemble_file(input_file, &output_file, asm_matches.value_of("LISTING_FILE"), object, optimize);
+        std::process::exit(0);
+    }
+
+    if let Some(link_matches) = matches.subcommand_matches("link") {
+        let input_files: Vec<&str> = link_matches.values_of("INPUT_FILES").unwrap().collect();
+        link_files(&input_files, link_matches.value_of("OUTPUT_FILE").unwrap());
+        std::process::exit(0);
+    }
+
+    if let Some(disasm_matches) = matches.subcommand_matches("disasm") {
+        disassemble_file(disasm_matches.value_of("INPUT_FILE").unwrap());
+        std::process::exit(0);
+    }
+
+    if let Some(fmt_matches) = matches.subcommand_matches("fmt") {
+        let input_files: Vec<&str> = fmt_matches.values_of("INPUT_FILES").unwrap().collect();
+        let all_formatted = format_files(&input_files, fmt_matches.is_present("CHECK"));
+        std::process::exit(if all_formatted { 0 } else { 1 });
+    }
 
     let data_root_dir = matches.value_of("DATA_ROOT_DIR").unwrap_or("/var/lib/iridium/");
 
+    // Running or checking a program doesn't make this a node, so both happen before the data directory and node
+    // ID are set up
+    if let Some(run_matches) = matches.subcommand_matches("run") {
+        let args = match run_matches.values_of("ARGS") {
+            Some(args) => args.map(|a| a.to_string()).collect(),
+            None => vec![],
+        };
+        run_file(
+            run_matches.value_of("INPUT_FILE").unwrap(),
+            program_runtime(&matches, data_root_dir).with_args(args),
+        );
+    }
+
+    if let Some(check_matches) = matches.subcommand_matches("check") {
+        let input_files: Vec<&str> = check_matches.values_of("INPUT_FILES").unwrap().collect();
+        let all_valid = check_files(&input_files, &program_runtime(&matches, data_root_dir));
+        std::process::exit(if all_valid { 0 } else { 1 });
+    }
+
     if make_directory(data_root_dir).is_err() {
         println!("There was an error creating the default root data directory");
         std::process::exit(1);
     };
 
-    if matches.is_present("ENABLE_REMOTE_ACCESS") {
-        let port = matches.value_of("LISTEN_PORT").unwrap_or(DEFAULT_REMOTE_ACCESS_PORT);
-        let host = matches.value_of("LISTEN_HOST").unwrap_or("127.0.0.1");
-        start_remote_server(host.to_string(), port.to_string());
+    let remote_port = matches.value_of("LISTEN_PORT").unwrap_or(DEFAULT_REMOTE_ACCESS_PORT);
+    let remote_host = matches.value_of("LISTEN_HOST").unwrap_or("127.0.0.1");
+    if let Some(passwd_matches) = matches.subcommand_matches("passwd") {
+        let users_file = Path::new(data_root_dir).join(USERS_FILENAME);
+        set_password(&users_file, passwd_matches.value_of("USERNAME").unwrap(), passwd_matches.is_present("DELETE"));
+        std::process::exit(0);
+    }
+
+    let tls = load_tls(&matches);
+    let idle_timeout = match matches.value_of("IDLE_TIMEOUT") {
+        Some(seconds) => match seconds.parse::<u64>() {
+            Ok(seconds) => Duration::from_secs(seconds),
+            Err(_) => {
+                eprintln!("Invalid idle timeout: {}", seconds);
+                std::process::exit(1);
+            }
+        },
+        None => DEFAULT_IDLE_TIMEOUT,
+    };
+    let rpc_port = matches.value_of("RPC_PORT");
+    let rpc_socket = matches.value_of("RPC_SOCKET");
+    if matches.is_present("ENABLE_REMOTE_ACCESS") && daemon_mode != "true" {
+        start_remote_server(remote_host.to_string(), remote_port.to_string(), data_root_dir, tls.clone(), idle_timeout);
     }
 
     // Find or generate a unique node ID
@@ -69,46 +156,52 @@ fn main() {
     let server_addr = matches.value_of("SERVER_LISTEN_HOST").unwrap_or("127.0.0.1");
     let server_port = matches.value_of("SERVER_LISTEN_PORT").unwrap_or(DEFAULT_NODE_LISTEN_PORT);
 
-    let num_threads = match matches.value_of("THREADS") {
-        Some(number) => match number.parse::<usize>() {
-            Ok(v) => v,
-            Err(_e) => {
-                println!("Invalid argument for number of threads: {}. Using default.", number);
-                num_cpus::get()
-            }
-        },
-        None => num_cpus::get(),
-    };
+    let runtime = Runtime::new()
+        .with_alias(alias.to_string())
+        .with_logical_cores(thread_count(&matches))
+        .with_data_root(data_root_dir.into());
 
     if daemon_mode == "true" {
-        // TODO: Fill this in
+        // A daemon always listens for remote access, as that's the only way to give it programs
+        let mut daemon = Daemon::new(data_root_dir.into(), alias.to_string())
+            .with_cluster_bind(server_addr.into(), server_port.into())
+            .with_remote_bind(remote_host.into(), remote_port.into())
+            .with_idle_timeout(idle_timeout);
+        if let Some(port) = rpc_port {
+            daemon = daemon.with_rpc_bind(remote_host.into(), port.into());
+        }
+        if let Some(path) = rpc_socket {
+            daemon = daemon.with_rpc_socket(path.into());
+        }
+        if let Some(tls) = tls {
+            daemon = daemon.with_tls(tls);
+        }
+        if let Err(e) = daemon.run() {
+            error!("The daemon stopped with an error: {}", e);
+            eprintln!("The daemon stopped with an error: {}", e);
+            std::process::exit(1);
+        }
     } else {
         let target_file = matches.value_of("INPUT_FILE");
         match target_file {
-            Some(filename) => {
-                let program = read_file(filename);
-                let mut asm = Assembler::new();
-                let mut vm = VM::new()
-                    .with_alias(alias.to_string())
-                    .with_cluster_bind(server_addr.into(), server_port.into());
-                vm.logical_cores = num_threads;
-                let program = asm.assemble(&program);
-                match program {
-                    Ok(p) => {
-                        vm.add_bytes(p);
-                        let _events = vm.run();
-                        println!("{:#?}", vm.registers);
-                        std::process::exit(0);
-                    }
-                    Err(_e) => {}
-                }
-            }
+            Some(filename) => run_file(filename, runtime),
             None => {
                 debug!("Spawning REPL with alias {}", alias);
                 let mut vm = VM::new()
                     .with_alias(alias.to_string())
                     .with_cluster_bind(server_addr.into(), server_port.into());
+                if let Some(ref tls) = tls {
+                    vm = vm.with_cluster_tls(tls.clone());
+                }
+                if rpc_port.is_some() || rpc_socket.is_some() {
+                    let server = rpc_server(data_root_dir, tls).with_cluster(vm.connection_manager());
+                    start_rpc_server(server, remote_host, rpc_port, rpc_socket);
+                }
                 let mut repl = REPL::new(vm);
+                match History::load(&Path::new(data_root_dir).join(HISTORY_FILENAME)) {
+                    Ok(history) => repl = repl.with_history(history),
+                    Err(e) => println!("Unable to read the REPL history: {}", e),
+                }
                 let mut rx = repl.rx_pipe.take();
                 thread::spawn(move || {
                     let chan = rx.unwrap();
@@ -147,13 +240,304 @@ fn read_file(tmp: &str) -> String {
     }
 }
 
-fn start_remote_server(listen_host: String, listen_port: String) {
+/// Reads a program to run from `input_file`, which may be assembly or an executable. Executables are told
+/// apart by their header rather than their extension.
+fn load_program(input_file: &str, runtime: &Runtime) -> Result<Instance, RuntimeError> {
+    let bytes = read_bytes(input_file);
+    if is_pie(&bytes) {
+        return runtime.load_bytes(bytes);
+    }
+    match String::from_utf8(bytes) {
+        Ok(source) => runtime.load_source(&source),
+        Err(_) => {
+            eprintln!("{} is neither Iridium assembly nor an executable", input_file);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs the program in `input_file` and exits with its stop code as the status, so scripts can tell how it ended
+fn run_file(input_file: &str, runtime: Runtime) -> ! {
+    match load_program(input_file, &runtime).map(|mut instance| instance.run()) {
+        Ok(result) => {
+            debug!("Registers at exit: {:?}", result.registers);
+            if let Some(ref error) = result.error {
+                eprintln!("{} crashed: {}", input_file, error);
+            }
+            std::process::exit(result.exit_status());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Checks each of `input_files` assembles and could be loaded to run. Returns false if any couldn't.
+fn check_files(input_files: &[&str], runtime: &Runtime) -> bool {
+    let mut all_valid = true;
+    for input_file in input_files {
+        if let Err(e) = load_program(input_file, runtime) {
+            println!("{}: {}", input_file, e);
+            all_valid = false;
+        }
+    }
+    all_valid
+}
+
+/// Prints the assembly of the executable in `input_file`
+fn disassemble_file(input_file: &str) {
+    match disassemble(&read_bytes(input_file)) {
+        Some(assembly) => print!("{}", assembly),
+        None => {
+            println!("{} is not an Iridium executable", input_file);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Assembles `input_file` and writes the bytecode (or an object file, if `object` is set) to `output_file`,
+/// plus a listing if `listing_file` is given. If `optimize` is set, the peephole optimizer is run first.
+fn assemble_file(input_file: &str, output_file: &str, listing_file: Option<&str>, object: bool, optimize: bool) {
+    let program = read_file(input_file);
+    let mut asm = Assembler::new();
+    if listing_file.is_some() {
+        asm = asm.with_listing();
+    }
+    if optimize {
+        asm = asm.with_optimizations();
+    }
+    let result = if object {
+        asm.assemble_object(&program).map(|o| o.to_bytes().expect("Unable to serialize object file"))
+    } else {
+        asm.assemble(&program)
+    };
+    let bytecode = match result {
+        Ok(bytecode) => bytecode,
+        Err(errors) => {
+            for error in errors {
+                println!("Unable to assemble {}: {}", input_file, error);
+            }
+            std::process::exit(1);
+        }
+    };
+    write_file(output_file, &bytecode);
+    if let (Some(path), Some(listing)) = (listing_file, asm.listing()) {
+        write_file(path, listing.to_string().as_bytes());
+    }
+}
+
+/// Links the object files in `input_files` into an executable at `output_file`
+fn link_files(input_files: &[&str], output_file: &str) {
+    let mut linker = Linker::new();
+    for input_file in input_files {
+        let bytes = read_bytes(input_file);
+        match ObjectFile::from_bytes(&bytes) {
+            Some(object) => linker.add_object(input_file, object),
+            None => {
+                println!("{} is not an Iridium object file", input_file);
+                std::process::exit(1);
+            }
+        }
+    }
+    match linker.link() {
+        Ok(bytecode) => write_file(output_file, &bytecode),
+        Err(errors) => {
+            for error in errors {
+                println!("Unable to link: {}", error);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+fn read_bytes(tmp: &str) -> Vec<u8> {
+    let mut contents = vec![];
+    let result = File::open(Path::new(tmp)).and_then(|mut fh| fh.read_to_end(&mut contents));
+    if let Err(e) = result {
+        println!("There was an error reading file {}: {:?}", tmp, e);
+        std::process::exit(1);
+    }
+    contents
+}
+
+/// Formats each of `input_files` in place, or with `check` set, only reports the ones that need it. Returns
+/// false if any file couldn't be formatted or, when checking, wasn't formatted already.
+fn format_files(input_files: &[&str], check: bool) -> bool {
+    let mut all_formatted = true;
+    for input_file in input_files {
+        let source = read_file(input_file);
+        let formatted = match formatter::format(&source) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                println!("Unable to format {}: {}", input_file, e);
+                all_formatted = false;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", input_file);
+            all_formatted = false;
+        } else {
+            write_file(input_file, formatted.as_bytes());
+        }
+    }
+    all_formatted
+}
+
+fn write_file(tmp: &str, contents: &[u8]) {
+    let result = File::create(Path::new(tmp)).and_then(|mut fh| fh.write_all(contents));
+    if let Err(e) = result {
+        println!("There was an error writing file {}: {:?}", tmp, e);
+        std::process::exit(1);
+    }
+}
+
+/// The runtime for `run` and `check`, which only has an alias if one was given
+fn program_runtime(matches: &clap::ArgMatches, data_root_dir: &str) -> Runtime {
+    let runtime = Runtime::new().with_logical_cores(thread_count(matches)).with_data_root(data_root_dir.into());
+    match matches.value_of("NODE_ALIAS") {
+        Some(alias) => runtime.with_alias(alias.to_string()),
+        None => runtime,
+    }
+}
+
+/// How many threads the node should report having, from `--threads` or else the number of CPUs
+fn thread_count(matches: &clap::ArgMatches) -> usize {
+    match matches.value_of("THREADS") {
+        Some(number) => match number.parse::<usize>() {
+            Ok(v) => v,
+            Err(_e) => {
+                println!("Invalid argument for number of threads: {}. Using default.", number);
+                num_cpus::get()
+            }
+        },
+        None => num_cpus::get(),
+    }
+}
+
+/// Loads the certificates given with --tls-cert, --tls-key and --tls-ca, exiting if they can't be
+fn load_tls(matches: &clap::ArgMatches) -> Option<TlsConfig> {
+    let (cert, key) = match (matches.value_of("TLS_CERT"), matches.value_of("TLS_KEY")) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return None,
+    };
+    match TlsConfig::load(Path::new(cert), Path::new(key), matches.value_of("TLS_CA").map(Path::new)) {
+        Ok(tls) => Some(tls),
+        Err(e) => {
+            eprintln!("Unable to load the TLS certificate and key: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Loads the users who may log in through remote access or RPC, and their roles, exiting if they can't be or
+/// there are no users
+fn load_users(data_root_dir: &str) -> (UserStore, RoleConfig) {
+    let users = match UserStore::load(&Path::new(data_root_dir).join(USERS_FILENAME)) {
+        Ok(ref users) if users.is_empty() => {
+            println!("Remote access needs at least one user to log in as. Add one with `iridium passwd <username>`.");
+            std::process::exit(1);
+        }
+        Ok(users) => users,
+        Err(e) => {
+            println!("Unable to read the users allowed remote access: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let roles = match RoleConfig::load(&Path::new(data_root_dir).join(ROLES_FILENAME)) {
+        Ok(roles) => roles,
+        Err(e) => {
+            println!("Unable to read the roles of remote access users: {}", e);
+            std::process::exit(1);
+        }
+    };
+    (users, roles)
+}
+
+fn start_remote_server(listen_host: String, listen_port: String, data_root_dir: &str, tls: Option<TlsConfig>, idle_timeout: Duration) {
+    let (users, roles) = load_users(data_root_dir);
+    let history_dir = Path::new(data_root_dir).join(USER_HISTORY_DIRNAME);
     let _t = std::thread::spawn(move || {
-        let mut sh = iridium::remote::server::Server::new(listen_host, listen_port);
+        let mut sh = iridium::remote::server::Server::new(listen_host, listen_port)
+            .with_authenticator(Authenticator::new(users))
+            .with_roles(roles)
+            .with_idle_timeout(idle_timeout)
+            .with_history_dir(history_dir);
+        if let Some(tls) = tls {
+            sh = sh.with_tls(tls);
+        }
         sh.listen();
     });
 }
 
+/// An RPC server that users log in to, as for remote access
+fn rpc_server(data_root_dir: &str, tls: Option<TlsConfig>) -> rpc::server::Server {
+    let (users, roles) = load_users(data_root_dir);
+    let server = rpc::server::Server::new().with_authenticator(Authenticator::new(users)).with_roles(roles);
+    match tls {
+        Some(tls) => server.with_tls(tls),
+        None => server,
+    }
+}
+
+/// Listens for RPC connections on `port` of `host` and on the Unix socket at `socket`, whichever are given
+fn start_rpc_server(server: rpc::server::Server, host: &str, port: Option<&str>, socket: Option<&str>) {
+    if let Some(port) = port {
+        let server = server.clone();
+        let addr = format!("{}:{}", host, port);
+        thread::spawn(move || {
+            if let Err(e) = server.listen_tcp(&addr) {
+                println!("Unable to listen for RPC connections on {}: {}", addr, e);
+            }
+        });
+    }
+    if let Some(socket) = socket {
+        let path = socket.to_string();
+        thread::spawn(move || {
+            if let Err(e) = server.listen_unix(Path::new(&path)) {
+                println!("Unable to listen for RPC connections on {}: {}", path, e);
+            }
+        });
+    }
+}
+
+/// Sets the password of `username` to one read from stdin, adding them if they're new, or with `delete` set,
+/// removes them
+fn set_password(users_file: &Path, username: &str, delete: bool) {
+    let mut users = match UserStore::load(users_file) {
+        Ok(users) => users,
+        Err(e) => {
+            println!("Unable to read {}: {}", users_file.display(), e);
+            std::process::exit(1);
+        }
+    };
+    if delete {
+        if !users.remove(username) {
+            println!("There is no user named {}", username);
+            std::process::exit(1);
+        }
+    } else {
+        println!("Password for {}:", username);
+        let mut password = String::new();
+        if std::io::stdin().read_line(&mut password).is_err() || password.trim_end().is_empty() {
+            println!("No password given");
+            std::process::exit(1);
+        }
+        if let Err(e) = users.set_password(username, password.trim_end()) {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = users.save(users_file) {
+        println!("Unable to write {}: {}", users_file.display(), e);
+        std::process::exit(1);
+    }
+}
+
 fn make_directory(dir: &str) -> std::io::Result<()> {
     std::fs::create_dir_all(dir)?;
     Ok(())
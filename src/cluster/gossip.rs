@@ -0,0 +1,299 @@
+//! Keeps each node's membership up to date SWIM-style (see `membership`). Every `PROTOCOL_PERIOD` a node pings
+//! another. If that one doesn't answer within `PING_TIMEOUT`, `INDIRECT_PROBES` others are asked to ping it, and
+//! if none of them gets an answer either, it is suspected. Every message carries updates to the membership, so
+//! they reach the whole cluster in a few periods without any node having to be connected to every other.
+
+use std::io;
+use std::net::IpAddr;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cluster::client::ClusterClient;
+use cluster::manager::Manager;
+use cluster::membership::{Member, NodeState};
+use cluster::message::IridiumMessage;
+use tls::TlsConfig;
+
+/// How often each node probes another
+pub const PROTOCOL_PERIOD: Duration = Duration::from_secs(1);
+
+/// How long a node has to answer a ping before others are asked to try
+pub const PING_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How many other nodes are asked to ping one that didn't answer
+pub const INDIRECT_PROBES: usize = 3;
+
+/// How many nodes a node leaving tells, who pass it on
+pub const LEAVE_FANOUT: usize = 3;
+
+/// How long the node joined through has to answer
+pub const JOIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many protocol periods go by between tries at reaching a node that was declared dead, which heals the
+/// cluster after a partition
+pub const RECONNECT_PERIODS: u32 = 10;
+
+/// Probes a member every `PROTOCOL_PERIOD` on a thread of its own, and every `RECONNECT_PERIODS` tries one that
+/// was declared dead, until this node leaves the cluster
+pub fn start(manager: Arc<RwLock<Manager>>, tls: Option<TlsConfig>) {
+    thread::spawn(move || {
+        let mut period: u32 = 0;
+        loop {
+            let started = Instant::now();
+            let (target, dead) = match manager.write() {
+                Ok(mut manager) => {
+                    if manager.has_left() {
+                        return;
+                    }
+                    manager.expire();
+                    let dead = if period.is_multiple_of(RECONNECT_PERIODS) {
+                        manager.reconnect_target()
+                    } else {
+                        None
+                    };
+                    (manager.probe_target(), dead)
+                }
+                Err(_) => return,
+            };
+            if let Some(target) = target {
+                probe(&manager, &target, tls.as_ref());
+            }
+            if let Some(dead) = dead {
+                reconnect(&manager, dead, tls.as_ref());
+            }
+            period = period.wrapping_add(1);
+            if let Some(rest) = PROTOCOL_PERIOD.checked_sub(started.elapsed()) {
+                thread::sleep(rest);
+            }
+        }
+    });
+}
+
+/// The updates `sender` piggybacked that this node should believe. When nodes have certificates of their own, a
+/// node is only believed about itself when it says another has left or died, so no node can evict another just by
+/// saying so; this node finds out about those with its own probes instead. Being told that this node is dead is
+/// kept, as it only makes it refute that.
+pub fn trusted_updates(updates: Vec<Member>, sender: Option<&str>, manager: &Manager, tls: Option<&TlsConfig>) -> Vec<Member> {
+    if !tls.is_some_and(|tls| tls.node_identities()) {
+        return updates;
+    }
+    let myself = manager.myself();
+    updates
+        .into_iter()
+        .filter(|update| {
+            let departed = update.state == NodeState::Dead || update.state == NodeState::Left;
+            let vouched = Some(update.alias()) == sender || myself.as_ref().is_some_and(|m| m.alias() == update.alias());
+            if departed && !vouched {
+                debug!("Ignoring {}'s word that {} is {}", sender.unwrap_or("a node"), update.alias(), update.state);
+            }
+            !departed || vouched
+        })
+        .collect()
+}
+
+/// Pings `target`, applying the updates it answers with. Returns whether it answered within `timeout`.
+pub fn ping(manager: &Arc<RwLock<Manager>>, target: &Member, tls: Option<&TlsConfig>, timeout: Duration) -> bool {
+    let updates = manager.write().unwrap().gossip();
+    ping_with(manager, target, updates, tls, timeout)
+}
+
+fn ping_with(manager: &Arc<RwLock<Manager>>, target: &Member, updates: Vec<Member>, tls: Option<&TlsConfig>, timeout: Duration) -> bool {
+    let from = match manager.read().unwrap().myself() {
+        Some(myself) => myself,
+        None => return false,
+    };
+    match ClusterClient::request(&target.addr(), &IridiumMessage::Ping { from, updates }, tls, timeout) {
+        // Another node now at the same address doesn't count as an answer
+        Ok(IridiumMessage::Ack { ref from, .. }) if from.alias() != target.alias() => {
+            warn!("{} answered a ping for {}", from.alias(), target.alias());
+            false
+        }
+        Ok(IridiumMessage::Ack { from, updates }) => {
+            let mut manager = manager.write().unwrap();
+            let updates = trusted_updates(updates, Some(from.alias()), &manager, tls);
+            manager.apply(&[from]);
+            manager.apply(&updates);
+            true
+        }
+        Ok(message) => {
+            error!("Unexpected answer to a ping from {}: {:?}", target.alias(), message);
+            false
+        }
+        Err(e) => {
+            debug!("No answer to a ping from {}: {}", target.alias(), e);
+            false
+        }
+    }
+}
+
+/// Joins the cluster of the node at `seed`, given as `host:port`. This node has to be listening for others
+/// already. Returns how many members the cluster has, not counting this node.
+pub fn join(seed: &str, manager: &Arc<RwLock<Manager>>, tls: Option<&TlsConfig>) -> io::Result<usize> {
+    let mut client = ClusterClient::connect(seed, tls, JOIN_TIMEOUT)?;
+    let myself = {
+        let mut manager = manager.write().unwrap();
+        if !manager.is_member() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "this node isn't listening for others"));
+        }
+        manager.learn_address(client.local_ip()?);
+        manager.myself()
+    };
+    client.send(&IridiumMessage::Hello { member: myself.unwrap() })?;
+    match client.receive()? {
+        IridiumMessage::HelloAck { mut members } => {
+            // A node listening on all addresses is reached at the one connected to
+            let seed_ip = client.peer_ip()?;
+            for member in &mut members {
+                if member.node.1.parse::<IpAddr>().is_ok_and(|ip| ip.is_unspecified()) {
+                    member.node.1 = seed_ip.to_string();
+                }
+            }
+            let mut manager = manager.write().unwrap();
+            let members = trusted_updates(members, None, &manager, tls);
+            manager.apply(&members);
+            Ok(members.len())
+        }
+        message => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected answer to hello: {:?}", message))),
+    }
+}
+
+/// Leaves the cluster, telling up to `LEAVE_FANOUT` members so they can pass it on. Does nothing if this node
+/// isn't a member.
+pub fn leave(manager: &Arc<RwLock<Manager>>, tls: Option<&TlsConfig>) {
+    let (update, members) = {
+        let mut manager = manager.write().unwrap();
+        match manager.leave() {
+            Some(update) => {
+                let members = manager.random_members(LEAVE_FANOUT, update.alias());
+                (update, members)
+            }
+            None => return,
+        }
+    };
+    info!("Leaving the cluster");
+    let message = IridiumMessage::Leave { member: update };
+    for member in members {
+        if let Err(e) = ClusterClient::connect(&member.addr(), tls, PING_TIMEOUT).and_then(|mut client| client.send(&message)) {
+            warn!("Unable to tell {} this node is leaving: {}", member.alias(), e);
+        }
+    }
+}
+
+/// Pings `dead`, telling it it was declared dead. If it is there after all, it answers with a higher incarnation,
+/// which brings it back, and the others find out from this node.
+fn reconnect(manager: &Arc<RwLock<Manager>>, dead: Member, tls: Option<&TlsConfig>) {
+    debug!("Trying {}, which was declared dead", dead.alias());
+    let mut updates = manager.write().unwrap().gossip();
+    updates.push(dead.clone());
+    ping_with(manager, &dead, updates, tls, PING_TIMEOUT);
+}
+
+/// Probes `target` directly, then through others, and suspects it if none of them get an answer
+fn probe(manager: &Arc<RwLock<Manager>>, target: &Member, tls: Option<&TlsConfig>) {
+    if ping(manager, target, tls, PING_TIMEOUT) {
+        return;
+    }
+    debug!("{} didn't answer a ping, asking others to try", target.alias());
+    let helpers = manager.read().unwrap().random_members(INDIRECT_PROBES, target.alias());
+    let timeout = PROTOCOL_PERIOD - PING_TIMEOUT;
+    let (tx, rx) = channel();
+    for helper in helpers {
+        let message = IridiumMessage::PingReq {
+            target: target.clone(),
+            updates: manager.write().unwrap().gossip(),
+        };
+        let manager = manager.clone();
+        let tls = tls.cloned();
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let answered = match ClusterClient::request(&helper.addr(), &message, tls.as_ref(), timeout) {
+                Ok(IridiumMessage::Ack { from, updates }) => {
+                    let mut manager = manager.write().unwrap();
+                    let updates = trusted_updates(updates, Some(from.alias()), &manager, tls.as_ref());
+                    manager.apply(&[from]);
+                    manager.apply(&updates);
+                    true
+                }
+                _ => false,
+            };
+            let _ = tx.send(answered);
+        });
+    }
+    drop(tx);
+    // Ends once a helper gets an answer, or all of them have given up
+    if rx.iter().any(|answered| answered) {
+        return;
+    }
+    manager.write().unwrap().suspect(target.alias());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cluster::membership::{Membership, NodeState};
+    use cluster::server;
+    use std::net::TcpListener;
+
+    /// Starts a node listening on a free port on localhost
+    fn node(alias: &str, membership: Membership) -> Arc<RwLock<Manager>> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let manager = Arc::new(RwLock::new(Manager::new().with_membership(membership)));
+        manager.write().unwrap().start((alias.to_string(), "127.0.0.1".to_string(), port));
+        let cloned = manager.clone();
+        thread::spawn(move || server::listen(listener, cloned, None));
+        start(manager.clone(), None);
+        manager
+    }
+
+    fn addr(manager: &Arc<RwLock<Manager>>) -> String {
+        manager.read().unwrap().myself().unwrap().addr()
+    }
+
+    /// Waits up to ten seconds for `condition` to hold of `manager`
+    fn eventually<F: Fn(&Manager) -> bool>(manager: &Arc<RwLock<Manager>>, condition: F) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            if condition(&manager.read().unwrap()) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        false
+    }
+
+    #[test]
+    fn test_gossip() {
+        let a = node("a", Membership::new());
+        let b = node("b", Membership::new());
+        let c = node("c", Membership::new());
+        assert_eq!(join(&addr(&a), &b, None).unwrap(), 1);
+        // c only ever talks to b, and a finds out about it from b
+        assert_eq!(join(&addr(&b), &c, None).unwrap(), 2);
+        assert!(eventually(&a, |m| m.members().len() == 2));
+
+        leave(&c, None);
+        assert!(eventually(&a, |m| m.members().len() == 1));
+        assert!(eventually(&b, |m| m.members().len() == 1));
+        let events: Vec<String> = a.read().unwrap().events().iter().map(|e| e.to_string()).collect();
+        assert_eq!(events, vec!["joined b", "joined c", "left c"]);
+    }
+
+    #[test]
+    fn test_failure_detection() {
+        let a = node("a", Membership::new().with_suspicion_timeout(Duration::from_secs(1)));
+        // Nothing listens on the port once this is dropped
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let ghost = Member {
+            node: ("ghost".to_string(), "127.0.0.1".to_string(), port.to_string()),
+            incarnation: 1,
+            state: NodeState::Alive,
+        };
+        a.write().unwrap().apply(&[ghost]);
+        assert!(eventually(&a, |m| m.members().is_empty()));
+        let events: Vec<String> = a.read().unwrap().events().iter().map(|e| e.to_string()).collect();
+        assert_eq!(events, vec!["joined ghost", "suspected ghost", "died ghost"]);
+    }
+}
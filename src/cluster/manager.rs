@@ -1,48 +1,121 @@
-use cluster::client::ClusterClient;
+use cluster::membership::{Member, Membership, MembershipEvent};
 use cluster::NodeInfo;
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use std::thread;
+use std::net::IpAddr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 #[derive(Default, Debug)]
 pub struct Manager {
-    pub clients: HashMap<NodeInfo, Arc<RwLock<ClusterClient>>>,
+    /// Who is in the cluster, and how that has changed
+    membership: Membership,
 }
 
 impl Manager {
     pub fn new() -> Manager {
-        Manager { clients: HashMap::new() }
+        Manager::default()
     }
 
-    pub fn add_client(&mut self, alias: NodeInfo, client: ClusterClient) -> bool {
-        if self.clients.contains_key(&alias) {
-            error!("Tried to add a client that already existed");
-            return false;
+    /// Starts from `membership`, such as one with a different suspicion timeout
+    pub fn with_membership(mut self, membership: Membership) -> Self {
+        self.membership = membership;
+        self
+    }
+
+    /// Makes this node a member as `node`, once it is listening for others. Its first incarnation is the time, so
+    /// that if it has been a member before and restarted, it isn't taken for the node that died or left.
+    pub fn start(&mut self, node: NodeInfo) {
+        let incarnation = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.membership.start(node, incarnation);
+    }
+
+    /// Whether this node is listening for others, and hasn't left
+    pub fn is_member(&self) -> bool {
+        self.membership.myself().is_some() && !self.membership.has_left()
+    }
+
+    pub fn myself(&self) -> Option<Member> {
+        self.membership.myself().cloned()
+    }
+
+    /// Learns the address this node is reached at, from a connection to or from another node, if it is listening
+    /// on all of them and so doesn't know which to tell the others
+    pub fn learn_address(&mut self, ip: IpAddr) {
+        let unspecified = self
+            .membership
+            .myself()
+            .and_then(|m| m.node.1.parse::<IpAddr>().ok())
+            .is_some_and(|ip| ip.is_unspecified());
+        if unspecified && !ip.is_unspecified() {
+            debug!("Telling other nodes this one is at {}", ip);
+            self.membership.set_address(ip.to_string());
         }
-        let client = Arc::new(RwLock::new(client));
-        self.clients.insert(alias.clone(), client);
-        let cloned_client = self.get_client(alias).unwrap();
-        thread::spawn(move || {
-            cloned_client.write().unwrap().run();
-        });
-        true
     }
 
-    pub fn get_client(&mut self, alias: NodeInfo) -> Option<Arc<RwLock<ClusterClient>>> {
-        Some(self.clients.get_mut(&alias).unwrap().clone())
+    pub fn has_left(&self) -> bool {
+        self.membership.has_left()
     }
 
-    pub fn del_client(&mut self, alias: &NodeInfo) {
-        self.clients.remove(alias);
+    /// Leaves the cluster, returning the update that tells the others
+    pub fn leave(&mut self) -> Option<Member> {
+        self.membership.leave()
     }
 
-    pub fn get_client_names(&self) -> Vec<NodeInfo> {
-        debug!("Getting client names");
-        let mut results = vec![];
-        for alias in self.clients.keys() {
-            results.push((alias.0.to_owned(), alias.1.to_string(), alias.2.to_string()));
+    /// Applies updates another node sent
+    pub fn apply(&mut self, updates: &[Member]) {
+        let now = Instant::now();
+        for update in updates {
+            self.membership.apply(update, now);
         }
-        results
+    }
+
+    /// Suspects a member that didn't answer a probe
+    pub fn suspect(&mut self, alias: &str) {
+        self.membership.suspect(alias, Instant::now());
+    }
+
+    /// Declares dead the members that were suspected and haven't refuted it in time
+    pub fn expire(&mut self) {
+        self.membership.expire(Instant::now());
+    }
+
+    pub fn probe_target(&mut self) -> Option<Member> {
+        self.membership.probe_target()
+    }
+
+    pub fn random_members(&self, count: usize, alias: &str) -> Vec<Member> {
+        self.membership.random_members(count, alias)
+    }
+
+    /// A member that was declared dead, to try again
+    pub fn reconnect_target(&self) -> Option<Member> {
+        self.membership.reconnect_target()
+    }
+
+    /// What was known of `alias` when it died or left, if it has and hasn't come back since
+    pub fn departed(&self, alias: &str) -> Option<Member> {
+        match self.membership.state(alias) {
+            Some(_) => None,
+            None => self.membership.departed(alias).cloned(),
+        }
+    }
+
+    /// The updates to piggyback on the next message
+    pub fn gossip(&mut self) -> Vec<Member> {
+        self.membership.gossip()
+    }
+
+    /// The other members, with their state, by alias
+    pub fn members(&self) -> Vec<Member> {
+        self.membership.members()
+    }
+
+    /// The membership changes kept, oldest first
+    pub fn events(&self) -> Vec<MembershipEvent> {
+        self.membership.events()
+    }
+
+    /// The oldest membership change kept numbered `seq` or later
+    pub fn next_event(&self, seq: u64) -> Option<MembershipEvent> {
+        self.membership.next_event(seq).cloned()
     }
 }
 
@@ -50,8 +123,15 @@ impl Manager {
 mod test {
     use super::Manager;
 
-    #[allow(dead_code)]
-    fn test_create_manager() {
-        let _test_manager = Manager::new();
+    #[test]
+    fn test_learn_address() {
+        let mut manager = Manager::new();
+        manager.start(("node1".to_string(), "0.0.0.0".to_string(), "2254".to_string()));
+        manager.learn_address("10.0.0.2".parse().unwrap());
+        manager.learn_address("10.0.0.3".parse().unwrap());
+        assert_eq!(manager.myself().unwrap().node.1, "10.0.0.2");
+        assert!(manager.is_member());
+        manager.leave();
+        assert!(!manager.is_member());
     }
 }
@@ -0,0 +1,503 @@
+//! Who is in the cluster, kept up to date SWIM-style. Each node probes another every protocol period; one that
+//! doesn't answer, directly or through others, is suspected of having failed, and if it doesn't refute that
+//! within `SUSPICION_TIMEOUT` is declared dead. Changes are spread by piggybacking them on the probes, rather than
+//! every node being connected to every other.
+//!
+//! Each member has an incarnation number, which only it increases, to refute suspicions of itself. An update
+//! about a member only replaces what is known of it if it is at least as recent: a higher incarnation, or the
+//! same incarnation and a worse state.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use chrono::prelude::*;
+use rand::{self, Rng};
+
+use cluster::{NodeAlias, NodeInfo};
+
+/// How long a suspected member has to refute it before it is declared dead
+pub const SUSPICION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many updates are piggybacked on each message
+pub const MAX_PIGGYBACK: usize = 8;
+
+/// Each update is piggybacked this many times the log of the cluster's size, which is enough for it to reach
+/// every member with high probability
+pub const RETRANSMIT_MULTIPLIER: usize = 3;
+
+/// How many events are kept. Older ones are dropped.
+pub const EVENT_LIMIT: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum NodeState {
+    Alive,
+    /// Didn't answer a probe, but may yet refute it
+    Suspect,
+    Dead,
+    /// Left the cluster of its own accord
+    Left,
+}
+
+impl fmt::Display for NodeState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NodeState::Alive => f.write_str("alive"),
+            NodeState::Suspect => f.write_str("suspect"),
+            NodeState::Dead => f.write_str("dead"),
+            NodeState::Left => f.write_str("left"),
+        }
+    }
+}
+
+/// What is known of a node, which is also the update sent to tell others about it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Member {
+    /// Its alias, and the address and port it listens for other nodes on
+    pub node: NodeInfo,
+    pub incarnation: u64,
+    pub state: NodeState,
+}
+
+impl Member {
+    pub fn alias(&self) -> &str {
+        &self.node.0
+    }
+
+    /// Where to connect to it, as `host:port`
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.node.1, self.node.2)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MembershipChange {
+    Joined,
+    Suspected,
+    /// A suspected node refuted it
+    Recovered,
+    Died,
+    Left,
+}
+
+impl fmt::Display for MembershipChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MembershipChange::Joined => f.write_str("joined"),
+            MembershipChange::Suspected => f.write_str("suspected"),
+            MembershipChange::Recovered => f.write_str("recovered"),
+            MembershipChange::Died => f.write_str("died"),
+            MembershipChange::Left => f.write_str("left"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MembershipEvent {
+    /// Numbers events in the order they happened, starting from 0
+    pub seq: u64,
+    pub at: DateTime<Utc>,
+    pub node: NodeInfo,
+    pub change: MembershipChange,
+}
+
+impl fmt::Display for MembershipEvent {
+    /// Such as `died node2`, which is how programs are given it
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.change, self.node.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct Membership {
+    /// This node, once it is listening for others
+    myself: Option<Member>,
+    /// The other members that are alive or suspected, with when they were last changed
+    members: HashMap<NodeAlias, (Member, Instant)>,
+    /// The members that died or left, as they were then, so older news of them being alive is ignored
+    departed: HashMap<NodeAlias, Member>,
+    /// Updates still to be piggybacked, with how many more times each is to be sent
+    updates: Vec<(Member, usize)>,
+    /// Members left to probe this round, which goes through every member in a random order
+    probe_order: Vec<NodeAlias>,
+    events: VecDeque<MembershipEvent>,
+    next_seq: u64,
+    suspicion_timeout: Duration,
+}
+
+impl Default for Membership {
+    fn default() -> Membership {
+        Membership {
+            myself: None,
+            members: HashMap::new(),
+            departed: HashMap::new(),
+            updates: vec![],
+            probe_order: vec![],
+            events: VecDeque::new(),
+            next_seq: 0,
+            suspicion_timeout: SUSPICION_TIMEOUT,
+        }
+    }
+}
+
+impl Membership {
+    pub fn new() -> Membership {
+        Membership::default()
+    }
+
+    /// Sets how long a suspected member has to refute it
+    pub fn with_suspicion_timeout(mut self, suspicion_timeout: Duration) -> Self {
+        self.suspicion_timeout = suspicion_timeout;
+        self
+    }
+
+    /// Makes this node a member as `node`. A node that restarts should start with a higher incarnation than it
+    /// had, so that it isn't taken for the one that died.
+    pub fn start(&mut self, node: NodeInfo, incarnation: u64) {
+        self.myself = Some(Member {
+            node,
+            incarnation,
+            state: NodeState::Alive,
+        });
+    }
+
+    /// Changes the address other nodes reach this one at
+    pub fn set_address(&mut self, ip: String) {
+        if let Some(ref mut myself) = self.myself {
+            myself.node.1 = ip;
+        }
+    }
+
+    pub fn myself(&self) -> Option<&Member> {
+        self.myself.as_ref()
+    }
+
+    /// Whether this node has left the cluster
+    pub fn has_left(&self) -> bool {
+        self.myself.as_ref().is_some_and(|m| m.state == NodeState::Left)
+    }
+
+    /// Leaves the cluster, and returns the update telling the others so. Returns `None` if this node isn't a
+    /// member.
+    pub fn leave(&mut self) -> Option<Member> {
+        let myself = self.myself.as_mut().filter(|m| m.state != NodeState::Left)?;
+        myself.incarnation += 1;
+        myself.state = NodeState::Left;
+        Some(myself.clone())
+    }
+
+    /// Applies an update about a member, from another node or this one's own probes. Returns whether it changed
+    /// anything, in which case it is passed on to others.
+    pub fn apply(&mut self, update: &Member, now: Instant) -> bool {
+        if self.myself.as_ref().is_some_and(|m| m.alias() == update.alias()) {
+            return self.refute(update);
+        }
+        let current = self.members.get(update.alias()).map(|(m, _)| m.clone());
+        let change = match current {
+            None => {
+                let departed = self.departed.get(update.alias()).is_some_and(|m| m.incarnation >= update.incarnation);
+                if departed {
+                    return false;
+                }
+                if !is_member(update.state) {
+                    self.departed.insert(update.alias().to_string(), update.clone());
+                    return false;
+                }
+                MembershipChange::Joined
+            }
+            Some(ref current) => {
+                let newer = update.incarnation > current.incarnation || (update.incarnation == current.incarnation && update.state > current.state);
+                if !newer {
+                    return false;
+                }
+                match update.state {
+                    NodeState::Alive if current.state == NodeState::Suspect => MembershipChange::Recovered,
+                    NodeState::Alive => {
+                        // A newer incarnation of a member that is alive changes nothing worth reporting
+                        self.members.insert(update.alias().to_string(), (update.clone(), now));
+                        self.queue(update.clone());
+                        return true;
+                    }
+                    NodeState::Suspect if current.state == NodeState::Suspect => {
+                        self.members.insert(update.alias().to_string(), (update.clone(), now));
+                        self.queue(update.clone());
+                        return true;
+                    }
+                    NodeState::Suspect => MembershipChange::Suspected,
+                    NodeState::Dead => MembershipChange::Died,
+                    NodeState::Left => MembershipChange::Left,
+                }
+            }
+        };
+        if is_member(update.state) {
+            self.members.insert(update.alias().to_string(), (update.clone(), now));
+        } else {
+            self.members.remove(update.alias());
+            self.departed.insert(update.alias().to_string(), update.clone());
+        }
+        match change {
+            MembershipChange::Suspected => warn!("Node {} is suspected of having failed", update.alias()),
+            MembershipChange::Died => warn!("Node {} has failed", update.alias()),
+            _ => info!("Node {} {}", update.alias(), change),
+        }
+        self.record(&update.node, change);
+        self.queue(update.clone());
+        true
+    }
+
+    /// Suspects a member that didn't answer a probe
+    pub fn suspect(&mut self, alias: &str, now: Instant) {
+        let member = match self.members.get(alias) {
+            Some((member, _)) if member.state == NodeState::Alive => member.clone(),
+            _ => return,
+        };
+        self.apply(
+            &Member {
+                state: NodeState::Suspect,
+                ..member
+            },
+            now,
+        );
+    }
+
+    /// Declares dead the members that have been suspected for longer than the suspicion timeout
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<Member> = self
+            .members
+            .values()
+            .filter(|(m, since)| m.state == NodeState::Suspect && now.saturating_duration_since(*since) >= self.suspicion_timeout)
+            .map(|(m, _)| m.clone())
+            .collect();
+        for member in expired {
+            self.apply(
+                &Member {
+                    state: NodeState::Dead,
+                    ..member
+                },
+                now,
+            );
+        }
+    }
+
+    /// The other members that are alive or suspected, by alias
+    pub fn members(&self) -> Vec<Member> {
+        let mut members: Vec<Member> = self.members.values().map(|(m, _)| m.clone()).collect();
+        members.sort_by(|a, b| a.node.cmp(&b.node));
+        members
+    }
+
+    pub fn state(&self, alias: &str) -> Option<NodeState> {
+        self.members.get(alias).map(|(m, _)| m.state)
+    }
+
+    /// What was known of a member that died or left, when it did
+    pub fn departed(&self, alias: &str) -> Option<&Member> {
+        self.departed.get(alias)
+    }
+
+    /// A member that was declared dead, chosen at random, to try again in case it was only cut off from this one
+    pub fn reconnect_target(&self) -> Option<Member> {
+        let dead: Vec<&Member> = self.departed.values().filter(|m| m.state == NodeState::Dead).collect();
+        rand::thread_rng().choose(&dead).map(|m| (*m).clone())
+    }
+
+    /// The next member to probe. Every member is probed once, in a random order, before any is probed again.
+    pub fn probe_target(&mut self) -> Option<Member> {
+        loop {
+            if self.probe_order.is_empty() {
+                self.probe_order = self.members.keys().cloned().collect();
+                rand::thread_rng().shuffle(&mut self.probe_order);
+            }
+            let alias = self.probe_order.pop()?;
+            // Members that have gone since the round started are skipped
+            if let Some((member, _)) = self.members.get(&alias) {
+                return Some(member.clone());
+            }
+        }
+    }
+
+    /// Up to `count` members other than `alias`, chosen at random, to probe it through
+    pub fn random_members(&self, count: usize, alias: &str) -> Vec<Member> {
+        let others: Vec<Member> = self.members.values().map(|(m, _)| m).filter(|m| m.alias() != alias).cloned().collect();
+        rand::seq::sample_slice(&mut rand::thread_rng(), &others, count.min(others.len()))
+    }
+
+    /// The updates to piggyback on the next message, those sent the fewest times first
+    pub fn gossip(&mut self) -> Vec<Member> {
+        self.updates.sort_by_key(|u| Reverse(u.1));
+        let mut gossip = vec![];
+        for update in self.updates.iter_mut().take(MAX_PIGGYBACK) {
+            gossip.push(update.0.clone());
+            update.1 -= 1;
+        }
+        self.updates.retain(|u| u.1 > 0);
+        gossip
+    }
+
+    /// The events kept, oldest first
+    pub fn events(&self) -> Vec<MembershipEvent> {
+        self.events.iter().cloned().collect()
+    }
+
+    /// The oldest event kept numbered `seq` or later
+    pub fn next_event(&self, seq: u64) -> Option<&MembershipEvent> {
+        self.events.iter().find(|e| e.seq >= seq)
+    }
+
+    /// Answers news of this node being suspected or dead by raising its incarnation, which overrides it
+    fn refute(&mut self, update: &Member) -> bool {
+        let myself = match self.myself {
+            Some(ref mut myself) if myself.state != NodeState::Left => myself,
+            _ => return false,
+        };
+        if update.state == NodeState::Alive || update.incarnation < myself.incarnation {
+            return false;
+        }
+        info!("Refuting that this node is {}", update.state);
+        myself.incarnation = update.incarnation + 1;
+        let myself = myself.clone();
+        self.queue(myself);
+        true
+    }
+
+    /// Queues `update` to be piggybacked, replacing any older update about the same member
+    fn queue(&mut self, update: Member) {
+        let transmissions = RETRANSMIT_MULTIPLIER * log2(self.members.len() + 2);
+        self.updates.retain(|u| u.0.alias() != update.alias());
+        self.updates.push((update, transmissions));
+    }
+
+    fn record(&mut self, node: &NodeInfo, change: MembershipChange) {
+        if self.events.len() == EVENT_LIMIT {
+            self.events.pop_front();
+        }
+        self.events.push_back(MembershipEvent {
+            seq: self.next_seq,
+            at: Utc::now(),
+            node: node.clone(),
+            change,
+        });
+        self.next_seq += 1;
+    }
+}
+
+fn is_member(state: NodeState) -> bool {
+    state == NodeState::Alive || state == NodeState::Suspect
+}
+
+/// The base 2 logarithm of `n`, rounded up
+fn log2(n: usize) -> usize {
+    (usize::BITS - (n - 1).leading_zeros()) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(alias: &str, incarnation: u64, state: NodeState) -> Member {
+        Member {
+            node: (alias.to_string(), "127.0.0.1".to_string(), "2254".to_string()),
+            incarnation,
+            state,
+        }
+    }
+
+    fn changes(membership: &Membership) -> Vec<String> {
+        membership.events().iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn test_updates() {
+        let mut membership = Membership::new();
+        let now = Instant::now();
+        assert!(membership.apply(&member("a", 1, NodeState::Alive), now));
+        assert!(membership.apply(&member("b", 1, NodeState::Alive), now));
+        // Old news is ignored
+        assert!(!membership.apply(&member("a", 0, NodeState::Suspect), now));
+        assert!(membership.apply(&member("a", 1, NodeState::Suspect), now));
+        assert!(!membership.apply(&member("a", 1, NodeState::Alive), now));
+        assert_eq!(membership.state("a"), Some(NodeState::Suspect));
+        assert!(membership.apply(&member("a", 2, NodeState::Alive), now));
+        assert!(membership.apply(&member("b", 1, NodeState::Left), now));
+        // A member that has gone only comes back with a higher incarnation
+        assert!(!membership.apply(&member("b", 1, NodeState::Alive), now));
+        assert!(!membership.apply(&member("c", 4, NodeState::Dead), now));
+        assert!(!membership.apply(&member("c", 3, NodeState::Alive), now));
+        assert!(membership.apply(&member("b", 2, NodeState::Alive), now));
+
+        assert_eq!(
+            changes(&membership),
+            vec!["joined a", "joined b", "suspected a", "recovered a", "left b", "joined b"]
+        );
+        let members: Vec<String> = membership.members().iter().map(|m| m.alias().to_string()).collect();
+        assert_eq!(members, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_suspicion() {
+        let mut membership = Membership::new().with_suspicion_timeout(Duration::from_secs(5));
+        let start = Instant::now();
+        membership.apply(&member("a", 1, NodeState::Alive), start);
+        membership.suspect("a", start + Duration::from_secs(1));
+        membership.expire(start + Duration::from_secs(5));
+        assert_eq!(membership.state("a"), Some(NodeState::Suspect));
+        membership.expire(start + Duration::from_secs(6));
+        assert_eq!(membership.state("a"), None);
+        assert_eq!(membership.reconnect_target(), Some(member("a", 1, NodeState::Dead)));
+        assert_eq!(changes(&membership), vec!["joined a", "suspected a", "died a"]);
+    }
+
+    #[test]
+    fn test_refute() {
+        let mut membership = Membership::new();
+        membership.start(member("me", 5, NodeState::Alive).node, 5);
+        assert!(!membership.apply(&member("me", 4, NodeState::Suspect), Instant::now()));
+        assert!(membership.apply(&member("me", 5, NodeState::Suspect), Instant::now()));
+        assert_eq!(membership.myself().map(|m| m.incarnation), Some(6));
+        assert_eq!(membership.gossip(), vec![member("me", 6, NodeState::Alive)]);
+        assert_eq!(membership.leave(), Some(member("me", 7, NodeState::Left)));
+        assert!(membership.has_left());
+        assert_eq!(membership.leave(), None);
+    }
+
+    #[test]
+    fn test_gossip() {
+        let mut membership = Membership::new();
+        let now = Instant::now();
+        for i in 0..10 {
+            membership.apply(&member(&i.to_string(), 1, NodeState::Alive), now);
+        }
+        // Each update is sent a number of times, then no more
+        let mut sent = 0;
+        loop {
+            let gossip = membership.gossip();
+            if gossip.is_empty() {
+                break;
+            }
+            assert!(gossip.len() <= MAX_PIGGYBACK);
+            sent += gossip.len();
+        }
+        assert!(sent >= 10 * RETRANSMIT_MULTIPLIER);
+
+        // Every member is probed once a round
+        let mut probed: Vec<String> = (0..10).filter_map(|_| membership.probe_target()).map(|m| m.alias().to_string()).collect();
+        probed.sort();
+        probed.dedup();
+        assert_eq!(probed.len(), 10);
+        assert_eq!(membership.random_members(3, "0").len(), 3);
+        assert!(membership.random_members(20, "0").iter().all(|m| m.alias() != "0"));
+    }
+
+    #[test]
+    fn test_event_limit() {
+        let mut membership = Membership::new();
+        let now = Instant::now();
+        for i in 0..EVENT_LIMIT + 5 {
+            membership.apply(&member(&i.to_string(), 1, NodeState::Alive), now);
+        }
+        assert_eq!(membership.events().len(), EVENT_LIMIT);
+        // Events that were dropped are skipped over
+        assert_eq!(membership.next_event(0).map(|e| e.seq), Some(5));
+    }
+}
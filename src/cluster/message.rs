@@ -1,50 +1,51 @@
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-
 use bincode::*;
 
-use cluster::client::ClusterClient;
-use cluster::{NodeAlias, NodeInfo, NodePort};
+use cluster::membership::Member;
 
-#[derive(Serialize, Deserialize, Debug)]
-/// These are the message types that cluster nodes can exchange between themselves
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+/// These are the message types that cluster nodes can exchange between themselves. Each is sent on a connection
+/// of its own, which carries its answer, if it has one, back.
 pub enum IridiumMessage {
-    Hello { alias: NodeAlias, port: NodePort },
-    HelloAck { alias: NodeInfo, nodes: Vec<NodeInfo> },
-    Join { alias: NodeAlias, port: NodePort },
+    /// Asks to join the cluster as `member`. Answered with a HelloAck.
+    Hello {
+        member: Member,
+    },
+    /// Every member the node that was sent a Hello knows of, itself included
+    HelloAck {
+        members: Vec<Member>,
+    },
+    /// Probes whether a node is still there, from the member `from`. Answered with an Ack.
+    Ping {
+        from: Member,
+        updates: Vec<Member>,
+    },
+    /// Asks a node to probe `target` for the sender, which couldn't reach it. Answered with an Ack if `target`
+    /// answers.
+    PingReq {
+        target: Member,
+        updates: Vec<Member>,
+    },
+    Ack {
+        from: Member,
+        updates: Vec<Member>,
+    },
+    /// The sender is leaving the cluster
+    Leave {
+        member: Member,
+    },
 }
 
 impl IridiumMessage {
-    pub fn join(alias: &str, port: &str) -> Result<Vec<u8>> {
-        trace!("Generating join message!");
-        let new_message = IridiumMessage::Join {
-            alias: alias.into(),
-            port: port.into(),
-        };
-        serialize(&new_message)
-    }
-
     /// Creates and serializes a Hello message
-    pub fn hello(alias: &str, port: &str) -> Result<Vec<u8>> {
+    pub fn hello(member: Member) -> Result<Vec<u8>> {
         trace!("Generating hello message");
-        let new_message = IridiumMessage::Hello {
-            alias: alias.into(),
-            port: port.into(),
-        };
-        serialize(&new_message)
+        serialize(&IridiumMessage::Hello { member })
     }
 
     /// Creates and serializes a HelloAck message, whch sends back a list of all cluster nodes to the sender
-    pub fn hello_ack(myself: NodeInfo, clients: &HashMap<NodeAlias, Arc<RwLock<ClusterClient>>>) -> Result<Vec<u8>> {
+    pub fn hello_ack(members: Vec<Member>) -> Result<Vec<u8>> {
         trace!("Generating helloack message");
-        let mut results: Vec<NodeInfo> = Vec::new();
-        for (key, value) in clients.iter() {
-            if let Ok(client_data) = value.read() {
-                results.push((key.to_string(), client_data.ip_as_string().unwrap(), client_data.port_as_string().unwrap()));
-            }
-        }
-        let new_message = IridiumMessage::HelloAck { alias: myself, nodes: results };
-        serialize(&new_message)
+        serialize(&IridiumMessage::HelloAck { members })
     }
 
     pub fn process_message(message: &[u8]) -> Result<IridiumMessage> {
@@ -54,4 +55,18 @@ impl IridiumMessage {
 }
 
 #[cfg(test)]
-mod test {}
+mod test {
+    use super::*;
+    use cluster::membership::NodeState;
+
+    #[test]
+    fn test_round_trip() {
+        let member = Member {
+            node: ("node1".to_string(), "127.0.0.1".to_string(), "2254".to_string()),
+            incarnation: 3,
+            state: NodeState::Suspect,
+        };
+        let message = IridiumMessage::process_message(&IridiumMessage::hello(member.clone()).unwrap()).unwrap();
+        assert_eq!(message, IridiumMessage::Hello { member });
+    }
+}
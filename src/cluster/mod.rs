@@ -1,6 +1,8 @@
 pub mod alias;
 pub mod client;
+pub mod gossip;
 pub mod manager;
+pub mod membership;
 pub mod message;
 pub mod server;
 
@@ -1,75 +1,224 @@
-use std::net::{SocketAddr, TcpListener};
+use std::net::{IpAddr, TcpListener};
+use std::slice;
 use std::sync::{Arc, RwLock};
 use std::thread;
 
 use cluster::client::ClusterClient;
+use cluster::gossip::{self, PING_TIMEOUT};
 use cluster::manager::Manager;
+use cluster::membership::Member;
 use cluster::message::IridiumMessage;
+use tls::{self, TlsConfig};
 
-pub fn listen(my_alias: String, addr: SocketAddr, connection_manager: Arc<RwLock<Manager>>) {
+/// Answers other nodes on `listener`, one message to a connection. With `tls`, connections are encrypted and nodes
+/// have to present a certificate.
+pub fn listen(listener: TcpListener, connection_manager: Arc<RwLock<Manager>>, tls: Option<TlsConfig>) {
     info!("Initializing Cluster server...");
-    let listener = TcpListener::bind(addr).unwrap();
     for stream in listener.incoming() {
-        let tmp_alias = my_alias.clone();
-        let mut cmgr = connection_manager.clone();
-        info!("New Node connected!");
-        let stream = stream.unwrap();
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Unable to accept a node's connection: {}", e);
+                continue;
+            }
+        };
+        let cmgr = connection_manager.clone();
+        let tls = tls.clone();
         thread::spawn(move || {
-            debug!("Handling an incoming connection on a thread");
-            let mut client = ClusterClient::new(stream, cmgr.clone(), addr.port().to_string());
-            let result: bincode::Result<IridiumMessage> = bincode::deserialize_from(&mut client.reader);
-            match result {
+            trace!("Handling an incoming connection on a thread");
+            let stream = match tls::accept_node(stream, tls.as_ref()) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Rejected node connection: {}", e);
+                    return;
+                }
+            };
+            // A node that connects and sends nothing doesn't get to tie up a thread
+            if let Err(e) = stream.set_read_timeout(Some(tls::HANDSHAKE_TIMEOUT)) {
+                error!("Unable to set a timeout on a node's connection: {}", e);
+                return;
+            }
+            let mut client = ClusterClient::new(stream);
+            if let Some(fingerprint) = client.peer_fingerprint() {
+                trace!("Node presented certificate {}", fingerprint);
+            }
+            if let Ok(ip) = client.local_ip() {
+                cmgr.write().unwrap().learn_address(ip);
+            }
+            match client.receive() {
                 Ok(message) => {
-                    match message {
-                        IridiumMessage::Hello { alias, port } => {
-                            debug!("Received hello");
-                            let mut members: Vec<(String, String, String)> = Vec::new();
-
-                            // Now we need to send back a list of cluster members in the form of a Vector of tuples, containing their alias
-                            debug!("Generating member list");
-                            let mut cmgr_lock = cmgr.read().unwrap();
-                            debug!("Grabbed read lock on manager");
-                            for (key, value) in &cmgr_lock.clients {
-                                debug!("Processing kv: {:#?} {:#?}", key, value);
-                                let tuple = (key.0.to_string(), key.1.to_string(), key.2.to_string());
-                                members.push(tuple);
-                            }
-                            debug!("Generating hello_ack");
-                            let hello_ack = IridiumMessage::HelloAck {
-                                nodes: members,
-                                alias: (tmp_alias.clone(), addr.ip().to_string(), addr.port().to_string()),
-                            };
-
-                            client.write_bytes(&bincode::serialize(&hello_ack).unwrap());
-                            debug!("Adding {} to clients. Client info: {:?}", alias, client);
-                            {
-                                let mut cmgr_lock = cmgr.write().unwrap();
-                                let client_tuple = (alias.to_string(), client.remote_ip_as_string().unwrap(), port.to_string());
-                                cmgr_lock.add_client(client_tuple, client);
-                            }
-                            debug!("Client added to manager");
-                        }
-
-                        // Handles another node sending a Join message. In this case, we don't want to send back a list of all known nodes.
-                        IridiumMessage::Join { alias, port } => {
-                            debug!("Received join message from alias: {:?}", alias);
-                            if let Ok(mut connection_manager) = cmgr.write() {
-                                debug!("Added new client {} to conneciton manager", alias);
-                                let client_tuple = (alias.to_string(), client.remote_ip_as_string().unwrap(), port);
-                                connection_manager.add_client(client_tuple, client);
-                            } else {
-                                error!("Unable to add {} to connection manager", alias);
-                            }
-                        }
-                        _ => {
-                            error!("Unknown message received from node");
+                    if let Some(answer) = answer(message, &client, &cmgr, tls.as_ref()) {
+                        if let Err(e) = client.send(&answer) {
+                            debug!("Unable to answer a node: {}", e);
                         }
                     }
                 }
                 Err(e) => {
-                    error!("Error deserializing Iridium message: {:?}", e);
+                    error!("Error deserializing Iridium message: {}", e);
+                }
+            }
+        });
+    }
+}
+
+/// Handles a message from another node, returning the answer to send back if it has one
+fn answer(message: IridiumMessage, client: &ClusterClient, manager: &Arc<RwLock<Manager>>, tls: Option<&TlsConfig>) -> Option<IridiumMessage> {
+    // A node that has left doesn't take part any more
+    if manager.read().unwrap().has_left() {
+        return None;
+    }
+    let claimed = match message {
+        IridiumMessage::Hello { ref member } | IridiumMessage::Leave { ref member } => Some(member.alias()),
+        IridiumMessage::Ping { ref from, .. } => Some(from.alias()),
+        _ => None,
+    };
+    if let Some(alias) = claimed {
+        if let Err(e) = client.verify_alias(alias, tls) {
+            warn!("Ignoring a message from a node claiming to be {}: {}", alias, e);
+            return None;
+        }
+    }
+    match message {
+        IridiumMessage::Hello { mut member } => {
+            debug!("Received hello from {}", member.alias());
+            // A node listening on all addresses is reached at the one it connected from
+            if member.node.1.parse::<IpAddr>().is_ok_and(|ip| ip.is_unspecified()) {
+                if let Ok(ip) = client.peer_ip() {
+                    member.node.1 = ip.to_string();
                 }
             }
+            let mut manager = manager.write().unwrap();
+            manager.apply(&[member.clone()]);
+            let mut members: Vec<Member> = manager.members().into_iter().filter(|m| m.alias() != member.alias()).collect();
+            members.extend(manager.myself());
+            Some(IridiumMessage::HelloAck { members })
+        }
+        IridiumMessage::Ping { from, updates } => {
+            let mut manager = manager.write().unwrap();
+            let updates = gossip::trusted_updates(updates, Some(from.alias()), &manager, tls);
+            manager.apply(slice::from_ref(&from));
+            manager.apply(&updates);
+            let mut updates = manager.gossip();
+            // A node this one thinks is dead is told, so it can refute it
+            updates.extend(manager.departed(from.alias()));
+            Some(IridiumMessage::Ack {
+                from: manager.myself()?,
+                updates,
+            })
+        }
+        IridiumMessage::PingReq { target, updates } => {
+            {
+                // Who asked isn't known, so they aren't believed about anyone
+                let mut manager = manager.write().unwrap();
+                let updates = gossip::trusted_updates(updates, None, &manager, tls);
+                manager.apply(&updates);
+            }
+            if gossip::ping(manager, &target, tls, PING_TIMEOUT) {
+                let mut manager = manager.write().unwrap();
+                Some(IridiumMessage::Ack {
+                    from: manager.myself()?,
+                    updates: manager.gossip(),
+                })
+            } else {
+                None
+            }
+        }
+        IridiumMessage::Leave { member } => {
+            manager.write().unwrap().apply(&[member]);
+            None
+        }
+        message => {
+            error!("Unexpected message received from node: {:?}", message);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cluster::membership::NodeState;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls").join(name)
+    }
+
+    fn member(alias: &str, incarnation: u64, state: NodeState) -> Member {
+        Member {
+            node: (alias.to_string(), "127.0.0.1".to_string(), "1".to_string()),
+            incarnation,
+            state,
+        }
+    }
+
+    #[test]
+    fn test_alias_bound_to_certificate() {
+        // The node trusts the stranger's certificate as well as the CA, so the stranger gets past the handshake
+        let trusted = std::env::temp_dir().join(format!("iridium-trusted-test-{}.pem", std::process::id()));
+        let mut bundle = fs::read(fixture("ca.pem")).unwrap();
+        bundle.extend(fs::read(fixture("stranger.pem")).unwrap());
+        fs::write(&trusted, bundle).unwrap();
+        let tls = TlsConfig::load(&fixture("node.pem"), &fixture("node-key.pem"), Some(&trusted)).unwrap();
+        fs::remove_file(&trusted).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let manager = Arc::new(RwLock::new(Manager::new()));
+        manager.write().unwrap().start(("node".to_string(), "127.0.0.1".to_string(), "0".to_string()));
+        manager.write().unwrap().apply(&[member("node2", 1, NodeState::Alive)]);
+        let cloned = manager.clone();
+        thread::spawn(move || listen(listener, cloned, Some(tls)));
+
+        let stranger = TlsConfig::load(&fixture("stranger.pem"), &fixture("stranger-key.pem"), Some(&fixture("ca.pem"))).unwrap();
+        let send = |message: IridiumMessage| ClusterClient::request(&addr, &message, Some(&stranger), Duration::from_secs(5));
+        let aliases = || -> Vec<String> { manager.read().unwrap().members().iter().map(|m| m.alias().to_string()).collect() };
+
+        // Claiming to be another node gets no answer, and changes nothing
+        assert!(send(IridiumMessage::Hello {
+            member: member("node3", 1, NodeState::Alive)
+        })
+        .is_err());
+        let ping = IridiumMessage::Ping {
+            from: member("node2", 2, NodeState::Alive),
+            updates: vec![member("node3", 1, NodeState::Alive)],
+        };
+        assert!(send(ping).is_err());
+        let _ = send(IridiumMessage::Leave {
+            member: member("node2", 2, NodeState::Left),
         });
+        assert_eq!(aliases(), vec!["node2"]);
+        assert_eq!(manager.read().unwrap().members()[0].incarnation, 1);
+
+        match send(IridiumMessage::Hello {
+            member: member("stranger", 1, NodeState::Alive),
+        }) {
+            Ok(IridiumMessage::HelloAck { members }) => assert!(members.iter().any(|m| m.alias() == "node")),
+            answer => panic!("Expected a HelloAck, got {:?}", answer),
+        }
+        assert_eq!(aliases(), vec!["node2", "stranger"]);
+
+        // Nor can a node evict another by piggybacking that it left or died, however high the incarnation
+        let forged = vec![member("node2", 1000, NodeState::Left), member("node2", 1000, NodeState::Dead)];
+        let ping = IridiumMessage::Ping {
+            from: member("stranger", 2, NodeState::Alive),
+            updates: forged.clone(),
+        };
+        assert!(send(ping).is_ok());
+        let _ = send(IridiumMessage::PingReq {
+            target: member("node2", 1, NodeState::Alive),
+            updates: forged,
+        });
+        assert_eq!(aliases(), vec!["node2", "stranger"]);
+
+        // It is believed about itself, though
+        let ping = IridiumMessage::Ping {
+            from: member("stranger", 3, NodeState::Alive),
+            updates: vec![member("stranger", 4, NodeState::Left)],
+        };
+        let _ = send(ping);
+        assert_eq!(aliases(), vec!["node2"]);
     }
 }
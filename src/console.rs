@@ -0,0 +1,230 @@
+//! Where a VM's output goes and where its input comes from. By default that's the terminal, but embedders can
+//! capture output in memory, send it over a channel or write it to a file, and feed input the same ways.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Somewhere for the text a program prints to go
+pub trait OutputSink: Send {
+    fn write(&mut self, text: &str);
+}
+
+/// Somewhere for a program to read lines of input from
+pub trait InputSource: Send {
+    /// Returns the next line, without its line ending, or `None` once there is no more input
+    fn read_line(&mut self) -> Option<String>;
+}
+
+/// Writes to the process's stdout
+#[derive(Debug, Default)]
+pub struct StdoutSink;
+
+impl OutputSink for StdoutSink {
+    fn write(&mut self, text: &str) {
+        print!("{}", text);
+        let _ = io::stdout().flush();
+    }
+}
+
+/// Collects output in memory. Clones share the same buffer, so keep one to read what was written.
+#[derive(Debug, Default, Clone)]
+pub struct MemorySink {
+    buffer: Arc<Mutex<String>>,
+}
+
+impl MemorySink {
+    pub fn new() -> MemorySink {
+        MemorySink::default()
+    }
+
+    /// Everything written so far
+    pub fn contents(&self) -> String {
+        self.buffer.lock().unwrap().clone()
+    }
+
+    /// Returns everything written so far and empties the buffer
+    pub fn take(&self) -> String {
+        self.buffer.lock().unwrap().split_off(0)
+    }
+}
+
+impl OutputSink for MemorySink {
+    fn write(&mut self, text: &str) {
+        self.buffer.lock().unwrap().push_str(text);
+    }
+}
+
+/// Sends each piece of output as a message, such as to the REPL's pipe
+#[derive(Debug)]
+pub struct ChannelSink {
+    sender: Sender<String>,
+}
+
+impl ChannelSink {
+    pub fn new(sender: Sender<String>) -> ChannelSink {
+        ChannelSink { sender }
+    }
+}
+
+impl OutputSink for ChannelSink {
+    fn write(&mut self, text: &str) {
+        // Nobody is listening anymore, so there's nowhere for the output to go
+        let _ = self.sender.send(text.to_string());
+    }
+}
+
+#[derive(Debug)]
+pub struct FileSink {
+    file: File,
+}
+
+impl FileSink {
+    pub fn new(file: File) -> FileSink {
+        FileSink { file }
+    }
+}
+
+impl OutputSink for FileSink {
+    fn write(&mut self, text: &str) {
+        if let Err(e) = self.file.write_all(text.as_bytes()) {
+            error!("Unable to write program output to file: {}", e);
+        }
+    }
+}
+
+/// Reads from the process's stdin
+#[derive(Debug, Default)]
+pub struct StdinSource;
+
+impl InputSource for StdinSource {
+    fn read_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        match io::stdin().lock().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(trim_line_ending(line)),
+        }
+    }
+}
+
+/// Reads from lines given up front
+#[derive(Debug, Default, Clone)]
+pub struct MemorySource {
+    lines: VecDeque<String>,
+}
+
+impl MemorySource {
+    pub fn new(input: &str) -> MemorySource {
+        MemorySource {
+            lines: input.lines().map(|l| l.to_string()).collect(),
+        }
+    }
+}
+
+impl InputSource for MemorySource {
+    fn read_line(&mut self) -> Option<String> {
+        self.lines.pop_front()
+    }
+}
+
+/// Reads each message received on the channel as a line. Blocks until one arrives.
+#[derive(Debug)]
+pub struct ChannelSource {
+    receiver: Receiver<String>,
+}
+
+impl ChannelSource {
+    pub fn new(receiver: Receiver<String>) -> ChannelSource {
+        ChannelSource { receiver }
+    }
+}
+
+impl InputSource for ChannelSource {
+    fn read_line(&mut self) -> Option<String> {
+        self.receiver.recv().ok().map(trim_line_ending)
+    }
+}
+
+fn trim_line_ending(mut line: String) -> String {
+    while line.ends_with('\n') || line.ends_with('\r') {
+        line.pop();
+    }
+    line
+}
+
+/// The output sink and input source of a VM. Clones share them, so a VM copied into another thread keeps
+/// writing to the same place.
+#[derive(Clone)]
+pub struct Console {
+    output: Arc<Mutex<dyn OutputSink>>,
+    input: Arc<Mutex<dyn InputSource>>,
+}
+
+impl Default for Console {
+    fn default() -> Console {
+        Console::new()
+    }
+}
+
+impl Console {
+    /// Creates a console that uses stdout and stdin
+    pub fn new() -> Console {
+        Console {
+            output: Arc::new(Mutex::new(StdoutSink)),
+            input: Arc::new(Mutex::new(StdinSource)),
+        }
+    }
+
+    pub fn with_output<S: OutputSink + 'static>(mut self, sink: S) -> Self {
+        self.output = Arc::new(Mutex::new(sink));
+        self
+    }
+
+    pub fn with_input<S: InputSource + 'static>(mut self, source: S) -> Self {
+        self.input = Arc::new(Mutex::new(source));
+        self
+    }
+
+    pub fn write(&self, text: &str) {
+        self.output.lock().unwrap().write(text);
+    }
+
+    pub fn read_line(&self) -> Option<String> {
+        self.input.lock().unwrap().read_line()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_memory_sink_and_source() {
+        let sink = MemorySink::new();
+        let console = Console::new().with_output(sink.clone()).with_input(MemorySource::new("one\r\ntwo\n"));
+        console.write("Hello, ");
+        console.clone().write("World");
+        assert_eq!(sink.contents(), "Hello, World");
+        assert_eq!(sink.take(), "Hello, World");
+        assert_eq!(sink.contents(), "");
+        assert_eq!(console.read_line(), Some("one".to_string()));
+        assert_eq!(console.read_line(), Some("two".to_string()));
+        assert_eq!(console.read_line(), None);
+    }
+
+    #[test]
+    fn test_channels() {
+        let (out_tx, out_rx) = mpsc::channel();
+        let (in_tx, in_rx) = mpsc::channel();
+        let console = Console::new().with_output(ChannelSink::new(out_tx)).with_input(ChannelSource::new(in_rx));
+        console.write("42");
+        assert_eq!(out_rx.recv().unwrap(), "42");
+        in_tx.send("7\n".to_string()).unwrap();
+        drop(in_tx);
+        assert_eq!(console.read_line(), Some("7".to_string()));
+        assert_eq!(console.read_line(), None);
+    }
+}
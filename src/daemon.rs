@@ -0,0 +1,292 @@
+//! Running a node headless, such as in a container. There is no REPL: the node binds its cluster, remote
+//! access and RPC servers, runs the programs users submit through them, and writes its PID file and logs
+//! under the data root until it receives SIGTERM or SIGINT.
+
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use policy::Policy;
+use remote::auth::{Authenticator, UserStore, USERS_FILENAME};
+use remote::roles::{RoleConfig, ROLES_FILENAME};
+use remote::server::Server;
+use remote::session::DEFAULT_IDLE_TIMEOUT;
+use repl::history::USER_HISTORY_DIRNAME;
+use rpc;
+use scheduler::Shutdown;
+use tls::TlsConfig;
+use vm::VM;
+
+/// The file in the data root the daemon writes its process ID to
+pub const PID_FILENAME: &str = "iridium.pid";
+
+/// The file, relative to the data root, that the daemon's logs and anything printed go to
+pub const LOG_FILENAME: &str = "logs/iridium.log";
+
+/// How long running programs are given to stop once the daemon is asked to shut down
+pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Set by the signal handler. Very little is safe to do in one, so the daemon polls this instead.
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(_signal: libc::c_int) {
+    SIGNALLED.store(true, Ordering::SeqCst);
+}
+
+pub struct Daemon {
+    data_root: PathBuf,
+    alias: String,
+    /// Address and port to listen for other nodes on
+    cluster_bind: Option<(String, String)>,
+    /// Address and port to listen for remote access on
+    remote_bind: Option<(String, String)>,
+    /// What programs submitted through remote access may do
+    policy: Policy,
+    /// Encrypts remote access and cluster connections
+    tls: Option<TlsConfig>,
+    /// How long remote access sessions are kept once their connection drops
+    idle_timeout: Duration,
+    /// Address and port to listen for RPC connections on
+    rpc_bind: Option<(String, String)>,
+    /// Unix socket to listen for RPC connections on
+    rpc_socket: Option<PathBuf>,
+}
+
+impl Daemon {
+    pub fn new(data_root: PathBuf, alias: String) -> Daemon {
+        Daemon {
+            data_root,
+            alias,
+            cluster_bind: None,
+            remote_bind: None,
+            policy: Policy::restrictive(),
+            tls: None,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            rpc_bind: None,
+            rpc_socket: None,
+        }
+    }
+
+    pub fn with_cluster_bind(mut self, server_addr: String, server_port: String) -> Self {
+        self.cluster_bind = Some((server_addr, server_port));
+        self
+    }
+
+    pub fn with_remote_bind(mut self, host: String, port: String) -> Self {
+        self.remote_bind = Some((host, port));
+        self
+    }
+
+    /// Sets what programs submitted through remote access may do. Defaults to `Policy::restrictive()`.
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Encrypts remote access and cluster connections with TLS
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Sets how long remote access sessions are kept once their connection drops, and how long a connection
+    /// may be idle. Defaults to `session::DEFAULT_IDLE_TIMEOUT`.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn with_rpc_bind(mut self, host: String, port: String) -> Self {
+        self.rpc_bind = Some((host, port));
+        self
+    }
+
+    pub fn with_rpc_socket(mut self, path: PathBuf) -> Self {
+        self.rpc_socket = Some(path);
+        self
+    }
+
+    /// Runs the node until it receives SIGTERM or SIGINT, then stops the programs it is running, giving them
+    /// `SHUTDOWN_GRACE_PERIOD` to do so, leaves the cluster, and removes its PID file and RPC socket. Remote access and RPC users
+    /// have to log in as one of the users in the data root, so there must be at least one, and can run the
+    /// commands their role in the data root's roles file allows.
+    pub fn run(self) -> io::Result<()> {
+        let users = UserStore::load(&self.data_root.join(USERS_FILENAME))?;
+        let logins_needed = self.remote_bind.is_some() || self.rpc_bind.is_some() || self.rpc_socket.is_some();
+        if logins_needed && users.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "remote access needs at least one user to log in as. Add one with `iridium passwd <username>`.",
+            ));
+        }
+        let roles = RoleConfig::load(&self.data_root.join(ROLES_FILENAME))?;
+        let log_file = self.data_root.join(LOG_FILENAME);
+        if let Some(logs) = log_file.parent() {
+            fs::create_dir_all(logs)?;
+        }
+        redirect_output(&log_file)?;
+        let pid_file = self.data_root.join(PID_FILENAME);
+        write_pid_file(&pid_file)?;
+        install_signal_handlers();
+        info!("Daemon started with PID {} and alias {}", process::id(), self.alias);
+
+        let shutdown = Shutdown::new();
+        let authenticator = Authenticator::new(users);
+        let mut rpc_server = rpc::server::Server::new()
+            .with_policy(self.policy.clone())
+            .with_shutdown(shutdown.clone())
+            .with_authenticator(authenticator.clone())
+            .with_roles(roles.clone());
+        let mut cluster_vm = None;
+        if let Some((addr, port)) = self.cluster_bind {
+            let mut vm = VM::new().with_alias(self.alias.clone()).with_cluster_bind(addr, port);
+            if let Some(ref tls) = self.tls {
+                vm = vm.with_cluster_tls(tls.clone());
+            }
+            if let Err(e) = vm.bind_cluster_server() {
+                error!("Unable to listen for other nodes: {}", e);
+            }
+            rpc_server = rpc_server.with_cluster(vm.connection_manager());
+            cluster_vm = Some(vm);
+        }
+        if let Some((host, port)) = self.remote_bind {
+            let mut server = Server::new(host, port)
+                .with_policy(self.policy.clone())
+                .with_shutdown(shutdown.clone())
+                .with_authenticator(authenticator)
+                .with_roles(roles)
+                .with_idle_timeout(self.idle_timeout)
+                .with_history_dir(self.data_root.join(USER_HISTORY_DIRNAME));
+            if let Some(ref tls) = self.tls {
+                server = server.with_tls(tls.clone());
+            }
+            thread::spawn(move || server.listen());
+        }
+        if let Some(ref tls) = self.tls {
+            rpc_server = rpc_server.with_tls(tls.clone());
+        }
+        if let Some((host, port)) = self.rpc_bind {
+            let server = rpc_server.clone();
+            thread::spawn(move || {
+                if let Err(e) = server.listen_tcp(&format!("{}:{}", host, port)) {
+                    error!("Unable to listen for RPC connections on {}:{}: {}", host, port, e);
+                }
+            });
+        }
+        if let Some(path) = self.rpc_socket.clone() {
+            thread::spawn(move || {
+                if let Err(e) = rpc_server.listen_unix(&path) {
+                    error!("Unable to listen for RPC connections on {}: {}", path.display(), e);
+                }
+            });
+        }
+
+        while !SIGNALLED.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
+        }
+        info!("Shutting down with {} programs running", shutdown.running());
+        shutdown.request();
+        let deadline = Instant::now() + SHUTDOWN_GRACE_PERIOD;
+        while shutdown.running() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        if shutdown.running() > 0 {
+            warn!("{} programs did not stop in time", shutdown.running());
+        }
+        if let Some(ref mut vm) = cluster_vm {
+            vm.leave_cluster();
+        }
+        if let Some(ref path) = self.rpc_socket {
+            let _ = fs::remove_file(path);
+        }
+        fs::remove_file(&pid_file)?;
+        info!("Daemon stopped");
+        Ok(())
+    }
+}
+
+/// Writes this process's ID to `path`. Fails if the file names another process that is still running, as
+/// two daemons sharing a data root would step on each other.
+pub fn write_pid_file(path: &Path) -> io::Result<()> {
+    if let Ok(contents) = fs::read_to_string(path) {
+        if let Ok(pid) = contents.trim().parse::<u32>() {
+            if pid != process::id() && is_running(pid) {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("Iridium is already running with PID {}", pid),
+                ));
+            }
+        }
+    }
+    fs::write(path, format!("{}\n", process::id()))
+}
+
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    // Signal 0 only checks that the process exists
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+fn install_signal_handlers() {
+    unsafe {
+        libc::signal(libc::SIGTERM, handle_signal as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGINT, handle_signal as *const () as libc::sighandler_t);
+    }
+}
+
+/// Appends stdout and stderr, and with them the logs, to the file at `path`
+#[cfg(unix)]
+fn redirect_output(path: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    for fd in &[libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(file.as_raw_fd(), *fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn redirect_output(path: &Path) -> io::Result<()> {
+    OpenOptions::new().create(true).append(true).open(path)?;
+    warn!("Output can only be redirected to {} on Unix", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_pid_file() {
+        let dir = std::env::temp_dir().join(format!("iridium-daemon-test-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(PID_FILENAME);
+
+        // A PID file left behind by a process that has exited is replaced
+        fs::write(&path, "4000000000\n").unwrap();
+        write_pid_file(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", process::id()));
+
+        #[cfg(unix)]
+        {
+            let parent = unsafe { libc::getppid() };
+            fs::write(&path, format!("{}\n", parent)).unwrap();
+            assert_eq!(write_pid_file(&path).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
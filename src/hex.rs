@@ -0,0 +1,26 @@
+//! Hex encoding, for hashes and for turning arbitrary names into safe file names
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes a string from `to_hex`. Returns `None` if it has an odd length or anything other than hex digits.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(to_hex(b"\x00\xffalice"), "00ff616c696365");
+        assert_eq!(from_hex("00ff616c696365"), Some(b"\x00\xffalice".to_vec()));
+        assert_eq!(from_hex("0"), None);
+        assert_eq!(from_hex("zz"), None);
+    }
+}
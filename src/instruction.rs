@@ -52,6 +52,13 @@ pub enum Opcode {
     POP,
     CALL,
     RET,
+    DJMP,
+    DJMPF,
+    DJMPB,
+    CALLN,
+    READ,
+    SYSCALL,
+    EXIT,
 }
 
 impl From<Opcode> for u8 {
@@ -105,6 +112,13 @@ impl From<Opcode> for u8 {
             Opcode::POP => 45,
             Opcode::CALL => 46,
             Opcode::RET => 47,
+            Opcode::DJMP => 48,
+            Opcode::DJMPF => 49,
+            Opcode::DJMPB => 50,
+            Opcode::CALLN => 51,
+            Opcode::READ => 52,
+            Opcode::SYSCALL => 53,
+            Opcode::EXIT => 54,
             Opcode::IGL => 100,
         }
     }
@@ -161,6 +175,13 @@ impl From<u8> for Opcode {
             45 => Opcode::POP,
             46 => Opcode::CALL,
             47 => Opcode::RET,
+            48 => Opcode::DJMP,
+            49 => Opcode::DJMPF,
+            50 => Opcode::DJMPB,
+            51 => Opcode::CALLN,
+            52 => Opcode::READ,
+            53 => Opcode::SYSCALL,
+            54 => Opcode::EXIT,
             _ => Opcode::IGL,
         }
     }
@@ -219,6 +240,13 @@ impl<'a> From<CompleteStr<'a>> for Opcode {
             CompleteStr("pop") => Opcode::POP,
             CompleteStr("call") => Opcode::CALL,
             CompleteStr("ret") => Opcode::RET,
+            CompleteStr("djmp") => Opcode::DJMP,
+            CompleteStr("djmpf") => Opcode::DJMPF,
+            CompleteStr("djmpb") => Opcode::DJMPB,
+            CompleteStr("calln") => Opcode::CALLN,
+            CompleteStr("read") => Opcode::READ,
+            CompleteStr("syscall") => Opcode::SYSCALL,
+            CompleteStr("exit") => Opcode::EXIT,
             _ => Opcode::IGL,
         }
     }
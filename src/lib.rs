@@ -5,16 +5,37 @@ extern crate log;
 #[macro_use]
 extern crate nom;
 extern crate bincode;
+extern crate libc;
 extern crate num_cpus;
+extern crate rand;
+extern crate ring;
+extern crate rustls;
+extern crate rustls_pemfile;
+extern crate rustyline;
 extern crate uuid;
+extern crate webpki;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+#[macro_use]
+extern crate serde_json;
 
 pub mod assembler;
 pub mod cluster;
+pub mod console;
+pub mod daemon;
+pub mod hex;
 pub mod instruction;
+pub mod linker;
+pub mod lsp;
+pub mod native;
+pub mod policy;
 pub mod remote;
 pub mod repl;
+pub mod rpc;
+pub mod runtime;
 pub mod scheduler;
+pub mod syscall;
+pub mod tls;
 pub mod vm;
+pub mod vm_errors;
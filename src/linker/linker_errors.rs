@@ -0,0 +1,61 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LinkerError {
+    NoObjects,
+    DuplicateSymbol {
+        name: String,
+        first_module: String,
+        second_module: String,
+    },
+    UndefinedSymbol {
+        name: String,
+        module: String,
+    },
+    RelocationOutOfRange {
+        name: String,
+        module: String,
+    },
+    /// The object file is corrupt: a relocation points past the end of its code
+    RelocationOutsideCode {
+        name: String,
+        module: String,
+    },
+}
+
+impl fmt::Display for LinkerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LinkerError::NoObjects => f.write_str("No object files were given to link"),
+            LinkerError::DuplicateSymbol {
+                ref name,
+                ref first_module,
+                ref second_module,
+            } => f.write_str(&format!("The symbol {} is exported by both {} and {}", name, first_module, second_module)),
+            LinkerError::UndefinedSymbol { ref name, ref module } => {
+                f.write_str(&format!("The symbol {} used in {} is not exported by any module", name, module))
+            }
+            LinkerError::RelocationOutOfRange { ref name, ref module } => f.write_str(&format!(
+                "The symbol {} used in {} is too far away to be encoded in the instruction",
+                name, module
+            )),
+            LinkerError::RelocationOutsideCode { ref name, ref module } => f.write_str(&format!(
+                "The use of {} in {} is past the end of its code, so the object file is corrupt",
+                name, module
+            )),
+        }
+    }
+}
+
+impl Error for LinkerError {
+    fn description(&self) -> &str {
+        match self {
+            LinkerError::NoObjects => "No object files were given to link",
+            LinkerError::DuplicateSymbol { .. } => "A symbol is exported by more than one module",
+            LinkerError::UndefinedSymbol { .. } => "A symbol is not exported by any module",
+            LinkerError::RelocationOutOfRange { .. } => "A symbol is too far away to be encoded in the instruction",
+            LinkerError::RelocationOutsideCode { .. } => "A relocation is past the end of its module's code",
+        }
+    }
+}
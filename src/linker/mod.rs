@@ -0,0 +1,268 @@
+//! Combines separately assembled object files into a single executable
+
+pub mod linker_errors;
+
+use std::collections::HashMap;
+
+use assembler::object::{ObjectFile, ObjectSymbol, RelocationKind, Section};
+use assembler::{natives_table, pie_header, PIE_HEADER_LENGTH};
+use linker::linker_errors::LinkerError;
+
+/// Where a module's sections ended up in the linked program
+struct Placement {
+    /// Offset of the module's read-only data from the start of the read-only section
+    ro_base: u32,
+    /// Absolute offset of the module's code in the program
+    code_base: u32,
+}
+
+impl Placement {
+    fn address_of(&self, symbol: &ObjectSymbol) -> u32 {
+        match symbol.section {
+            Section::Code => self.code_base + symbol.offset,
+            Section::Data => self.ro_base + symbol.offset,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Linker {
+    /// Object files to link, along with a name (usually the path) used to refer to them in errors
+    objects: Vec<(String, ObjectFile)>,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker { objects: vec![] }
+    }
+
+    /// Adds a module to the program. Execution starts at the beginning of the first module added.
+    pub fn add_object(&mut self, name: &str, object: ObjectFile) {
+        self.objects.push((name.to_string(), object));
+    }
+
+    /// Links every module added so far into an executable
+    pub fn link(&self) -> Result<Vec<u8>, Vec<LinkerError>> {
+        if self.objects.is_empty() {
+            return Err(vec![LinkerError::NoObjects]);
+        }
+        let mut errors = vec![];
+
+        // Every module's native functions go in a single import table
+        let mut natives: Vec<String> = vec![];
+        for (_, object) in &self.objects {
+            for relocation in object.relocations.iter().filter(|r| r.kind == RelocationKind::Native) {
+                if !natives.contains(&relocation.symbol) {
+                    natives.push(relocation.symbol.clone());
+                }
+            }
+        }
+        let mut natives_table = natives_table(&natives);
+
+        // The read-only sections of every module go first, then the import table, followed by all of their code
+        let ro_length: u32 = self.objects.iter().map(|(_, o)| o.ro.len() as u32).sum();
+        let mut placements = vec![];
+        let mut ro_base = 0;
+        let mut code_base = PIE_HEADER_LENGTH as u32 + 4 + ro_length + natives_table.len() as u32;
+        for (_, object) in &self.objects {
+            placements.push(Placement { ro_base, code_base });
+            ro_base += object.ro.len() as u32;
+            code_base += object.code.len() as u32;
+        }
+
+        // Build the table of symbols modules can see from each other
+        let mut exports: HashMap<&str, (&str, u32)> = HashMap::new();
+        for ((module, object), placement) in self.objects.iter().zip(&placements) {
+            for symbol in object.exports() {
+                if let Some(&(first_module, _)) = exports.get(symbol.name.as_str()) {
+                    errors.push(LinkerError::DuplicateSymbol {
+                        name: symbol.name.clone(),
+                        first_module: first_module.to_string(),
+                        second_module: module.clone(),
+                    });
+                    continue;
+                }
+                exports.insert(&symbol.name, (module, placement.address_of(symbol)));
+            }
+        }
+
+        let mut ro = vec![];
+        let mut code = vec![];
+        for ((module, object), placement) in self.objects.iter().zip(&placements) {
+            ro.extend_from_slice(&object.ro);
+            let mut module_code = object.code.clone();
+            for relocation in &object.relocations {
+                // Relocations come from the object file, which may be truncated or corrupt
+                let at = relocation.offset as usize + relocation.position as usize;
+                if at + 1 >= module_code.len() {
+                    errors.push(LinkerError::RelocationOutsideCode {
+                        name: relocation.symbol.clone(),
+                        module: module.clone(),
+                    });
+                    continue;
+                }
+                if relocation.kind == RelocationKind::Native {
+                    // `natives` was collected from these same relocations, so every one is in it
+                    let index = natives
+                        .iter()
+                        .position(|n| *n == relocation.symbol)
+                        .expect("native relocation missing from the import table");
+                    module_code[at] = (index >> 8) as u8;
+                    module_code[at + 1] = index as u8;
+                    continue;
+                }
+                // A module's own symbols take precedence over anything exported by other modules
+                let target = match object.symbol(&relocation.symbol) {
+                    Some(symbol) => placement.address_of(symbol),
+                    None => match exports.get(relocation.symbol.as_str()) {
+                        Some(&(_, address)) => address,
+                        None => {
+                            let error = LinkerError::UndefinedSymbol {
+                                name: relocation.symbol.clone(),
+                                module: module.clone(),
+                            };
+                            if !errors.contains(&error) {
+                                errors.push(error);
+                            }
+                            continue;
+                        }
+                    },
+                };
+                let next_instruction = i64::from(placement.code_base + relocation.offset) + 4;
+                let value = match relocation.kind {
+                    RelocationKind::Absolute => i64::from(target),
+                    RelocationKind::RelativeForward => i64::from(target) - next_instruction,
+                    RelocationKind::RelativeBackward => next_instruction - i64::from(target),
+                    RelocationKind::Native => unreachable!(),
+                };
+                if value < 0 || value > i64::from(u16::MAX) {
+                    errors.push(LinkerError::RelocationOutOfRange {
+                        name: relocation.symbol.clone(),
+                        module: module.clone(),
+                    });
+                    continue;
+                }
+                // Operands are stored with the high byte first, the same way the assembler writes them
+                module_code[at] = (value >> 8) as u8;
+                module_code[at + 1] = value as u8;
+            }
+            code.append(&mut module_code);
+        }
+
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        let mut program = pie_header(ro_length, natives_table.len() as u32);
+        program.append(&mut ro);
+        program.append(&mut natives_table);
+        program.append(&mut code);
+        Ok(program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assembler::Assembler;
+    use vm::VM;
+
+    fn object(code: &str) -> ObjectFile {
+        Assembler::new().assemble_object(code).unwrap()
+    }
+
+    #[test]
+    fn test_link_two_modules() {
+        let main = object(
+            r"
+            .data
+            greeting: .asciiz 'Hi'
+            .code
+            load $1 #4
+            call @double
+            jmp @done
+            done: load $2 @farewell
+            hlt
+            ",
+        );
+        let library = object(
+            r"
+            .data
+            farewell: .asciiz 'Bye'
+            .code
+            .global @double
+            .global @farewell
+            double: add $1 $1 $1
+            ret
+            ",
+        );
+        let mut linker = Linker::new();
+        linker.add_object("main", main);
+        linker.add_object("library", library);
+        let program = linker.link().unwrap();
+        // Both read-only sections come after the header, main's first
+        assert_eq!(&program[68..75], b"Hi\0Bye\0");
+
+        let mut vm = VM::new();
+        vm.add_bytes(program);
+        let events = vm.run();
+        assert_eq!(events[1].event.stop_code(), 0);
+        assert_eq!(vm.registers()[1], 8);
+        assert_eq!(vm.registers()[2], 3);
+    }
+
+    #[test]
+    fn test_link_natives() {
+        let main = object(".data\n.code\ncalln $0 @triple\ncall @bump\nhlt");
+        let library = object(".data\n.code\n.global @bump\nbump: calln $0 @increment\ncalln $0 @triple\nret");
+        let mut linker = Linker::new();
+        linker.add_object("main", main);
+        linker.add_object("library", library);
+        let program = linker.link().unwrap();
+        assert_eq!(&program[68..85], b"triple\0increment\0");
+
+        let mut vm = VM::new();
+        vm.register_native("triple", 1, |args| Ok(vec![args[0] * 3]));
+        vm.register_native("increment", 1, |args| Ok(vec![args[0] + 1]));
+        vm.registers_mut()[0] = 1;
+        vm.add_bytes(program);
+        let events = vm.run();
+        assert_eq!(events[1].event.stop_code(), 0);
+        assert_eq!(vm.registers()[0], 12);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let exports_main = ".data\n.code\n.global @main\nmain: hlt";
+        let mut linker = Linker::new();
+        linker.add_object("a", object(exports_main));
+        linker.add_object("b", object(exports_main));
+        linker.add_object("c", object(".data\n.code\ncall @missing\nhlt"));
+        let errors = linker.link().unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors.contains(&LinkerError::DuplicateSymbol {
+            name: "main".to_string(),
+            first_module: "a".to_string(),
+            second_module: "b".to_string(),
+        }));
+        assert!(errors.contains(&LinkerError::UndefinedSymbol {
+            name: "missing".to_string(),
+            module: "c".to_string(),
+        }));
+        assert_eq!(Linker::new().link().unwrap_err(), vec![LinkerError::NoObjects]);
+    }
+
+    #[test]
+    fn test_link_corrupt_object() {
+        let mut truncated = object(".data\n.code\ncall @main\nmain: calln $0 @triple\nhlt");
+        truncated.code.truncate(5);
+        let mut linker = Linker::new();
+        linker.add_object("truncated", truncated);
+        assert_eq!(
+            linker.link().unwrap_err(),
+            vec![LinkerError::RelocationOutsideCode {
+                name: "triple".to_string(),
+                module: "truncated".to_string(),
+            },]
+        );
+    }
+}
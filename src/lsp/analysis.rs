@@ -0,0 +1,620 @@
+//! Works out what is where in an .iasm file, so the server can answer questions about it. Each line is
+//! parsed on its own with the assembler's parsers to find out what it means, and split into words to
+//! find out where each part of it is. Columns count characters; the server converts them to and from the UTF-16
+//! code units LSP clients count in.
+
+use std::collections::HashMap;
+
+use nom::types::CompleteStr;
+
+use assembler::assembler_errors::AssemblerError;
+use assembler::instruction_parsers::AssemblerInstruction;
+use assembler::program_parsers::program;
+use assembler::pseudo_instructions::PseudoOpcode;
+use assembler::signatures::{encoding, opcode_signature, opcodes, pseudo_opcodes, pseudo_signature};
+use assembler::{Assembler, Token};
+use instruction::Opcode;
+
+/// How many registers of each kind the VM has
+const REGISTER_COUNT: usize = 32;
+
+/// Directives the assembler understands, without the leading `.`
+const DIRECTIVES: [&str; 5] = ["data", "code", "asciiz", "integer", "global"];
+
+/// Where a word is: its line, and the columns it starts at and ends just before
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// Whether the cursor at `line`, `character` is in or right at the end of the span
+    pub fn contains(&self, line: usize, character: usize) -> bool {
+        self.line == line && self.start <= character && character <= self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub severity: Severity,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WordKind {
+    LabelDeclaration,
+    LabelUsage,
+    Directive,
+    Register,
+    Number,
+    Mnemonic,
+    Text,
+}
+
+/// A whitespace-separated piece of a line. For labels and directives `text` leaves out the `:`, `@` or `.`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Word {
+    pub kind: WordKind,
+    pub text: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    Section,
+    CodeLabel,
+    DataLabel,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DocumentSymbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    /// Where the symbol's name is
+    pub span: Span,
+    /// The last line that belongs to the symbol. For a section, that is the line before the next one.
+    pub last_line: usize,
+    pub children: Vec<DocumentSymbol>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    pub label: String,
+    pub detail: String,
+}
+
+#[derive(Debug)]
+struct Line {
+    text: String,
+    words: Vec<Word>,
+    instruction: Option<AssemblerInstruction>,
+}
+
+impl Line {
+    fn mnemonic(&self) -> Option<&Word> {
+        self.words.iter().find(|w| w.kind == WordKind::Mnemonic)
+    }
+
+    fn directive(&self) -> Option<&Word> {
+        self.words.iter().find(|w| w.kind == WordKind::Directive)
+    }
+
+    /// The span of everything on the line other than leading and trailing whitespace and comments
+    fn code_span(&self, line: usize) -> Span {
+        match (self.words.first(), self.words.last()) {
+            (Some(first), Some(last)) => Span {
+                line,
+                start: first.span.start,
+                end: last.span.end,
+            },
+            _ => Span { line, start: 0, end: 0 },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Document {
+    lines: Vec<Line>,
+    /// Where each label is declared
+    declarations: HashMap<String, Span>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Document {
+    pub fn new(text: &str) -> Document {
+        let mut document = Document {
+            lines: text.lines().enumerate().map(|(n, l)| parse_line(n, l)).collect(),
+            declarations: HashMap::new(),
+            diagnostics: vec![],
+        };
+        document.check();
+        document
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// The number of characters on a line
+    pub fn line_length(&self, line: usize) -> usize {
+        self.lines.get(line).map(|l| l.text.chars().count()).unwrap_or(0)
+    }
+
+    /// Converts a column on `line` counted in UTF-16 code units to one counted in characters
+    pub fn char_column(&self, line: usize, utf16: usize) -> usize {
+        let text = match self.lines.get(line) {
+            Some(l) => &l.text,
+            None => return utf16,
+        };
+        let mut units = 0;
+        for (column, c) in text.chars().enumerate() {
+            if units >= utf16 {
+                return column;
+            }
+            units += c.len_utf16();
+        }
+        // Past the end of the line, every column is one unit
+        text.chars().count() + utf16.saturating_sub(units)
+    }
+
+    /// Converts a column on `line` counted in characters to one counted in UTF-16 code units
+    pub fn utf16_column(&self, line: usize, character: usize) -> usize {
+        match self.lines.get(line) {
+            Some(l) => {
+                let units: usize = l.text.chars().take(character).map(char::len_utf16).sum();
+                units + character.saturating_sub(l.text.chars().count())
+            }
+            None => character,
+        }
+    }
+
+    pub fn word_at(&self, line: usize, character: usize) -> Option<&Word> {
+        self.lines.get(line)?.words.iter().find(|w| w.span.contains(line, character))
+    }
+
+    /// Where the label at the cursor is declared
+    pub fn definition(&self, line: usize, character: usize) -> Option<Span> {
+        match self.word_at(line, character) {
+            Some(word) if word.kind == WordKind::LabelUsage || word.kind == WordKind::LabelDeclaration => self.declarations.get(&word.text).cloned(),
+            _ => None,
+        }
+    }
+
+    /// Every use of the label at the cursor, and optionally its declaration
+    pub fn references(&self, line: usize, character: usize, include_declaration: bool) -> Vec<Span> {
+        let name = match self.word_at(line, character) {
+            Some(word) if word.kind == WordKind::LabelUsage || word.kind == WordKind::LabelDeclaration => word.text.clone(),
+            _ => return vec![],
+        };
+        self.words()
+            .filter(|w| w.text == name)
+            .filter(|w| w.kind == WordKind::LabelUsage || (include_declaration && w.kind == WordKind::LabelDeclaration))
+            .map(|w| w.span)
+            .collect()
+    }
+
+    /// Markdown describing the mnemonic or label at the cursor
+    pub fn hover(&self, line: usize, character: usize) -> Option<String> {
+        let word = self.word_at(line, character)?;
+        match word.kind {
+            WordKind::Mnemonic => {
+                if let Some(code) = PseudoOpcode::from_mnemonic(CompleteStr(&word.text)) {
+                    let signature = pseudo_signature(code);
+                    return Some(format!(
+                        "```\n{:?} {}\n```\n{}\n\nPseudo-instruction, expanded by the assembler",
+                        code,
+                        signature.operands.join(" "),
+                        signature.summary
+                    ));
+                }
+                let code = Opcode::from(CompleteStr(&word.text));
+                if code == Opcode::IGL && !word.text.eq_ignore_ascii_case("igl") {
+                    return None;
+                }
+                let signature = opcode_signature(code);
+                Some(format!(
+                    "```\n{:?} {}\n```\n{}\n\nEncoding: `{}`",
+                    code,
+                    signature.operands.join(" "),
+                    signature.summary,
+                    encoding(code, signature.operands)
+                ))
+            }
+            WordKind::LabelUsage | WordKind::LabelDeclaration => {
+                let declaration = self.declarations.get(&word.text)?;
+                Some(format!(
+                    "```\n{}\n```\nDeclared on line {}",
+                    self.lines[declaration.line].text.trim(),
+                    declaration.line + 1
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// What could be typed at the cursor. Registers, labels and directives are offered once their sigil has
+    /// been typed, and mnemonics at the start of an instruction.
+    pub fn completions(&self, line: usize, character: usize) -> Vec<Completion> {
+        let text = self.lines.get(line).map(|l| l.text.as_str()).unwrap_or("");
+        let before: String = text.chars().take(character).collect();
+        let prefix = before
+            .split_whitespace()
+            .last()
+            .filter(|_| !before.ends_with(char::is_whitespace))
+            .unwrap_or("");
+        if prefix.starts_with('$') {
+            return (0..REGISTER_COUNT)
+                .map(|n| Completion {
+                    label: format!("${}", n),
+                    detail: "Register".to_string(),
+                })
+                .collect();
+        }
+        if prefix.starts_with('@') {
+            let mut names: Vec<&String> = self.declarations.keys().collect();
+            names.sort();
+            return names
+                .into_iter()
+                .map(|name| Completion {
+                    label: format!("@{}", name),
+                    detail: format!("Label declared on line {}", self.declarations[name].line + 1),
+                })
+                .collect();
+        }
+        if prefix.starts_with('.') {
+            return DIRECTIVES
+                .iter()
+                .map(|d| Completion {
+                    label: format!(".{}", d),
+                    detail: "Directive".to_string(),
+                })
+                .collect();
+        }
+        // Mnemonics can only come after an optional label declaration
+        let words_before = before.split_whitespace().count() - if prefix.is_empty() { 0 } else { 1 };
+        let after_label = words_before == 1 && before.split_whitespace().next().is_some_and(|w| w.ends_with(':'));
+        if words_before > 0 && !after_label {
+            return vec![];
+        }
+        let mut results: Vec<Completion> = opcodes()
+            .into_iter()
+            .map(|code| Completion {
+                label: format!("{:?}", code).to_lowercase(),
+                detail: opcode_signature(code).operands.join(" "),
+            })
+            .collect();
+        results.extend(pseudo_opcodes().into_iter().map(|code| Completion {
+            label: format!("{:?}", code).to_lowercase(),
+            detail: pseudo_signature(code).operands.join(" "),
+        }));
+        results
+    }
+
+    /// The `.data` and `.code` sections, with the labels declared in each
+    pub fn symbols(&self) -> Vec<DocumentSymbol> {
+        let mut results: Vec<DocumentSymbol> = vec![];
+        let mut in_section = false;
+        let mut label_kind = SymbolKind::CodeLabel;
+        for (n, line) in self.lines.iter().enumerate() {
+            if let Some(directive) = line.directive() {
+                if directive.text == "data" || directive.text == "code" {
+                    if in_section {
+                        if let Some(section) = results.last_mut() {
+                            section.last_line = n.saturating_sub(1);
+                        }
+                    }
+                    in_section = true;
+                    label_kind = if directive.text == "data" {
+                        SymbolKind::DataLabel
+                    } else {
+                        SymbolKind::CodeLabel
+                    };
+                    results.push(DocumentSymbol {
+                        name: format!(".{}", directive.text),
+                        kind: SymbolKind::Section,
+                        span: directive.span,
+                        last_line: n,
+                        children: vec![],
+                    });
+                }
+            }
+            for word in line.words.iter().filter(|w| w.kind == WordKind::LabelDeclaration) {
+                let symbol = DocumentSymbol {
+                    name: word.text.clone(),
+                    kind: label_kind,
+                    span: word.span,
+                    last_line: n,
+                    children: vec![],
+                };
+                match results.last_mut() {
+                    Some(section) if in_section => section.children.push(symbol),
+                    _ => results.push(symbol),
+                }
+            }
+        }
+        if in_section {
+            if let Some(section) = results.last_mut() {
+                section.last_line = self.lines.len().saturating_sub(1);
+            }
+        }
+        results
+    }
+
+    fn words(&self) -> impl Iterator<Item = &Word> {
+        self.lines.iter().flat_map(|l| l.words.iter())
+    }
+
+    fn error(&mut self, span: Span, message: String) {
+        self.diagnostics.push(Diagnostic {
+            span,
+            severity: Severity::Error,
+            message,
+        });
+    }
+
+    /// Finds everything wrong with the document
+    fn check(&mut self) {
+        let mut errors = vec![];
+        for (n, line) in self.lines.iter().enumerate() {
+            if line.words.is_empty() {
+                continue;
+            }
+            match line.instruction {
+                None => errors.push((line.code_span(n), "Unable to parse this line".to_string())),
+                Some(ref i) => {
+                    if let (Some(Token::Op { code: Opcode::IGL }), Some(mnemonic)) = (&i.opcode, line.mnemonic()) {
+                        if !mnemonic.text.eq_ignore_ascii_case("igl") {
+                            errors.push((mnemonic.span, format!("Unknown opcode {}", mnemonic.text)));
+                        }
+                    }
+                }
+            }
+        }
+
+        let declarations: Vec<Word> = self.words().filter(|w| w.kind == WordKind::LabelDeclaration).cloned().collect();
+        for word in declarations {
+            if let Some(first) = self.declarations.get(&word.text) {
+                errors.push((word.span, format!("The label {} was already declared on line {}", word.text, first.line + 1)));
+                continue;
+            }
+            self.declarations.insert(word.text.clone(), word.span);
+        }
+        for (span, message) in errors {
+            self.error(span, message);
+        }
+
+        // The label given to CALLN names a native function rather than a label
+        let calls_native = |l: &&Line| l.instruction.as_ref().is_some_and(|i| i.opcode == Some(Token::Op { code: Opcode::CALLN }));
+        let undeclared: Vec<Word> = self
+            .lines
+            .iter()
+            .filter(|l| !calls_native(l))
+            .flat_map(|l| l.words.iter())
+            .filter(|w| w.kind == WordKind::LabelUsage && !self.declarations.contains_key(&w.text))
+            .cloned()
+            .collect();
+        for word in undeclared {
+            self.diagnostics.push(Diagnostic {
+                span: word.span,
+                severity: Severity::Warning,
+                message: format!(
+                    "The label @{} is not declared in this file, so it has to come from another module when linking",
+                    word.text
+                ),
+            });
+        }
+
+        // The assembler stops at the first thing it can't parse, so only ask it about the rest once every line parses
+        if self.diagnostics.iter().any(|d| d.severity == Severity::Error) {
+            return;
+        }
+        let source: Vec<&str> = self.lines.iter().map(|l| l.text.as_str()).collect();
+        if let Err(errors) = Assembler::new().assemble(&source.join("\n")) {
+            for error in errors {
+                if let Some(span) = self.error_span(&error) {
+                    self.error(span, error.to_string());
+                }
+            }
+        }
+    }
+
+    /// Where to show an error from the assembler. Returns `None` for the ones `check` already reports.
+    fn error_span(&self, error: &AssemblerError) -> Option<Span> {
+        let instruction = match *error {
+            AssemblerError::UnknownLabel { .. } | AssemblerError::SymbolAlreadyDeclared => return None,
+            AssemblerError::NoSegmentDeclarationFound { instruction }
+            | AssemblerError::StringConstantDeclaredWithoutLabel { instruction }
+            | AssemblerError::InvalidOperands { instruction, .. }
+            | AssemblerError::LabelOutOfRange { instruction, .. } => Some(instruction as usize),
+            AssemblerError::UnknownDirectiveFound { ref directive } => {
+                let found = self.words().find(|w| w.kind == WordKind::Directive && w.text == *directive);
+                return Some(found.map(|w| w.span).unwrap_or(Span { line: 0, start: 0, end: 0 }));
+            }
+            _ => None,
+        };
+        // Instruction numbers count the instructions the parser found, which is one per non-empty line
+        let line = instruction.and_then(|i| self.lines.iter().enumerate().filter(|(_, l)| l.instruction.is_some()).nth(i));
+        Some(match line {
+            Some((n, l)) => l.code_span(n),
+            None => self.lines.first().map(|l| l.code_span(0)).unwrap_or(Span { line: 0, start: 0, end: 0 }),
+        })
+    }
+}
+
+/// Returns the part of a line before any comment
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            '\'' => in_string = !in_string,
+            ';' if !in_string => return &line[..idx],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Splits the code on a line into words, keeping quoted strings together
+fn split_words(line: usize, code: &str) -> Vec<Word> {
+    let mut words = vec![];
+    let mut current = String::new();
+    let mut start = 0;
+    let mut in_string = false;
+    for (column, c) in code.chars().chain(Some(' ')).enumerate() {
+        if c.is_whitespace() && !in_string {
+            if !current.is_empty() {
+                // Only the first word that isn't a label declaration can be a mnemonic
+                let first = words.iter().all(|w: &Word| w.kind == WordKind::LabelDeclaration);
+                words.push(classify(&current, Span { line, start, end: column }, first));
+                current.clear();
+            }
+            continue;
+        }
+        if current.is_empty() {
+            start = column;
+        }
+        if c == '\'' {
+            in_string = !in_string;
+        }
+        current.push(c);
+    }
+    words
+}
+
+fn classify(text: &str, span: Span, first: bool) -> Word {
+    let (kind, text) = if let Some(name) = text.strip_suffix(':') {
+        (WordKind::LabelDeclaration, name)
+    } else if let Some(name) = text.strip_prefix('@') {
+        (WordKind::LabelUsage, name)
+    } else if let Some(name) = text.strip_prefix('.') {
+        (WordKind::Directive, name)
+    } else if text.starts_with('$') {
+        (WordKind::Register, text)
+    } else if text.starts_with('#') {
+        (WordKind::Number, text)
+    } else if text.starts_with('\'') {
+        (WordKind::Text, text)
+    } else {
+        (WordKind::Mnemonic, text)
+    };
+    let kind = if kind == WordKind::Mnemonic && !first { WordKind::Text } else { kind };
+    Word {
+        kind,
+        text: text.to_string(),
+        span,
+    }
+}
+
+fn parse_line(line: usize, text: &str) -> Line {
+    let code = strip_comment(text);
+    let words = split_words(line, code);
+    let instruction = if words.is_empty() {
+        None
+    } else {
+        match program(CompleteStr(code)) {
+            Ok((rest, mut p)) if rest.trim().is_empty() && p.instructions.len() == 1 => p.instructions.pop(),
+            _ => None,
+        }
+    };
+    Line {
+        text: text.to_string(),
+        words,
+        instruction,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = ".data\ngreeting: .asciiz 'Hello; there'\n.code\nstart: load $0 #10 ; count\nloop: dec $0\nprts @greeting\njmp @loop\nhlt\n";
+
+    #[test]
+    fn test_words() {
+        let document = Document::new(SOURCE);
+        let word = document.word_at(1, 20).unwrap();
+        assert_eq!(word.kind, WordKind::Text);
+        assert_eq!(word.text, "'Hello; there'");
+        let word = document.word_at(3, 8).unwrap();
+        assert_eq!(word.kind, WordKind::Mnemonic);
+        assert_eq!(word.span, Span { line: 3, start: 7, end: 11 });
+        assert!(document.word_at(3, 22).is_none());
+        assert!(document.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn test_definition_and_references() {
+        let document = Document::new(SOURCE);
+        assert_eq!(document.definition(6, 6), Some(Span { line: 4, start: 0, end: 5 }));
+        assert_eq!(document.references(4, 1, false), vec![Span { line: 6, start: 4, end: 9 }]);
+        assert_eq!(document.references(6, 5, true).len(), 2);
+        assert!(document.definition(7, 1).is_none());
+    }
+
+    #[test]
+    fn test_hover() {
+        let document = Document::new(SOURCE);
+        let hover = document.hover(3, 8).unwrap();
+        assert!(hover.contains("LOAD $reg #value"));
+        assert!(hover.contains("[0x00] [reg] [value hi] [value lo]"));
+        assert!(document.hover(5, 8).unwrap().contains("greeting: .asciiz 'Hello; there'"));
+    }
+
+    #[test]
+    fn test_completions() {
+        let document = Document::new(SOURCE);
+        assert_eq!(document.completions(4, 11).len(), REGISTER_COUNT);
+        let labels: Vec<String> = document.completions(6, 5).into_iter().map(|c| c.label).collect();
+        assert_eq!(labels, vec!["@greeting", "@loop", "@start"]);
+        assert!(document.completions(7, 2).iter().any(|c| c.label == "hlt"));
+        assert!(document.completions(4, 6).iter().any(|c| c.label == "mov"));
+        assert!(document.completions(3, 12).is_empty());
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let document = Document::new(".data\n.code\nfoo $1\nx: hlt\nx: hlt\njmp @missing\n");
+        let diagnostics = document.diagnostics();
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!(diagnostics[0].span, Span { line: 2, start: 0, end: 3 });
+        assert_eq!(diagnostics[1].span.line, 4);
+        assert_eq!(diagnostics[2].severity, Severity::Warning);
+
+        let document = Document::new(".code\nmov $1 #2\nhlt\n");
+        let diagnostics = document.diagnostics();
+        assert!(diagnostics.iter().all(|d| d.severity == Severity::Error));
+        assert!(diagnostics.iter().any(|d| d.span.line == 1 && d.message.contains("MOV")));
+    }
+
+    #[test]
+    fn test_utf16_columns() {
+        // The emoji is one character but two UTF-16 code units
+        let document = Document::new("hlt ; \u{1F600} x");
+        assert_eq!(document.utf16_column(0, 7), 8);
+        assert_eq!(document.char_column(0, 8), 7);
+        assert_eq!(document.char_column(0, 4), 4);
+        // Past the end of the line
+        assert_eq!(document.utf16_column(0, 10), 11);
+        assert_eq!(document.char_column(0, 11), 10);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let symbols = Document::new(SOURCE).symbols();
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols[0].name, ".data");
+        assert_eq!(symbols[0].last_line, 1);
+        assert_eq!(symbols[0].children[0].kind, SymbolKind::DataLabel);
+        assert_eq!(symbols[1].children.len(), 2);
+        assert_eq!(symbols[1].children[1].kind, SymbolKind::CodeLabel);
+    }
+}
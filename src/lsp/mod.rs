@@ -0,0 +1,333 @@
+//! A Language Server Protocol server for .iasm files, run by the `iridium-lsp` binary over stdio. Documents
+//! are synced in full on every change and re-analyzed from scratch, which is plenty fast for assembly.
+
+pub mod analysis;
+pub mod transport;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value;
+
+use lsp::analysis::{Document, Severity, Span, SymbolKind};
+use lsp::transport::{read_message, write_message};
+
+/// JSON-RPC error code for a message that isn't valid JSON
+const PARSE_ERROR: i64 = -32700;
+/// JSON-RPC error code for a request made after `shutdown`
+const INVALID_REQUEST: i64 = -32600;
+/// JSON-RPC error code for a method we don't support
+const METHOD_NOT_FOUND: i64 = -32601;
+
+#[derive(Debug, Default)]
+pub struct Server {
+    /// Open documents, by URI
+    documents: HashMap<String, Document>,
+    /// Set once the client sends `shutdown`
+    shut_down: bool,
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server {
+            documents: HashMap::new(),
+            shut_down: false,
+        }
+    }
+
+    /// Whether the client asked the server to shut down before exiting, which decides the exit code
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    /// Handles messages from `input` until the client sends `exit` or closes it. A message that can't be parsed
+    /// is answered with an error, and the server carries on with the next one.
+    pub fn run<R: BufRead, W: Write>(&mut self, input: &mut R, output: &mut W) -> io::Result<()> {
+        loop {
+            let message = match read_message(input) {
+                Ok(Some(message)) => message,
+                Ok(None) => break,
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                    // Without a parsed message there is no id to answer, so the response has a null one
+                    write_message(output, &error_response(Value::Null, PARSE_ERROR, &e.to_string()))?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if message["method"] == "exit" {
+                break;
+            }
+            for reply in self.handle(&message) {
+                write_message(output, &reply)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a single request or notification, returning the response and any notifications to send
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return self.handle_notification(method, params),
+        };
+        if self.shut_down {
+            return vec![error_response(id, INVALID_REQUEST, "The server is shutting down")];
+        }
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    // Full document sync
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": ["$", "@", "."] },
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "iridium-lsp", "version": env!("CARGO_PKG_VERSION") },
+            }),
+            "shutdown" => {
+                self.shut_down = true;
+                Value::Null
+            }
+            "textDocument/definition" => self.with_document(params, |document, uri, line, character| match document.definition(line, character) {
+                Some(span) => location(document, uri, span),
+                None => Value::Null,
+            }),
+            "textDocument/references" => self.with_document(params, |document, uri, line, character| {
+                let include_declaration = params["context"]["includeDeclaration"].as_bool().unwrap_or(false);
+                let spans = document.references(line, character, include_declaration);
+                Value::Array(spans.into_iter().map(|span| location(document, uri, span)).collect())
+            }),
+            "textDocument/hover" => self.with_document(params, |document, _, line, character| match document.hover(line, character) {
+                Some(text) => json!({ "contents": { "kind": "markdown", "value": text } }),
+                None => Value::Null,
+            }),
+            "textDocument/completion" => self.with_document(params, |document, _, line, character| {
+                let items = document
+                    .completions(line, character)
+                    .into_iter()
+                    .map(|c| json!({ "label": c.label, "detail": c.detail }))
+                    .collect();
+                Value::Array(items)
+            }),
+            "textDocument/documentSymbol" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+                match self.documents.get(uri) {
+                    Some(document) => Value::Array(document.symbols().iter().map(|s| document_symbol(document, s)).collect()),
+                    None => Value::Null,
+                }
+            }
+            _ => return vec![error_response(id, METHOD_NOT_FOUND, &format!("Unsupported method {}", method))],
+        };
+        vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })]
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = match params["textDocument"]["uri"].as_str() {
+            Some(uri) => uri.to_string(),
+            None => return vec![],
+        };
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+            // With full sync, the last change holds the whole document
+            "textDocument/didChange" => params["contentChanges"].as_array().and_then(|c| c.last()).and_then(|c| c["text"].as_str()),
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                return vec![publish_diagnostics(&uri, vec![])];
+            }
+            _ => None,
+        };
+        let document = match text {
+            Some(text) => Document::new(text),
+            None => return vec![],
+        };
+        let diagnostics = document
+            .diagnostics()
+            .iter()
+            .map(|d| {
+                json!({
+                    "range": range(&document, d.span),
+                    "severity": if d.severity == Severity::Error { 1 } else { 2 },
+                    "source": "iridium",
+                    "message": d.message,
+                })
+            })
+            .collect();
+        self.documents.insert(uri.clone(), document);
+        vec![publish_diagnostics(&uri, diagnostics)]
+    }
+
+    /// Calls `f` with the document and cursor position a request is about, with the column converted from
+    /// UTF-16 code units to characters, or returns null if the document isn't open
+    fn with_document<F>(&self, params: &Value, f: F) -> Value
+    where
+        F: Fn(&Document, &str, usize, usize) -> Value,
+    {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let line = params["position"]["line"].as_u64().unwrap_or(0) as usize;
+        let character = params["position"]["character"].as_u64().unwrap_or(0) as usize;
+        match self.documents.get(uri) {
+            Some(document) => f(document, uri, line, document.char_column(line, character)),
+            None => Value::Null,
+        }
+    }
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
+
+/// A position in `document`, with the column converted from characters to UTF-16 code units
+fn position(document: &Document, line: usize, character: usize) -> Value {
+    json!({ "line": line, "character": document.utf16_column(line, character) })
+}
+
+fn range(document: &Document, span: Span) -> Value {
+    json!({ "start": position(document, span.line, span.start), "end": position(document, span.line, span.end) })
+}
+
+fn location(document: &Document, uri: &str, span: Span) -> Value {
+    json!({ "uri": uri, "range": range(document, span) })
+}
+
+fn document_symbol(document: &Document, symbol: &analysis::DocumentSymbol) -> Value {
+    // These are the LSP's numbers for namespaces, functions and constants
+    let kind = match symbol.kind {
+        SymbolKind::Section => 3,
+        SymbolKind::CodeLabel => 12,
+        SymbolKind::DataLabel => 14,
+    };
+    let children: Vec<Value> = symbol.children.iter().map(|c| document_symbol(document, c)).collect();
+    json!({
+        "name": symbol.name,
+        "kind": kind,
+        "range": {
+            "start": position(document, symbol.span.line, 0),
+            "end": position(document, symbol.last_line, document.line_length(symbol.last_line)),
+        },
+        "selectionRange": range(document, symbol.span),
+        "children": children,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const URI: &str = "file:///test.iasm";
+
+    fn open(server: &mut Server, text: &str) -> Vec<Value> {
+        server.handle(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": { "textDocument": { "uri": URI, "languageId": "iasm", "version": 1, "text": text } },
+        }))
+    }
+
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Value {
+        let mut replies = server.handle(&json!({
+            "jsonrpc": "2.0",
+            "id": 7,
+            "method": method,
+            "params": { "textDocument": { "uri": URI }, "position": { "line": line, "character": character } },
+        }));
+        assert_eq!(replies.len(), 1);
+        replies.remove(0)
+    }
+
+    #[test]
+    fn test_diagnostics_are_published() {
+        let mut server = Server::new();
+        let replies = open(&mut server, ".data\n.code\nfoo $1\nhlt\n");
+        assert_eq!(replies[0]["method"], "textDocument/publishDiagnostics");
+        let diagnostics = replies[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["range"]["start"]["line"], 2);
+        assert_eq!(diagnostics[0]["severity"], 1);
+    }
+
+    #[test]
+    fn test_requests() {
+        let mut server = Server::new();
+        open(&mut server, ".data\n.code\nloop: inc $0\njmp @loop\n");
+        let definition = request(&mut server, "textDocument/definition", 3, 6);
+        assert_eq!(definition["result"]["range"]["start"]["line"], 2);
+        assert_eq!(definition["id"], 7);
+        let hover = request(&mut server, "textDocument/hover", 2, 7);
+        assert!(hover["result"]["contents"]["value"].as_str().unwrap().contains("INC $reg"));
+        let symbols = request(&mut server, "textDocument/documentSymbol", 0, 0);
+        assert_eq!(symbols["result"][1]["children"][0]["name"], "loop");
+        let unknown = request(&mut server, "textDocument/rename", 0, 0);
+        assert_eq!(unknown["error"]["code"], METHOD_NOT_FOUND);
+    }
+
+    #[test]
+    fn test_run_until_exit() {
+        let mut input = vec![];
+        write_message(&mut input, &json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}})).unwrap();
+        write_message(&mut input, &json!({"jsonrpc": "2.0", "id": 2, "method": "shutdown"})).unwrap();
+        write_message(&mut input, &json!({"jsonrpc": "2.0", "method": "exit"})).unwrap();
+        let mut output = vec![];
+        let mut server = Server::new();
+        server.run(&mut Cursor::new(input), &mut output).unwrap();
+        assert!(server.is_shut_down());
+        let mut output = Cursor::new(output);
+        let initialize = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(initialize["result"]["capabilities"]["hoverProvider"], true);
+        assert_eq!(read_message(&mut output).unwrap().unwrap()["id"], 2);
+        assert!(read_message(&mut output).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let mut input = b"Content-Length: 8\r\n\r\nnot json".to_vec();
+        write_message(&mut input, &json!({"jsonrpc": "2.0", "id": 1, "method": "shutdown"})).unwrap();
+        let mut output = vec![];
+        let mut server = Server::new();
+        server.run(&mut Cursor::new(input), &mut output).unwrap();
+        // The server answers the message it couldn't parse, and goes on to the next
+        let mut output = Cursor::new(output);
+        let error = read_message(&mut output).unwrap().unwrap();
+        assert_eq!(error["error"]["code"], PARSE_ERROR);
+        assert_eq!(error["id"], Value::Null);
+        assert_eq!(read_message(&mut output).unwrap().unwrap()["id"], 1);
+        assert!(server.is_shut_down());
+    }
+
+    #[test]
+    fn test_assembler_errors_are_diagnostics() {
+        for (text, line) in &[(".data\n.text\n.code\nhlt\n", 1), (".data\n.asciiz 'hi'\n.code\nhlt\n", 1)] {
+            let mut input = vec![];
+            write_message(
+                &mut input,
+                &json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/didOpen",
+                    "params": { "textDocument": { "uri": URI, "languageId": "iasm", "version": 1, "text": text } },
+                }),
+            )
+            .unwrap();
+            let mut output = vec![];
+            Server::new().run(&mut Cursor::new(input), &mut output).unwrap();
+            // Whatever the assembler found comes back as a diagnostic, and nothing else is written
+            let mut output = Cursor::new(output);
+            let published = read_message(&mut output).unwrap().unwrap();
+            let diagnostics = published["params"]["diagnostics"].as_array().unwrap();
+            assert_eq!(diagnostics.len(), 1, "{}", text);
+            assert_eq!(diagnostics[0]["range"]["start"]["line"], *line);
+            assert!(read_message(&mut output).unwrap().is_none());
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Reads and writes Language Server Protocol messages, which are JSON bodies preceded by HTTP-style headers
+
+use std::io::{self, BufRead, Read, Write};
+
+use serde_json::{self, Value};
+
+/// The largest body a client may send. Documents are source files, so this leaves plenty of room.
+pub const MAX_CONTENT_LENGTH: usize = 16 * 1024 * 1024;
+
+const CONTENT_LENGTH: &[u8] = b"Content-Length";
+
+/// Reads the next message. Returns `None` once the input is closed.
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        // A blank line separates the headers from the body
+        if header.is_empty() {
+            break;
+        }
+        let mut parts = header.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let content_length = match content_length {
+        Some(length) => length,
+        None => {
+            // Without a length there's no telling where the body ends, so pick up again at the next message's header
+            skip_to_header(input)?;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Message is missing a Content-Length header"));
+        }
+    };
+    if content_length > MAX_CONTENT_LENGTH {
+        io::copy(&mut input.by_ref().take(content_length as u64), &mut io::sink())?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Message is {} bytes, more than the limit of {}", content_length, MAX_CONTENT_LENGTH),
+        ));
+    }
+    let mut body = vec![0; content_length];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Discards input up to the next `Content-Length` header, or to the end of the input
+fn skip_to_header<R: BufRead>(input: &mut R) -> io::Result<()> {
+    loop {
+        let (skip, found) = {
+            let buf = input.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(());
+            }
+            match buf.windows(CONTENT_LENGTH.len()).position(|w| w == CONTENT_LENGTH) {
+                Some(start) => (start, true),
+                None => {
+                    // Keep any start of the header name at the end of what's buffered, unless that is all there is
+                    let partial = (1..CONTENT_LENGTH.len())
+                        .rev()
+                        .find(|&n| n <= buf.len() && buf.ends_with(&CONTENT_LENGTH[..n]))
+                        .unwrap_or(0);
+                    (if partial == buf.len() { 1 } else { buf.len() - partial }, false)
+                }
+            }
+        };
+        input.consume(skip);
+        if found {
+            return Ok(());
+        }
+    }
+}
+
+/// Writes a message, along with the header giving its length
+pub fn write_message<W: Write>(output: &mut W, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_message_round_trip() {
+        let mut buf = vec![];
+        write_message(&mut buf, &json!({"jsonrpc": "2.0", "method": "exit"})).unwrap();
+        write_message(&mut buf, &json!({"jsonrpc": "2.0", "id": 1})).unwrap();
+        let mut input = Cursor::new(buf);
+        assert_eq!(read_message(&mut input).unwrap().unwrap()["method"], "exit");
+        assert_eq!(read_message(&mut input).unwrap().unwrap()["id"], 1);
+        assert!(read_message(&mut input).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_missing_content_length() {
+        let mut input = Cursor::new(b"Content-Type: json\r\n\r\n{}".to_vec());
+        assert!(read_message(&mut input).is_err());
+    }
+
+    #[test]
+    fn test_resync_after_bad_header() {
+        let mut buf = b"Content-Type: json\r\n\r\n{\"id\": 0}".to_vec();
+        write_message(&mut buf, &json!({"jsonrpc": "2.0", "id": 1})).unwrap();
+        let mut input = Cursor::new(buf);
+        assert!(read_message(&mut input).is_err());
+        assert_eq!(read_message(&mut input).unwrap().unwrap()["id"], 1);
+    }
+
+    #[test]
+    fn test_oversized_body_is_skipped() {
+        let mut buf = format!("Content-Length: {}\r\n\r\n", MAX_CONTENT_LENGTH + 1).into_bytes();
+        buf.resize(buf.len() + MAX_CONTENT_LENGTH + 1, b' ');
+        write_message(&mut buf, &json!({"jsonrpc": "2.0", "id": 1})).unwrap();
+        let mut input = Cursor::new(buf);
+        assert!(read_message(&mut input).is_err());
+        assert_eq!(read_message(&mut input).unwrap().unwrap()["id"], 1);
+    }
+}
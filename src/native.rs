@@ -0,0 +1,102 @@
+//! Rust functions that embedders make available to bytecode, which calls them with `CALLN`
+
+use std::fmt;
+use std::sync::Arc;
+
+/// What a native function returns: the values to write back to the registers, or a message
+/// explaining why it failed
+pub type NativeResult = Result<Vec<i32>, String>;
+
+type NativeFn = dyn Fn(&[i32]) -> NativeResult + Send + Sync;
+
+/// A named Rust function that can be called from bytecode
+#[derive(Clone)]
+pub struct NativeFunction {
+    name: String,
+    /// How many registers the function takes its arguments from
+    arity: u8,
+    function: Arc<NativeFn>,
+}
+
+impl NativeFunction {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn arity(&self) -> u8 {
+        self.arity
+    }
+
+    /// Calls the function. `args` should hold exactly `arity` values.
+    pub fn call(&self, args: &[i32]) -> NativeResult {
+        (self.function)(args)
+    }
+}
+
+impl fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NativeFunction").field("name", &self.name).field("arity", &self.arity).finish()
+    }
+}
+
+/// The native functions a VM makes available, looked up by name when a program is loaded
+#[derive(Clone, Debug, Default)]
+pub struct NativeRegistry {
+    functions: Vec<NativeFunction>,
+}
+
+impl NativeRegistry {
+    pub fn new() -> NativeRegistry {
+        NativeRegistry { functions: vec![] }
+    }
+
+    /// Registers `function` under `name`, replacing any function already registered with that name.
+    /// It is called with the values of `arity` registers, and whatever it returns is written back to
+    /// the registers starting at the first of those.
+    pub fn register<F>(&mut self, name: &str, arity: u8, function: F)
+    where
+        F: Fn(&[i32]) -> NativeResult + Send + Sync + 'static,
+    {
+        let native = NativeFunction {
+            name: name.to_string(),
+            arity,
+            function: Arc::new(function),
+        };
+        match self.functions.iter().position(|f| f.name == name) {
+            Some(idx) => self.functions[idx] = native,
+            None => self.functions.push(native),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&NativeFunction> {
+        self.functions.iter().find(|f| f.name == name)
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.functions.iter().map(|f| f.name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_and_call() {
+        let mut registry = NativeRegistry::new();
+        registry.register("add", 2, |args| Ok(vec![args[0] + args[1]]));
+        let add = registry.get("add").unwrap();
+        assert_eq!(add.arity(), 2);
+        assert_eq!(add.call(&[2, 3]), Ok(vec![5]));
+        assert!(registry.get("sub").is_none());
+    }
+
+    #[test]
+    fn test_register_replaces() {
+        let mut registry = NativeRegistry::new();
+        registry.register("answer", 0, |_| Ok(vec![41]));
+        registry.register("answer", 0, |_| Ok(vec![42]));
+        assert_eq!(registry.names(), vec!["answer"]);
+        assert_eq!(registry.get("answer").unwrap().call(&[]), Ok(vec![42]));
+    }
+}
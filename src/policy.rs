@@ -0,0 +1,148 @@
+//! Policies controlling which host-facing capabilities a program may use. Each VM has one; using a capability
+//! it doesn't allow crashes the program with `VMError::CapabilityDenied` and is recorded in the audit log.
+
+use std::fmt;
+
+use syscall::Syscall;
+use vm_errors::VMError;
+
+/// The log target denials are written to, so they can be routed to their own audit log
+pub const AUDIT_LOG_TARGET: &str = "iridium::audit";
+
+/// Something a program can do that reaches outside of its VM
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Capability {
+    /// Printing with `PRTS`
+    Print,
+    /// Reading input with `READ`
+    Input,
+    /// Calling native functions with `CALLN`
+    Native,
+    /// Making a particular system call
+    Syscall(Syscall),
+    /// Starting, joining and leaving a cluster
+    Cluster,
+    /// Loading programs from files on the host and starting them in new processes
+    Spawn,
+}
+
+impl Capability {
+    /// Every capability, including each system call
+    pub fn all() -> Vec<Capability> {
+        let mut all = vec![Capability::Print, Capability::Input, Capability::Native];
+        all.extend(Syscall::all().into_iter().map(Capability::Syscall));
+        all.push(Capability::Cluster);
+        all.push(Capability::Spawn);
+        all
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Capability::Print => f.write_str("printing"),
+            Capability::Input => f.write_str("reading input"),
+            Capability::Native => f.write_str("calling native functions"),
+            Capability::Syscall(syscall) => f.write_str(&format!("the {:?} system call", syscall)),
+            Capability::Cluster => f.write_str("cluster access"),
+            Capability::Spawn => f.write_str("spawning programs"),
+        }
+    }
+}
+
+/// The capabilities a program is allowed to use. The default allows everything, as running a program locally
+/// always has.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Policy {
+    allowed: Vec<Capability>,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy::permissive()
+    }
+}
+
+impl Policy {
+    /// Allows every capability
+    pub fn permissive() -> Policy {
+        Policy { allowed: Capability::all() }
+    }
+
+    /// Allows nothing
+    pub fn deny_all() -> Policy {
+        Policy { allowed: vec![] }
+    }
+
+    /// Allows printing and the system calls that only read clocks or generate random numbers. Used for programs
+    /// from users who shouldn't be trusted with the host, such as those connected through remote access.
+    pub fn restrictive() -> Policy {
+        Policy::deny_all()
+            .allow(Capability::Print)
+            .allow(Capability::Syscall(Syscall::MonotonicTime))
+            .allow(Capability::Syscall(Syscall::WallTime))
+            .allow(Capability::Syscall(Syscall::Random))
+    }
+
+    pub fn allow(mut self, capability: Capability) -> Self {
+        if !self.allows(capability) {
+            self.allowed.push(capability);
+        }
+        self
+    }
+
+    pub fn deny(mut self, capability: Capability) -> Self {
+        self.allowed.retain(|c| *c != capability);
+        self
+    }
+
+    pub fn allows(&self, capability: Capability) -> bool {
+        self.allowed.contains(&capability)
+    }
+
+    /// Checks that `capability` is allowed, recording it in the audit log if it isn't. `who` identifies the
+    /// program for the log.
+    pub fn check(&self, who: &str, capability: Capability) -> Result<(), VMError> {
+        if self.allows(capability) {
+            Ok(())
+        } else {
+            warn!(target: AUDIT_LOG_TARGET, "{} was denied {}", who, capability);
+            Err(VMError::CapabilityDenied { capability })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow_and_deny() {
+        let policy = Policy::deny_all().allow(Capability::Print).allow(Capability::Print);
+        assert!(policy.allows(Capability::Print));
+        assert!(!policy.allows(Capability::Input));
+        assert_eq!(policy.clone().deny(Capability::Print), Policy::deny_all());
+        assert_eq!(policy.check("test", Capability::Print), Ok(()));
+        assert_eq!(
+            policy.check("test", Capability::Syscall(Syscall::Open)),
+            Err(VMError::CapabilityDenied {
+                capability: Capability::Syscall(Syscall::Open)
+            })
+        );
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(Policy::default(), Policy::permissive());
+        assert!(Capability::all().into_iter().all(|c| Policy::permissive().allows(c)));
+        let restrictive = Policy::restrictive();
+        assert!(restrictive.allows(Capability::Print));
+        assert!(restrictive.allows(Capability::Syscall(Syscall::Random)));
+        assert!(!restrictive.allows(Capability::Input));
+        assert!(!restrictive.allows(Capability::Native));
+        assert!(!restrictive.allows(Capability::Syscall(Syscall::Open)));
+        assert!(!restrictive.allows(Capability::Syscall(Syscall::Env)));
+        assert!(!restrictive.allows(Capability::Cluster));
+        assert!(!restrictive.allows(Capability::Spawn));
+    }
+}
@@ -0,0 +1,251 @@
+//! Who may use remote access. Users and their password hashes are kept in a file under the data root, one
+//! `username:hash` per line. An address is locked out for a while after too many failed attempts to log in from
+//! it, whichever usernames they were for, so guessing is throttled without letting anyone lock out a user.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use policy::AUDIT_LOG_TARGET;
+use remote::auth_errors::AuthError;
+use remote::password::{hash_password, pbkdf2_sha256, verify_password, PBKDF2_ITERATIONS};
+
+/// The file in the data root users are stored in
+pub const USERS_FILENAME: &str = "users";
+
+/// How many times in a row logging in from an address may fail before it is locked out
+pub const MAX_FAILED_ATTEMPTS: u32 = 5;
+
+/// How long an address is locked out for the first time. Each time after that it is twice as long.
+pub const LOCKOUT_DURATION: Duration = Duration::from_secs(300);
+
+/// The longest an address is locked out for. Failed attempts from an address are forgotten once it has gone
+/// this long without one.
+pub const MAX_LOCKOUT_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The users allowed to log in, by name, with their password hashes
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UserStore {
+    users: Vec<(String, String)>,
+}
+
+impl UserStore {
+    pub fn new() -> UserStore {
+        UserStore::default()
+    }
+
+    /// Reads the users in the file at `path`. A file that doesn't exist has no users.
+    pub fn load(path: &Path) -> io::Result<UserStore> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(UserStore::new()),
+            Err(e) => return Err(e),
+        };
+        let mut store = UserStore::new();
+        for (number, line) in contents.lines().enumerate() {
+            match line.find(':') {
+                Some(split) => store.users.push((line[..split].to_string(), line[split + 1..].to_string())),
+                None if line.trim().is_empty() => {}
+                None => warn!("Ignoring line {} of {}, which isn't a username and hash", number + 1, path.display()),
+            }
+        }
+        Ok(store)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let contents: String = self.users.iter().map(|(name, hash)| format!("{}:{}\n", name, hash)).collect();
+        fs::write(path, contents)
+    }
+
+    /// Adds a user, or changes their password if they exist
+    pub fn set_password(&mut self, username: &str, password: &str) -> Result<(), AuthError> {
+        if username.is_empty() || username.contains(':') || username.contains(char::is_whitespace) {
+            return Err(AuthError::InvalidUsername {
+                username: username.to_string(),
+            });
+        }
+        let hash = hash_password(password);
+        match self.users.iter_mut().find(|(name, _)| name == username) {
+            Some(user) => user.1 = hash,
+            None => self.users.push((username.to_string(), hash)),
+        }
+        Ok(())
+    }
+
+    /// Removes a user. Returns false if there wasn't one by that name.
+    pub fn remove(&mut self, username: &str) -> bool {
+        let count = self.users.len();
+        self.users.retain(|(name, _)| name != username);
+        self.users.len() != count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        match self.users.iter().find(|(name, _)| name == username) {
+            Some((_, hash)) => verify_password(password, hash),
+            None => {
+                // Take as long as checking a real password would, so whether a user exists can't be timed
+                pbkdf2_sha256(password.as_bytes(), username.as_bytes(), PBKDF2_ITERATIONS);
+                false
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FailedAttempts {
+    count: u32,
+    /// How many times the address has been locked out, which makes the next lockout longer
+    lockouts: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// How long an address is locked out for the `lockouts`th time
+pub fn lockout_duration(lockouts: u32) -> Duration {
+    let doublings = lockouts.saturating_sub(1).min(16);
+    (LOCKOUT_DURATION * 2u32.pow(doublings)).min(MAX_LOCKOUT_DURATION)
+}
+
+/// Checks the credentials of users logging in. Clones share the count of failed attempts, so one can be
+/// handed to every connection.
+#[derive(Clone, Debug)]
+pub struct Authenticator {
+    users: Arc<UserStore>,
+    failures: Arc<Mutex<HashMap<IpAddr, FailedAttempts>>>,
+}
+
+impl Authenticator {
+    pub fn new(users: UserStore) -> Authenticator {
+        Authenticator {
+            users: Arc::new(users),
+            failures: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Checks `password` is the user's, unless `peer`, the address they are logging in from, is locked out.
+    /// Every attempt is recorded in the audit log.
+    pub fn authenticate(&self, peer: IpAddr, username: &str, password: &str) -> Result<(), AuthError> {
+        if let Some(seconds_left) = self.lockout_remaining(peer) {
+            warn!(target: AUDIT_LOG_TARGET, "Refused to log in {} from {}, which is locked out", username, peer);
+            return Err(AuthError::LockedOut { seconds_left });
+        }
+        let valid = self.users.verify(username, password);
+        let mut failures = self.failures.lock().unwrap();
+        if valid {
+            failures.remove(&peer);
+            info!(target: AUDIT_LOG_TARGET, "{} logged in from {}", username, peer);
+            return Ok(());
+        }
+        let now = Instant::now();
+        failures.retain(|_, attempts| attempts.locked_until.is_some_and(|until| until > now) || now - attempts.last_failure < MAX_LOCKOUT_DURATION);
+        let attempts = failures.entry(peer).or_insert(FailedAttempts {
+            count: 0,
+            lockouts: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        attempts.count += 1;
+        attempts.last_failure = now;
+        warn!(target: AUDIT_LOG_TARGET, "Failed attempt {} to log in as {} from {}", attempts.count, username, peer);
+        if attempts.count >= MAX_FAILED_ATTEMPTS {
+            attempts.count = 0;
+            attempts.lockouts += 1;
+            let duration = lockout_duration(attempts.lockouts);
+            attempts.locked_until = Some(now + duration);
+            warn!(target: AUDIT_LOG_TARGET, "Locked out {} for {} seconds", peer, duration.as_secs());
+        }
+        Err(AuthError::InvalidCredentials)
+    }
+
+    /// How many seconds are left of the address's lockout, if it is locked out
+    fn lockout_remaining(&self, peer: IpAddr) -> Option<u64> {
+        let failures = self.failures.lock().unwrap();
+        let locked_until = failures.get(&peer)?.locked_until?;
+        let now = Instant::now();
+        if locked_until > now {
+            Some((locked_until - now).as_secs() + 1)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex::to_hex;
+
+    /// A store with a user whose password is hashed with a single iteration, to keep the tests fast
+    fn store() -> UserStore {
+        let hash = to_hex(&pbkdf2_sha256(b"secret", b"salt", 1));
+        UserStore {
+            users: vec![("alice".to_string(), format!("pbkdf2-sha256$1$73616c74${}", hash))],
+        }
+    }
+
+    #[test]
+    fn test_user_store() {
+        let mut users = store();
+        assert!(users.verify("alice", "secret"));
+        assert!(!users.verify("alice", "wrong"));
+        assert!(!users.verify("bob", "secret"));
+        assert_eq!(
+            users.set_password("bo b", "pw"),
+            Err(AuthError::InvalidUsername { username: "bo b".to_string() })
+        );
+
+        let path = std::env::temp_dir().join(format!("iridium-users-test-{}", std::process::id()));
+        users.save(&path).unwrap();
+        assert_eq!(UserStore::load(&path).unwrap(), users);
+        fs::remove_file(&path).unwrap();
+        assert!(UserStore::load(&path).unwrap().is_empty());
+
+        assert!(users.remove("alice"));
+        assert!(!users.remove("alice"));
+    }
+
+    #[test]
+    fn test_lockout() {
+        let auth = Authenticator::new(store());
+        let attacker: IpAddr = "10.0.0.66".parse().unwrap();
+        let alice: IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(auth.authenticate(attacker, "alice", "secret"), Ok(()));
+        for _ in 0..MAX_FAILED_ATTEMPTS {
+            assert_eq!(auth.authenticate(attacker, "alice", "wrong"), Err(AuthError::InvalidCredentials));
+        }
+        match auth.clone().authenticate(attacker, "alice", "secret") {
+            Err(AuthError::LockedOut { seconds_left }) => assert!(seconds_left <= LOCKOUT_DURATION.as_secs() + 1),
+            result => panic!("Expected the address to be locked out, got {:?}", result),
+        }
+        // Whoever else is trying to log in as alice isn't affected
+        assert_eq!(auth.authenticate(alice, "alice", "secret"), Ok(()));
+    }
+
+    #[test]
+    fn test_lockout_across_usernames() {
+        let auth = Authenticator::new(store());
+        let sprayer: IpAddr = "10.0.0.66".parse().unwrap();
+        for attempt in 0..MAX_FAILED_ATTEMPTS {
+            let username = format!("user{}", attempt);
+            assert_eq!(auth.authenticate(sprayer, &username, "password"), Err(AuthError::InvalidCredentials));
+        }
+        assert!(matches!(auth.authenticate(sprayer, "alice", "secret"), Err(AuthError::LockedOut { .. })));
+        assert_eq!(auth.authenticate("10.0.0.2".parse().unwrap(), "alice", "secret"), Ok(()));
+    }
+
+    #[test]
+    fn test_lockout_duration() {
+        assert_eq!(lockout_duration(1), LOCKOUT_DURATION);
+        assert_eq!(lockout_duration(2), LOCKOUT_DURATION * 2);
+        assert_eq!(lockout_duration(3), LOCKOUT_DURATION * 4);
+        assert_eq!(lockout_duration(100), MAX_LOCKOUT_DURATION);
+    }
+}
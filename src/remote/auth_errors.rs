@@ -0,0 +1,35 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthError {
+    /// The username doesn't exist or the password is wrong. Which one isn't said, so usernames can't be guessed.
+    InvalidCredentials,
+    /// Too many attempts to log in from this address have failed recently
+    LockedOut { seconds_left: u64 },
+    /// Usernames can't be empty, or contain whitespace or `:`
+    InvalidUsername { username: String },
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AuthError::InvalidCredentials => f.write_str("Invalid username or password"),
+            AuthError::LockedOut { seconds_left } => f.write_str(&format!(
+                "Too many failed attempts to log in from this address. Try again in {} seconds.",
+                seconds_left
+            )),
+            AuthError::InvalidUsername { ref username } => f.write_str(&format!("{:?} is not a valid username", username)),
+        }
+    }
+}
+
+impl Error for AuthError {
+    fn description(&self) -> &str {
+        match self {
+            AuthError::InvalidCredentials => "Invalid username or password",
+            AuthError::LockedOut { .. } => "Too many failed attempts to log in from this address",
+            AuthError::InvalidUsername { .. } => "Not a valid username",
+        }
+    }
+}
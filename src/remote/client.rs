@@ -1,32 +1,137 @@
 use repl;
-use std::io::{BufRead, Write};
+use std::io::{self, BufRead, Write};
 use std::io::{BufReader, BufWriter};
-use std::net::TcpStream;
-use std::thread;
+use std::path::PathBuf;
+use std::sync::Arc;
 
+use policy::Policy;
+use remote::auth::Authenticator;
+use remote::roles::RoleConfig;
+use remote::session::{Session, SessionRegistry};
+use repl::history::History;
+use scheduler::Shutdown;
+use tls::Stream;
 use vm::VM;
 
 pub struct Client {
-    reader: BufReader<TcpStream>,
-    writer: BufWriter<TcpStream>,
-    raw_stream: TcpStream,
-    repl: repl::REPL,
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    raw_stream: Stream,
+    /// The REPL for the session the user starts, until it is handed to the session registry
+    repl: Option<repl::REPL>,
+    /// Checks who is connecting before they get a prompt. Without one, anyone can connect.
+    authenticator: Option<Authenticator>,
+    /// Which commands each user who logs in may run
+    roles: RoleConfig,
+    /// The sessions of everyone connected, which the user's session joins
+    sessions: SessionRegistry,
+    /// Where each user's history is kept. Without it, or if users don't log in, it isn't kept.
+    history_dir: Option<PathBuf>,
+}
+
+/// How many times a user may try to log in before they are disconnected
+pub const LOGIN_ATTEMPTS: u32 = 3;
+
+/// The session a connection is attached to. Dropping it detaches the session, so a connection that goes away
+/// for any reason, including a panic, never leaves its session attached.
+struct Attached {
+    sessions: SessionRegistry,
+    session: Arc<Session>,
+    generation: u64,
+}
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        self.sessions.detach(&self.session, self.generation);
+    }
 }
 
 impl Client {
-    pub fn new(stream: TcpStream) -> Client {
+    /// Creates a client whose programs run under `policy`, and are stopped by `shutdown`
+    pub fn new(stream: Stream, policy: Policy, shutdown: Shutdown) -> Client {
         // TODO: Handle this better
         let reader = stream.try_clone().unwrap();
         let writer = stream.try_clone().unwrap();
-        let vm = VM::new();
-        let repl = repl::REPL::new(vm);
+        let vm = VM::new().with_policy(policy);
+        let repl = repl::REPL::new(vm).with_shutdown(shutdown).for_remote_session();
 
         Client {
             reader: BufReader::new(reader),
             writer: BufWriter::new(writer),
             raw_stream: stream,
-            repl,
+            repl: Some(repl),
+            authenticator: None,
+            roles: RoleConfig::default(),
+            sessions: SessionRegistry::default(),
+            history_dir: None,
+        }
+    }
+
+    /// Requires users to log in with a username and password before they get a prompt
+    pub fn with_authenticator(mut self, authenticator: Authenticator) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Limits the commands users may run once they have logged in. Defaults to letting them run any.
+    pub fn with_roles(mut self, roles: RoleConfig) -> Self {
+        self.roles = roles;
+        self
+    }
+
+    /// Adds the user's session to `sessions`, so they can return to it after disconnecting
+    pub fn with_sessions(mut self, sessions: SessionRegistry) -> Self {
+        self.sessions = sessions;
+        self
+    }
+
+    /// Keeps the history of each user who logs in in a file of their own in `dir`
+    pub fn with_history_dir(mut self, dir: PathBuf) -> Self {
+        self.history_dir = Some(dir);
+        self
+    }
+
+    /// Reads a line from the client, without its line ending. Returns `None` once they have disconnected.
+    fn read_line(&mut self) -> Option<String> {
+        let mut buf = String::new();
+        match self.reader.read_line(&mut buf) {
+            Ok(0) => None,
+            Ok(_) => Some(buf.trim_end().to_string()),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                debug!("Connection idle for longer than {:?}", self.sessions.idle_timeout());
+                None
+            }
+            Err(e) => {
+                println!("Error receiving: {:#?}", e);
+                None
+            }
+        }
+    }
+
+    /// Asks for a username and password until they are right, or `LOGIN_ATTEMPTS` have failed. Returns the
+    /// username they logged in as.
+    fn log_in(&mut self, authenticator: &Authenticator) -> Option<String> {
+        let peer = match self.raw_stream.peer_addr() {
+            Ok(addr) => addr.ip(),
+            Err(e) => {
+                warn!("Unable to tell where a remote access client connected from: {}", e);
+                return None;
+            }
+        };
+        for _ in 0..LOGIN_ATTEMPTS {
+            self.w("Username: ");
+            let username = self.read_line()?;
+            self.w("Password: ");
+            let password = self.read_line()?;
+            match authenticator.authenticate(peer, &username, &password) {
+                Ok(()) => return Some(username),
+                Err(e) => {
+                    self.w(&format!("{}\n", e));
+                }
+            }
         }
+        self.w("Too many failed attempts\n");
+        None
     }
 
     fn w(&mut self, msg: &str) -> bool {
@@ -45,45 +150,146 @@ impl Client {
         }
     }
 
-    fn recv_loop(&mut self) {
-        let rx = self.repl.rx_pipe.take();
-        // TODO: Make this safer on unwrap
-        let mut writer = self.raw_stream.try_clone().unwrap();
-        let _t = thread::spawn(move || {
-            let chan = rx.unwrap();
-            loop {
-                match chan.recv() {
-                    Ok(msg) => {
-                        match writer.write_all(msg.as_bytes()) {
-                            Ok(_) => {}
-                            Err(_e) => {}
-                        };
-                        match writer.flush() {
-                            Ok(_) => {}
-                            Err(_e) => {}
+    /// Logs the user in if they have to, then runs their commands in a new session until they quit. If they
+    /// disconnect, or are idle for too long, the session is detached rather than ended.
+    pub fn run(&mut self) {
+        let _ = self.raw_stream.set_read_timeout(Some(self.sessions.idle_timeout()));
+        let mut repl = match self.repl.take() {
+            Some(repl) => repl,
+            None => return,
+        };
+        let mut username = None;
+        if let Some(authenticator) = self.authenticator.clone() {
+            match self.log_in(&authenticator) {
+                Some(name) => {
+                    repl.set_user(name.clone(), self.roles.role_for(&name));
+                    if let Some(ref dir) = self.history_dir {
+                        match History::load(&History::user_path(dir, &name)) {
+                            Ok(history) => repl.set_history(history),
+                            Err(e) => warn!("Unable to read the history of {}: {}", name, e),
                         }
                     }
-                    Err(_e) => {}
+                    username = Some(name);
+                }
+                None => {
+                    let _ = self.raw_stream.shutdown();
+                    return;
                 }
             }
-        });
-    }
-
-    pub fn run(&mut self) {
-        self.recv_loop();
-        let mut buf = String::new();
-        let banner = repl::REMOTE_BANNER.to_owned() + "\n" + repl::PROMPT;
-        self.w(&banner);
-        loop {
-            match self.reader.read_line(&mut buf) {
-                Ok(_) => {
-                    buf.trim_end();
-                    self.repl.run_single(&buf);
+        }
+        let session = self.sessions.create(username.clone(), repl);
+        self.w(&format!("{}\nSession {}\n{}", repl::REMOTE_BANNER, session.id(), repl::PROMPT));
+        let generation = match self.raw_stream.try_clone() {
+            Ok(stream) => self.sessions.attach(session.id(), username.as_deref(), stream).map(|(_, g)| g).unwrap_or(0),
+            Err(_) => 0,
+        };
+        let mut attached = Attached {
+            sessions: self.sessions.clone(),
+            session,
+            generation,
+        };
+        while let Some(line) = self.read_line() {
+            let id = attached.session.id();
+            let (quit, attach_request) = match attached.session.repl.lock() {
+                Ok(mut repl) => {
+                    repl.run_single(&line);
+                    (repl.has_quit(), repl.take_attach_request())
                 }
-                Err(e) => {
-                    println!("Error receiving: {:#?}", e);
+                Err(_) => {
+                    // Something panicked while running a command, so there is no knowing what state the REPL is in
+                    warn!("Ending session {}, as a command in it panicked", id);
+                    self.w(&format!("Session {} has ended, as a command in it failed\n", id));
+                    self.sessions.end(id);
+                    break;
+                }
+            };
+            if quit {
+                self.sessions.end(id);
+                break;
+            }
+            if let Some(id) = attach_request {
+                let attached_to = match self.raw_stream.try_clone() {
+                    Ok(stream) => self.sessions.attach(id, username.as_deref(), stream),
+                    Err(_) => break,
+                };
+                match attached_to {
+                    Ok((session, generation)) => {
+                        // Replacing the old attachment detaches its session
+                        attached = Attached {
+                            sessions: self.sessions.clone(),
+                            session,
+                            generation,
+                        };
+                        self.w(&format!("Attached to session {}\n", id));
+                    }
+                    Err(e) => {
+                        self.w(&format!("{}\n", e));
+                    }
                 }
             }
         }
+        let _ = self.raw_stream.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Connects a new client to `sessions`, which runs in the background. Returns the user's end of the connection.
+    fn connect(sessions: &SessionRegistry) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let user = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        user.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let mut client = Client::new(Stream::Plain(server), Policy::restrictive(), Shutdown::new()).with_sessions(sessions.clone());
+        thread::spawn(move || client.run());
+        user
+    }
+
+    /// Reads until `text` turns up, or the connection is closed. Returns everything read.
+    fn read_until(stream: &mut TcpStream, text: &str) -> String {
+        let mut read = String::new();
+        let mut buf = [0u8; 256];
+        while !read.contains(text) {
+            match stream.read(&mut buf).unwrap() {
+                0 => break,
+                n => read.push_str(&String::from_utf8_lossy(&buf[..n])),
+            }
+        }
+        read
+    }
+
+    #[test]
+    fn test_attach_after_crash() {
+        let sessions = SessionRegistry::default();
+        let mut first = connect(&sessions);
+        read_until(&mut first, repl::PROMPT);
+        let session = sessions.list(None)[0].clone();
+
+        // Panicking while running a command leaves the session's REPL poisoned
+        let crashed = session.clone();
+        let _ = thread::spawn(move || {
+            let _repl = crashed.repl.lock().unwrap();
+            panic!("The command crashed");
+        })
+        .join();
+
+        // The session ends, and its connection is closed rather than left hanging
+        first.write_all(b"!registers\n").unwrap();
+        let mut output = String::new();
+        first.read_to_string(&mut output).unwrap();
+        assert!(output.contains(&format!("Session {} has ended", session.id())));
+        assert!(sessions.list(None).is_empty());
+
+        let mut second = connect(&sessions);
+        read_until(&mut second, repl::PROMPT);
+        second.write_all(format!("!attach {}\n", session.id()).as_bytes()).unwrap();
+        assert!(read_until(&mut second, "There is no session").contains("There is no session"));
+        second.write_all(b"!quit\n").unwrap();
     }
 }
@@ -1,2 +1,8 @@
+pub mod auth;
+pub mod auth_errors;
 pub mod client;
+pub mod password;
+pub mod roles;
 pub mod server;
+pub mod session;
+pub mod session_errors;
@@ -0,0 +1,74 @@
+//! Password hashing for remote access users: PBKDF2 with HMAC-SHA256, and a random salt per password.
+//! Hashes are stored as `pbkdf2-sha256$<iterations>$<salt>$<hash>`, with the salt and hash in hex.
+
+use std::num::NonZeroU32;
+
+use rand;
+use ring::pbkdf2;
+
+use hex::{from_hex, to_hex};
+
+/// How many rounds of HMAC each password goes through. More makes guessing passwords from a stolen users
+/// file slower, and logging in slower too.
+pub const PBKDF2_ITERATIONS: u32 = 10_000;
+
+const SALT_LENGTH: usize = 16;
+const HASH_LENGTH: usize = 32;
+const HASH_PREFIX: &str = "pbkdf2-sha256";
+
+/// Hashes `password` with a new random salt, for storing
+pub fn hash_password(password: &str) -> String {
+    let salt: [u8; SALT_LENGTH] = rand::random();
+    let hash = pbkdf2_sha256(password.as_bytes(), &salt, PBKDF2_ITERATIONS);
+    format!("{}${}${}${}", HASH_PREFIX, PBKDF2_ITERATIONS, to_hex(&salt), to_hex(&hash))
+}
+
+/// Checks `password` against a hash from `hash_password`, in constant time. Returns false if the hash isn't in
+/// that format.
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    let parts: Vec<&str> = stored.split('$').collect();
+    if parts.len() != 4 || parts[0] != HASH_PREFIX {
+        return false;
+    }
+    let iterations = parts[1].parse::<u32>().ok().and_then(NonZeroU32::new);
+    match (iterations, from_hex(parts[2]), from_hex(parts[3])) {
+        (Some(iterations), Some(salt), Some(expected)) => pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &expected).is_ok(),
+        _ => false,
+    }
+}
+
+/// PBKDF2 with HMAC-SHA256, producing one block of 32 bytes. Takes at least one iteration.
+pub fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> [u8; HASH_LENGTH] {
+    let iterations = NonZeroU32::new(iterations).unwrap_or(NonZeroU32::MIN);
+    let mut hash = [0u8; HASH_LENGTH];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, password, &mut hash);
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_digests() {
+        // From RFC 7914, section 11
+        assert_eq!(
+            to_hex(&pbkdf2_sha256(b"passwd", b"salt", 1)),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+        assert_eq!(
+            to_hex(&pbkdf2_sha256(b"password", b"salt", 2)),
+            "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43"
+        );
+    }
+
+    #[test]
+    fn test_hash_and_verify() {
+        let hash = hash_password("hunter2");
+        assert!(hash.starts_with("pbkdf2-sha256$10000$"));
+        assert!(verify_password("hunter2", &hash));
+        assert!(!verify_password("hunter3", &hash));
+        assert_ne!(hash, hash_password("hunter2"));
+        assert!(!verify_password("hunter2", "hunter2"));
+    }
+}
@@ -0,0 +1,175 @@
+//! Which REPL commands remote users may run. Roles are configured in a file in the data root:
+//!
+//! ```text
+//! [roles]
+//! admin = *
+//! readonly = !registers !fregs !state !mem !stack !ro !symbols !cluster_members
+//!
+//! [users]
+//! alice = admin
+//! bob = readonly
+//! ```
+//!
+//! `*` allows every command, and `execute` allows running instructions, typed directly or submitted with
+//! `!submit`. Users who aren't listed get the `default` role, or no commands at all if there isn't one. Without
+//! the file, everyone may run everything.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The file in the data root roles are configured in
+pub const ROLES_FILENAME: &str = "roles";
+
+/// What users who aren't given a role get
+pub const DEFAULT_ROLE: &str = "default";
+
+/// The name that allows running instructions typed at the prompt, rather than a command
+pub const EXECUTE: &str = "execute";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Role {
+    name: String,
+    /// Commands the role may run, including the `!`, or `*` for all of them
+    commands: Vec<String>,
+}
+
+impl Role {
+    pub fn new(name: &str, commands: &[&str]) -> Role {
+        Role {
+            name: name.to_string(),
+            commands: commands.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn allows(&self, command: &str) -> bool {
+        self.commands.iter().any(|c| c == "*" || c == command)
+    }
+}
+
+/// The roles and which user has which
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoleConfig {
+    roles: HashMap<String, Role>,
+    users: HashMap<String, String>,
+}
+
+impl Default for RoleConfig {
+    /// Lets everyone run everything, as when there is no roles file
+    fn default() -> RoleConfig {
+        let mut roles = HashMap::new();
+        roles.insert(DEFAULT_ROLE.to_string(), Role::new(DEFAULT_ROLE, &["*"]));
+        RoleConfig { roles, users: HashMap::new() }
+    }
+}
+
+impl RoleConfig {
+    /// Reads the roles file at `path`. If there isn't one, everyone may run everything.
+    pub fn load(path: &Path) -> io::Result<RoleConfig> {
+        match fs::read_to_string(path) {
+            Ok(contents) => RoleConfig::parse(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(RoleConfig::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<RoleConfig, String> {
+        let mut config = RoleConfig {
+            roles: HashMap::new(),
+            users: HashMap::new(),
+        };
+        let mut section = None;
+        for (number, line) in contents.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(comment) => &line[..comment],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = Some(line[1..line.len() - 1].trim().to_string());
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(split) => (line[..split].trim(), line[split + 1..].trim()),
+                None => return Err(format!("Line {} should look like `name = value`", number + 1)),
+            };
+            match section.as_deref() {
+                Some("roles") => {
+                    let commands: Vec<&str> = value.split_whitespace().collect();
+                    config.roles.insert(key.to_string(), Role::new(key, &commands));
+                }
+                Some("users") => {
+                    config.users.insert(key.to_string(), value.to_string());
+                }
+                _ => return Err(format!("Line {} should be in a [roles] or [users] section", number + 1)),
+            }
+        }
+        for (user, role) in &config.users {
+            if !config.roles.contains_key(role) {
+                return Err(format!("{} is given the role {}, which isn't defined", user, role));
+            }
+        }
+        Ok(config)
+    }
+
+    /// The role of `username`. Users without one get the `default` role, or one that allows nothing.
+    pub fn role_for(&self, username: &str) -> Role {
+        let name = self.users.get(username).map(|r| r.as_str()).unwrap_or(DEFAULT_ROLE);
+        match self.roles.get(name) {
+            Some(role) => role.clone(),
+            None => Role::new(name, &[]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = RoleConfig::parse(
+            "# Who may do what
+            [roles]
+            admin = *
+            readonly = !registers !symbols   # Looking, not touching
+
+            [users]
+            alice = admin
+            bob = readonly
+            ",
+        )
+        .unwrap();
+        assert!(config.role_for("alice").allows("!spawn"));
+        let bob = config.role_for("bob");
+        assert_eq!(bob.name(), "readonly");
+        assert!(bob.allows("!registers"));
+        assert!(!bob.allows("!spawn"));
+        assert!(!bob.allows(EXECUTE));
+        let carol = config.role_for("carol");
+        assert_eq!(carol.name(), DEFAULT_ROLE);
+        assert!(!carol.allows("!registers"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(RoleConfig::parse("admin = *").is_err());
+        assert!(RoleConfig::parse("[roles]\nadmin").is_err());
+        assert!(RoleConfig::parse("[users]\nalice = admin").is_err());
+    }
+
+    #[test]
+    fn test_default() {
+        assert!(RoleConfig::default().role_for("anyone").allows("!quit"));
+        let path = std::env::temp_dir().join("iridium-roles-test-missing");
+        assert_eq!(RoleConfig::load(&path).unwrap(), RoleConfig::default());
+    }
+}
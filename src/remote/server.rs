@@ -1,24 +1,128 @@
+use policy::Policy;
+use remote::auth::Authenticator;
 use remote::client::Client;
+use remote::roles::RoleConfig;
+use remote::session::SessionRegistry;
+use scheduler::Shutdown;
 use std::net::TcpListener;
+use std::path::PathBuf;
 use std::thread;
+use std::time::Duration;
+use tls::{self, TlsConfig};
 
 pub struct Server {
     bind_hostname: String,
     bind_port: String,
+    /// What programs run by connected users may do. Anyone who can connect can run programs, so by default
+    /// this is `Policy::restrictive()`.
+    policy: Policy,
+    /// Stops the programs connected users run
+    shutdown: Shutdown,
+    /// Checks who is connecting. Without one, anyone who can connect gets a prompt.
+    authenticator: Option<Authenticator>,
+    /// Which commands each user may run once they have logged in
+    roles: RoleConfig,
+    /// Encrypts connections. Without it, sessions, passwords included, are sent in plaintext.
+    tls: Option<TlsConfig>,
+    /// Every connected user's session, including those detached by a dropped connection
+    sessions: SessionRegistry,
+    /// Where each user's history is kept
+    history_dir: Option<PathBuf>,
 }
 
+/// How often sessions that have been detached for longer than the idle timeout are looked for
+pub const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
 impl Server {
     pub fn new(bind_hostname: String, bind_port: String) -> Server {
-        Server { bind_hostname, bind_port }
+        Server {
+            bind_hostname,
+            bind_port,
+            policy: Policy::restrictive(),
+            shutdown: Shutdown::new(),
+            authenticator: None,
+            roles: RoleConfig::default(),
+            tls: None,
+            sessions: SessionRegistry::default(),
+            history_dir: None,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Requires users to log in before they get a prompt
+    pub fn with_authenticator(mut self, authenticator: Authenticator) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Limits the commands users may run once they have logged in
+    pub fn with_roles(mut self, roles: RoleConfig) -> Self {
+        self.roles = roles;
+        self
+    }
+
+    /// Encrypts connections with TLS
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Sets how long a session is kept once its connection drops, and how long a connection may be idle
+    /// before it is dropped. Defaults to `session::DEFAULT_IDLE_TIMEOUT`.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.sessions = SessionRegistry::new(idle_timeout);
+        self
+    }
+
+    /// Keeps the history of each user who logs in in a file of their own in `dir`
+    pub fn with_history_dir(mut self, dir: PathBuf) -> Self {
+        self.history_dir = Some(dir);
+        self
     }
 
     pub fn listen(&mut self) {
         println!("Initializing TCP server...");
+        let sessions = self.sessions.clone();
+        thread::spawn(move || loop {
+            thread::sleep(IDLE_CHECK_INTERVAL);
+            for id in sessions.end_idle() {
+                info!("Session {} was detached for too long", id);
+            }
+        });
         let listener = TcpListener::bind(self.bind_hostname.clone() + ":" + &self.bind_port).unwrap();
         for stream in listener.incoming() {
             let stream = stream.unwrap();
-            thread::spawn(|| {
-                let mut client = Client::new(stream);
+            let policy = self.policy.clone();
+            let shutdown = self.shutdown.clone();
+            let authenticator = self.authenticator.clone();
+            let roles = self.roles.clone();
+            let tls = self.tls.clone();
+            let sessions = self.sessions.clone();
+            let history_dir = self.history_dir.clone();
+            thread::spawn(move || {
+                let stream = match tls::accept_user(stream, tls.as_ref()) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Rejected remote access connection: {}", e);
+                        return;
+                    }
+                };
+                let mut client = Client::new(stream, policy, shutdown).with_roles(roles).with_sessions(sessions);
+                if let Some(authenticator) = authenticator {
+                    client = client.with_authenticator(authenticator);
+                }
+                if let Some(dir) = history_dir {
+                    client = client.with_history_dir(dir);
+                }
                 client.run();
             });
         }
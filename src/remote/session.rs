@@ -0,0 +1,306 @@
+//! Remote access sessions. Each has its own REPL and VM, and outlives the connection that started it: when the
+//! connection drops, the session is detached, and its owner can pick it up again from a new connection with
+//! `!attach` until it has been detached for longer than the idle timeout.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use remote::session_errors::SessionError;
+use repl::REPL;
+use tls::Stream;
+
+pub type SessionId = usize;
+
+/// How long a session is kept once detached, and how long a connection may go without sending anything before
+/// its session is detached
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// How many messages a detached session keeps, to show whoever attaches to it next
+pub const BACKLOG_LIMIT: usize = 1000;
+
+/// Where a session's output goes
+struct Attachment {
+    /// The connection the session is attached to, if any
+    stream: Option<Stream>,
+    /// Counts attachments, so a connection that has been replaced can't detach its replacement
+    generation: u64,
+    detached_at: Option<Instant>,
+    /// Output from while the session was detached
+    backlog: VecDeque<String>,
+}
+
+pub struct Session {
+    id: SessionId,
+    /// Who logged in to start the session, if users have to
+    owner: Option<String>,
+    pub repl: Mutex<REPL>,
+    attachment: Arc<Mutex<Attachment>>,
+}
+
+impl Session {
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    pub fn owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+
+    /// How long the session has been detached, or `None` if it is attached
+    pub fn detached_for(&self) -> Option<Duration> {
+        self.attachment.lock().unwrap().detached_at.map(|at| at.elapsed())
+    }
+
+    /// Sends the session's output to `stream`, starting with any it has kept while detached. If the session is
+    /// already attached, that connection is closed, as it may be one that dropped without the server noticing.
+    /// Returns the generation to detach with.
+    fn attach(&self, mut stream: Stream) -> u64 {
+        let mut attachment = self.attachment.lock().unwrap();
+        if let Some(previous) = attachment.stream.take() {
+            let _ = previous.shutdown();
+        }
+        for message in attachment.backlog.drain(..) {
+            let _ = stream.write_all(message.as_bytes());
+        }
+        attachment.stream = Some(stream);
+        attachment.generation += 1;
+        attachment.detached_at = None;
+        attachment.generation
+    }
+
+    /// Detaches the session from its connection, unless it has been attached to another one since `generation`
+    fn detach(&self, generation: u64) -> bool {
+        let mut attachment = self.attachment.lock().unwrap();
+        if attachment.generation != generation || attachment.stream.is_none() {
+            return false;
+        }
+        attachment.stream = None;
+        attachment.detached_at = Some(Instant::now());
+        true
+    }
+
+    fn close(&self) {
+        if let Some(stream) = self.attachment.lock().unwrap().stream.take() {
+            let _ = stream.shutdown();
+        }
+    }
+}
+
+/// The sessions of everyone connected through remote access
+#[derive(Clone)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<HashMap<SessionId, Arc<Session>>>>,
+    next_id: Arc<AtomicUsize>,
+    idle_timeout: Duration,
+}
+
+impl Default for SessionRegistry {
+    fn default() -> SessionRegistry {
+        SessionRegistry::new(DEFAULT_IDLE_TIMEOUT)
+    }
+}
+
+impl SessionRegistry {
+    pub fn new(idle_timeout: Duration) -> SessionRegistry {
+        SessionRegistry {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            next_id: Arc::new(AtomicUsize::new(1)),
+            idle_timeout,
+        }
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Starts a detached session running `repl`, owned by `owner`
+    pub fn create(&self, owner: Option<String>, mut repl: REPL) -> Arc<Session> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        repl.set_session(self.clone(), id);
+        let output = repl.rx_pipe.take();
+        let attachment = Arc::new(Mutex::new(Attachment {
+            stream: None,
+            generation: 0,
+            detached_at: Some(Instant::now()),
+            backlog: VecDeque::new(),
+        }));
+        if let Some(output) = output {
+            let attachment = attachment.clone();
+            // Runs until the REPL, and every program it started, has gone
+            thread::spawn(move || {
+                for message in output.iter() {
+                    let message = message + "\n";
+                    let mut attachment = attachment.lock().unwrap();
+                    let sent = match attachment.stream {
+                        Some(ref mut stream) => stream.write_all(message.as_bytes()).is_ok(),
+                        None => false,
+                    };
+                    if !sent {
+                        if attachment.backlog.len() == BACKLOG_LIMIT {
+                            attachment.backlog.pop_front();
+                        }
+                        attachment.backlog.push_back(message);
+                    }
+                }
+            });
+        }
+        let session = Arc::new(Session {
+            id,
+            owner,
+            repl: Mutex::new(repl),
+            attachment,
+        });
+        self.sessions.lock().unwrap().insert(id, session.clone());
+        info!("Started session {}", id);
+        session
+    }
+
+    /// The sessions `user` may attach to, in the order they were started
+    pub fn list(&self, user: Option<&str>) -> Vec<Arc<Session>> {
+        let mut sessions: Vec<Arc<Session>> = self.sessions.lock().unwrap().values().filter(|s| s.owner() == user).cloned().collect();
+        sessions.sort_by_key(|s| s.id);
+        sessions
+    }
+
+    /// Attaches `user`'s connection to the session `id`. Returns the session and the generation to detach with.
+    pub fn attach(&self, id: SessionId, user: Option<&str>, stream: Stream) -> Result<(Arc<Session>, u64), SessionError> {
+        let session = match self.sessions.lock().unwrap().get(&id) {
+            Some(session) => session.clone(),
+            None => return Err(SessionError::NotFound { id }),
+        };
+        if session.owner() != user {
+            return Err(SessionError::NotOwner { id });
+        }
+        // A command panicked in the session's REPL, so it is as good as ended
+        if session.repl.is_poisoned() {
+            self.end(id);
+            return Err(SessionError::NotFound { id });
+        }
+        let generation = session.attach(stream);
+        info!("Attached to session {}", id);
+        Ok((session, generation))
+    }
+
+    /// Detaches `session` from the connection that attached with `generation`, keeping it for `idle_timeout`
+    pub fn detach(&self, session: &Session, generation: u64) {
+        if session.detach(generation) {
+            info!("Detached from session {}", session.id);
+        }
+    }
+
+    /// Ends the session `id`, closing its connection
+    pub fn end(&self, id: SessionId) {
+        if let Some(session) = self.sessions.lock().unwrap().remove(&id) {
+            session.close();
+            info!("Ended session {}", id);
+        }
+    }
+
+    /// Ends the sessions that have been detached for longer than the idle timeout, returning their IDs
+    pub fn end_idle(&self) -> Vec<SessionId> {
+        let idle: Vec<SessionId> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|s| s.detached_for().is_some_and(|d| d >= self.idle_timeout))
+            .map(|s| s.id)
+            .collect();
+        for id in &idle {
+            self.end(*id);
+        }
+        idle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use vm::VM;
+
+    /// Both ends of a connection
+    fn connection() -> (Stream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (Stream::Plain(server), client)
+    }
+
+    fn read_some(stream: &mut TcpStream) -> String {
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = [0u8; 256];
+        let n = stream.read(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..n]).into_owned()
+    }
+
+    #[test]
+    fn test_attach_and_detach() {
+        let registry = SessionRegistry::new(DEFAULT_IDLE_TIMEOUT);
+        let session = registry.create(Some("alice".to_string()), REPL::new(VM::new()));
+        assert!(session.detached_for().is_some());
+
+        // Output while detached is kept until someone attaches
+        session.repl.lock().unwrap().send_message("Hello".to_string());
+        thread::sleep(Duration::from_millis(50));
+        let (server, mut client) = connection();
+        let (attached, generation) = registry.attach(session.id(), Some("alice"), server).unwrap();
+        assert_eq!(attached.id(), session.id());
+        assert_eq!(read_some(&mut client), "Hello\n");
+        assert_eq!(session.detached_for(), None);
+
+        // Attaching from another connection takes over, and the old one can no longer detach it
+        let (server, _client) = connection();
+        let (_, new_generation) = registry.attach(session.id(), Some("alice"), server).unwrap();
+        registry.detach(&session, generation);
+        assert_eq!(session.detached_for(), None);
+        registry.detach(&session, new_generation);
+        assert!(session.detached_for().is_some());
+
+        let (server, _client) = connection();
+        match registry.attach(session.id(), Some("bob"), server) {
+            Err(e) => assert_eq!(e, SessionError::NotOwner { id: session.id() }),
+            Ok(_) => panic!("Attached to another user's session"),
+        }
+        assert_eq!(registry.list(Some("alice")).len(), 1);
+        assert_eq!(registry.list(Some("bob")).len(), 0);
+        registry.end(session.id());
+        let (server, _client) = connection();
+        assert!(registry.attach(session.id(), Some("alice"), server).is_err());
+    }
+
+    #[test]
+    fn test_crashed_session() {
+        let registry = SessionRegistry::new(DEFAULT_IDLE_TIMEOUT);
+        let session = registry.create(None, REPL::new(VM::new()));
+        let crashed = session.clone();
+        let _ = thread::spawn(move || {
+            let _repl = crashed.repl.lock().unwrap();
+            panic!("The command crashed");
+        })
+        .join();
+        // Attaching to a session whose REPL panicked ends it instead
+        let (server, _client) = connection();
+        match registry.attach(session.id(), None, server) {
+            Err(e) => assert_eq!(e, SessionError::NotFound { id: session.id() }),
+            Ok(_) => panic!("Attached to a crashed session"),
+        }
+        assert!(registry.list(None).is_empty());
+    }
+
+    #[test]
+    fn test_end_idle() {
+        let registry = SessionRegistry::new(Duration::from_millis(0));
+        let detached = registry.create(None, REPL::new(VM::new()));
+        let attached = registry.create(None, REPL::new(VM::new()));
+        let (server, _client) = connection();
+        registry.attach(attached.id(), None, server).unwrap();
+        assert_eq!(registry.end_idle(), vec![detached.id()]);
+        assert_eq!(registry.list(None).len(), 1);
+    }
+}
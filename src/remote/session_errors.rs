@@ -0,0 +1,30 @@
+use std::error::Error;
+use std::fmt;
+
+use remote::session::SessionId;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionError {
+    /// There is no session with this ID, or it has ended
+    NotFound { id: SessionId },
+    /// The session was started by another user
+    NotOwner { id: SessionId },
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SessionError::NotFound { id } => f.write_str(&format!("There is no session {}", id)),
+            SessionError::NotOwner { id } => f.write_str(&format!("Session {} belongs to another user", id)),
+        }
+    }
+}
+
+impl Error for SessionError {
+    fn description(&self) -> &str {
+        match self {
+            SessionError::NotFound { .. } => "No such session",
+            SessionError::NotOwner { .. } => "The session belongs to another user",
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError {
+    /// A quote was opened but not closed
+    UnterminatedQuote,
+    UnknownCommand {
+        command: String,
+    },
+    MissingArgument {
+        name: String,
+    },
+    UnexpectedArgument {
+        arg: String,
+    },
+    UnknownFlag {
+        flag: String,
+    },
+    /// A flag that takes a value was given without one
+    MissingFlagValue {
+        flag: String,
+    },
+    /// An argument or flag value is the wrong type, such as a word where a number belongs
+    InvalidValue {
+        name: String,
+        value: String,
+    },
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CommandError::UnterminatedQuote => f.write_str("A quote is missing its closing quote"),
+            CommandError::UnknownCommand { ref command } => f.write_str(&format!("There is no command {}", command)),
+            CommandError::MissingArgument { ref name } => f.write_str(&format!("Missing {}", name)),
+            CommandError::UnexpectedArgument { ref arg } => f.write_str(&format!("Unexpected argument {:?}", arg)),
+            CommandError::UnknownFlag { ref flag } => f.write_str(&format!("Unknown flag {}", flag)),
+            CommandError::MissingFlagValue { ref flag } => f.write_str(&format!("{} needs a value", flag)),
+            CommandError::InvalidValue { ref name, ref value } => f.write_str(&format!("{:?} is not a valid {}", value, name)),
+        }
+    }
+}
+
+impl Error for CommandError {
+    fn description(&self) -> &str {
+        match self {
+            CommandError::UnterminatedQuote => "A quote is missing its closing quote",
+            CommandError::UnknownCommand { .. } => "No such command",
+            CommandError::MissingArgument { .. } => "Missing argument",
+            CommandError::UnexpectedArgument { .. } => "Unexpected argument",
+            CommandError::UnknownFlag { .. } => "Unknown flag",
+            CommandError::MissingFlagValue { .. } => "Flag needs a value",
+            CommandError::InvalidValue { .. } => "Invalid value",
+        }
+    }
+}
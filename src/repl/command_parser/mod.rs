@@ -1,9 +1,250 @@
+//! Parses the arguments of REPL commands. Each command describes its arguments in a `CommandSpec`, written the
+//! way its usage is shown: `<path>` is required, `[name]` is optional and `[text...]` takes the rest of the line.
+//! Arguments can be quoted, with `'` or `"`, to include spaces.
+
+pub mod command_errors;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use repl::command_parser::command_errors::CommandError;
+
+/// A flag a command accepts, such as `--threads <n>`
+pub struct Flag {
+    /// Including the leading `--`
+    pub name: &'static str,
+    /// What the flag's value is called, or `None` if it doesn't take one
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+}
+
+pub struct CommandSpec {
+    /// Including the leading `!`
+    pub name: &'static str,
+    pub args: &'static [&'static str],
+    pub flags: &'static [Flag],
+    pub summary: &'static str,
+}
+
+impl CommandSpec {
+    /// The command's usage on one line, e.g. `!spawn <path> [--threads <n>]`
+    pub fn usage(&self) -> String {
+        let mut usage = self.name.to_string();
+        for arg in self.args {
+            usage.push(' ');
+            usage.push_str(arg);
+        }
+        for flag in self.flags {
+            match flag.value {
+                Some(value) => usage.push_str(&format!(" [{} <{}>]", flag.name, value)),
+                None => usage.push_str(&format!(" [{}]", flag.name)),
+            }
+        }
+        usage
+    }
+
+    /// The usage, what the command does, and what each flag does
+    pub fn help(&self) -> String {
+        let mut help = format!("Usage: {}\n{}", self.usage(), self.summary);
+        for flag in self.flags {
+            help.push_str(&format!("\n  {}  {}", flag.name, flag.help));
+        }
+        help
+    }
+
+    /// Parses the arguments after the command's name
+    pub fn parse(&self, tokens: &[String]) -> Result<Args, CommandError> {
+        let mut args = Args::default();
+        let mut tokens = tokens.iter();
+        while let Some(token) = tokens.next() {
+            if !token.starts_with("--") {
+                args.positional.push(token.clone());
+                continue;
+            }
+            let (name, inline_value) = match token.find('=') {
+                Some(i) => (&token[..i], Some(token[i + 1..].to_string())),
+                None => (token.as_str(), None),
+            };
+            let flag = match self.flags.iter().find(|f| f.name == name) {
+                Some(flag) => flag,
+                None => return Err(CommandError::UnknownFlag { flag: name.to_string() }),
+            };
+            let value = match (flag.value, inline_value) {
+                (Some(_), Some(value)) => Some(value),
+                (Some(_), None) => match tokens.next() {
+                    Some(value) => Some(value.clone()),
+                    None => return Err(CommandError::MissingFlagValue { flag: name.to_string() }),
+                },
+                (None, Some(value)) => return Err(CommandError::UnexpectedArgument { arg: value }),
+                (None, None) => None,
+            };
+            args.flags.insert(name.to_string(), value);
+        }
+
+        // Whatever the last argument doesn't take is one too many
+        let takes_rest = self.args.last().is_some_and(|a| a.ends_with("...]") || a.ends_with("...>"));
+        if !takes_rest && args.positional.len() > self.args.len() {
+            return Err(CommandError::UnexpectedArgument {
+                arg: args.positional[self.args.len()].clone(),
+            });
+        }
+        for (i, arg) in self.args.iter().enumerate() {
+            if arg.starts_with('<') && args.positional.len() <= i {
+                return Err(CommandError::MissingArgument { name: arg.to_string() });
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// The arguments given to a command
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    positional: Vec<String>,
+    /// Flags given, with their values
+    flags: HashMap<String, Option<String>>,
+}
+
+impl Args {
+    /// The argument at `index`, if it was given
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.positional.get(index).map(|a| a.as_str())
+    }
+
+    /// The argument at `index` and every one after it, joined with spaces
+    pub fn rest(&self, index: usize) -> Option<String> {
+        if index < self.positional.len() {
+            Some(self.positional[index..].join(" "))
+        } else {
+            None
+        }
+    }
+
+    /// The argument at `index` as a `T`, if it was given. `name` describes it in the error if it isn't one.
+    pub fn parse<T: FromStr>(&self, index: usize, name: &str) -> Result<Option<T>, CommandError> {
+        match self.get(index) {
+            Some(value) => value.parse().map(Some).map_err(|_| CommandError::InvalidValue {
+                name: name.to_string(),
+                value: value.to_string(),
+            }),
+            None => Ok(None),
+        }
+    }
+
+    pub fn has_flag(&self, name: &str) -> bool {
+        self.flags.contains_key(name)
+    }
+
+    /// The value of the flag `name` as a `T`, if it was given
+    pub fn flag<T: FromStr>(&self, name: &str) -> Result<Option<T>, CommandError> {
+        match self.flags.get(name) {
+            Some(Some(value)) => value.parse().map(Some).map_err(|_| CommandError::InvalidValue {
+                name: name.to_string(),
+                value: value.to_string(),
+            }),
+            _ => Ok(None),
+        }
+    }
+}
+
 pub struct CommandParser {}
 
 impl CommandParser {
-    pub fn tokenize(input: &str) -> Vec<&str> {
-        let split = input.split_whitespace();
-        let vec: Vec<&str> = split.collect();
-        vec
+    /// Splits a command line into words. Quotes group words, and `\` takes the next character as it is.
+    pub fn tokenize(input: &str) -> Result<Vec<String>, CommandError> {
+        let mut tokens = vec![];
+        let mut token = String::new();
+        // Whether there is a token, which may be an empty quoted one
+        let mut in_token = false;
+        let mut quote = None;
+        let mut chars = input.chars();
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (_, '\\') => {
+                    if let Some(escaped) = chars.next() {
+                        token.push(escaped);
+                    }
+                    in_token = true;
+                }
+                (Some(q), c) if c == q => quote = None,
+                (Some(_), c) => token.push(c),
+                (None, '\'') | (None, '"') => {
+                    quote = Some(c);
+                    in_token = true;
+                }
+                (None, c) if c.is_whitespace() => {
+                    if in_token {
+                        tokens.push(token.split_off(0));
+                        in_token = false;
+                    }
+                }
+                (None, c) => {
+                    token.push(c);
+                    in_token = true;
+                }
+            }
+        }
+        if quote.is_some() {
+            return Err(CommandError::UnterminatedQuote);
+        }
+        if in_token {
+            tokens.push(token);
+        }
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPAWN: CommandSpec = CommandSpec {
+        name: "!spawn",
+        args: &["<path>"],
+        flags: &[Flag {
+            name: "--threads",
+            value: Some("n"),
+            help: "Threads the VM may use",
+        }],
+        summary: "Runs a program in the background",
+    };
+
+    fn tokens(input: &str) -> Vec<String> {
+        CommandParser::tokenize(input).unwrap()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokens("!load_file  prog.iasm "), vec!["!load_file", "prog.iasm"]);
+        assert_eq!(
+            tokens(r#"!load_file "my prog.iasm" 'it''s' a\ b """#),
+            vec!["!load_file", "my prog.iasm", "its", "a b", ""]
+        );
+        assert_eq!(CommandParser::tokenize("!load_file \"prog"), Err(CommandError::UnterminatedQuote));
+    }
+
+    #[test]
+    fn test_parse() {
+        let args = SPAWN.parse(&tokens("prog.iasm --threads 2")).unwrap();
+        assert_eq!(args.get(0), Some("prog.iasm"));
+        assert_eq!(args.flag::<usize>("--threads"), Ok(Some(2)));
+        let args = SPAWN.parse(&tokens("--threads=4 prog.iasm")).unwrap();
+        assert_eq!(args.flag::<usize>("--threads"), Ok(Some(4)));
+
+        assert_eq!(SPAWN.parse(&[]), Err(CommandError::MissingArgument { name: "<path>".to_string() }));
+        assert!(SPAWN.parse(&tokens("a.iasm b.iasm")).is_err());
+        assert!(SPAWN.parse(&tokens("a.iasm --verbose")).is_err());
+        assert!(SPAWN.parse(&tokens("a.iasm --threads")).is_err());
+        let args = SPAWN.parse(&tokens("a.iasm --threads many")).unwrap();
+        assert!(args.flag::<usize>("--threads").is_err());
+    }
+
+    #[test]
+    fn test_usage() {
+        assert_eq!(SPAWN.usage(), "!spawn <path> [--threads <n>]");
+        assert_eq!(
+            SPAWN.help(),
+            "Usage: !spawn <path> [--threads <n>]\nRuns a program in the background\n  --threads  Threads the VM may use"
+        );
     }
 }
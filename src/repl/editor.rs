@@ -0,0 +1,119 @@
+//! Line editing for the local REPL: tab completion of commands and their flags, opcodes, registers and labels, and programs
+//! spanning several lines, such as a pasted `.data`/`.code` block, entered as one.
+
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Helper};
+
+use assembler::signatures::{opcodes, pseudo_opcodes};
+use repl::{command, COMMANDS};
+
+/// Whether `line` starts a program with sections, which continues until a blank line
+pub fn starts_program(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with(".data") || line.starts_with(".code")
+}
+
+/// Completes the word that ends at `pos` in `line`. Returns where the word starts and what it could be.
+pub fn complete(line: &str, pos: usize, labels: &[String]) -> (usize, Vec<String>) {
+    let before = &line[..pos];
+    let start = before.rfind([' ', '\t', '\n']).map_or(0, |i| i + 1);
+    let word = &before[start..];
+    let line_start = before[..start].rfind('\n').map_or(0, |i| i + 1);
+    let first_word = before[line_start..start].trim().is_empty();
+    let candidates: Vec<String> = if word.starts_with('!') && start == 0 {
+        COMMANDS.iter().map(|c| c.name.to_string()).collect()
+    } else if word.starts_with("--") {
+        // The flags of the command the line starts with
+        let name = before.split_whitespace().next().unwrap_or_default();
+        command(name).map_or(vec![], |c| c.flags.iter().map(|f| f.name.to_string()).collect())
+    } else if word.starts_with('$') {
+        (0..32).map(|r| format!("${}", r)).collect()
+    } else if word.starts_with('@') {
+        labels.iter().map(|l| format!("@{}", l)).collect()
+    } else if first_word {
+        let mut names: Vec<String> = opcodes().into_iter().map(|c| format!("{:?}", c).to_lowercase()).collect();
+        names.extend(pseudo_opcodes().into_iter().map(|c| format!("{:?}", c).to_lowercase()));
+        names.push(".data".to_string());
+        names.push(".code".to_string());
+        names
+    } else {
+        vec![]
+    };
+    let word = word.to_lowercase();
+    let mut matches: Vec<String> = candidates.into_iter().filter(|c| c.to_lowercase().starts_with(&word)).collect();
+    matches.sort();
+    (start, matches)
+}
+
+/// Completes and validates what is typed at the local REPL
+#[derive(Default)]
+pub struct ReplHelper {
+    /// Labels of the last program assembled
+    labels: Vec<String>,
+}
+
+impl ReplHelper {
+    pub fn new() -> ReplHelper {
+        ReplHelper::default()
+    }
+
+    pub fn set_labels(&mut self, labels: Vec<String>) {
+        self.labels = labels;
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(complete(line, pos, &self.labels))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {
+    /// A program with sections is only finished by a blank line, so Enter adds another line until then
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        let input = ctx.input();
+        if starts_program(input) && !input.ends_with('\n') {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+impl Helper for ReplHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complete() {
+        let labels = vec!["loop".to_string(), "done".to_string()];
+        assert_eq!(complete("!reg", 4, &labels), (0, vec!["!registers".to_string()]));
+        assert_eq!(complete("!spawn a.iasm --t", 17, &labels), (14, vec!["--threads".to_string()]));
+        assert_eq!(complete("loa", 3, &labels).1, vec!["load", "loadf64", "loadm"]);
+        assert_eq!(complete("add $1", 6, &labels).1.len(), 11);
+        assert_eq!(complete("jmp @l", 6, &labels), (4, vec!["@loop".to_string()]));
+        // Only the first word of a line is an opcode
+        assert_eq!(complete("load lo", 7, &labels).1.len(), 0);
+        assert_eq!(complete(".data\n.code\nhl", 14, &labels), (12, vec!["hlt".to_string()]));
+    }
+
+    #[test]
+    fn test_starts_program() {
+        assert!(starts_program(".data\nhello: .asciiz 'Hi'"));
+        assert!(starts_program("  .code"));
+        assert!(!starts_program("load $0 #10"));
+    }
+}
@@ -0,0 +1,152 @@
+//! Lines entered at the REPL, kept in a file under the data root so they survive restarts. The local REPL has
+//! one file, and each remote access user has their own.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use hex::to_hex;
+
+/// The file in the data root the local REPL's history is kept in
+pub const HISTORY_FILENAME: &str = "repl_history";
+
+/// The directory in the data root remote access users' histories are kept in, one file per user
+pub const USER_HISTORY_DIRNAME: &str = "history";
+
+/// How many entries are kept
+pub const HISTORY_LIMIT: usize = 1000;
+
+#[derive(Debug, Default)]
+pub struct History {
+    entries: Vec<String>,
+    /// The file entries are appended to. Without one, they are only kept in memory.
+    path: Option<PathBuf>,
+}
+
+impl History {
+    /// A history that is only kept in memory
+    pub fn new() -> History {
+        History::default()
+    }
+
+    /// Reads the history kept at `path`, if there is one yet, and appends new entries to it
+    pub fn load(path: &Path) -> io::Result<History> {
+        let mut entries: Vec<String> = match fs::read_to_string(path) {
+            Ok(contents) => contents.lines().map(unescape).collect(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        if entries.len() > HISTORY_LIMIT {
+            entries.drain(..entries.len() - HISTORY_LIMIT);
+            let contents: String = entries.iter().map(|e| escape(e) + "\n").collect();
+            fs::write(path, contents)?;
+        }
+        Ok(History {
+            entries,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// Where the history of remote access user `username` is kept under `dir`. Usernames that wouldn't make a
+    /// safe file name are hex encoded after an `=`, which a safe one can't contain, so the two never collide.
+    pub fn user_path(dir: &Path, username: &str) -> PathBuf {
+        let safe = !username.is_empty() && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if safe {
+            dir.join(username)
+        } else {
+            dir.join(format!("={}", to_hex(username.as_bytes())))
+        }
+    }
+
+    /// Every entry, oldest first
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// The entries containing `text`, newest first
+    pub fn search(&self, text: &str) -> Vec<&str> {
+        self.entries.iter().rev().filter(|e| e.contains(text)).map(|e| e.as_str()).collect()
+    }
+
+    /// Adds an entry, unless it is blank or the same as the last one
+    pub fn push(&mut self, entry: &str) {
+        let entry = entry.trim_end();
+        if entry.trim().is_empty() || self.entries.last().is_some_and(|last| last == entry) {
+            return;
+        }
+        if self.entries.len() == HISTORY_LIMIT {
+            self.entries.remove(0);
+        }
+        self.entries.push(entry.to_string());
+        if let Some(ref path) = self.path {
+            if let Err(e) = append(path, entry) {
+                warn!("Unable to save history to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn append(path: &Path, entry: &str) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", escape(entry))
+}
+
+/// Entries may span lines, such as a pasted program, so each is kept on one line with its line breaks escaped
+fn escape(entry: &str) -> String {
+    entry.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(line: &str) -> String {
+    let mut entry = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                entry.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                entry.push('\\');
+                chars.next();
+            }
+            _ => entry.push(c),
+        }
+    }
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn test_persistence() {
+        let dir = std::env::temp_dir().join(format!("iridium-history-test-{}", process::id()));
+        let path = dir.join(HISTORY_FILENAME);
+        let mut history = History::load(&path).unwrap();
+        history.push("load $0 #10");
+        history.push("load $0 #10");
+        history.push("   ");
+        history.push(".data\n.code\nhlt\\n");
+        history.push("!registers");
+
+        let history = History::load(&path).unwrap();
+        assert_eq!(history.entries(), &["load $0 #10", ".data\n.code\nhlt\\n", "!registers"]);
+        assert_eq!(history.search("load"), vec!["load $0 #10"]);
+        assert_eq!(history.search("!"), vec!["!registers"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_user_path() {
+        let dir = Path::new("/data/history");
+        assert_eq!(History::user_path(dir, "alice"), dir.join("alice"));
+        assert_eq!(History::user_path(dir, "../alice"), dir.join("=2e2e2f616c696365"));
+        // A safe name that looks like an encoded one still gets a file of its own
+        assert_ne!(History::user_path(dir, "612e62"), History::user_path(dir, "a.b"));
+    }
+}
@@ -0,0 +1,114 @@
+//! Formatting for the REPL commands that show what is in the VM, such as `!mem` and `!ro`
+
+use std::str::FromStr;
+
+/// How many bytes `!mem` shows when not told
+pub const DEFAULT_LENGTH: usize = 64;
+
+/// Bytes on each line of a hexdump
+const LINE_WIDTH: usize = 16;
+
+/// How `!mem` shows memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryFormat {
+    /// Offsets, bytes in hex and the same bytes as ASCII, as `hexdump -C` does
+    Hex,
+    /// Little-endian 32-bit integers, as `LOADM` reads them
+    I32,
+    /// Text, with non-printable bytes escaped
+    Ascii,
+}
+
+impl FromStr for MemoryFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<MemoryFormat, ()> {
+        match s {
+            "hex" => Ok(MemoryFormat::Hex),
+            "i32" => Ok(MemoryFormat::I32),
+            "ascii" => Ok(MemoryFormat::Ascii),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Parses an address or byte, in decimal or, with `0x`, hex
+pub fn parse_number(s: &str) -> Option<usize> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        usize::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+/// Parses a register, written as in assembly such as `$3`
+pub fn parse_register(s: &str) -> Option<usize> {
+    if !s.starts_with('$') {
+        return None;
+    }
+    s[1..].parse().ok().filter(|r| *r < 32)
+}
+
+/// Shows `bytes`, which start at `base`, in `format`
+pub fn format_memory(bytes: &[u8], base: usize, format: MemoryFormat) -> String {
+    match format {
+        MemoryFormat::Hex => hexdump(bytes, base),
+        MemoryFormat::I32 => {
+            let lines: Vec<String> = bytes
+                .chunks(4)
+                .enumerate()
+                .map(|(i, word)| match *word {
+                    [a, b, c, d] => format!("{:08x}  {}", base + i * 4, i32::from_le_bytes([a, b, c, d])),
+                    // Bytes left over after the last whole word aren't a number, so they are shown as they are
+                    _ => {
+                        let hex: Vec<String> = word.iter().map(|b| format!("{:02x}", b)).collect();
+                        format!("{:08x}  {}", base + i * 4, hex.join(" "))
+                    }
+                })
+                .collect();
+            lines.join("\n")
+        }
+        MemoryFormat::Ascii => bytes.iter().flat_map(|b| std::ascii::escape_default(*b)).map(char::from).collect(),
+    }
+}
+
+/// Shows `bytes`, which start at `base`, sixteen to a line with their offset and as ASCII
+pub fn hexdump(bytes: &[u8], base: usize) -> String {
+    let lines: Vec<String> = bytes
+        .chunks(LINE_WIDTH)
+        .enumerate()
+        .map(|(i, line)| {
+            let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = line.iter().map(|b| if b.is_ascii_graphic() || *b == b' ' { *b as char } else { '.' }).collect();
+            format!("{:08x}  {:<47}  |{}|", base + i * LINE_WIDTH, hex.join(" "), ascii)
+        })
+        .collect();
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_number("16"), Some(16));
+        assert_eq!(parse_number("0x10"), Some(16));
+        assert_eq!(parse_number("sixteen"), None);
+        assert_eq!(parse_register("$31"), Some(31));
+        assert_eq!(parse_register("$32"), None);
+        assert_eq!(parse_register("3"), None);
+    }
+
+    #[test]
+    fn test_format_memory() {
+        let bytes = b"Hello, world!\0\x01\x02\x03";
+        assert_eq!(
+            hexdump(bytes, 16),
+            "00000010  48 65 6c 6c 6f 2c 20 77 6f 72 6c 64 21 00 01 02  |Hello, world!...|\n\
+             00000020  03                                               |.|"
+        );
+        assert_eq!(format_memory(&[42, 0, 0, 0, 255, 255], 0, MemoryFormat::I32), "00000000  42\n00000004  ff ff");
+        assert_eq!(format_memory(b"Hi\n\0", 0, MemoryFormat::Ascii), "Hi\\n\\x00");
+    }
+}
@@ -1,35 +1,239 @@
 pub mod command_parser;
+pub mod editor;
+pub mod history;
+pub mod inspect;
 
 use std;
 use std::fs::File;
-use std::io;
 use std::io::Read;
-use std::net::TcpStream;
 use std::num::ParseIntError;
 use std::path::Path;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 
 use nom::types::CompleteStr;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Config, Editor};
 
 use assembler::program_parsers::program;
 use assembler::Assembler;
 use cluster;
-use repl::command_parser::CommandParser;
-use scheduler::Scheduler;
+use console::ChannelSink;
+use policy::{Capability, AUDIT_LOG_TARGET};
+use remote::roles::{Role, EXECUTE};
+use remote::session::{SessionId, SessionRegistry};
+use repl::command_parser::command_errors::CommandError;
+use repl::command_parser::{Args, CommandParser, CommandSpec, Flag};
+use repl::editor::{starts_program, ReplHelper};
+use repl::history::History;
+use repl::inspect::{format_memory, hexdump, parse_number, parse_register, MemoryFormat, DEFAULT_LENGTH};
+use scheduler::{Scheduler, Shutdown};
 use vm::VM;
 const COMMAND_PREFIX: char = '!';
 
 pub static REMOTE_BANNER: &'static str = "Welcome to Iridium! Let's be productive!";
 pub static PROMPT: &'static str = ">>> ";
 
+/// Every command, with the arguments it takes, for parsing them, completion and `!help`
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "!help",
+        args: &["[command]"],
+        flags: &[],
+        summary: "Lists the commands, or shows how to use one",
+    },
+    CommandSpec {
+        name: "!quit",
+        args: &[],
+        flags: &[],
+        summary: "Ends the session, or at the local REPL, the node",
+    },
+    CommandSpec {
+        name: "!history",
+        args: &["[text...]"],
+        flags: &[],
+        summary: "Lists what has been entered, or the entries containing text, newest first",
+    },
+    CommandSpec {
+        name: "!program",
+        args: &[],
+        flags: &[],
+        summary: "Lists the VM's program",
+    },
+    CommandSpec {
+        name: "!clear_program",
+        args: &[],
+        flags: &[],
+        summary: "Empties the VM's program",
+    },
+    CommandSpec {
+        name: "!clear_registers",
+        args: &[],
+        flags: &[],
+        summary: "Sets every register to 0",
+    },
+    CommandSpec {
+        name: "!registers",
+        args: &[],
+        flags: &[],
+        summary: "Lists the registers",
+    },
+    CommandSpec {
+        name: "!symbols",
+        args: &[],
+        flags: &[],
+        summary: "Lists the labels of the last program assembled",
+    },
+    CommandSpec {
+        name: "!fregs",
+        args: &[],
+        flags: &[],
+        summary: "Lists the float registers",
+    },
+    CommandSpec {
+        name: "!state",
+        args: &[],
+        flags: &[],
+        summary: "Shows the program counter, stack and base pointers, flags and the size of each section",
+    },
+    CommandSpec {
+        name: "!mem",
+        args: &["<addr>", "[len]", "[fmt]"],
+        flags: &[],
+        summary: "Shows len bytes of the heap from addr, 64 by default, as hex, i32 or ascii",
+    },
+    CommandSpec {
+        name: "!stack",
+        args: &[],
+        flags: &[],
+        summary: "Lists the stack, from the bottom",
+    },
+    CommandSpec {
+        name: "!ro",
+        args: &[],
+        flags: &[],
+        summary: "Shows the read-only section of the program",
+    },
+    CommandSpec {
+        name: "!set",
+        args: &["<register>", "<value>"],
+        flags: &[Flag {
+            name: "--float",
+            value: None,
+            help: "Sets a float register instead",
+        }],
+        summary: "Sets a register",
+    },
+    CommandSpec {
+        name: "!poke",
+        args: &["<addr>", "<byte...>"],
+        flags: &[],
+        summary: "Writes bytes to the heap from addr",
+    },
+    CommandSpec {
+        name: "!load_file",
+        args: &["<path>"],
+        flags: &[],
+        summary: "Assembles the program in a file on the node and runs it in the REPL's VM",
+    },
+    CommandSpec {
+        name: "!spawn",
+        args: &["<path>"],
+        flags: &[Flag {
+            name: "--threads",
+            value: Some("n"),
+            help: "How many threads the program may use, by default one per core",
+        }],
+        summary: "Assembles the program in a file on the node and runs it in the background",
+    },
+    CommandSpec {
+        name: "!submit",
+        args: &[],
+        flags: &[],
+        summary: "Runs the program entered on the following lines, up to !end",
+    },
+    CommandSpec {
+        name: "!start_cluster",
+        args: &[],
+        flags: &[],
+        summary: "Listens for other nodes joining the cluster",
+    },
+    CommandSpec {
+        name: "!join_cluster",
+        args: &["<host>", "<port>"],
+        flags: &[],
+        summary: "Joins the cluster of the node at host and port",
+    },
+    CommandSpec {
+        name: "!leave_cluster",
+        args: &[],
+        flags: &[],
+        summary: "Leaves the cluster, telling the other nodes",
+    },
+    CommandSpec {
+        name: "!cluster_members",
+        args: &[],
+        flags: &[],
+        summary: "Lists the other nodes in the cluster and whether each is alive or suspected of having failed",
+    },
+    CommandSpec {
+        name: "!cluster_events",
+        args: &[],
+        flags: &[],
+        summary: "Lists the recent changes to the cluster's membership",
+    },
+    CommandSpec {
+        name: "!sessions",
+        args: &[],
+        flags: &[],
+        summary: "Lists your remote access sessions",
+    },
+    CommandSpec {
+        name: "!attach",
+        args: &["<session>"],
+        flags: &[],
+        summary: "Switches to another of your remote access sessions",
+    },
+];
+
+/// Tells the user `value` isn't a valid `name`
+fn invalid(name: &str, value: Option<&str>) -> String {
+    CommandError::InvalidValue {
+        name: name.to_string(),
+        value: value.unwrap_or_default().to_string(),
+    }
+    .to_string()
+}
+
+/// The command called `name`
+pub fn command(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
 /// Core structure for the REPL for the Assembler
 #[derive(Default)]
 pub struct REPL {
-    command_buffer: Vec<String>,
+    /// What has been entered, which may be kept in a file
+    history: History,
     vm: VM,
     asm: Assembler,
     scheduler: Scheduler,
+    /// Lines of a program being submitted with `!submit`, until `!end`
+    submission: Option<Vec<String>>,
+    /// Lines of a program with sections being entered a line at a time, until a blank line
+    program_lines: Option<Vec<String>>,
+    /// Whether this is a remote user's session, which `!quit` ends instead of the whole process
+    remote: bool,
+    /// Set once a remote user has quit
+    quit: bool,
+    /// The remote user this session belongs to and their role, which limits the commands they can run.
+    /// Without one, every command is allowed.
+    user: Option<(String, Role)>,
+    /// The remote access session this REPL belongs to, and the registry listing the others
+    session: Option<(SessionRegistry, SessionId)>,
+    /// A session the user asked to switch to with `!attach`
+    attach_request: Option<SessionId>,
     pub tx_pipe: Option<Box<Sender<String>>>,
     pub rx_pipe: Option<Box<Receiver<String>>>,
 }
@@ -38,57 +242,126 @@ impl REPL {
     /// Creates and returns a new assembly REPL
     pub fn new(vm: VM) -> REPL {
         let (tx, rx): (Sender<String>, Receiver<String>) = mpsc::channel();
+        // Whatever programs print goes to the same place as the REPL's own messages, which for a remote
+        // session is the connected user rather than the server's terminal
+        let console = vm.console().clone().with_output(ChannelSink::new(tx.clone()));
+        let vm = vm.with_console(console);
         REPL {
             vm,
-            command_buffer: vec![],
+            history: History::new(),
             asm: Assembler::new(),
             scheduler: Scheduler::new(),
+            submission: None,
+            program_lines: None,
+            remote: false,
+            quit: false,
+            user: None,
+            session: None,
+            attach_request: None,
             tx_pipe: Some(Box::new(tx)),
             rx_pipe: Some(Box::new(rx)),
         }
     }
 
+    /// Lets `shutdown` stop the programs run from this REPL
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.vm = self.vm.with_interrupt(shutdown.interrupt());
+        self.scheduler = self.scheduler.with_shutdown(shutdown);
+        self
+    }
+
+    /// Keeps what is entered in `history`, and adds to it what was entered before
+    pub fn with_history(mut self, history: History) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Replaces the history, such as with the user's own once they have logged in
+    pub fn set_history(&mut self, history: History) {
+        self.history = history;
+    }
+
+    /// Makes `!quit` end only this session rather than the process, for remote users
+    pub fn for_remote_session(mut self) -> Self {
+        self.remote = true;
+        self
+    }
+
+    /// Limits the session to the commands `role` allows, once `username` has logged in
+    pub fn set_user(&mut self, username: String, role: Role) {
+        self.user = Some((username, role));
+    }
+
+    /// Makes this REPL the one for session `id` in `registry`
+    pub fn set_session(&mut self, registry: SessionRegistry, id: SessionId) {
+        self.session = Some((registry, id));
+    }
+
+    /// Takes the session the user asked to switch to with `!attach`, if they did
+    pub fn take_attach_request(&mut self) -> Option<SessionId> {
+        self.attach_request.take()
+    }
+
+    /// Whether a remote user has quit, and their connection should be closed
+    pub fn has_quit(&self) -> bool {
+        self.quit
+    }
+
     /// Run loop similar to the VM execution loop, but the instructions are taken from the user directly
-    /// at the terminal and not from pre-compiled bytecode
+    /// at the terminal and not from pre-compiled bytecode. Lines can be edited, and completed with Tab, and
+    /// the history is searched with the arrow keys and Ctrl-R.
     pub fn run(&mut self) {
-        debug!("Starting REPL run loop with VM ID of {:?}", self.vm.alias);
+        debug!("Starting REPL run loop with VM ID of {:?}", self.vm.alias());
         self.send_message(REMOTE_BANNER.to_string());
-        self.send_prompt();
+        let config = Config::builder().auto_add_history(true).build();
+        let mut editor: Editor<ReplHelper, DefaultHistory> = match Editor::with_config(config) {
+            Ok(editor) => editor,
+            Err(e) => {
+                error!("Unable to set up line editing: {}", e);
+                std::process::exit(1);
+            }
+        };
+        editor.set_helper(Some(ReplHelper::new()));
+        for entry in self.history.entries() {
+            let _ = editor.add_history_entry(entry.as_str());
+        }
         loop {
-            // This allocates a new String in which to store whatever the user types each iteration.
-            // TODO: Figure out how allocate this outside of the loop and re-use it every iteration
-            let mut buffer = String::new();
-
-            // Blocking call until the user types in a command
-            let stdin = io::stdin();
-
-            // Here we'll look at the string the user gave us.
-            stdin.read_line(&mut buffer).expect("Unable to read line from user");
-
-            let historical_copy = buffer.clone();
-            self.command_buffer.push(historical_copy);
-
-            if buffer.starts_with(COMMAND_PREFIX) {
-                self.execute_command(&buffer);
-            } else {
-                let program = match program(CompleteStr(&buffer)) {
-                    Ok((_remainder, program)) => program,
-                    Err(e) => {
-                        self.send_message(format!("There was an error executing the program: {:?}", e));
-                        continue;
-                    }
-                };
-                self.vm.program.append(&mut program.to_bytes(&self.asm.symbols));
-                self.vm.run_once();
+            if let Some(helper) = editor.helper_mut() {
+                helper.set_labels(self.asm.symbols.symbols.iter().map(|s| s.name().to_string()).collect());
+            }
+            match editor.readline(PROMPT) {
+                Ok(line) => {
+                    self.run_single(&line);
+                }
+                // Ctrl-C abandons the line being edited
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => self.quit(&Args::default()),
+                Err(e) => {
+                    error!("Unable to read line from user: {}", e);
+                    std::process::exit(1);
+                }
             }
         }
     }
 
     pub fn run_single(&mut self, buffer: &str) -> Option<String> {
+        self.history.push(buffer);
+        if self.collect_submission(buffer) || self.collect_program_line(buffer) {
+            return None;
+        }
+        if starts_program(buffer) {
+            if self.permitted(EXECUTE) {
+                self.run_program(buffer, None);
+            }
+            return None;
+        }
         if buffer.starts_with(COMMAND_PREFIX) {
             self.execute_command(&buffer);
             None
         } else {
+            if !self.permitted(EXECUTE) {
+                return None;
+            }
             let program = match program(CompleteStr(&buffer)) {
                 Ok((_remainder, program)) => Some(program),
                 Err(e) => {
@@ -98,8 +371,7 @@ impl REPL {
             };
             match program {
                 Some(p) => {
-                    let mut bytes = p.to_bytes(&self.asm.symbols);
-                    self.vm.program.append(&mut bytes);
+                    self.vm.add_bytes(p.to_bytes(&self.asm.symbols));
                     self.vm.run_once();
                     None
                 }
@@ -129,33 +401,6 @@ impl REPL {
         }
     }
 
-    fn get_data_from_load(&mut self) -> Option<String> {
-        let stdin = io::stdin();
-        self.send_message("Please enter the path to the file you wish to load: ".to_string());
-        let mut tmp = String::new();
-
-        stdin.read_line(&mut tmp).expect("Unable to read line from user");
-        self.send_message("Attempting to load program from file...".to_string());
-
-        let tmp = tmp.trim();
-        let filename = Path::new(&tmp);
-        let mut f = match File::open(&filename) {
-            Ok(f) => f,
-            Err(e) => {
-                self.send_message(format!("There was an error opening that file: {:?}", e));
-                return None;
-            }
-        };
-        let mut contents = String::new();
-        match f.read_to_string(&mut contents) {
-            Ok(_bytes_read) => Some(contents),
-            Err(e) => {
-                self.send_message(format!("there was an error reading that file: {:?}", e));
-                None
-            }
-        }
-    }
-
     /// Accepts a hexadecimal string WITHOUT a leading `0x` and returns a Vec of u8
     /// Example for a LOAD command: 00 01 03 E8
     #[allow(dead_code)]
@@ -177,72 +422,150 @@ impl REPL {
     }
 
     fn execute_command(&mut self, input: &str) {
-        let args = CommandParser::tokenize(input);
-        match args[0] {
-            "!quit" => self.quit(&args[1..]),
-            "!history" => self.history(&args[1..]),
-            "!program" => self.program(&args[1..]),
-            "!clear_program" => self.clear_program(&args[1..]),
-            "!clear_registers" => self.clear_registers(&args[1..]),
-            "!registers" => self.registers(&args[1..]),
-            "!symbols" => self.symbols(&args[1..]),
-            "!load_file" => self.load_file(&args[1..]),
-            "!spawn" => self.spawn(&args[1..]),
-            "!start_cluster" => self.start_cluster(&args[1..]),
-            "!join_cluster" => self.join_cluster(&args[1..]),
-            "!cluster_members" => self.cluster_members(&args[1..]),
+        let tokens = match CommandParser::tokenize(input) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                self.send_message(e.to_string());
+                return;
+            }
+        };
+        let spec = match tokens.first().and_then(|name| command(name)) {
+            Some(spec) => spec,
+            None => {
+                self.send_message("Invalid command!".to_string());
+                return;
+            }
+        };
+        if !self.permitted(spec.name) {
+            return;
+        }
+        let args = match spec.parse(&tokens[1..]) {
+            Ok(args) => args,
+            Err(e) => {
+                self.send_message(format!("{}\nUsage: {}", e, spec.usage()));
+                return;
+            }
+        };
+        match spec.name {
+            "!help" => self.help(&args),
+            "!quit" => self.quit(&args),
+            "!history" => self.history(&args),
+            "!program" => self.program(&args),
+            "!clear_program" => self.clear_program(&args),
+            "!clear_registers" => self.clear_registers(&args),
+            "!registers" => self.registers(&args),
+            "!symbols" => self.symbols(&args),
+            "!fregs" => self.fregs(&args),
+            "!state" => self.state(&args),
+            "!mem" => self.mem(&args),
+            "!stack" => self.stack(&args),
+            "!ro" => self.ro(&args),
+            "!set" => self.set(&args),
+            "!poke" => self.poke(&args),
+            "!load_file" => self.load_file(&args),
+            "!spawn" => self.spawn(&args),
+            "!submit" => self.submit(&args),
+            "!start_cluster" => self.start_cluster(&args),
+            "!join_cluster" => self.join_cluster(&args),
+            "!leave_cluster" => self.leave_cluster(&args),
+            "!cluster_members" => self.cluster_members(&args),
+            "!cluster_events" => self.cluster_events(&args),
+            "!sessions" => self.sessions(&args),
+            "!attach" => self.attach(&args),
             _ => {
                 self.send_message("Invalid command!".to_string());
             }
         };
     }
 
-    fn quit(&mut self, _args: &[&str]) {
-        self.send_message("Farewell! Have a great day!".to_string());
-        std::process::exit(0);
+    /// Checks the user's role allows `command`, telling them and recording it in the audit log if it doesn't
+    fn permitted(&mut self, command: &str) -> bool {
+        let denied = match self.user {
+            Some((ref username, ref role)) if !role.allows(command) => {
+                warn!(target: AUDIT_LOG_TARGET, "{} (role {}) was denied {}", username, role.name(), command);
+                format!("Your role, {}, doesn't allow {}", role.name(), command)
+            }
+            _ => return true,
+        };
+        self.send_message(denied);
+        false
     }
 
-    fn history(&mut self, _args: &[&str]) {
-        let mut results = vec![];
-        for command in &self.command_buffer {
-            results.push(command.clone());
+    /// Lists every command, or with an argument, shows how to use that one
+    fn help(&mut self, args: &Args) {
+        let message = match args.get(0) {
+            Some(name) => {
+                let name = if name.starts_with(COMMAND_PREFIX) {
+                    name.to_string()
+                } else {
+                    format!("{}{}", COMMAND_PREFIX, name)
+                };
+                match command(&name) {
+                    Some(spec) => spec.help(),
+                    None => CommandError::UnknownCommand { command: name }.to_string(),
+                }
+            }
+            None => {
+                let width = COMMANDS.iter().map(|c| c.usage().len()).max().unwrap_or(0);
+                let lines: Vec<String> = COMMANDS.iter().map(|c| format!("{:width$}  {}", c.usage(), c.summary, width = width)).collect();
+                lines.join("\n")
+            }
+        };
+        self.send_message(message);
+    }
+
+    fn quit(&mut self, _args: &Args) {
+        self.send_message("Farewell! Have a great day!".to_string());
+        if self.remote {
+            self.quit = true;
+        } else {
+            self.vm.leave_cluster();
+            std::process::exit(0);
         }
+    }
+
+    /// Lists what has been entered, or with an argument, the entries containing it, newest first
+    fn history(&mut self, args: &Args) {
+        let results: Vec<String> = match args.rest(0) {
+            Some(text) => self.history.search(&text).into_iter().map(|e| e.to_string()).collect(),
+            None => self.history.entries().to_vec(),
+        };
         self.send_message(format!("{:#?}", results));
     }
 
-    fn program(&mut self, _args: &[&str]) {
+    fn program(&mut self, _args: &Args) {
         self.send_message("Listing instructions currently in VM's program vector: ".to_string());
         let mut results = vec![];
-        for instruction in &self.vm.program {
+        for instruction in self.vm.program() {
             results.push(instruction.clone())
         }
         self.send_message(format!("{:#?}", results));
         self.send_message("End of Program Listing".to_string());
     }
 
-    fn clear_program(&mut self, _args: &[&str]) {
-        self.vm.program.clear();
+    fn clear_program(&mut self, _args: &Args) {
+        self.vm.clear_program();
     }
 
-    fn clear_registers(&mut self, _args: &[&str]) {
+    fn clear_registers(&mut self, _args: &Args) {
         self.send_message("Setting all registers to 0".to_string());
-        for i in 0..self.vm.registers.len() {
-            self.vm.registers[i] = 0;
+        for register in self.vm.registers_mut().iter_mut() {
+            *register = 0;
         }
         self.send_message("Done!".to_string());
     }
 
-    fn registers(&mut self, _args: &[&str]) {
+    fn registers(&mut self, _args: &Args) {
         self.send_message("Listing registers and all contents:".to_string());
         let mut results = vec![];
-        for register in &self.vm.registers {
+        for register in self.vm.registers() {
             results.push(register.clone());
         }
         self.send_message(format!("{:#?}", results));
         self.send_message("End of Register Listing".to_string());
     }
 
-    fn symbols(&mut self, _args: &[&str]) {
+    fn symbols(&mut self, _args: &Args) {
         let mut results = vec![];
         for symbol in &self.asm.symbols.symbols {
             results.push(symbol.clone());
@@ -252,80 +575,421 @@ impl REPL {
         self.send_message("End of Symbols Listing".to_string());
     }
 
-    fn load_file(&mut self, _args: &[&str]) {
-        let contents = self.get_data_from_load();
-        if let Some(contents) = contents {
-            match self.asm.assemble(&contents) {
-                Ok(mut assembled_program) => {
-                    self.send_message("Sending assembled program to VM".to_string());
-                    self.vm.program.append(&mut assembled_program);
-                    self.vm.run();
+    fn fregs(&mut self, _args: &Args) {
+        self.send_message("Listing float registers and all contents:".to_string());
+        let results = self.vm.float_registers().to_vec();
+        self.send_message(format!("{:#?}", results));
+        self.send_message("End of Float Register Listing".to_string());
+    }
+
+    fn state(&mut self, _args: &Args) {
+        let lines = [
+            format!("pc: {}", self.vm.pc()),
+            format!("sp: {}", self.vm.sp()),
+            format!("bp: {}", self.vm.bp()),
+            format!("equal_flag: {}", self.vm.equal_flag()),
+            format!("remainder: {}", self.vm.remainder()),
+            format!("loop_counter: {}", self.vm.loop_counter()),
+            format!("program: {} bytes", self.vm.program().len()),
+            format!("ro: {} bytes", self.vm.ro_data().len()),
+            format!("heap: {} bytes", self.vm.heap().len()),
+            format!("stack: {} entries", self.vm.stack().len()),
+        ];
+        self.send_message(lines.join("\n"));
+    }
+
+    /// Shows part of the heap. The range is cut short at the end of the heap.
+    fn mem(&mut self, args: &Args) {
+        let addr = match args.get(0).map(parse_number) {
+            Some(Some(addr)) => addr,
+            _ => return self.send_message(invalid("address", args.get(0))),
+        };
+        let len = match args.get(1).map(parse_number) {
+            Some(Some(len)) => len,
+            Some(None) => return self.send_message(invalid("length", args.get(1))),
+            None => DEFAULT_LENGTH,
+        };
+        let format = match args.parse::<MemoryFormat>(2, "format (hex, i32 or ascii)") {
+            Ok(format) => format.unwrap_or(MemoryFormat::Hex),
+            Err(e) => return self.send_message(e.to_string()),
+        };
+        let heap = self.vm.heap();
+        if addr >= heap.len() {
+            let message = format!("The heap is {} bytes", heap.len());
+            return self.send_message(message);
+        }
+        let end = heap.len().min(addr.saturating_add(len));
+        let dump = format_memory(&heap[addr..end], addr, format);
+        self.send_message(dump);
+    }
+
+    /// Lists the stack, marking where the base pointer is, and then gives both pointers
+    fn stack(&mut self, _args: &Args) {
+        let (sp, bp) = (self.vm.sp(), self.vm.bp());
+        let mut lines: Vec<String> = self
+            .vm
+            .stack()
+            .iter()
+            .enumerate()
+            .map(|(i, value)| {
+                let mut line = format!("{:>4}: {}", i, value);
+                if i == bp {
+                    line.push_str("  <- bp");
                 }
-                Err(errors) => {
-                    for error in errors {
-                        self.send_message(format!("Unable to parse input: {}", error));
-                    }
-                    return;
+                line
+            })
+            .collect();
+        lines.push(format!("sp: {}, bp: {}", sp, bp));
+        self.send_message(lines.join("\n"));
+    }
+
+    fn ro(&mut self, _args: &Args) {
+        let dump = if self.vm.ro_data().is_empty() {
+            "The read-only section is empty".to_string()
+        } else {
+            hexdump(self.vm.ro_data(), 0)
+        };
+        self.send_message(dump);
+    }
+
+    fn set(&mut self, args: &Args) {
+        let register = match args.get(0).and_then(parse_register) {
+            Some(register) => register,
+            None => return self.send_message(invalid("register ($0 to $31)", args.get(0))),
+        };
+        let result = if args.has_flag("--float") {
+            args.parse::<f64>(1, "float").map(|value| {
+                self.vm.float_registers_mut()[register] = value.unwrap_or_default();
+            })
+        } else {
+            args.parse::<i32>(1, "integer").map(|value| {
+                self.vm.registers_mut()[register] = value.unwrap_or_default();
+            })
+        };
+        if let Err(e) = result {
+            self.send_message(e.to_string());
+        }
+    }
+
+    /// Writes bytes to the heap, which has to be big enough for them already
+    fn poke(&mut self, args: &Args) {
+        let addr = match args.get(0).map(parse_number) {
+            Some(Some(addr)) => addr,
+            _ => return self.send_message(invalid("address", args.get(0))),
+        };
+        let mut bytes = vec![];
+        for i in 1.. {
+            match args.get(i).map(|b| parse_number(b).filter(|b| *b <= 0xff)) {
+                Some(Some(byte)) => bytes.push(byte as u8),
+                Some(None) => return self.send_message(invalid("byte", args.get(i))),
+                None => break,
+            }
+        }
+        let heap = self.vm.heap_mut();
+        if addr.saturating_add(bytes.len()) > heap.len() {
+            let message = format!("The heap is {} bytes", heap.len());
+            return self.send_message(message);
+        }
+        heap[addr..addr + bytes.len()].copy_from_slice(&bytes);
+    }
+
+    /// Checks the VM's policy allows `capability`, telling the user if it doesn't
+    fn allowed(&mut self, capability: Capability) -> bool {
+        match self.vm.check_capability(capability) {
+            Ok(()) => true,
+            Err(e) => {
+                self.send_message(e.to_string());
+                false
+            }
+        }
+    }
+
+    /// Reads the program in the file at `path`, on the node rather than the user's machine
+    fn read_program(&mut self, path: &str) -> Option<String> {
+        self.send_message(format!("Attempting to load program from {}...", path));
+        let mut f = match File::open(Path::new(path)) {
+            Ok(f) => f,
+            Err(e) => {
+                self.send_message(format!("There was an error opening that file: {}", e));
+                return None;
+            }
+        };
+        let mut contents = String::new();
+        match f.read_to_string(&mut contents) {
+            Ok(_bytes_read) => Some(contents),
+            Err(e) => {
+                self.send_message(format!("There was an error reading that file: {}", e));
+                None
+            }
+        }
+    }
+
+    fn load_file(&mut self, args: &Args) {
+        if !self.allowed(Capability::Spawn) {
+            return;
+        }
+        let contents = match args.get(0).and_then(|path| self.read_program(path)) {
+            Some(contents) => contents,
+            None => return,
+        };
+        match self.asm.assemble(&contents) {
+            Ok(assembled_program) => {
+                self.send_message("Sending assembled program to VM".to_string());
+                self.vm.add_bytes(assembled_program);
+                self.vm.run();
+            }
+            Err(errors) => {
+                for error in errors {
+                    self.send_message(format!("Unable to parse input: {}", error));
                 }
             }
-        } else {
+        }
+    }
+
+    fn spawn(&mut self, args: &Args) {
+        if !self.allowed(Capability::Spawn) {
             return;
         }
+        let threads = match args.flag::<usize>("--threads") {
+            Ok(Some(0)) => {
+                self.send_message("--threads must be at least 1".to_string());
+                return;
+            }
+            Ok(threads) => threads,
+            Err(e) => {
+                self.send_message(e.to_string());
+                return;
+            }
+        };
+        if let Some(contents) = args.get(0).and_then(|path| self.read_program(path)) {
+            self.run_program(&contents, threads);
+        }
     }
 
-    fn spawn(&mut self, _args: &[&str]) {
-        let contents = self.get_data_from_load();
-        self.send_message(format!("Loaded contents: {:#?}", contents));
-        if let Some(contents) = contents {
-            match self.asm.assemble(&contents) {
-                Ok(mut assembled_program) => {
-                    self.send_message("Sending assembled program to VM".to_string());
-                    self.vm.program.append(&mut assembled_program);
-                    self.scheduler.get_thread(self.vm.clone());
+    /// Starts collecting the lines of a program, for users who can't load one from a file such as those
+    /// connected through remote access. `!end` runs it.
+    fn submit(&mut self, _args: &Args) {
+        self.submission = Some(vec![]);
+        self.send_message("Enter the program, then !end on a line of its own".to_string());
+    }
+
+    /// If a program is being submitted, adds `line` to it, or runs it if the line is `!end`. Returns false if
+    /// no program is being submitted.
+    fn collect_submission(&mut self, line: &str) -> bool {
+        let mut lines = match self.submission.take() {
+            Some(lines) => lines,
+            None => return false,
+        };
+        if line.trim() != "!end" {
+            lines.push(line.to_string());
+            self.submission = Some(lines);
+            return true;
+        }
+        if self.permitted(EXECUTE) {
+            self.run_program(&lines.join("\n"), None);
+        }
+        true
+    }
+
+    /// Collects the lines of a program with sections entered a line at a time, as over remote access, and
+    /// runs it at the first blank line. Returns false if no such program is being entered.
+    fn collect_program_line(&mut self, line: &str) -> bool {
+        let mut lines = match self.program_lines.take() {
+            Some(lines) => lines,
+            // One entered all at once, as at the local REPL, is run by `run_single` instead
+            None if starts_program(line) && !line.contains('\n') => {
+                self.program_lines = Some(vec![line.to_string()]);
+                return true;
+            }
+            None => return false,
+        };
+        if !line.trim().is_empty() {
+            lines.push(line.to_string());
+            self.program_lines = Some(lines);
+            return true;
+        }
+        if self.permitted(EXECUTE) {
+            self.run_program(&lines.join("\n"), None);
+        }
+        true
+    }
+
+    /// Assembles a whole program and runs it in the background in a VM of its own, under the same policy and
+    /// printing to the same place, and with `threads` threads if given. Its labels are kept, for instructions
+    /// entered afterwards and `!symbols`.
+    fn run_program(&mut self, source: &str, threads: Option<usize>) {
+        let mut asm = Assembler::new();
+        match asm.assemble(source) {
+            Ok(program) => {
+                let mut vm = VM::new()
+                    .with_policy(self.vm.policy().clone())
+                    .with_console(self.vm.console().clone())
+                    .with_connection_manager(self.vm.connection_manager());
+                if let Some(threads) = threads {
+                    vm = vm.with_logical_cores(threads);
                 }
-                Err(errors) => {
-                    for error in errors {
-                        self.send_message(format!("Unable to parse input: {}", error));
-                    }
-                    return;
+                vm.add_bytes(program);
+                self.send_message(format!("Running program in VM {}", vm.id()));
+                self.scheduler.get_thread(vm);
+                self.asm = asm;
+            }
+            Err(errors) => {
+                for error in errors {
+                    self.send_message(format!("Unable to parse input: {}", error));
                 }
             }
-        } else {
-            return;
         }
     }
 
-    fn start_cluster(&mut self, _args: &[&str]) {
-        self.send_message("Started cluster server!".to_string());
-        self.vm.bind_cluster_server();
+    fn start_cluster(&mut self, _args: &Args) {
+        if !self.allowed(Capability::Cluster) {
+            return;
+        }
+        match self.vm.bind_cluster_server() {
+            Ok(()) => self.send_message("Started cluster server!".to_string()),
+            Err(e) => self.send_message(format!("Unable to start cluster server: {}", e)),
+        }
     }
 
-    fn join_cluster(&mut self, args: &[&str]) {
-        debug!("Joining cluster with VM ID: {:?}", self.vm.alias);
-        self.send_message("Attempting to join cluster...".to_string());
-        let ip = args[0];
-        let port = args[1];
-        let addr = ip.to_owned() + ":" + port.clone();
-        if let Ok(stream) = TcpStream::connect(addr) {
-            self.send_message("Connected to cluster!".to_string());
-            let mut cc = cluster::client::ClusterClient::new(stream, self.vm.connection_manager.clone(), self.vm.server_port.clone().unwrap())
-                .with_alias(self.vm.alias.clone().unwrap());
-            debug!("CC Hello is: {:#?}", cc);
-            cc.send_hello();
-            if let Some(ref a) = self.vm.alias {
-                if let Ok(mut lock) = self.vm.connection_manager.write() {
-                    let client_tuple = (a.to_string(), cc.ip_as_string().unwrap(), cc.port_as_string().unwrap());
-                    lock.add_client(client_tuple, cc);
-                }
+    /// Joins the cluster of the node at the host and port given, listening for other nodes first if need be
+    fn join_cluster(&mut self, args: &Args) {
+        if !self.allowed(Capability::Cluster) {
+            return;
+        }
+        debug!("Joining cluster with VM ID: {:?}", self.vm.alias());
+        let port = match args.parse::<u16>(1, "port") {
+            Ok(Some(port)) => port,
+            Ok(None) => return,
+            Err(e) => {
+                self.send_message(e.to_string());
+                return;
             }
-        } else {
-            self.send_message("Could not connect to cluster!".to_string());
+        };
+        if let Err(e) = self.vm.bind_cluster_server() {
+            self.send_message(format!("Unable to start cluster server: {}", e));
+            return;
+        }
+        self.send_message("Attempting to join cluster...".to_string());
+        let addr = format!("{}:{}", args.get(0).unwrap_or_default(), port);
+        match cluster::gossip::join(&addr, &self.vm.connection_manager(), self.vm.cluster_tls()) {
+            Ok(members) => self.send_message(format!("Joined cluster! Other nodes: {}", members)),
+            Err(e) => self.send_message(format!("Could not join cluster: {}", e)),
+        }
+    }
+
+    fn leave_cluster(&mut self, _args: &Args) {
+        if !self.allowed(Capability::Cluster) {
+            return;
+        }
+        if !self.vm.connection_manager().read().unwrap().is_member() {
+            self.send_message("Not in a cluster".to_string());
+            return;
         }
+        self.vm.leave_cluster();
+        self.send_message("Left cluster".to_string());
     }
 
-    fn cluster_members(&mut self, _args: &[&str]) {
+    /// Lists the other members of the cluster. Only changing the membership needs `Capability::Cluster`, so
+    /// roles alone decide who may look at it.
+    fn cluster_members(&mut self, _args: &Args) {
         self.send_message("Listing Known Nodes:".to_string());
-        let cluster_members = self.vm.connection_manager.read().unwrap().get_client_names();
-        self.send_message(format!("{:#?}", cluster_members));
+        let members = self.vm.connection_manager().read().unwrap().members();
+        let lines: Vec<String> = members
+            .iter()
+            .map(|m| format!("{} {} {} (incarnation {})", m.alias(), m.addr(), m.state, m.incarnation))
+            .collect();
+        self.send_message(lines.join("\n"));
+    }
+
+    /// Lists the recent changes to the cluster's membership, oldest first
+    fn cluster_events(&mut self, _args: &Args) {
+        let events = self.vm.connection_manager().read().unwrap().events();
+        let lines: Vec<String> = events
+            .iter()
+            .map(|e| format!("{} {} {} ({}:{})", e.at.to_rfc3339(), e.change, e.node.0, e.node.1, e.node.2))
+            .collect();
+        self.send_message(lines.join("\n"));
+    }
+
+    /// Lists the user's remote access sessions, marking this one with `*`
+    fn sessions(&mut self, _args: &Args) {
+        let (registry, current) = match self.session {
+            Some((ref registry, id)) => (registry.clone(), id),
+            None => {
+                self.send_message("Sessions are only kept for remote access".to_string());
+                return;
+            }
+        };
+        let user = self.user.as_ref().map(|(username, _)| username.as_str());
+        let mut results = vec![];
+        for session in registry.list(user) {
+            let marker = if session.id() == current { "*" } else { " " };
+            let state = match session.detached_for() {
+                Some(detached) => format!("detached for {}s", detached.as_secs()),
+                None => "attached".to_string(),
+            };
+            results.push(format!("{}{} {}", marker, session.id(), state));
+        }
+        self.send_message(results.join("\n"));
+    }
+
+    /// Switches to another of the user's sessions, such as one left behind by a dropped connection
+    fn attach(&mut self, args: &Args) {
+        if self.session.is_none() {
+            self.send_message("Sessions are only kept for remote access".to_string());
+            return;
+        }
+        match args.parse::<SessionId>(0, "session") {
+            Ok(Some(id)) => self.attach_request = Some(id),
+            Ok(None) => {}
+            Err(e) => self.send_message(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use policy::Policy;
+
+    /// A remote session for a user with a role allowing `commands`, under the policy remote access defaults to
+    fn remote_repl(commands: &[&str]) -> REPL {
+        let mut repl = REPL::new(VM::new().with_policy(Policy::restrictive())).for_remote_session();
+        repl.set_user("bob".to_string(), Role::new("limited", commands));
+        repl
+    }
+
+    fn messages(repl: &REPL) -> Vec<String> {
+        repl.rx_pipe.as_ref().unwrap().try_iter().collect()
+    }
+
+    #[test]
+    fn test_role_allows_cluster_inspection() {
+        let mut repl = remote_repl(&["!cluster_members", "!cluster_events", "!start_cluster"]);
+        repl.run_single("!cluster_members");
+        assert_eq!(messages(&repl), vec!["Listing Known Nodes:".to_string(), String::new()]);
+        repl.run_single("!cluster_events");
+        assert_eq!(messages(&repl), vec![String::new()]);
+        // Changing the membership still needs the policy to allow it
+        repl.run_single("!start_cluster");
+        assert_eq!(messages(&repl), vec!["The program's policy does not allow cluster access".to_string()]);
+    }
+
+    #[test]
+    fn test_submit_needs_execute() {
+        let mut repl = remote_repl(&["!submit"]);
+        for line in &["!submit", ".data", ".code", "hlt", "!end"] {
+            repl.run_single(line);
+        }
+        let sent = messages(&repl);
+        assert_eq!(sent.last().unwrap(), "Your role, limited, doesn't allow execute");
+        assert!(!sent.iter().any(|m| m.starts_with("Running program")));
+
+        let mut repl = remote_repl(&["!submit", EXECUTE]);
+        for line in &["!submit", ".data", ".code", "hlt", "!end"] {
+            repl.run_single(line);
+        }
+        let sent = messages(&repl);
+        assert!(sent.last().unwrap().starts_with("Running program"));
     }
 }
@@ -0,0 +1,541 @@
+//! A JSON-RPC 2.0 control protocol, for tooling that drives a node programmatically rather than through the REPL.
+//! Requests and responses are one JSON object per line, over TCP or a Unix socket. Each connection has its own
+//! VM, like a remote access session, and users log in and are limited by their role in the same way.
+
+pub mod rpc_errors;
+pub mod server;
+
+use std::io::{self, BufRead, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, RwLock};
+
+use serde_json::{self, Value};
+
+use assembler::Assembler;
+use cluster::manager::Manager;
+use console::{Console, MemorySink, MemorySource};
+use policy::{Policy, AUDIT_LOG_TARGET};
+use remote::auth::Authenticator;
+use remote::roles::{Role, RoleConfig, EXECUTE};
+use rpc::rpc_errors::RpcError;
+use scheduler::{ProcessState, ProcessTable, Scheduler, Shutdown};
+use vm::VM;
+
+/// How many instructions `run` executes unless given `max_instructions`, and the most `run` or `step` will
+/// execute in one request, so a program that never stops can't tie up the connection. They can be called again
+/// to carry on.
+pub const DEFAULT_MAX_INSTRUCTIONS: u64 = 1_000_000;
+
+/// The REPL command a method corresponds to, which is what roles list. `processes` has no command of its
+/// own, so roles list it as `!processes`.
+fn command_for(method: &str) -> Option<&'static str> {
+    match method {
+        "assemble" | "load" | "run" | "step" => Some(EXECUTE),
+        "spawn" => Some("!spawn"),
+        "registers" => Some("!registers"),
+        "memory" => Some("!mem"),
+        "symbols" => Some("!symbols"),
+        "processes" => Some("!processes"),
+        "cluster_members" => Some("!cluster_members"),
+        _ => None,
+    }
+}
+
+/// Handles the requests of one connection
+pub struct Handler {
+    /// Runs the program given to `load`
+    vm: VM,
+    /// Whether the VM has a program that hasn't stopped yet
+    loaded: bool,
+    /// What the loaded program has printed since the last `run` or `step`
+    output: MemorySink,
+    /// Holds the symbols of the loaded program
+    asm: Assembler,
+    policy: Policy,
+    shutdown: Shutdown,
+    processes: ProcessTable,
+    authenticator: Option<Authenticator>,
+    roles: RoleConfig,
+    /// Who has logged in, and their role
+    user: Option<(String, Role)>,
+    /// The address the client connected from, which failed attempts to log in are counted against
+    peer: IpAddr,
+    /// The node's cluster membership, if not the VM's own
+    cluster: Option<Arc<RwLock<Manager>>>,
+}
+
+impl Default for Handler {
+    fn default() -> Handler {
+        Handler::new()
+    }
+}
+
+impl Handler {
+    /// Creates a handler whose programs run under `Policy::restrictive()`, and that doesn't ask users to log in
+    pub fn new() -> Handler {
+        Handler {
+            vm: VM::new(),
+            loaded: false,
+            output: MemorySink::new(),
+            asm: Assembler::new(),
+            policy: Policy::restrictive(),
+            shutdown: Shutdown::new(),
+            processes: ProcessTable::new(),
+            authenticator: None,
+            roles: RoleConfig::default(),
+            user: None,
+            peer: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            cluster: None,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Records the programs started with `spawn` in `processes`, which `processes` lists
+    pub fn with_processes(mut self, processes: ProcessTable) -> Self {
+        self.processes = processes;
+        self
+    }
+
+    /// Requires clients to call `login` before anything else
+    pub fn with_authenticator(mut self, authenticator: Authenticator) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    pub fn with_roles(mut self, roles: RoleConfig) -> Self {
+        self.roles = roles;
+        self
+    }
+
+    /// Records where the client connected from. Defaults to this host, as clients of the Unix socket are.
+    pub fn with_peer(mut self, peer: IpAddr) -> Self {
+        self.peer = peer;
+        self
+    }
+
+    /// Lists the members of the cluster `manager` keeps, rather than those of the connection's own VM
+    pub fn with_cluster(mut self, manager: Arc<RwLock<Manager>>) -> Self {
+        self.cluster = Some(manager);
+        self
+    }
+
+    /// Handles requests from `input`, one per line, until it is closed
+    pub fn serve<R: BufRead, W: Write>(&mut self, input: &mut R, output: &mut W) -> io::Result<()> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.handle_line(&line) {
+                writeln!(output, "{}", response)?;
+                output.flush()?;
+            }
+        }
+    }
+
+    /// Handles a request given as JSON, returning the response, or nothing for a notification
+    pub fn handle_line(&mut self, line: &str) -> Option<Value> {
+        match serde_json::from_str::<Value>(line) {
+            Ok(message) => self.handle(&message),
+            Err(e) => Some(error_response(Value::Null, &RpcError::ParseError { error: e.to_string() })),
+        }
+    }
+
+    /// Handles a request, returning the response, or nothing for a notification
+    pub fn handle(&mut self, message: &Value) -> Option<Value> {
+        let id = message.get("id").cloned();
+        let method = match message["method"].as_str() {
+            Some(method) if message["jsonrpc"] == "2.0" => method,
+            _ => return Some(error_response(id.unwrap_or(Value::Null), &RpcError::InvalidRequest)),
+        };
+        let result = self.call(method, &message["params"]);
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => error_response(id, &e),
+        })
+    }
+
+    fn call(&mut self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        if method == "login" {
+            return self.login(params);
+        }
+        let command = match command_for(method) {
+            Some(command) => command,
+            None => return Err(RpcError::MethodNotFound { method: method.to_string() }),
+        };
+        if self.authenticator.is_some() && self.user.is_none() {
+            return Err(RpcError::NotLoggedIn);
+        }
+        self.permitted(command)?;
+        match method {
+            "assemble" => self.assemble(params),
+            "load" => self.load(params),
+            "run" => self.run(params),
+            "step" => self.step(params),
+            "registers" => Ok(self.registers()),
+            "memory" => self.memory(params),
+            "symbols" => Ok(self.symbols()),
+            "spawn" => self.spawn(params),
+            "processes" => Ok(self.list_processes()),
+            _ => self.cluster_members(),
+        }
+    }
+
+    /// Checks the user's role allows `command`, recording it in the audit log if it doesn't
+    fn permitted(&self, command: &str) -> Result<(), RpcError> {
+        match self.user {
+            Some((ref username, ref role)) if !role.allows(command) => {
+                warn!(target: AUDIT_LOG_TARGET, "{} (role {}) was denied {} over RPC", username, role.name(), command);
+                Err(RpcError::PermissionDenied {
+                    reason: format!("Your role, {}, doesn't allow {}", role.name(), command),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn login(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let username = str_param(params, "username")?;
+        let password = str_param(params, "password")?;
+        let authenticator = match self.authenticator {
+            Some(ref authenticator) => authenticator,
+            None => {
+                return Err(RpcError::LoginFailed {
+                    reason: "This node doesn't require logging in".to_string(),
+                })
+            }
+        };
+        authenticator
+            .authenticate(self.peer, username, password)
+            .map_err(|e| RpcError::LoginFailed { reason: e.to_string() })?;
+        let role = self.roles.role_for(username);
+        let result = json!({ "username": username, "role": role.name() });
+        self.user = Some((username.to_string(), role));
+        Ok(result)
+    }
+
+    fn assemble(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let mut asm = Assembler::new();
+        let program = assemble_source(&mut asm, params)?;
+        Ok(json!({ "program": program, "symbols": symbol_list(&asm) }))
+    }
+
+    /// Assembles a program into a new VM, ready for `run` or `step`. Like one given to `!submit`, it comes from
+    /// the client rather than a file on the node, so doesn't need `Capability::Spawn`.
+    fn load(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let mut asm = Assembler::new();
+        let program = assemble_source(&mut asm, params)?;
+        let console = Console::new().with_output(self.output.clone()).with_input(MemorySource::new(""));
+        let mut vm = VM::new()
+            .with_policy(self.policy.clone())
+            .with_interrupt(self.shutdown.interrupt())
+            .with_console(console);
+        if let Some(ref manager) = self.cluster {
+            vm = vm.with_connection_manager(manager.clone());
+        }
+        vm.add_bytes(program);
+        self.output.take();
+        self.asm = asm;
+        self.vm = vm;
+        self.loaded = false;
+        self.vm.start().map_err(|e| RpcError::InvalidParams { reason: e.to_string() })?;
+        self.loaded = true;
+        Ok(json!({ "id": self.vm.id().to_string(), "length": self.vm.program().len() }))
+    }
+
+    fn run(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let max_instructions = u64_param(params, "max_instructions")?.unwrap_or(DEFAULT_MAX_INSTRUCTIONS);
+        self.advance(max_instructions)
+    }
+
+    fn step(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let count = u64_param(params, "count")?.unwrap_or(1);
+        self.advance(count)
+    }
+
+    /// Runs `count` instructions of the loaded program, up to `DEFAULT_MAX_INSTRUCTIONS`, stopping early if it
+    /// stops. The VM checks `shutdown` before each instruction, so a node shutting down stops it too.
+    fn advance(&mut self, count: u64) -> Result<Value, RpcError> {
+        if !self.loaded {
+            return Err(RpcError::NoProgram);
+        }
+        let count = count.min(DEFAULT_MAX_INSTRUCTIONS);
+        let mut executed = 0;
+        let mut code = None;
+        while executed < count {
+            executed += 1;
+            code = self.vm.step();
+            if code.is_some() {
+                self.loaded = false;
+                break;
+            }
+        }
+        Ok(json!({
+            "executed": executed,
+            "pc": self.vm.pc(),
+            "stopped": code.is_some(),
+            "code": code,
+            "error": self.vm.error().map(|e| e.to_string()),
+            "output": self.output.take(),
+        }))
+    }
+
+    fn registers(&self) -> Value {
+        json!({
+            "registers": &self.vm.registers()[..],
+            "float_registers": &self.vm.float_registers()[..],
+            "pc": self.vm.pc(),
+            "equal_flag": self.vm.equal_flag(),
+        })
+    }
+
+    /// Reads `length` bytes of the heap from `offset`, or to the end of it
+    fn memory(&self, params: &Value) -> Result<Value, RpcError> {
+        let heap = self.vm.heap();
+        let offset = u64_param(params, "offset")?.unwrap_or(0) as usize;
+        let length = match u64_param(params, "length")? {
+            Some(length) => length as usize,
+            None => heap.len().saturating_sub(offset),
+        };
+        match offset.checked_add(length) {
+            Some(end) if end <= heap.len() => Ok(json!({ "offset": offset, "bytes": &heap[offset..end], "heap_size": heap.len() })),
+            _ => Err(RpcError::InvalidParams {
+                reason: format!("{} bytes from {} is outside the heap, which is {} bytes", length, offset, heap.len()),
+            }),
+        }
+    }
+
+    fn symbols(&self) -> Value {
+        symbol_list(&self.asm)
+    }
+
+    /// Assembles a program and runs it in the background in a VM of its own
+    fn spawn(&mut self, params: &Value) -> Result<Value, RpcError> {
+        let program = assemble_source(&mut Assembler::new(), params)?;
+        let mut vm = VM::new().with_policy(self.policy.clone());
+        if let Some(ref manager) = self.cluster {
+            vm = vm.with_connection_manager(manager.clone());
+        }
+        vm.add_bytes(program);
+        let id = vm.id();
+        Scheduler::new()
+            .with_shutdown(self.shutdown.clone())
+            .with_processes(self.processes.clone())
+            .get_thread(vm);
+        Ok(json!({ "id": id.to_string() }))
+    }
+
+    fn list_processes(&self) -> Value {
+        let processes = self
+            .processes
+            .list()
+            .into_iter()
+            .map(|(id, state)| {
+                let (state, code) = match state {
+                    ProcessState::Running => ("running", None),
+                    ProcessState::Stopped { code } => ("stopped", Some(code)),
+                    ProcessState::Crashed { code } => ("crashed", Some(code)),
+                };
+                json!({ "id": id.to_string(), "state": state, "code": code })
+            })
+            .collect();
+        Value::Array(processes)
+    }
+
+    fn cluster_members(&self) -> Result<Value, RpcError> {
+        let manager = match self.cluster {
+            Some(ref manager) => manager.clone(),
+            None => self.vm.connection_manager(),
+        };
+        let members = manager
+            .read()
+            .unwrap()
+            .members()
+            .into_iter()
+            .map(|m| {
+                json!({
+                    "alias": m.node.0,
+                    "address": m.node.1,
+                    "port": m.node.2,
+                    "state": m.state.to_string(),
+                    "incarnation": m.incarnation,
+                })
+            })
+            .collect();
+        Ok(Value::Array(members))
+    }
+}
+
+fn error_response(id: Value, error: &RpcError) -> Value {
+    let mut response = json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": error.code(), "message": error.to_string() },
+    });
+    if let RpcError::AssemblyFailed { ref errors } = *error {
+        response["error"]["data"] = json!(errors);
+    }
+    response
+}
+
+fn str_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params[name].as_str().ok_or_else(|| RpcError::InvalidParams {
+        reason: format!("{} must be a string", name),
+    })
+}
+
+/// An optional parameter that has to be a non-negative integer if given
+fn u64_param(params: &Value, name: &str) -> Result<Option<u64>, RpcError> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value.as_u64().map(Some).ok_or_else(|| RpcError::InvalidParams {
+            reason: format!("{} must be a non-negative integer", name),
+        }),
+    }
+}
+
+/// Assembles the `source` parameter with `asm`
+fn assemble_source(asm: &mut Assembler, params: &Value) -> Result<Vec<u8>, RpcError> {
+    let source = str_param(params, "source")?;
+    asm.assemble(source).map_err(|errors| RpcError::AssemblyFailed {
+        errors: errors.iter().map(|e| e.to_string()).collect(),
+    })
+}
+
+fn symbol_list(asm: &Assembler) -> Value {
+    let symbols = asm
+        .symbols
+        .symbols
+        .iter()
+        .map(|s| json!({ "name": s.name(), "type": s.symbol_type().to_string(), "offset": s.offset() }))
+        .collect();
+    Value::Array(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use remote::auth::UserStore;
+    use std::io::Cursor;
+    use vm_errors::VMError;
+
+    const PROGRAM: &str = ".data\n.code\nload $0 #7\nload $1 #5\nadd $0 $1 $2\nhlt";
+
+    fn request(handler: &mut Handler, method: &str, params: Value) -> Value {
+        handler
+            .handle(&json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_load_step_and_run() {
+        let mut handler = Handler::new();
+        assert_eq!(request(&mut handler, "run", json!({}))["error"]["code"], -32004);
+        let loaded = request(&mut handler, "load", json!({ "source": PROGRAM }));
+        assert!(loaded["result"]["id"].is_string());
+
+        let stepped = request(&mut handler, "step", json!({ "count": 2 }))["result"].clone();
+        assert_eq!(stepped["executed"], 2);
+        assert_eq!(stepped["stopped"], false);
+        assert_eq!(request(&mut handler, "registers", json!({}))["result"]["registers"][1], 5);
+
+        let ran = request(&mut handler, "run", json!({}))["result"].clone();
+        assert_eq!(ran["stopped"], true);
+        assert_eq!(ran["executed"], 2);
+        assert_eq!(ran["code"], 0);
+        assert_eq!(request(&mut handler, "registers", json!({}))["result"]["registers"][2], 12);
+        assert_eq!(
+            request(&mut handler, "memory", json!({ "offset": 0, "length": 4 }))["result"]["bytes"],
+            json!([0, 0, 0, 0])
+        );
+        assert_eq!(request(&mut handler, "memory", json!({ "offset": 1000 }))["error"]["code"], -32602);
+    }
+
+    #[test]
+    fn test_run_limit() {
+        let mut handler = Handler::new();
+        request(&mut handler, "load", json!({ "source": ".data\n.code\nloop: djmp @loop" }));
+        let ran = request(&mut handler, "run", json!({ "max_instructions": 100 }))["result"].clone();
+        assert_eq!(ran["executed"], 100);
+        assert_eq!(ran["stopped"], false);
+        let ran = request(&mut handler, "run", json!({}))["result"].clone();
+        assert_eq!(ran["executed"], DEFAULT_MAX_INSTRUCTIONS);
+        assert_eq!(ran["stopped"], false);
+        // Asking for more than the limit gets the limit
+        let ran = request(&mut handler, "run", json!({ "max_instructions": u64::MAX }))["result"].clone();
+        assert_eq!(ran["executed"], DEFAULT_MAX_INSTRUCTIONS);
+        let stepped = request(&mut handler, "step", json!({ "count": u64::MAX }))["result"].clone();
+        assert_eq!(stepped["executed"], DEFAULT_MAX_INSTRUCTIONS);
+    }
+
+    #[test]
+    fn test_run_stops_on_shutdown() {
+        let shutdown = Shutdown::new();
+        let mut handler = Handler::new().with_shutdown(shutdown.clone());
+        request(&mut handler, "load", json!({ "source": ".data\n.code\nloop: djmp @loop" }));
+        shutdown.request();
+        let ran = request(&mut handler, "run", json!({}))["result"].clone();
+        assert_eq!(ran["executed"], 1);
+        assert_eq!(ran["stopped"], true);
+        assert_eq!(ran["code"], VMError::Interrupted.code());
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut handler = Handler::new();
+        assert_eq!(handler.handle_line("{not json").unwrap()["error"]["code"], -32700);
+        assert_eq!(handler.handle(&json!({ "id": 1, "method": "run" })).unwrap()["error"]["code"], -32600);
+        assert_eq!(request(&mut handler, "format_disk", json!({}))["error"]["code"], -32601);
+        assert_eq!(request(&mut handler, "assemble", json!({}))["error"]["code"], -32602);
+        let failed = request(&mut handler, "assemble", json!({ "source": ".data\n.code\njmp @nowhere" }));
+        assert_eq!(failed["error"]["code"], -32000);
+        assert!(failed["error"]["data"].is_array());
+        // Notifications get no response
+        assert!(handler.handle(&json!({ "jsonrpc": "2.0", "method": "registers" })).is_none());
+        // Listing the cluster only needs a role that allows it, not cluster access
+        assert_eq!(request(&mut handler, "cluster_members", json!({}))["result"], json!([]));
+    }
+
+    #[test]
+    fn test_login_and_roles() {
+        let mut users = UserStore::new();
+        users.set_password("bob", "hunter2").unwrap();
+        let roles = RoleConfig::parse("[roles]\nreadonly = !registers\n[users]\nbob = readonly").unwrap();
+        let mut handler = Handler::new().with_authenticator(Authenticator::new(users)).with_roles(roles);
+        assert_eq!(request(&mut handler, "registers", json!({}))["error"]["code"], -32001);
+        let failed = request(&mut handler, "login", json!({ "username": "bob", "password": "hunter3" }));
+        assert_eq!(failed["error"]["code"], -32002);
+        let logged_in = request(&mut handler, "login", json!({ "username": "bob", "password": "hunter2" }));
+        assert_eq!(logged_in["result"]["role"], "readonly");
+        assert!(request(&mut handler, "registers", json!({}))["result"].is_object());
+        assert_eq!(request(&mut handler, "memory", json!({}))["error"]["code"], -32003);
+        assert_eq!(request(&mut handler, "load", json!({ "source": PROGRAM }))["error"]["code"], -32003);
+    }
+
+    #[test]
+    fn test_serve() {
+        let mut handler = Handler::new().with_policy(Policy::permissive());
+        let mut input =
+            Cursor::new("{\"jsonrpc\": \"2.0\", \"id\": 1, \"method\": \"symbols\"}\n\n{\"jsonrpc\": \"2.0\", \"id\": 2, \"method\": \"processes\"}\n");
+        let mut output = vec![];
+        handler.serve(&mut input, &mut output).unwrap();
+        let responses: Vec<Value> = String::from_utf8(output).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(responses.len(), 2);
+        assert_eq!(responses[1]["id"], 2);
+        assert_eq!(responses[1]["result"], json!([]));
+    }
+}
@@ -0,0 +1,85 @@
+use std::error::Error;
+use std::fmt;
+
+/// Errors returned to control protocol clients. Each has the JSON-RPC error code clients should match on,
+/// rather than the message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RpcError {
+    /// The request wasn't valid JSON
+    ParseError {
+        error: String,
+    },
+    /// The request was JSON, but not a JSON-RPC request
+    InvalidRequest,
+    MethodNotFound {
+        method: String,
+    },
+    /// A parameter is missing or has the wrong type or value
+    InvalidParams {
+        reason: String,
+    },
+    /// The source given to `assemble`, `load` or `spawn` has errors
+    AssemblyFailed {
+        errors: Vec<String>,
+    },
+    /// The node requires clients to call `login` first
+    NotLoggedIn,
+    LoginFailed {
+        reason: String,
+    },
+    /// The user's role, or the node's policy, doesn't allow the method
+    PermissionDenied {
+        reason: String,
+    },
+    /// `run` or `step` was called before a program was loaded, or after it stopped
+    NoProgram,
+}
+
+impl RpcError {
+    /// The JSON-RPC error code. Those from -32000 down are ours.
+    pub fn code(&self) -> i64 {
+        match self {
+            RpcError::ParseError { .. } => -32700,
+            RpcError::InvalidRequest => -32600,
+            RpcError::MethodNotFound { .. } => -32601,
+            RpcError::InvalidParams { .. } => -32602,
+            RpcError::AssemblyFailed { .. } => -32000,
+            RpcError::NotLoggedIn => -32001,
+            RpcError::LoginFailed { .. } => -32002,
+            RpcError::PermissionDenied { .. } => -32003,
+            RpcError::NoProgram => -32004,
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RpcError::ParseError { ref error } => f.write_str(&format!("Unable to parse the request: {}", error)),
+            RpcError::InvalidRequest => f.write_str("Not a JSON-RPC 2.0 request"),
+            RpcError::MethodNotFound { ref method } => f.write_str(&format!("There is no method {:?}", method)),
+            RpcError::InvalidParams { ref reason } => f.write_str(&format!("Invalid params: {}", reason)),
+            RpcError::AssemblyFailed { ref errors } => f.write_str(&format!("Unable to assemble the program: {}", errors.join("; "))),
+            RpcError::NotLoggedIn => f.write_str("Call login first"),
+            RpcError::LoginFailed { ref reason } => f.write_str(reason),
+            RpcError::PermissionDenied { ref reason } => f.write_str(reason),
+            RpcError::NoProgram => f.write_str("No program is loaded, or it has stopped"),
+        }
+    }
+}
+
+impl Error for RpcError {
+    fn description(&self) -> &str {
+        match self {
+            RpcError::ParseError { .. } => "Unable to parse the request",
+            RpcError::InvalidRequest => "Not a JSON-RPC 2.0 request",
+            RpcError::MethodNotFound { .. } => "No such method",
+            RpcError::InvalidParams { .. } => "Invalid params",
+            RpcError::AssemblyFailed { .. } => "Unable to assemble the program",
+            RpcError::NotLoggedIn => "Not logged in",
+            RpcError::LoginFailed { .. } => "Unable to log in",
+            RpcError::PermissionDenied { .. } => "Permission denied",
+            RpcError::NoProgram => "No program is loaded",
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use std::io::{self, BufReader};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use cluster::manager::Manager;
+use policy::Policy;
+use remote::auth::Authenticator;
+use remote::roles::RoleConfig;
+use rpc::Handler;
+use scheduler::{ProcessTable, Shutdown};
+use tls::{self, TlsConfig};
+
+/// Accepts control protocol connections, handling each in a thread of its own
+#[derive(Clone)]
+pub struct Server {
+    /// What programs run by clients may do. By default this is `Policy::restrictive()`, as for remote access.
+    policy: Policy,
+    shutdown: Shutdown,
+    /// Every program clients have spawned
+    processes: ProcessTable,
+    /// Checks who is connecting. Without one, clients don't have to log in.
+    authenticator: Option<Authenticator>,
+    roles: RoleConfig,
+    /// Encrypts TCP connections
+    tls: Option<TlsConfig>,
+    /// The node's cluster membership, for `cluster_members`
+    cluster: Option<Arc<RwLock<Manager>>>,
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server::new()
+    }
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server {
+            policy: Policy::restrictive(),
+            shutdown: Shutdown::new(),
+            processes: ProcessTable::new(),
+            authenticator: None,
+            roles: RoleConfig::default(),
+            tls: None,
+            cluster: None,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn with_processes(mut self, processes: ProcessTable) -> Self {
+        self.processes = processes;
+        self
+    }
+
+    /// Requires clients to call `login` before anything else
+    pub fn with_authenticator(mut self, authenticator: Authenticator) -> Self {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    pub fn with_roles(mut self, roles: RoleConfig) -> Self {
+        self.roles = roles;
+        self
+    }
+
+    /// Encrypts TCP connections with TLS. Unix socket connections never leave the machine, so aren't.
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn with_cluster(mut self, manager: Arc<RwLock<Manager>>) -> Self {
+        self.cluster = Some(manager);
+        self
+    }
+
+    /// A handler for a new connection
+    fn handler(&self) -> Handler {
+        let mut handler = Handler::new()
+            .with_policy(self.policy.clone())
+            .with_shutdown(self.shutdown.clone())
+            .with_processes(self.processes.clone())
+            .with_roles(self.roles.clone());
+        if let Some(ref authenticator) = self.authenticator {
+            handler = handler.with_authenticator(authenticator.clone());
+        }
+        if let Some(ref cluster) = self.cluster {
+            handler = handler.with_cluster(cluster.clone());
+        }
+        handler
+    }
+
+    /// Listens for connections on `addr`, such as `127.0.0.1:2255`. Only returns if it can't bind.
+    pub fn listen_tcp(&self, addr: &str) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Listening for RPC connections on {}", addr);
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Unable to accept an RPC connection: {}", e);
+                    continue;
+                }
+            };
+            let server = self.clone();
+            thread::spawn(move || {
+                let stream = match tls::accept_user(stream, server.tls.as_ref()) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Rejected RPC connection: {}", e);
+                        return;
+                    }
+                };
+                let mut handler = server.handler();
+                if let Ok(addr) = stream.peer_addr() {
+                    handler = handler.with_peer(addr.ip());
+                }
+                let result = stream
+                    .try_clone()
+                    .and_then(|reader| handler.serve(&mut BufReader::new(reader), &mut stream.try_clone()?));
+                if let Err(e) = result {
+                    debug!("RPC connection closed: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Listens for connections on a Unix socket at `path`, replacing any socket left there. Only the user the
+    /// node runs as may connect. Only returns if it can't bind.
+    #[cfg(unix)]
+    pub fn listen_unix(&self, path: &Path) -> io::Result<()> {
+        use std::fs;
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+        use std::os::unix::net::UnixListener;
+
+        if fs::symlink_metadata(path).map(|m| m.file_type().is_socket()).unwrap_or(false) {
+            fs::remove_file(path)?;
+        }
+        // Created without permissions for anyone else to begin with, so nobody can connect before they're set.
+        // The umask is the whole process's, but only ever makes files created meanwhile more private.
+        let umask = unsafe { libc::umask(0o177) };
+        let bound = UnixListener::bind(path);
+        unsafe { libc::umask(umask) };
+        let listener = bound?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        info!("Listening for RPC connections on {}", path.display());
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Unable to accept an RPC connection: {}", e);
+                    continue;
+                }
+            };
+            let mut handler = self.handler();
+            thread::spawn(move || {
+                let result = stream.try_clone().and_then(|reader| handler.serve(&mut BufReader::new(reader), &mut &stream));
+                if let Err(e) = result {
+                    debug!("RPC connection closed: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn listen_unix(&self, path: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("Unix sockets, such as {}, are only supported on Unix", path.display()),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{self, Value};
+    use std::io::{BufRead, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    fn call<S: BufRead, W: Write>(reader: &mut S, writer: &mut W, request: &str) -> Value {
+        writeln!(writer, "{}", request).unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn test_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let server = Server::new();
+        let bind = addr.clone();
+        thread::spawn(move || server.listen_tcp(&bind));
+        let mut stream = None;
+        for _ in 0..50 {
+            if let Ok(s) = TcpStream::connect(&addr) {
+                stream = Some(s);
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let mut stream = stream.unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let response = call(&mut reader, &mut stream, r#"{"jsonrpc": "2.0", "id": "a", "method": "registers"}"#);
+        assert_eq!(response["id"], "a");
+        assert_eq!(response["result"]["registers"].as_array().unwrap().len(), 32);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::os::unix::net::UnixStream;
+
+        let path = std::env::temp_dir().join(format!("iridium-rpc-test-{}.sock", std::process::id()));
+        let server = Server::new();
+        let bind = path.clone();
+        thread::spawn(move || server.listen_unix(&bind));
+        let mut stream = None;
+        for _ in 0..50 {
+            if let Ok(s) = UnixStream::connect(&path) {
+                stream = Some(s);
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let mut stream = stream.unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let response = call(&mut reader, &mut stream, r#"{"jsonrpc": "2.0", "id": 1, "method": "symbols"}"#);
+        assert_eq!(response["result"], Value::Array(vec![]));
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,402 @@
+//! The interface for embedding Iridium in other programs. A `Runtime` holds the configuration (limits, native
+//! functions, where output goes and input comes from, and cluster binding), and loads programs into `Instance`s that can be run to
+//! completion or one instruction at a time.
+
+pub mod runtime_errors;
+
+use assembler::Assembler;
+use console::{Console, InputSource, MemorySink, OutputSink};
+use native::{NativeRegistry, NativeResult};
+use policy::Policy;
+use runtime::runtime_errors::RuntimeError;
+use std::path::PathBuf;
+use vm::{Limits, VMEvent, VM};
+use vm_errors::VMError;
+
+#[derive(Default, Clone)]
+pub struct Runtime {
+    natives: NativeRegistry,
+    limits: Limits,
+    /// Where programs print to and read from. Every program loaded shares it.
+    console: Console,
+    /// If true, what each program prints is collected in `RunResult::output` instead of going to the console
+    capture_output: bool,
+    alias: Option<String>,
+    /// Address and port to listen for other nodes on
+    cluster_bind: Option<(String, String)>,
+    logical_cores: Option<usize>,
+    /// What programs may do outside of their VM
+    policy: Policy,
+    /// Where programs may open files
+    data_root: Option<PathBuf>,
+    args: Vec<String>,
+}
+
+impl Runtime {
+    pub fn new() -> Runtime {
+        Runtime {
+            natives: NativeRegistry::new(),
+            limits: Limits::default(),
+            console: Console::new(),
+            capture_output: false,
+            alias: None,
+            cluster_bind: None,
+            logical_cores: None,
+            policy: Policy::permissive(),
+            data_root: None,
+            args: vec![],
+        }
+    }
+
+    /// Makes a Rust function callable from programs with `CALLN @name`. See `NativeRegistry::register`.
+    pub fn with_native<F>(mut self, name: &str, arity: u8, function: F) -> Self
+    where
+        F: Fn(&[i32]) -> NativeResult + Send + Sync + 'static,
+    {
+        self.natives.register(name, arity, function);
+        self
+    }
+
+    /// Replaces every native function registered so far with the ones in `natives`
+    pub fn with_natives(mut self, natives: NativeRegistry) -> Self {
+        self.natives = natives;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Collects what each program prints in `RunResult::output` instead of writing it to the output sink
+    pub fn with_captured_output(mut self) -> Self {
+        self.capture_output = true;
+        self
+    }
+
+    /// Sends what programs print to `sink` instead of stdout
+    pub fn with_output<S: OutputSink + 'static>(mut self, sink: S) -> Self {
+        self.console = self.console.with_output(sink);
+        self
+    }
+
+    /// Makes programs read their input from `source` instead of stdin
+    pub fn with_input<S: InputSource + 'static>(mut self, source: S) -> Self {
+        self.console = self.console.with_input(source);
+        self
+    }
+
+    pub fn with_alias(mut self, alias: String) -> Self {
+        self.alias = Some(alias);
+        self
+    }
+
+    /// Makes each loaded program listen for other nodes of the cluster on this address and port
+    pub fn with_cluster_bind(mut self, server_addr: String, server_port: String) -> Self {
+        self.cluster_bind = Some((server_addr, server_port));
+        self
+    }
+
+    pub fn with_logical_cores(mut self, logical_cores: usize) -> Self {
+        self.logical_cores = Some(logical_cores);
+        self
+    }
+
+    /// Sets what programs may do outside of their VM. By default they may do anything.
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Lets programs open files in this directory
+    pub fn with_data_root(mut self, data_root: PathBuf) -> Self {
+        self.data_root = Some(data_root);
+        self
+    }
+
+    /// Sets the arguments programs can read with the `Argc` and `Argv` system calls
+    pub fn with_args(mut self, args: Vec<String>) -> Self {
+        self.args = args;
+        self
+    }
+
+    /// Assembles `source` and loads it
+    pub fn load_source(&self, source: &str) -> Result<Instance, RuntimeError> {
+        match Assembler::new().assemble(source) {
+            Ok(program) => self.load_bytes(program),
+            Err(errors) => Err(RuntimeError::AssemblyFailed { errors }),
+        }
+    }
+
+    /// Loads an assembled program, checking its header and that every native function it imports is registered
+    pub fn load_bytes(&self, program: Vec<u8>) -> Result<Instance, RuntimeError> {
+        let captured_output = if self.capture_output { Some(MemorySink::new()) } else { None };
+        let console = match captured_output {
+            Some(ref sink) => self.console.clone().with_output(sink.clone()),
+            None => self.console.clone(),
+        };
+        let mut vm = VM::new()
+            .with_natives(self.natives.clone())
+            .with_limits(self.limits)
+            .with_console(console)
+            .with_args(self.args.clone())
+            .with_policy(self.policy.clone());
+        if let Some(ref data_root) = self.data_root {
+            vm = vm.with_data_root(data_root.clone());
+        }
+        if let Some(logical_cores) = self.logical_cores {
+            vm = vm.with_logical_cores(logical_cores);
+        }
+        if let Some(ref alias) = self.alias {
+            vm = vm.with_alias(alias.clone());
+        }
+        if let Some((ref addr, ref port)) = self.cluster_bind {
+            // Other nodes know us by our alias, so we need one to listen
+            if vm.alias().is_none() {
+                let alias = vm.id().to_string();
+                vm = vm.with_alias(alias);
+            }
+            vm = vm.with_cluster_bind(addr.clone(), port.clone());
+        }
+        vm.add_bytes(program);
+        if let Err(error) = vm.start() {
+            return Err(RuntimeError::LoadFailed { error });
+        }
+        if self.cluster_bind.is_some() {
+            if let Err(e) = vm.bind_cluster_server() {
+                error!("Unable to listen for other nodes: {}", e);
+            }
+        }
+        Ok(Instance {
+            vm,
+            captured_output,
+            result: None,
+        })
+    }
+
+    /// Assembles `source` and runs it to completion
+    pub fn run_source(&self, source: &str) -> Result<RunResult, RuntimeError> {
+        Ok(self.load_source(source)?.run())
+    }
+
+    /// Runs an assembled program to completion
+    pub fn run_bytes(&self, program: Vec<u8>) -> Result<RunResult, RuntimeError> {
+        Ok(self.load_bytes(program)?.run())
+    }
+}
+
+/// How a program ended
+#[derive(Debug, Clone)]
+pub struct RunResult {
+    /// The stop code of the last event, analogous to a process exit code
+    pub exit_code: u32,
+    /// Every event the VM recorded, starting with `Start`
+    pub events: Vec<VMEvent>,
+    /// What the program printed, if the runtime was made with `with_captured_output`
+    pub output: String,
+    /// Why the program crashed, if it did
+    pub error: Option<VMError>,
+    pub registers: [i32; 32],
+    pub float_registers: [f64; 32],
+}
+
+impl RunResult {
+    /// The exit code as a process exit status, which only keeps its lowest 8 bits. A program that stopped with
+    /// 0 gets 0, and one that stopped with 1 to 255 gets that, but any other code, including a negative one from
+    /// `EXIT`, gets 255 rather than something that could truncate to 0 and pass for a success.
+    pub fn exit_status(&self) -> i32 {
+        match self.exit_code {
+            0..=255 => self.exit_code as i32,
+            _ => 255,
+        }
+    }
+}
+
+/// A program loaded into its own VM, ready to run
+pub struct Instance {
+    vm: VM,
+    /// What the program has printed, if output is being captured
+    captured_output: Option<MemorySink>,
+    /// Set once the program has stopped
+    result: Option<RunResult>,
+}
+
+impl Instance {
+    /// Runs the program until it stops. If it already has, returns how it ended again.
+    pub fn run(&mut self) -> RunResult {
+        loop {
+            if let Some(result) = self.step() {
+                return result;
+            }
+        }
+    }
+
+    /// Executes one instruction. Returns `None` while the program is still running, and how it ended once it stops.
+    pub fn step(&mut self) -> Option<RunResult> {
+        if self.result.is_none() {
+            let exit_code = self.vm.step()?;
+            self.result = Some(RunResult {
+                exit_code,
+                events: self.vm.events().to_vec(),
+                output: self.captured_output.as_ref().map(|sink| sink.contents()).unwrap_or_default(),
+                error: self.vm.error().cloned(),
+                registers: *self.vm.registers(),
+                float_registers: *self.vm.float_registers(),
+            });
+        }
+        self.result.clone()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.result.is_some()
+    }
+
+    pub fn registers(&self) -> &[i32; 32] {
+        self.vm.registers()
+    }
+
+    pub fn registers_mut(&mut self) -> &mut [i32; 32] {
+        self.vm.registers_mut()
+    }
+
+    pub fn float_registers(&self) -> &[f64; 32] {
+        self.vm.float_registers()
+    }
+
+    /// The VM running the program, for anything not covered here
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use console::MemorySource;
+    use policy::Capability;
+
+    const COUNT: &str = "
+    .data
+    hello: .asciiz 'Hello'
+    .code
+    prts @hello
+    load $0 #3
+    back: dec $0
+    eq $0 $1
+    jmpe @done
+    jmp @back
+    done: hlt
+    ";
+
+    #[test]
+    fn test_run_source() {
+        let result = Runtime::new()
+            .with_captured_output()
+            .with_native("double", 1, |args| Ok(vec![args[0] * 2]))
+            .run_source(".data\nhello: .asciiz 'Hi'\n.code\nprts @hello\nload $1 #21\ncalln $1 @double\nhlt")
+            .unwrap();
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.output, "Hi");
+        assert_eq!(result.registers[1], 42);
+        assert!(result.error.is_none());
+        assert_eq!(result.events.len(), 2);
+    }
+
+    #[test]
+    fn test_step() {
+        let mut instance = Runtime::new().with_captured_output().load_source(COUNT).unwrap();
+        assert!(instance.step().is_none());
+        assert!(instance.step().is_none());
+        assert_eq!(instance.registers()[0], 3);
+        instance.registers_mut()[0] = 1;
+        let result = instance.run();
+        assert!(instance.is_finished());
+        assert_eq!(result.exit_code, 0);
+        assert_eq!(result.output, "Hello");
+        assert_eq!(instance.step().unwrap().exit_code, 0);
+    }
+
+    #[test]
+    fn test_input_and_output() {
+        let sink = MemorySink::new();
+        let runtime = Runtime::new().with_output(sink.clone()).with_input(MemorySource::new("20\n22"));
+        let result = runtime
+            .run_source(".data\nsum: .asciiz 'Sum'\n.code\nread $0\nread $1\nadd $0 $1 $2\nprts @sum\nhlt")
+            .unwrap();
+        assert_eq!(result.registers[2], 42);
+        assert_eq!(result.output, "");
+        assert_eq!(sink.contents(), "Sum");
+    }
+
+    #[test]
+    fn test_limits() {
+        let runtime = Runtime::new().with_limits(Limits {
+            max_instructions: Some(3),
+            max_heap_bytes: Some(100),
+        });
+        let result = runtime.run_source(".data\n.code\nloop: jmp @loop").unwrap();
+        assert_eq!(result.error, Some(VMError::InstructionLimitExceeded { limit: 3 }));
+        assert_eq!(result.exit_code, result.error.unwrap().code());
+
+        let result = runtime.run_source(".data\n.code\nload $0 #1000\naloc $0\nhlt").unwrap();
+        assert_eq!(result.error, Some(VMError::HeapLimitExceeded { limit: 100 }));
+    }
+
+    #[test]
+    fn test_crash_status() {
+        // Bad bytecode crashes the program with an error rather than the host
+        let result = Runtime::new().run_source(".data\n.code\npop $0\nhlt").unwrap();
+        assert_eq!(result.error, Some(VMError::StackUnderflow));
+        assert_eq!(result.exit_status(), 12);
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let runtime = Runtime::new();
+        let status = |code: &str| runtime.run_source(&format!(".data\n.code\nload $2 #{}\nexit $2", code)).unwrap().exit_status();
+        assert_eq!(status("0"), 0);
+        assert_eq!(status("3"), 3);
+        assert_eq!(status("255"), 255);
+        assert_eq!(status("256"), 255);
+        assert_eq!(status("512"), 255);
+        assert_eq!(status("-1"), 255);
+    }
+
+    #[test]
+    fn test_policy() {
+        let runtime = Runtime::new().with_captured_output().with_policy(Policy::deny_all());
+        let result = runtime.run_source(".data\nhi: .asciiz 'Hi'\n.code\nprts @hi\nhlt").unwrap();
+        assert_eq!(result.error, Some(VMError::CapabilityDenied { capability: Capability::Print }));
+        assert_eq!(result.exit_code, 9);
+        assert_eq!(result.output, "");
+
+        let runtime = runtime.with_policy(Policy::restrictive());
+        assert_eq!(runtime.run_source(".data\nhi: .asciiz 'Hi'\n.code\nprts @hi\nhlt").unwrap().output, "Hi");
+        let result = runtime
+            .with_native("double", 1, |args| Ok(vec![args[0] * 2]))
+            .run_source(".data\n.code\ncalln $0 @double\nhlt")
+            .unwrap();
+        assert_eq!(
+            result.error,
+            Some(VMError::CapabilityDenied {
+                capability: Capability::Native
+            })
+        );
+    }
+
+    #[test]
+    fn test_load_errors() {
+        match Runtime::new().load_source(".code\nhlt") {
+            Err(RuntimeError::AssemblyFailed { .. }) => {}
+            _ => panic!("Expected the program to fail to assemble"),
+        }
+        match Runtime::new().load_bytes(vec![1, 2, 3]) {
+            Err(RuntimeError::LoadFailed { error }) => assert_eq!(error, VMError::InvalidHeader),
+            _ => panic!("Expected the program to fail to load"),
+        }
+        match Runtime::new().load_source(".data\n.code\ncalln $0 @missing\nhlt") {
+            Err(RuntimeError::LoadFailed { error }) => assert_eq!(error, VMError::UnknownNative { name: "missing".to_string() }),
+            _ => panic!("Expected the program to fail to load"),
+        }
+    }
+}
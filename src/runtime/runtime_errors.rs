@@ -0,0 +1,32 @@
+use std::error::Error;
+use std::fmt;
+
+use assembler::assembler_errors::AssemblerError;
+use vm_errors::VMError;
+
+#[derive(Debug, Clone)]
+pub enum RuntimeError {
+    AssemblyFailed { errors: Vec<AssemblerError> },
+    LoadFailed { error: VMError },
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RuntimeError::AssemblyFailed { ref errors } => {
+                let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                f.write_str(&format!("The program could not be assembled: {}", errors.join("; ")))
+            }
+            RuntimeError::LoadFailed { ref error } => f.write_str(&format!("The program could not be loaded: {}", error)),
+        }
+    }
+}
+
+impl Error for RuntimeError {
+    fn description(&self) -> &str {
+        match self {
+            RuntimeError::AssemblyFailed { .. } => "The program could not be assembled",
+            RuntimeError::LoadFailed { .. } => "The program could not be loaded",
+        }
+    }
+}
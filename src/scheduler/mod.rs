@@ -1,26 +1,150 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use vm::{VMEvent, VM};
+use uuid::Uuid;
+use vm::{VMEvent, VMEventType, VM};
+
+/// Stops programs running in the background, and keeps count of how many still are. Clones share the same
+/// state, so one can be handed to every scheduler on the node.
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    running: Arc<AtomicUsize>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// Asks every program to stop before its next instruction
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// How many programs are still running
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// The flag VMs check before each instruction
+    pub fn interrupt(&self) -> Arc<AtomicBool> {
+        self.requested.clone()
+    }
+}
+
+/// Where a program run in the background has got to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProcessState {
+    Running,
+    Stopped { code: u32 },
+    Crashed { code: u32 },
+}
+
+/// The programs schedulers have run in the background, by the ID of their VM. Clones share the same table, so
+/// one can be handed to every scheduler on the node.
+#[derive(Clone, Debug, Default)]
+pub struct ProcessTable {
+    processes: Arc<Mutex<Vec<(Uuid, ProcessState)>>>,
+}
+
+impl ProcessTable {
+    pub fn new() -> ProcessTable {
+        ProcessTable::default()
+    }
+
+    /// Every program, in the order they were started
+    pub fn list(&self) -> Vec<(Uuid, ProcessState)> {
+        self.processes.lock().unwrap().clone()
+    }
+
+    pub fn state(&self, id: Uuid) -> Option<ProcessState> {
+        self.processes.lock().unwrap().iter().find(|p| p.0 == id).map(|p| p.1)
+    }
+
+    fn set(&self, id: Uuid, state: ProcessState) {
+        let mut processes = self.processes.lock().unwrap();
+        match processes.iter_mut().find(|p| p.0 == id) {
+            Some(process) => process.1 = state,
+            None => processes.push((id, state)),
+        }
+    }
+}
+
+/// Records how a program run in the background ended once its thread is done with it. Doing this on drop means
+/// a panic in the VM still leaves the program `Crashed` rather than `Running`, and still lets shutdown finish.
+struct Finished {
+    shutdown: Shutdown,
+    processes: ProcessTable,
+    id: Uuid,
+    state: ProcessState,
+}
+
+impl Drop for Finished {
+    fn drop(&mut self) {
+        self.processes.set(self.id, self.state);
+        self.shutdown.running.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
 #[derive(Default)]
 pub struct Scheduler {
     next_pid: u32,
     max_pid: u32,
+    shutdown: Shutdown,
+    processes: ProcessTable,
 }
 
 impl Scheduler {
     pub fn new() -> Scheduler {
-        Scheduler { next_pid: 0, max_pid: 50000 }
+        Scheduler {
+            next_pid: 0,
+            max_pid: 50000,
+            shutdown: Shutdown::new(),
+            processes: ProcessTable::new(),
+        }
+    }
+
+    /// Lets `shutdown` stop the programs this scheduler runs
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Records the programs this scheduler runs in `processes`
+    pub fn with_processes(mut self, processes: ProcessTable) -> Self {
+        self.processes = processes;
+        self
     }
 
     /// Takes a VM and runs it in a background thread
-    pub fn get_thread(&mut self, mut vm: VM) -> thread::JoinHandle<Vec<VMEvent>> {
+    pub fn get_thread(&mut self, vm: VM) -> thread::JoinHandle<Vec<VMEvent>> {
+        let shutdown = self.shutdown.clone();
+        let processes = self.processes.clone();
+        let mut vm = vm.with_interrupt(shutdown.interrupt());
+        shutdown.running.fetch_add(1, Ordering::SeqCst);
+        processes.set(vm.id(), ProcessState::Running);
         thread::spawn(move || {
+            let mut finished = Finished {
+                shutdown,
+                processes,
+                id: vm.id(),
+                state: ProcessState::Crashed { code: 1 },
+            };
             let events = vm.run();
-            println!("VM Events");
-            println!("--------------------------");
+            // Events aren't the program's output, so they are logged rather than printed
             for event in &events {
-                println!("{:#?}", event);
+                info!("VM {} event: {:?}", vm.id(), event);
             }
+            finished.state = match events.last().map(|e| &e.event) {
+                Some(VMEventType::GracefulStop { code }) => ProcessState::Stopped { code: *code },
+                Some(VMEventType::Crash { code }) => ProcessState::Crashed { code: *code },
+                _ => ProcessState::Crashed { code: 1 },
+            };
             events
         })
     }
@@ -42,11 +166,53 @@ impl Scheduler {
 
 mod tests {
     #[allow(unused_imports)]
-    use scheduler::Scheduler;
+    use assembler::Assembler;
+    #[allow(unused_imports)]
+    use scheduler::{ProcessState, ProcessTable, Scheduler, Shutdown};
+    #[allow(unused_imports)]
+    use vm::VM;
 
     #[test]
     fn test_make_scheduler() {
         let s = Scheduler::new();
         assert_eq!(s.next_pid, 0);
     }
+
+    #[test]
+    fn test_shutdown() {
+        let shutdown = Shutdown::new();
+        let mut scheduler = Scheduler::new().with_shutdown(shutdown.clone());
+        let mut vm = VM::new();
+        vm.add_bytes(Assembler::new().assemble(".data\n.code\nloop: jmp @loop").unwrap());
+        let handle = scheduler.get_thread(vm);
+        assert_eq!(shutdown.running(), 1);
+        shutdown.request();
+        let events = handle.join().unwrap();
+        assert_eq!(events[1].event.stop_code(), 10);
+        assert_eq!(shutdown.running(), 0);
+    }
+
+    #[test]
+    fn test_crashed_program() {
+        let shutdown = Shutdown::new();
+        let processes = ProcessTable::new();
+        let mut scheduler = Scheduler::new().with_shutdown(shutdown.clone()).with_processes(processes.clone());
+        let mut vm = VM::new();
+        vm.add_bytes(Assembler::new().assemble(".data\n.code\npop $0\nhlt").unwrap());
+        let id = vm.id();
+        scheduler.get_thread(vm).join().unwrap();
+        assert_eq!(processes.state(id), Some(ProcessState::Crashed { code: 12 }));
+        assert_eq!(shutdown.running(), 0);
+    }
+
+    #[test]
+    fn test_process_table() {
+        let processes = ProcessTable::new();
+        let mut scheduler = Scheduler::new().with_processes(processes.clone());
+        let mut vm = VM::new();
+        vm.add_bytes(Assembler::new().assemble(".data\n.code\nhlt").unwrap());
+        let id = vm.id();
+        scheduler.get_thread(vm).join().unwrap();
+        assert_eq!(processes.list(), vec![(id, ProcessState::Stopped { code: 0 })]);
+    }
 }
@@ -0,0 +1,206 @@
+//! System calls, which give programs access to files, clocks, randomness and their environment through the
+//! `SYSCALL` instruction. Arguments are taken from `$1`, `$2` and `$3`, and the result is put in `$0`, with -1
+//! meaning the call failed.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The directory in the data root programs' files are kept in, which is all they can open
+pub const FILES_DIRNAME: &str = "files";
+
+/// Every system call, with the number `SYSCALL` is given to make it
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Syscall {
+    /// Opens the file whose path is the string at offset `$1` of the read-only section, relative to the
+    /// `files` directory of the data root. `$2` is 0 to read, 1 to create or truncate and write, or 2 to append. Returns a handle.
+    Open,
+    /// Reads up to `$3` bytes from handle `$1` into the heap at offset `$2`. Returns how many were read.
+    Read,
+    /// Writes `$3` bytes from the heap at offset `$2` to handle `$1`. Returns how many were written.
+    Write,
+    /// Closes handle `$1`
+    Close,
+    /// Returns the milliseconds since the program started
+    MonotonicTime,
+    /// Returns the seconds since the Unix epoch
+    WallTime,
+    /// Sleeps for `$1` milliseconds
+    Sleep,
+    /// Returns a random number
+    Random,
+    /// Copies the value of the environment variable named by the string at offset `$1` of the read-only
+    /// section to the heap at offset `$2`, up to `$3` bytes. Returns its length.
+    Env,
+    /// Returns how many arguments the program was given
+    Argc,
+    /// Copies argument `$1` to the heap at offset `$2`, up to `$3` bytes. Returns its length.
+    Argv,
+    /// Copies the next change to the cluster's membership the program hasn't been given yet to the heap at
+    /// offset `$1`, up to `$2` bytes, as text such as `died node2`. Returns its length, or 0 if there hasn't been
+    /// one.
+    ClusterEvent,
+}
+
+impl Syscall {
+    pub fn from_number(number: u16) -> Option<Syscall> {
+        match number {
+            0 => Some(Syscall::Open),
+            1 => Some(Syscall::Read),
+            2 => Some(Syscall::Write),
+            3 => Some(Syscall::Close),
+            4 => Some(Syscall::MonotonicTime),
+            5 => Some(Syscall::WallTime),
+            6 => Some(Syscall::Sleep),
+            7 => Some(Syscall::Random),
+            8 => Some(Syscall::Env),
+            9 => Some(Syscall::Argc),
+            10 => Some(Syscall::Argv),
+            11 => Some(Syscall::ClusterEvent),
+            _ => None,
+        }
+    }
+
+    pub fn number(self) -> u16 {
+        match self {
+            Syscall::Open => 0,
+            Syscall::Read => 1,
+            Syscall::Write => 2,
+            Syscall::Close => 3,
+            Syscall::MonotonicTime => 4,
+            Syscall::WallTime => 5,
+            Syscall::Sleep => 6,
+            Syscall::Random => 7,
+            Syscall::Env => 8,
+            Syscall::Argc => 9,
+            Syscall::Argv => 10,
+            Syscall::ClusterEvent => 11,
+        }
+    }
+
+    /// Every system call, in order of number
+    pub fn all() -> Vec<Syscall> {
+        (0..).map_while(Syscall::from_number).collect()
+    }
+}
+
+/// The files a program has open, by handle. Clones share the same files.
+#[derive(Clone, Debug, Default)]
+pub struct FileTable {
+    files: Vec<Option<Arc<Mutex<File>>>>,
+}
+
+impl FileTable {
+    /// Adds a file and returns its handle, reusing the handle of a closed file if there is one
+    pub fn insert(&mut self, file: File) -> usize {
+        let file = Some(Arc::new(Mutex::new(file)));
+        match self.files.iter().position(|f| f.is_none()) {
+            Some(handle) => {
+                self.files[handle] = file;
+                handle
+            }
+            None => {
+                self.files.push(file);
+                self.files.len() - 1
+            }
+        }
+    }
+
+    pub fn get(&self, handle: usize) -> Option<Arc<Mutex<File>>> {
+        self.files.get(handle).and_then(|f| f.clone())
+    }
+
+    /// Closes the file. Returns false if the handle wasn't open.
+    pub fn remove(&mut self, handle: usize) -> bool {
+        match self.files.get_mut(handle) {
+            Some(file) => file.take().is_some(),
+            None => false,
+        }
+    }
+}
+
+/// Resolves a path given by a program against `root`. Returns `None` for paths that could lead outside of it:
+/// absolute paths and ones that go up a directory.
+pub fn confine_path(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = Path::new(path);
+    if !path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return None;
+    }
+    let path: PathBuf = path.components().filter(|c| *c != Component::CurDir).collect();
+    if path.as_os_str().is_empty() {
+        return None;
+    }
+    Some(root.join(path))
+}
+
+/// Opens a path given by a program inside `root`, creating `root` if it isn't there yet. The directory the file
+/// is in is resolved, symbolic links and all, and has to be inside `root`, and the file itself is opened without
+/// following a link, so a link can't lead a program out of it.
+pub fn open_confined(root: &Path, path: &str, options: &mut OpenOptions) -> io::Result<File> {
+    let outside = || io::Error::new(io::ErrorKind::PermissionDenied, "the path leads outside of the files directory");
+    let path = confine_path(root, path).ok_or_else(outside)?;
+    fs::create_dir_all(root)?;
+    let root = root.canonicalize()?;
+    let dir = path.parent().ok_or_else(outside)?.canonicalize()?;
+    if !dir.starts_with(&root) {
+        return Err(outside());
+    }
+    let name = path.file_name().ok_or_else(outside)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+    options.open(dir.join(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numbers() {
+        let all = Syscall::all();
+        assert_eq!(all.len(), 12);
+        for syscall in all {
+            assert_eq!(Syscall::from_number(syscall.number()), Some(syscall));
+        }
+        assert_eq!(Syscall::from_number(12), None);
+    }
+
+    #[test]
+    fn test_confine_path() {
+        let root = Path::new("/var/lib/iridium");
+        assert_eq!(confine_path(root, "logs/out.txt"), Some(root.join("logs/out.txt")));
+        assert_eq!(confine_path(root, "./logs/./out.txt"), Some(root.join("logs/out.txt")));
+        assert_eq!(confine_path(root, "."), None);
+        assert_eq!(confine_path(root, "../etc/passwd"), None);
+        assert_eq!(confine_path(root, "logs/../../etc/passwd"), None);
+        assert_eq!(confine_path(root, "/etc/passwd"), None);
+        assert_eq!(confine_path(root, ""), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_open_confined_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = std::env::temp_dir().join(format!("iridium-confine-test-{}", std::process::id()));
+        let root = dir.join(FILES_DIRNAME);
+        fs::create_dir_all(root.join("logs")).unwrap();
+        fs::write(dir.join("users"), "alice:secret").unwrap();
+        symlink(dir.join("users"), root.join("users")).unwrap();
+        symlink(&dir, root.join("up")).unwrap();
+        symlink(root.join("logs"), root.join("inside")).unwrap();
+
+        assert!(open_confined(&root, "users", OpenOptions::new().read(true)).is_err());
+        assert!(open_confined(&root, "up/users", OpenOptions::new().read(true)).is_err());
+        assert!(open_confined(&root, "users", OpenOptions::new().write(true).truncate(true)).is_err());
+        assert_eq!(fs::read_to_string(dir.join("users")).unwrap(), "alice:secret");
+        // A link to a directory inside the root is fine
+        assert!(open_confined(&root, "inside/out.txt", OpenOptions::new().write(true).create(true)).is_ok());
+        assert!(root.join("logs/out.txt").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
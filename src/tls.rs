@@ -0,0 +1,445 @@
+//! TLS for remote access and cluster connections. Remote access users log in with a password, so only the
+//! node presents a certificate. Nodes in a cluster have to present one to each other, signed by the cluster's
+//! CA, which is how they know who they are talking to.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{self, SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use ring::digest;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection};
+use rustls_pemfile;
+
+use hex::to_hex;
+
+/// How long the other end has to complete a handshake, so a connection that never does doesn't tie up a thread
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificates and keys for a node, loaded once so problems with them are found at startup
+#[derive(Clone)]
+pub struct TlsConfig {
+    /// For remote access, where users don't present a certificate
+    user_server: Arc<ServerConfig>,
+    /// For the cluster, where connecting nodes have to present one signed by the CA
+    node_server: Arc<ServerConfig>,
+    /// For connecting to other nodes
+    node_client: Arc<ClientConfig>,
+    /// Whether each node has a certificate of its own, signed by a CA, rather than sharing one
+    node_identities: bool,
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("TlsConfig")
+    }
+}
+
+impl TlsConfig {
+    /// Loads the node's certificate chain and private key from PEM files. Other nodes' certificates have to be
+    /// signed by the CA in `ca`, or without one, be the same certificate, as when a cluster shares a self-signed
+    /// one.
+    pub fn load(cert: &Path, key: &Path, ca: Option<&Path>) -> io::Result<TlsConfig> {
+        let certs = read_certs(cert)?;
+        let key = read_key(key)?;
+        let mut roots = RootCertStore::empty();
+        for root in read_certs(ca.unwrap_or(cert))? {
+            roots.add(root).map_err(invalid_data)?;
+        }
+        let roots = Arc::new(roots);
+        let provider = Arc::new(ring::default_provider());
+
+        let user_server = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_no_client_auth()
+            .with_single_cert(certs.clone(), key.clone_key())
+            .map_err(invalid_data)?;
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots.clone(), provider.clone())
+            .build()
+            .map_err(invalid_data)?;
+        let node_server = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs.clone(), key.clone_key())
+            .map_err(invalid_data)?;
+        let node_client = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key)
+            .map_err(invalid_data)?;
+
+        Ok(TlsConfig {
+            user_server: Arc::new(user_server),
+            node_server: Arc::new(node_server),
+            node_client: Arc::new(node_client),
+            node_identities: ca.is_some(),
+        })
+    }
+
+    /// Whether each node's certificate names it, so a node can be held to the alias it claims
+    pub fn node_identities(&self) -> bool {
+        self.node_identities
+    }
+}
+
+fn invalid_data<E: fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn read_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid_data(format!("No certificates in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match rustls_pemfile::private_key(&mut reader)? {
+        Some(key) => Ok(key),
+        None => Err(invalid_data(format!("No private key in {}", path.display()))),
+    }
+}
+
+/// Accepts a remote access user's connection, over TLS if there is a config
+pub fn accept_user(socket: TcpStream, tls: Option<&TlsConfig>) -> io::Result<Stream> {
+    match tls {
+        Some(tls) => TlsStream::handshake(
+            socket,
+            Connection::Server(ServerConnection::new(tls.user_server.clone()).map_err(invalid_data)?),
+        ),
+        None => Ok(Stream::Plain(socket)),
+    }
+}
+
+/// Accepts another node's connection, over TLS if there is a config, in which case the node has to present a
+/// certificate
+pub fn accept_node(socket: TcpStream, tls: Option<&TlsConfig>) -> io::Result<Stream> {
+    match tls {
+        Some(tls) => TlsStream::handshake(
+            socket,
+            Connection::Server(ServerConnection::new(tls.node_server.clone()).map_err(invalid_data)?),
+        ),
+        None => Ok(Stream::Plain(socket)),
+    }
+}
+
+/// Connects to the node at `addr`, given as `host:port`, over TLS if there is a config. Its certificate has to
+/// be for `host`.
+pub fn connect_node(addr: &str, tls: Option<&TlsConfig>) -> io::Result<Stream> {
+    let socket = TcpStream::connect(addr)?;
+    secure_node(socket, addr, tls, HANDSHAKE_TIMEOUT)
+}
+
+/// Connects to the node at `addr` as `connect_node` does, but gives up if connecting, or the handshake, takes
+/// longer than `timeout`
+pub fn connect_node_within(addr: &str, tls: Option<&TlsConfig>, timeout: Duration) -> io::Result<Stream> {
+    let socket_addr = match addr.to_socket_addrs()?.next() {
+        Some(socket_addr) => socket_addr,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", addr))),
+    };
+    let socket = TcpStream::connect_timeout(&socket_addr, timeout)?;
+    secure_node(socket, addr, tls, timeout)
+}
+
+fn secure_node(socket: TcpStream, addr: &str, tls: Option<&TlsConfig>, timeout: Duration) -> io::Result<Stream> {
+    match tls {
+        Some(tls) => {
+            let host = match addr.rfind(':') {
+                Some(colon) => &addr[..colon],
+                None => addr,
+            };
+            let name = ServerName::try_from(host.trim_start_matches('[').trim_end_matches(']').to_string()).map_err(invalid_data)?;
+            let connection = ClientConnection::new(tls.node_client.clone(), name).map_err(invalid_data)?;
+            TlsStream::handshake_within(socket, Connection::Client(connection), timeout)
+        }
+        None => Ok(Stream::Plain(socket)),
+    }
+}
+
+/// Checks the node at the other end of `stream` may call itself `alias`. With a CA, a node's certificate has to
+/// name its alias as a DNS name, so one node can't pass itself off as another. Without TLS, or when the cluster
+/// shares one certificate, which tells nodes apart no better than their aliases do, any alias goes.
+pub fn verify_node_alias(stream: &Stream, alias: &str, tls: Option<&TlsConfig>) -> io::Result<()> {
+    match tls {
+        Some(tls) if tls.node_identities => {}
+        _ => return Ok(()),
+    }
+    let denied = || io::Error::new(io::ErrorKind::PermissionDenied, format!("the node's certificate isn't for {}", alias));
+    let certificate = stream.peer_certificate().ok_or_else(denied)?;
+    let certificate = webpki::EndEntityCert::try_from(&certificate).map_err(|_| denied())?;
+    let name = ServerName::try_from(alias).map_err(|_| denied())?;
+    certificate.verify_is_valid_for_subject_name(&name).map_err(|_| denied())
+}
+
+/// A connection that may or may not be encrypted. Like a `TcpStream`, it can be cloned to read on one thread
+/// and write on another.
+#[derive(Debug)]
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match *self {
+            Stream::Plain(ref socket) => Ok(Stream::Plain(socket.try_clone()?)),
+            Stream::Tls(ref stream) => Ok(Stream::Tls(stream.try_clone()?)),
+        }
+    }
+
+    /// Closes the connection, telling the other end first if it is encrypted
+    pub fn shutdown(&self) -> io::Result<()> {
+        if let Stream::Tls(ref stream) = *self {
+            stream.close_notify();
+        }
+        self.socket().shutdown(net::Shutdown::Both)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket().set_read_timeout(timeout)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket().local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket().peer_addr()
+    }
+
+    /// The SHA-256 fingerprint of the certificate the other end presented, in hex
+    pub fn peer_fingerprint(&self) -> Option<String> {
+        let certificate = self.peer_certificate()?;
+        Some(to_hex(digest::digest(&digest::SHA256, certificate.as_ref()).as_ref()))
+    }
+
+    /// The certificate the other end presented, if it did
+    fn peer_certificate(&self) -> Option<CertificateDer<'static>> {
+        match *self {
+            Stream::Plain(_) => None,
+            Stream::Tls(ref stream) => {
+                let connection = stream.connection.lock().ok()?;
+                connection.peer_certificates()?.first().cloned()
+            }
+        }
+    }
+
+    fn socket(&self) -> &TcpStream {
+        match *self {
+            Stream::Plain(ref socket) => socket,
+            Stream::Tls(ref stream) => &stream.socket,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut socket) => socket.read(buf),
+            Stream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut socket) => socket.write(buf),
+            Stream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut socket) => socket.flush(),
+            Stream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+/// An encrypted connection. Clones share the TLS state, but only lock it while they process what has been
+/// received or encrypt what they are sending, so one can wait to read while another writes.
+pub struct TlsStream {
+    socket: TcpStream,
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl fmt::Debug for TlsStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsStream").field("socket", &self.socket).finish()
+    }
+}
+
+impl TlsStream {
+    fn handshake(socket: TcpStream, connection: Connection) -> io::Result<Stream> {
+        TlsStream::handshake_within(socket, connection, HANDSHAKE_TIMEOUT)
+    }
+
+    fn handshake_within(mut socket: TcpStream, mut connection: Connection, timeout: Duration) -> io::Result<Stream> {
+        socket.set_read_timeout(Some(timeout))?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut socket)?;
+        }
+        socket.set_read_timeout(None)?;
+        Ok(Stream::Tls(TlsStream {
+            socket,
+            connection: Arc::new(Mutex::new(connection)),
+        }))
+    }
+
+    fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            socket: self.socket.try_clone()?,
+            connection: self.connection.clone(),
+        })
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, Connection>> {
+        self.connection.lock().map_err(|_| io::Error::other("TLS connection lock poisoned"))
+    }
+
+    /// Sends whatever the TLS state has waiting to go out
+    fn send_pending(&self, connection: &mut Connection) -> io::Result<()> {
+        let mut socket = &self.socket;
+        while connection.wants_write() {
+            connection.write_tls(&mut socket)?;
+        }
+        Ok(())
+    }
+
+    fn close_notify(&self) {
+        if let Ok(mut connection) = self.lock() {
+            connection.send_close_notify();
+            let _ = self.send_pending(&mut connection);
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = [0u8; 4096];
+        loop {
+            {
+                let mut connection = self.lock()?;
+                match connection.reader().read(buf) {
+                    Ok(n) => return Ok(n),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            let received = (&self.socket).read(&mut incoming)?;
+            if received == 0 {
+                return Ok(0);
+            }
+            let mut connection = self.lock()?;
+            let mut data = &incoming[..received];
+            while !data.is_empty() {
+                connection.read_tls(&mut data)?;
+                connection.process_new_packets().map_err(invalid_data)?;
+            }
+            self.send_pending(&mut connection)?;
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut connection = self.lock()?;
+        connection.writer().write_all(buf)?;
+        self.send_pending(&mut connection)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&self.socket).flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls").join(name)
+    }
+
+    fn node_config() -> TlsConfig {
+        TlsConfig::load(&fixture("node.pem"), &fixture("node-key.pem"), Some(&fixture("ca.pem"))).unwrap()
+    }
+
+    #[test]
+    fn test_node_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut stream = accept_node(socket, Some(&node_config())).unwrap();
+            assert!(stream.peer_fingerprint().is_some());
+            let mut line = [0u8; 5];
+            stream.read_exact(&mut line).unwrap();
+            stream.write_all(&line).unwrap();
+        });
+
+        let mut stream = connect_node(&addr.to_string(), Some(&node_config())).unwrap();
+        let mut reader = stream.try_clone().unwrap();
+        stream.write_all(b"hello").unwrap();
+        let mut echoed = [0u8; 5];
+        reader.read_exact(&mut echoed).unwrap();
+        assert_eq!(&echoed, b"hello");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_unknown_node_rejected() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            assert!(accept_node(socket, Some(&node_config())).is_err());
+        });
+
+        // Signed by itself rather than the cluster's CA
+        let stranger = TlsConfig::load(&fixture("stranger.pem"), &fixture("stranger-key.pem"), Some(&fixture("ca.pem"))).unwrap();
+        let _ = connect_node(&addr.to_string(), Some(&stranger)).and_then(|mut s| s.write_all(b"hello"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_verify_node_alias() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let config = node_config();
+            let stream = accept_node(socket, Some(&config)).unwrap();
+            assert!(verify_node_alias(&stream, "node", Some(&config)).is_ok());
+            assert!(verify_node_alias(&stream, "stranger", Some(&config)).is_err());
+            assert!(verify_node_alias(&stream, "not a name", Some(&config)).is_err());
+            // A shared certificate can't tell nodes apart
+            let shared = TlsConfig::load(&fixture("node.pem"), &fixture("node-key.pem"), None).unwrap();
+            assert!(verify_node_alias(&stream, "stranger", Some(&shared)).is_ok());
+        });
+        let stream = connect_node(&addr.to_string(), Some(&node_config())).unwrap();
+        server.join().unwrap();
+        drop(stream);
+    }
+
+    #[test]
+    fn test_load_errors() {
+        assert!(TlsConfig::load(&fixture("missing.pem"), &fixture("node-key.pem"), None).is_err());
+        assert!(TlsConfig::load(&fixture("node-key.pem"), &fixture("node-key.pem"), None).is_err());
+        assert!(TlsConfig::load(&fixture("node.pem"), &fixture("node.pem"), None).is_err());
+    }
+}
@@ -1,21 +1,34 @@
 //! Contains the core VM struct that executes bytecode
 
 use std;
-use std::io::Cursor;
-use std::net::SocketAddr;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use byteorder::*;
 use chrono::prelude::*;
 use num_cpus;
+use rand;
 use uuid::Uuid;
 
-use assembler::{PIE_HEADER_LENGTH, PIE_HEADER_PREFIX};
+use assembler::{is_pie, PIE_HEADER_LENGTH, PIE_HEADER_PREFIX};
 use cluster;
 use cluster::manager::Manager;
+use console::Console;
 use instruction::Opcode;
+use native::{NativeFunction, NativeRegistry, NativeResult};
+use policy::{Capability, Policy};
 use std::f64::EPSILON;
+use syscall::{open_confined, FileTable, Syscall, FILES_DIRNAME};
+use tls::TlsConfig;
+use vm_errors::VMError;
 
 #[derive(Clone, Debug)]
 /// Enum for various types of events that can happen to the VM
@@ -49,21 +62,30 @@ pub const DEFAULT_HEAP_STARTING_SIZE: usize = 64;
 /// Default stack starting space. We'll default to 2MB.
 pub const DEFAULT_STACK_SPACE: usize = 2097152;
 
+/// Limits on what a program may use. `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// How many instructions the program may execute before it is stopped
+    pub max_instructions: Option<u64>,
+    /// How many bytes the heap may grow to with `ALOC`
+    pub max_heap_bytes: Option<usize>,
+}
+
 /// Virtual machine struct that will execute bytecode
 #[derive(Default, Clone)]
 pub struct VM {
     /// Array that simulates having hardware registers
-    pub registers: [i32; 32],
+    registers: [i32; 32],
     /// Array that simulates having floating point hardware registers
-    pub float_registers: [f64; 32],
+    float_registers: [f64; 32],
     /// The bytecode of the program being run
-    pub program: Vec<u8>,
+    program: Vec<u8>,
     /// Number of logical cores the system reports
-    pub logical_cores: usize,
+    logical_cores: usize,
     /// An alias that can be specified by the user and used to refer to the Node
-    pub alias: Option<String>,
+    alias: Option<String>,
     /// Data structure to manage remote clients
-    pub connection_manager: Arc<RwLock<Manager>>,
+    connection_manager: Arc<RwLock<Manager>>,
     /// Program counter that tracks which byte is being executed
     pc: usize,
     /// Keeps track of where in the stack the program currently is
@@ -83,13 +105,42 @@ pub struct VM {
     /// Contains the read-only section data
     ro_data: Vec<u8>,
     /// Is a unique, randomly generated UUID for identifying this VM
-    pub id: Uuid,
+    id: Uuid,
     /// Keeps a list of events for a particular VM
     events: Vec<VMEvent>,
     /// Server address that the VM will bind to for server-to-server communications
     server_addr: Option<String>,
     /// Port the server will bind to for server-to-server communications
-    pub server_port: Option<String>,
+    server_port: Option<String>,
+    /// Certificates for encrypting connections to and from other nodes. Without them, those connections are
+    /// in plaintext.
+    cluster_tls: Option<TlsConfig>,
+    /// Rust functions the program may call with `CALLN`
+    natives: NativeRegistry,
+    /// The native functions the program imports, in the order of its import table. `CALLN` operands index into this.
+    imported_natives: Vec<NativeFunction>,
+    /// The error that stopped the VM, if it crashed
+    error: Option<VMError>,
+    /// Limits on how much the program may do
+    limits: Limits,
+    /// How many instructions have been executed since the program started
+    executed: u64,
+    /// Where `PRTS` writes to and `READ` reads from
+    console: Console,
+    /// What the program is allowed to do outside of the VM
+    policy: Policy,
+    /// The directory files opened with system calls are relative to. Without one, no files can be opened.
+    data_root: Option<PathBuf>,
+    /// Arguments given to the program
+    args: Vec<String>,
+    /// Files the program has opened with system calls
+    files: FileTable,
+    /// When the program started, for the monotonic clock
+    started_at: Option<Instant>,
+    /// Set by the host to stop the program before its next instruction
+    interrupt: Option<Arc<AtomicBool>>,
+    /// Numbers the next membership change the program is to be given by the `ClusterEvent` system call
+    cluster_events_seen: u64,
 }
 
 impl VM {
@@ -115,39 +166,125 @@ impl VM {
             logical_cores: num_cpus::get(),
             server_addr: None,
             server_port: None,
+            cluster_tls: None,
+            natives: NativeRegistry::new(),
+            imported_natives: vec![],
+            error: None,
+            limits: Limits::default(),
+            executed: 0,
+            console: Console::new(),
+            policy: Policy::permissive(),
+            data_root: None,
+            args: vec![],
+            files: FileTable::default(),
+            started_at: None,
+            interrupt: None,
+            cluster_events_seen: 0,
         }
     }
 
     /// Wraps execution in a loop so it will continue to run until done or there is an error
     /// executing instructions.
     pub fn run(&mut self) -> Vec<VMEvent> {
-        self.events.push(VMEvent {
-            event: VMEventType::Start,
-            at: Utc::now(),
-            application_id: self.id,
-        });
-        // TODO: Should setup custom errors here
-        if !self.verify_header() {
-            self.events.push(VMEvent {
-                event: VMEventType::Crash { code: 1 },
-                at: Utc::now(),
-                application_id: self.id,
-            });
-            error!("Header was incorrect");
-            return self.events.clone();
+        if self.start().is_ok() {
+            while self.step().is_none() {}
         }
+        self.events.clone()
+    }
 
-        self.pc = 68 + self.get_starting_offset();
-        let mut is_done = None;
-        while is_done.is_none() {
-            is_done = self.execute_instruction();
+    /// Checks the program's header and readies it to run from its first instruction. If the program can't be
+    /// run, the VM records a `Crash` event and the error.
+    pub fn start(&mut self) -> Result<(), VMError> {
+        self.push_event(VMEventType::Start);
+        self.error = None;
+        self.executed = 0;
+        let result = self.load_program();
+        if let Err(ref e) = result {
+            error!("Unable to start the program: {}", e);
+            self.error = Some(e.clone());
+            self.push_event(VMEventType::Crash { code: e.code() });
         }
+        result
+    }
+
+    /// Executes the next instruction of a program readied with `start`. Once the program stops, returns its stop
+    /// code, which is also recorded in the last event.
+    pub fn step(&mut self) -> Option<u32> {
+        let interrupted = self.interrupt.as_ref().is_some_and(|i| i.load(Ordering::SeqCst));
+        let is_done = match self.limits.max_instructions {
+            _ if interrupted => {
+                self.error = Some(VMError::Interrupted);
+                Some(1)
+            }
+            Some(limit) if self.executed >= limit => {
+                self.error = Some(VMError::InstructionLimitExceeded { limit });
+                Some(1)
+            }
+            _ => {
+                self.executed += 1;
+                self.execute_instruction()
+            }
+        };
+        let code = is_done?;
+        let event = match self.error {
+            Some(ref e) => VMEventType::Crash { code: e.code() },
+            None => VMEventType::GracefulStop { code },
+        };
+        let code = event.stop_code();
+        self.push_event(event);
+        Some(code)
+    }
+
+    fn push_event(&mut self, event: VMEventType) {
         self.events.push(VMEvent {
-            event: VMEventType::GracefulStop { code: is_done.unwrap() },
+            event,
             at: Utc::now(),
             application_id: self.id,
         });
-        self.events.clone()
+    }
+
+    /// Loads the read-only section and native imports, and points the program counter at the code
+    fn load_program(&mut self) -> Result<(), VMError> {
+        if !self.verify_header() {
+            return Err(VMError::InvalidHeader);
+        }
+        let ro_start = PIE_HEADER_LENGTH + 4;
+        let ro_length = self.get_starting_offset();
+        // The table of imported native functions sits between the read-only section and the code
+        let natives_start = ro_start + ro_length;
+        let natives_length = self.get_natives_length();
+        if natives_start + natives_length > self.program.len() {
+            return Err(VMError::InvalidHeader);
+        }
+        self.ro_data = self.program[ro_start..natives_start].to_vec();
+        self.import_natives(natives_start, natives_length)?;
+        self.pc = natives_start + natives_length;
+        self.started_at = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Every event recorded so far
+    pub fn events(&self) -> &[VMEvent] {
+        &self.events
+    }
+
+    /// Returns the error that crashed the VM, if any
+    pub fn error(&self) -> Option<&VMError> {
+        self.error.as_ref()
+    }
+
+    /// Makes a Rust function callable from bytecode with `CALLN @name`. See `NativeRegistry::register`.
+    pub fn register_native<F>(&mut self, name: &str, arity: u8, function: F)
+    where
+        F: Fn(&[i32]) -> NativeResult + Send + Sync + 'static,
+    {
+        self.natives.register(name, arity, function);
+    }
+
+    /// Replaces every registered native function with the ones in `natives`
+    pub fn with_natives(mut self, natives: NativeRegistry) -> Self {
+        self.natives = natives;
+        self
     }
 
     /// Creates a VM with a specific alias
@@ -160,6 +297,12 @@ impl VM {
         self
     }
 
+    /// Shares the cluster membership of another VM, such as the node's, so the program is told how it changes
+    pub fn with_connection_manager(mut self, connection_manager: Arc<RwLock<Manager>>) -> Self {
+        self.connection_manager = connection_manager;
+        self
+    }
+
     /// Binds a VM to a specific address and port so it can use the network
     pub fn with_cluster_bind(mut self, server_addr: String, server_port: String) -> Self {
         debug!("Binding VM to {}:{}", server_addr, server_port);
@@ -168,6 +311,160 @@ impl VM {
         self
     }
 
+    /// Encrypts connections to and from other nodes with TLS, with each node presenting its certificate
+    pub fn with_cluster_tls(mut self, tls: TlsConfig) -> Self {
+        self.cluster_tls = Some(tls);
+        self
+    }
+
+    /// Sets the number of logical cores the VM may use
+    pub fn with_logical_cores(mut self, logical_cores: usize) -> Self {
+        self.logical_cores = logical_cores;
+        self
+    }
+
+    /// Sets limits on what programs run by the VM may use
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets where the program's output goes and its input comes from, instead of stdout and stdin
+    pub fn with_console(mut self, console: Console) -> Self {
+        self.console = console;
+        self
+    }
+
+    pub fn console(&self) -> &Console {
+        &self.console
+    }
+
+    /// Sets what the program may do outside of the VM. By default it may do anything.
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    /// Checks the policy allows the program to use `capability`, recording it in the audit log if not
+    pub fn check_capability(&self, capability: Capability) -> Result<(), VMError> {
+        self.policy.check(&format!("VM {}", self.id), capability)
+    }
+
+    /// Sets the directory the program may open files in
+    pub fn with_data_root(mut self, data_root: PathBuf) -> Self {
+        self.data_root = Some(data_root);
+        self
+    }
+
+    /// Stops the program before its next instruction once `interrupt` is set, such as when the node shuts down
+    pub fn with_interrupt(mut self, interrupt: Arc<AtomicBool>) -> Self {
+        self.interrupt = Some(interrupt);
+        self
+    }
+
+    /// Sets the arguments the program can read with system calls
+    pub fn with_args(mut self, args: Vec<String>) -> Self {
+        self.args = args;
+        self
+    }
+
+    pub fn registers(&self) -> &[i32; 32] {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut [i32; 32] {
+        &mut self.registers
+    }
+
+    pub fn float_registers(&self) -> &[f64; 32] {
+        &self.float_registers
+    }
+
+    pub fn float_registers_mut(&mut self) -> &mut [f64; 32] {
+        &mut self.float_registers
+    }
+
+    /// Offset in the program of the next instruction to run
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    pub fn bp(&self) -> usize {
+        self.bp
+    }
+
+    pub fn stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    pub fn equal_flag(&self) -> bool {
+        self.equal_flag
+    }
+
+    pub fn remainder(&self) -> usize {
+        self.remainder
+    }
+
+    pub fn loop_counter(&self) -> usize {
+        self.loop_counter
+    }
+
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    /// The heap, to change what is in it. Only `ALOC` changes its size.
+    pub fn heap_mut(&mut self) -> &mut [u8] {
+        &mut self.heap
+    }
+
+    /// The read-only section of the program being run, once it has started
+    pub fn ro_data(&self) -> &[u8] {
+        &self.ro_data
+    }
+
+    /// The bytecode of the program being run
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    pub fn clear_program(&mut self) {
+        self.program.clear();
+    }
+
+    pub fn logical_cores(&self) -> usize {
+        self.logical_cores
+    }
+
+    pub fn alias(&self) -> Option<&str> {
+        self.alias.as_deref()
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn server_port(&self) -> Option<&str> {
+        self.server_port.as_deref()
+    }
+
+    pub fn cluster_tls(&self) -> Option<&TlsConfig> {
+        self.cluster_tls.as_ref()
+    }
+
+    /// Returns a handle to the manager of the VM's cluster membership
+    pub fn connection_manager(&self) -> Arc<RwLock<Manager>> {
+        self.connection_manager.clone()
+    }
+
     /// Executes one instruction. Meant to allow for more controlled execution of the VM
     pub fn run_once(&mut self) {
         self.execute_instruction();
@@ -186,99 +483,126 @@ impl VM {
     /// Executes an instruction and returns a bool. Meant to be called by the various public run
     /// functions.
     fn execute_instruction(&mut self) -> Option<u32> {
-        if self.pc >= self.program.len() {
+        // Every instruction is four bytes, so running into a partial one is the same as running off the end
+        if self.pc + 4 > self.program.len() {
             return Some(1);
         }
+        match self.execute_opcode() {
+            Ok(done) => done,
+            Err(e) => {
+                error!("Program crashed: {}", e);
+                self.error = Some(e);
+                Some(1)
+            }
+        }
+    }
+
+    /// Decodes and executes the instruction at `pc`. Returns the stop code once the program stops, or the
+    /// error that crashed it.
+    fn execute_opcode(&mut self) -> Result<Option<u32>, VMError> {
         match self.decode_opcode() {
             Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
+                let register = self.next_register()?;
                 let number = i32::from(self.next_16_bits());
                 self.registers[register] = number;
             }
             Opcode::ADD => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 + register2;
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register1.wrapping_add(register2);
             }
             Opcode::SUB => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 - register2;
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register1.wrapping_sub(register2);
             }
             Opcode::MUL => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 * register2;
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register1.wrapping_mul(register2);
             }
             Opcode::DIV => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 / register2;
-                self.remainder = (register1 % register2) as usize;
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                if register2 == 0 {
+                    return Err(VMError::DivisionByZero);
+                }
+                self.registers[self.next_register()?] = register1.wrapping_div(register2);
+                self.remainder = register1.wrapping_rem(register2) as usize;
             }
             Opcode::HLT => {
                 info!("HLT encountered");
-                return Some(0);
+                return Ok(Some(0));
+            }
+            Opcode::EXIT => {
+                let code = self.registers[self.next_register()?];
+                info!("EXIT encountered with code {}", code);
+                return Ok(Some(code as u32));
             }
             Opcode::IGL => {
                 error!("Illegal instruction encountered");
-                return Some(1);
+                return Ok(Some(1));
             }
             Opcode::JMP => {
-                let target = self.registers[self.next_8_bits() as usize];
+                let target = self.registers[self.next_register()?];
                 self.pc = target as usize;
             }
             Opcode::JMPF => {
-                let value = self.registers[self.next_8_bits() as usize] as usize;
-                self.pc += value;
+                let value = self.registers[self.next_register()?] as usize;
+                self.pc = self.pc.saturating_add(value);
             }
             Opcode::JMPB => {
-                let value = self.registers[self.next_8_bits() as usize] as usize;
-                self.pc -= value;
+                let offset = self.registers[self.next_register()?] as usize;
+                match self.pc.checked_sub(offset) {
+                    Some(pc) => self.pc = pc,
+                    None => return Err(VMError::JumpOutOfRange { offset }),
+                }
             }
             Opcode::EQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
                 self.equal_flag = register1 == register2;
                 self.next_8_bits();
             }
             Opcode::NEQ => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
                 self.equal_flag = register1 != register2;
                 self.next_8_bits();
             }
             Opcode::GT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
                 self.equal_flag = register1 > register2;
                 self.next_8_bits();
             }
             Opcode::GTE => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
                 self.equal_flag = register1 >= register2;
                 self.next_8_bits();
             }
             Opcode::LT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
                 self.equal_flag = register1 < register2;
                 self.next_8_bits();
             }
             Opcode::LTE => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
                 self.equal_flag = register1 <= register2;
                 self.next_8_bits();
             }
             Opcode::JMPE => {
                 if self.equal_flag {
-                    let register = self.next_8_bits() as usize;
+                    let register = self.next_register()?;
                     let target = self.registers[register];
                     self.pc = target as usize;
                 } else {
-                    // TODO: Fix the bits
+                    self.next_8_bits();
+                    self.next_8_bits();
+                    self.next_8_bits();
                 }
             }
             Opcode::NOP => {
@@ -287,23 +611,52 @@ impl VM {
                 self.next_8_bits();
             }
             Opcode::ALOC => {
-                let register = self.next_8_bits() as usize;
+                let register = self.next_register()?;
+                self.next_8_bits();
+                self.next_8_bits();
                 let bytes = self.registers[register];
-                let new_end = self.heap.len() as i32 + bytes;
+                // A negative size shrinks the heap, but not past its start
+                let new_end = self.heap.len() as i64 + i64::from(bytes);
+                if new_end < 0 {
+                    return Err(VMError::HeapOffsetOutOfRange { offset: bytes });
+                }
+                if let Some(limit) = self.limits.max_heap_bytes {
+                    if new_end as usize > limit {
+                        return Err(VMError::HeapLimitExceeded { limit });
+                    }
+                }
                 self.heap.resize(new_end as usize, 0);
             }
             Opcode::INC => {
-                let register_number = self.next_8_bits() as usize;
-                self.registers[register_number] += 1;
+                let register_number = self.next_register()?;
+                self.registers[register_number] = self.registers[register_number].wrapping_add(1);
                 self.next_8_bits();
                 self.next_8_bits();
             }
             Opcode::DEC => {
-                let register_number = self.next_8_bits() as usize;
-                self.registers[register_number] -= 1;
+                let register_number = self.next_register()?;
+                self.registers[register_number] = self.registers[register_number].wrapping_sub(1);
                 self.next_8_bits();
                 self.next_8_bits();
             }
+            Opcode::DJMP => {
+                let destination = self.next_16_bits();
+                self.pc = destination as usize;
+            }
+            Opcode::DJMPF => {
+                // Relative jumps are measured from the start of the next instruction
+                let offset = self.next_16_bits() as usize;
+                self.next_8_bits();
+                self.pc += offset;
+            }
+            Opcode::DJMPB => {
+                let offset = self.next_16_bits() as usize;
+                self.next_8_bits();
+                match self.pc.checked_sub(offset) {
+                    Some(pc) => self.pc = pc,
+                    None => return Err(VMError::JumpOutOfRange { offset }),
+                }
+            }
             Opcode::DJMPE => {
                 let destination = self.next_16_bits();
                 if self.equal_flag {
@@ -318,85 +671,88 @@ impl VM {
                 // This instruction then reads each byte and prints it, until it comes to a 0x00 byte, which indicates
                 // termination of the string
                 let starting_offset = self.next_16_bits() as usize;
-                let mut ending_offset = starting_offset;
-                let slice = self.ro_data.as_slice();
+                self.next_8_bits();
+                self.check_capability(Capability::Print)?;
                 // TODO: Find a better way to do this. Maybe we can store the byte length and not null terminate? Or some form of caching where we
                 // go through the entire ro_data on VM startup and find every string and its ending byte location?
-                while slice[ending_offset] != 0 {
-                    ending_offset += 1;
-                }
-                let result = std::str::from_utf8(&slice[starting_offset..ending_offset]);
-                match result {
-                    Ok(s) => {
-                        print!("{}", s);
-                    }
-                    Err(e) => println!("Error decoding string for prts instruction: {:#?}", e),
+                let slice = match self.ro_data.get(starting_offset..) {
+                    Some(slice) => slice,
+                    None => return Err(VMError::ReadOnlyOffsetOutOfRange { offset: starting_offset }),
+                };
+                // A string that runs off the end of the section was never terminated
+                let length = match slice.iter().position(|b| *b == 0) {
+                    Some(length) => length,
+                    None => return Err(VMError::ReadOnlyOffsetOutOfRange { offset: self.ro_data.len() }),
+                };
+                match std::str::from_utf8(&slice[..length]) {
+                    Ok(s) => self.console.write(s),
+                    Err(_) => return Err(VMError::InvalidString { offset: starting_offset }),
                 };
             }
             // Begin floating point 64-bit instructions
             Opcode::LOADF64 => {
-                let register = self.next_8_bits() as usize;
+                let register = self.next_register()?;
                 let number = f64::from(self.next_16_bits());
                 self.float_registers[register] = number;
             }
             Opcode::ADDF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
-                self.float_registers[self.next_8_bits() as usize] = register1 + register2;
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
+                self.float_registers[self.next_register()?] = register1 + register2;
             }
             Opcode::SUBF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
-                self.float_registers[self.next_8_bits() as usize] = register1 - register2;
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
+                self.float_registers[self.next_register()?] = register1 - register2;
             }
             Opcode::MULF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
-                self.float_registers[self.next_8_bits() as usize] = register1 * register2;
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
+                self.float_registers[self.next_register()?] = register1 * register2;
             }
             Opcode::DIVF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
-                self.float_registers[self.next_8_bits() as usize] = register1 / register2;
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
+                self.float_registers[self.next_register()?] = register1 / register2;
             }
             Opcode::EQF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
                 self.equal_flag = (register1 - register2).abs() < EPSILON;
                 self.next_8_bits();
             }
             Opcode::NEQF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
                 self.equal_flag = (register1 - register2).abs() > EPSILON;
                 self.next_8_bits();
             }
             Opcode::GTF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
                 self.equal_flag = register1 > register2;
                 self.next_8_bits();
             }
             Opcode::GTEF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
                 self.equal_flag = register1 >= register2;
                 self.next_8_bits();
             }
             Opcode::LTF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
                 self.equal_flag = register1 < register2;
                 self.next_8_bits();
             }
             Opcode::LTEF64 => {
-                let register1 = self.float_registers[self.next_8_bits() as usize];
-                let register2 = self.float_registers[self.next_8_bits() as usize];
+                let register1 = self.float_registers[self.next_register()?];
+                let register2 = self.float_registers[self.next_register()?];
                 self.equal_flag = register1 <= register2;
                 self.next_8_bits();
             }
             Opcode::SHL => {
-                let reg_num = self.next_8_bits() as usize;
+                let reg_num = self.next_register()?;
                 let num_bits = match self.next_8_bits() {
                     0 => 16,
                     other => other,
@@ -405,7 +761,7 @@ impl VM {
                 self.next_8_bits();
             }
             Opcode::SHR => {
-                let reg_num = self.next_8_bits() as usize;
+                let reg_num = self.next_register()?;
                 let num_bits = match self.next_8_bits() {
                     0 => 16,
                     other => other,
@@ -414,27 +770,27 @@ impl VM {
                 self.next_8_bits();
             }
             Opcode::AND => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 & register2;
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register1 & register2;
             }
             Opcode::OR => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 | register2;
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register1 | register2;
             }
             Opcode::XOR => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                let register2 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = register1 ^ register2;
+                let register1 = self.registers[self.next_register()?];
+                let register2 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = register1 ^ register2;
             }
             Opcode::NOT => {
-                let register1 = self.registers[self.next_8_bits() as usize];
-                self.registers[self.next_8_bits() as usize] = !register1;
+                let register1 = self.registers[self.next_register()?];
+                self.registers[self.next_register()?] = !register1;
                 self.next_8_bits();
             }
             Opcode::LUI => {
-                let register = self.next_8_bits() as usize;
+                let register = self.next_register()?;
                 let value = self.registers[register];
                 let uv1 = i32::from(self.next_8_bits());
                 let uv2 = i32::from(self.next_8_bits());
@@ -459,30 +815,55 @@ impl VM {
                 self.next_8_bits();
             }
             Opcode::LOADM => {
-                let offset = self.registers[self.next_8_bits() as usize] as usize;
-                let data: i32;
-                // Explicit scoping is necessary here because we do an immutable borrow of self, then a mutable borrow to assign the result
-                {
-                    let mut slice = &self.heap[offset..offset + 4];
-                    data = slice.read_i32::<LittleEndian>().unwrap();
-                }
-                self.registers[self.next_8_bits() as usize] = data;
+                let offset = self.registers[self.next_register()?];
+                let range = match self.heap_range(offset, 4) {
+                    Some(range) => range,
+                    None => return Err(VMError::HeapOffsetOutOfRange { offset }),
+                };
+                let data = LittleEndian::read_i32(&self.heap[range]);
+                self.registers[self.next_register()?] = data;
             }
             Opcode::SETM => {
-                let _offset = self.registers[self.next_8_bits() as usize] as usize;
-                let data = self.registers[self.next_8_bits() as usize];
+                let _offset = self.registers[self.next_register()?] as usize;
+                let data = self.registers[self.next_register()?];
                 let mut buf: [u8; 4] = [0, 0, 0, 0];
                 let _ = buf.as_mut().write_i32::<LittleEndian>(data);
             }
             Opcode::PUSH => {
-                let data = self.registers[self.next_8_bits() as usize];
+                let data = self.registers[self.next_register()?];
                 self.stack.push(data);
                 self.sp = self.sp + 1;
             }
             Opcode::POP => {
-                let target_register = self.next_8_bits() as usize;
-                self.registers[target_register] = self.stack.pop().unwrap();
-                self.sp = self.sp - 1
+                let target_register = self.next_register()?;
+                self.registers[target_register] = self.stack.pop().ok_or(VMError::StackUnderflow)?;
+                self.sp = self.sp.saturating_sub(1)
+            }
+            Opcode::CALLN => {
+                let first_register = self.next_8_bits() as usize;
+                let index = self.next_16_bits();
+                if let Err(e) = self.check_capability(Capability::Native).and_then(|_| self.call_native(first_register, index)) {
+                    error!("Native call failed: {}", e);
+                    return Err(e);
+                }
+            }
+            Opcode::READ => {
+                let register = self.next_register()?;
+                self.next_8_bits();
+                self.next_8_bits();
+                self.check_capability(Capability::Input)?;
+                // Running out of input, or a line that isn't a number, clears the equal flag so the program can tell
+                let number = self.console.read_line().and_then(|line| line.trim().parse::<i32>().ok());
+                self.equal_flag = number.is_some();
+                self.registers[register] = number.unwrap_or(0);
+            }
+            Opcode::SYSCALL => {
+                let number = self.next_16_bits();
+                self.next_8_bits();
+                if let Err(e) = self.syscall(number) {
+                    error!("System call failed: {}", e);
+                    return Err(e);
+                }
             }
             Opcode::CALL => {
                 // First we capture the return destination for when the function is done
@@ -499,11 +880,181 @@ impl VM {
             }
             Opcode::RET => {
                 self.sp = self.bp;
-                self.bp = self.stack.pop().unwrap() as usize;
-                self.pc = self.stack.pop().unwrap() as usize;
+                self.bp = self.stack.pop().ok_or(VMError::StackUnderflow)? as usize;
+                self.pc = self.stack.pop().ok_or(VMError::StackUnderflow)? as usize;
+            }
+        };
+        Ok(None)
+    }
+
+    /// Calls the imported native function at `index` with the values of the registers from `first_register` on,
+    /// and writes its results back to the registers starting at the same place
+    fn call_native(&mut self, first_register: usize, index: u16) -> Result<(), VMError> {
+        let native = match self.imported_natives.get(index as usize) {
+            Some(native) => native.clone(),
+            None => return Err(VMError::UnknownNativeIndex { index }),
+        };
+        let out_of_range = VMError::NativeRegistersOutOfRange {
+            name: native.name().to_string(),
+        };
+        let args_end = first_register + native.arity() as usize;
+        if args_end > self.registers.len() {
+            return Err(out_of_range);
+        }
+        let results = native
+            .call(&self.registers[first_register..args_end])
+            .map_err(|message| VMError::NativeFailed {
+                name: native.name().to_string(),
+                message,
+            })?;
+        if first_register + results.len() > self.registers.len() {
+            return Err(out_of_range);
+        }
+        self.registers[first_register..first_register + results.len()].copy_from_slice(&results);
+        Ok(())
+    }
+
+    /// Makes the system call with the given number. A call that fails puts -1 in `$0`; only calls the program
+    /// isn't allowed to make, or that don't exist, are errors.
+    fn syscall(&mut self, number: u16) -> Result<(), VMError> {
+        let syscall = match Syscall::from_number(number) {
+            Some(syscall) => syscall,
+            None => return Err(VMError::UnknownSyscall { number }),
+        };
+        self.check_capability(Capability::Syscall(syscall))?;
+        let (a, b, c) = (self.registers[1], self.registers[2], self.registers[3]);
+        let result = match syscall {
+            Syscall::Open => self.open_file(a, b),
+            Syscall::Read => self.read_file(a, b, c),
+            Syscall::Write => self.write_file(a, b, c),
+            Syscall::Close => {
+                if a >= 0 && self.files.remove(a as usize) {
+                    Some(0)
+                } else {
+                    None
+                }
+            }
+            Syscall::MonotonicTime => Some(self.started_at.map_or(0, |t| t.elapsed().as_millis() as i32)),
+            Syscall::WallTime => Some(Utc::now().timestamp() as i32),
+            Syscall::Sleep => {
+                if a >= 0 {
+                    thread::sleep(Duration::from_millis(a as u64));
+                    Some(0)
+                } else {
+                    None
+                }
             }
+            // Never negative, so it can't be mistaken for a failure
+            Syscall::Random => Some((rand::random::<u32>() >> 1) as i32),
+            Syscall::Env => match self.ro_string(a).and_then(|name| env::var(name).ok()) {
+                Some(value) => self.copy_to_heap(value.as_bytes(), b, c),
+                None => None,
+            },
+            Syscall::Argc => Some(self.args.len() as i32),
+            Syscall::Argv => match self.args.get(a as usize).cloned() {
+                Some(arg) if a >= 0 => self.copy_to_heap(arg.as_bytes(), b, c),
+                _ => None,
+            },
+            Syscall::ClusterEvent => self.next_cluster_event(a, b),
         };
-        None
+        self.registers[0] = result.unwrap_or(-1);
+        Ok(())
+    }
+
+    fn open_file(&mut self, path_offset: i32, mode: i32) -> Option<i32> {
+        let root = self.data_root.as_ref()?.join(FILES_DIRNAME);
+        let path = self.ro_string(path_offset)?;
+        let mut options = OpenOptions::new();
+        match mode {
+            0 => options.read(true),
+            1 => options.write(true).create(true).truncate(true),
+            2 => options.append(true).create(true),
+            _ => return None,
+        };
+        match open_confined(&root, &path, &mut options) {
+            Ok(file) => Some(self.files.insert(file) as i32),
+            Err(e) => {
+                debug!("Unable to open file for program: {}", e);
+                None
+            }
+        }
+    }
+
+    fn read_file(&mut self, handle: i32, offset: i32, length: i32) -> Option<i32> {
+        let file = self.file(handle)?;
+        let range = self.heap_range(offset, length)?;
+        let mut file = file.lock().unwrap();
+        file.read(&mut self.heap[range]).ok().map(|read| read as i32)
+    }
+
+    fn write_file(&mut self, handle: i32, offset: i32, length: i32) -> Option<i32> {
+        let file = self.file(handle)?;
+        let range = self.heap_range(offset, length)?;
+        let mut file = file.lock().unwrap();
+        file.write(&self.heap[range]).ok().map(|written| written as i32)
+    }
+
+    fn file(&self, handle: i32) -> Option<Arc<std::sync::Mutex<File>>> {
+        if handle < 0 {
+            return None;
+        }
+        self.files.get(handle as usize)
+    }
+
+    /// The part of the heap `length` bytes long starting at `offset`, if it's all there
+    fn heap_range(&self, offset: i32, length: i32) -> Option<Range<usize>> {
+        if offset < 0 || length < 0 || offset as usize + length as usize > self.heap.len() {
+            return None;
+        }
+        Some(offset as usize..offset as usize + length as usize)
+    }
+
+    fn next_cluster_event(&mut self, offset: i32, max_length: i32) -> Option<i32> {
+        let event = match self.connection_manager.read() {
+            Ok(manager) => manager.next_event(self.cluster_events_seen),
+            Err(_) => return None,
+        };
+        match event {
+            Some(event) => {
+                let length = self.copy_to_heap(event.to_string().as_bytes(), offset, max_length)?;
+                self.cluster_events_seen = event.seq + 1;
+                Some(length)
+            }
+            None => Some(0),
+        }
+    }
+
+    /// Copies as much of `bytes` as fits in `max_length` to the heap at `offset`, and returns how much that was
+    fn copy_to_heap(&mut self, bytes: &[u8], offset: i32, max_length: i32) -> Option<i32> {
+        let length = std::cmp::min(bytes.len(), max_length.max(0) as usize);
+        let range = self.heap_range(offset, length as i32)?;
+        self.heap[range].copy_from_slice(&bytes[..length]);
+        Some(length as i32)
+    }
+
+    /// Reads the null-terminated string at `offset` in the read-only section
+    fn ro_string(&self, offset: i32) -> Option<String> {
+        if offset < 0 {
+            return None;
+        }
+        let bytes = self.ro_data.get(offset as usize..)?;
+        let end = bytes.iter().position(|b| *b == 0)?;
+        String::from_utf8(bytes[..end].to_vec()).ok()
+    }
+
+    /// Looks up each name in the program's import table in the native registry
+    fn import_natives(&mut self, start: usize, length: usize) -> Result<(), VMError> {
+        self.imported_natives.clear();
+        let table = &self.program[start..start + length];
+        // Names are null-terminated, the same as strings in the read-only section
+        for name in table.split(|b| *b == 0).filter(|name| !name.is_empty()) {
+            let name = String::from_utf8_lossy(name);
+            match self.natives.get(&name) {
+                Some(native) => self.imported_natives.push(native.clone()),
+                None => return Err(VMError::UnknownNative { name: name.to_string() }),
+            }
+        }
+        Ok(())
     }
 
     pub fn print_i32_register(&self, register: usize) {
@@ -535,23 +1086,38 @@ impl VM {
         prepension
     }
 
-    pub fn bind_cluster_server(&mut self) {
-        if let Some(ref addr) = self.server_addr {
-            if let Some(ref port) = self.server_port {
-                debug!("Building socket_addr from addr: {} and port: {} and alias: {:?}", addr, port, self.alias);
-                let socket_addr: SocketAddr = (addr.to_string() + ":" + port).parse().unwrap();
-                let clone = self.connection_manager.clone();
-                let alias = self.alias.clone();
-                debug!("Spawning listening thread");
-                thread::spawn(move || {
-                    cluster::server::listen(alias.unwrap(), socket_addr, clone);
-                });
-            } else {
-                error!("Unable to bind to cluster server address: {}", addr);
-            }
-        } else {
-            error!("Unable to bind to cluster server port: {:?}", self.server_port);
+    /// Listens for other nodes on the cluster address and port, and starts probing them once there are any. Does
+    /// nothing if the VM is listening already.
+    pub fn bind_cluster_server(&mut self) -> io::Result<()> {
+        if self.connection_manager.read().unwrap().myself().is_some() {
+            debug!("Already listening for other nodes");
+            return Ok(());
         }
+        let (addr, port) = match (self.server_addr.as_ref(), self.server_port.as_ref()) {
+            (Some(addr), Some(port)) => (addr, port),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no cluster address and port to listen on")),
+        };
+        debug!("Building socket_addr from addr: {} and port: {} and alias: {:?}", addr, port, self.alias);
+        let socket_addr: SocketAddr = (addr.to_string() + ":" + port)
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}:{} is not an address: {}", addr, port, e)))?;
+        let listener = TcpListener::bind(socket_addr)?;
+        let alias = self.alias.clone().unwrap_or_default();
+        self.connection_manager.write().unwrap().start((alias, addr.clone(), port.clone()));
+        let clone = self.connection_manager.clone();
+        let tls = self.cluster_tls.clone();
+        debug!("Spawning listening thread");
+        thread::spawn(move || {
+            cluster::server::listen(listener, clone, tls);
+        });
+        cluster::gossip::start(self.connection_manager.clone(), self.cluster_tls.clone());
+        Ok(())
+    }
+
+    /// Leaves the cluster, telling some of the other nodes so it doesn't have to be detected as failed. Does nothing
+    /// if the VM isn't in one.
+    pub fn leave_cluster(&mut self) {
+        cluster::gossip::leave(&self.connection_manager, self.cluster_tls.as_ref());
     }
 
     fn get_starting_offset(&self) -> usize {
@@ -559,6 +1125,11 @@ impl VM {
         rdr.read_u32::<LittleEndian>().unwrap() as usize
     }
 
+    fn get_natives_length(&self) -> usize {
+        let mut rdr = Cursor::new(&self.program[4..8]);
+        rdr.read_u32::<LittleEndian>().unwrap() as usize
+    }
+
     // Attempts to decode the byte the VM's program counter is pointing at into an opcode
     fn decode_opcode(&mut self) -> Opcode {
         let opcode = Opcode::from(self.program[self.pc]);
@@ -579,6 +1150,15 @@ impl VM {
         result
     }
 
+    /// Grabs the next 8 bits as the number of a register, which has to be one the VM has
+    fn next_register(&mut self) -> Result<usize, VMError> {
+        let register = self.next_8_bits();
+        if register as usize >= self.registers.len() {
+            return Err(VMError::InvalidRegister { register });
+        }
+        Ok(register as usize)
+    }
+
     // Grabs the next 16 bits (2 bytes)
     fn next_16_bits(&mut self) -> u16 {
         let result = ((u16::from(self.program[self.pc])) << 8) | u16::from(self.program[self.pc + 1]);
@@ -588,16 +1168,16 @@ impl VM {
 
     // Processes the header of bytecode the VM is asked to execute
     fn verify_header(&self) -> bool {
-        if self.program[0..4] != PIE_HEADER_PREFIX {
-            return false;
-        }
-        true
+        is_pie(&self.program)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use assembler::Assembler;
+    use cluster::membership::{Member, Membership, NodeState};
+    use console::{MemorySink, MemorySource};
 
     #[test]
     fn test_create_vm() {
@@ -796,6 +1376,46 @@ mod tests {
         assert_eq!(test_vm.pc, 7);
     }
 
+    #[test]
+    fn test_jmpe_opcode_not_equal() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.registers[0] = 7;
+        test_vm.equal_flag = false;
+        test_vm.program = vec![15, 0, 0, 0, 15, 0, 0, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 4);
+    }
+
+    #[test]
+    fn test_djmp_opcode() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![48, 0, 8, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_djmpf_opcode() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![49, 0, 4, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 8);
+    }
+
+    #[test]
+    fn test_djmpb_opcode() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![5, 0, 0, 0, 50, 0, 8, 0];
+        test_vm.pc = 4;
+        test_vm.run_once();
+        assert_eq!(test_vm.pc, 0);
+        // Jumping back past the start crashes the program
+        test_vm.program = vec![50, 0, 9, 0];
+        test_vm.pc = 0;
+        test_vm.run_once();
+        assert_eq!(test_vm.error(), Some(&VMError::JumpOutOfRange { offset: 9 }));
+    }
+
     #[test]
     fn test_aloc_opcode() {
         let mut test_vm = VM::get_test_vm();
@@ -803,6 +1423,7 @@ mod tests {
         test_vm.program = vec![17, 0, 0, 0];
         test_vm.run_once();
         assert_eq!(test_vm.heap.len(), 1024 + DEFAULT_HEAP_STARTING_SIZE);
+        assert_eq!(test_vm.pc, 4);
     }
 
     #[test]
@@ -1074,4 +1695,288 @@ mod tests {
         test_vm.run_once();
         assert_eq!(test_vm.registers[0], 500);
     }
+
+    #[test]
+    fn test_stack_underflow() {
+        let mut test_vm = VM::new();
+        test_vm.program = vec![45, 0, 0, 0];
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+        assert_eq!(test_vm.error(), Some(&VMError::StackUnderflow));
+        let mut test_vm = VM::new();
+        test_vm.program = vec![47, 0, 0, 0];
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+        assert_eq!(test_vm.error(), Some(&VMError::StackUnderflow));
+    }
+
+    #[test]
+    fn test_heap_offset_out_of_range() {
+        let mut test_vm = VM::new();
+        // The last word would run two bytes past the end of the heap
+        test_vm.registers[0] = test_vm.heap.len() as i32 - 2;
+        test_vm.program = vec![42, 0, 1, 0];
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+        assert_eq!(test_vm.error(), Some(&VMError::HeapOffsetOutOfRange { offset: test_vm.registers[0] }));
+        let mut test_vm = VM::new();
+        test_vm.registers[0] = -1;
+        test_vm.program = vec![42, 0, 1, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.error(), Some(&VMError::HeapOffsetOutOfRange { offset: -1 }));
+    }
+
+    #[test]
+    fn test_ro_offset_out_of_range() {
+        let mut test_vm = VM::new();
+        test_vm.ro_data = b"Hi\0".to_vec();
+        test_vm.program = vec![21, 0, 9, 0];
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+        assert_eq!(test_vm.error(), Some(&VMError::ReadOnlyOffsetOutOfRange { offset: 9 }));
+        // A string without its terminator runs off the end of the section
+        let mut test_vm = VM::new();
+        test_vm.ro_data = b"Hi".to_vec();
+        test_vm.program = vec![21, 0, 0, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.error(), Some(&VMError::ReadOnlyOffsetOutOfRange { offset: 2 }));
+    }
+
+    #[test]
+    fn test_prts_invalid_string() {
+        let output = MemorySink::new();
+        let mut test_vm = VM::new().with_console(Console::new().with_output(output.clone()));
+        test_vm.ro_data = vec![b'H', 0xff, 0];
+        test_vm.program = vec![21, 0, 0, 0];
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+        assert_eq!(test_vm.error(), Some(&VMError::InvalidString { offset: 0 }));
+        assert_eq!(output.contents(), "");
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![1, 0, 1, 40];
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+        assert_eq!(test_vm.error(), Some(&VMError::InvalidRegister { register: 40 }));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let mut test_vm = VM::get_test_vm();
+        test_vm.program = vec![4, 0, 2, 3];
+        assert_eq!(test_vm.execute_instruction(), Some(1));
+        assert_eq!(test_vm.error(), Some(&VMError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_prts_and_read_use_console() {
+        let output = MemorySink::new();
+        let console = Console::new().with_output(output.clone()).with_input(MemorySource::new("12\nnope"));
+        let mut test_vm = VM::new().with_console(console);
+        test_vm.ro_data = b"Hi\0".to_vec();
+        test_vm.program = vec![21, 0, 0, 0, 52, 3, 0, 0, 52, 4, 0, 0];
+        test_vm.run_once();
+        assert_eq!(output.contents(), "Hi");
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[3], 12);
+        assert!(test_vm.equal_flag);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[4], 0);
+        assert!(!test_vm.equal_flag);
+    }
+
+    #[test]
+    fn test_exit_opcode() {
+        let program = Assembler::new().assemble(".data\n.code\nload $2 #3\nexit $2\nhlt").unwrap();
+        let mut test_vm = VM::new();
+        test_vm.add_bytes(program);
+        let events = test_vm.run();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].event.stop_code(), 3);
+        assert!(test_vm.error().is_none());
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let interrupt = Arc::new(AtomicBool::new(false));
+        let mut test_vm = VM::new().with_interrupt(interrupt.clone());
+        test_vm.add_bytes(Assembler::new().assemble(".data\n.code\nloop: jmp @loop").unwrap());
+        test_vm.start().unwrap();
+        assert_eq!(test_vm.step(), None);
+        interrupt.store(true, Ordering::SeqCst);
+        assert_eq!(test_vm.step(), Some(VMError::Interrupted.code()));
+        assert_eq!(test_vm.error(), Some(&VMError::Interrupted));
+    }
+
+    #[test]
+    fn test_syscall_opcode() {
+        let mut test_vm = VM::new().with_args(vec!["first".to_string(), "second".to_string()]);
+        test_vm.heap = vec![0; 8];
+        test_vm.started_at = Some(Instant::now());
+        // Argc, then Argv of argument 1 into the heap, then Random, WallTime and MonotonicTime
+        test_vm.program = vec![53, 0, 9, 0, 53, 0, 10, 0, 53, 0, 7, 0, 53, 0, 5, 0, 53, 0, 4, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 2);
+        test_vm.registers[1] = 1;
+        test_vm.registers[2] = 2;
+        test_vm.registers[3] = 4;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 4);
+        assert_eq!(&test_vm.heap, b"\0\0seco\0\0");
+        test_vm.run_once();
+        assert!(test_vm.registers[0] >= 0);
+        test_vm.run_once();
+        assert!(test_vm.registers[0] > 1_500_000_000);
+        test_vm.run_once();
+        assert!(test_vm.registers[0] >= 0);
+        assert!(test_vm.error().is_none());
+
+        // Copying outside of the heap fails
+        test_vm.registers[2] = 6;
+        test_vm.program = vec![53, 0, 10, 0];
+        test_vm.pc = 0;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], -1);
+    }
+
+    #[test]
+    fn test_syscall_files() {
+        let root = std::env::temp_dir().join(format!("iridium-syscall-test-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut test_vm = VM::new().with_data_root(root.clone());
+        test_vm.ro_data = b"out.txt\0../out.txt\0".to_vec();
+        test_vm.heap = b"Hello".to_vec();
+        // Open, Write, Close, then Open and Read
+        test_vm.program = vec![53, 0, 0, 0, 53, 0, 2, 0, 53, 0, 3, 0, 53, 0, 0, 0, 53, 0, 1, 0];
+        test_vm.registers[2] = 1;
+        test_vm.run_once();
+        let handle = test_vm.registers[0];
+        assert!(handle >= 0);
+        test_vm.registers[1] = handle;
+        test_vm.registers[2] = 0;
+        test_vm.registers[3] = 5;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 5);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 0);
+        assert_eq!(std::fs::read_to_string(root.join("files/out.txt")).unwrap(), "Hello");
+
+        test_vm.registers[1] = 0;
+        test_vm.registers[2] = 0;
+        test_vm.run_once();
+        test_vm.registers[1] = test_vm.registers[0];
+        test_vm.registers[2] = 1;
+        test_vm.registers[3] = 4;
+        test_vm.heap = vec![0; 5];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 4);
+        assert_eq!(&test_vm.heap, b"\0Hell");
+
+        // Paths can't lead out of the files directory
+        test_vm.registers[1] = 8;
+        test_vm.program = vec![53, 0, 0, 0];
+        test_vm.pc = 0;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], -1);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_syscall_cluster_event() {
+        let mut membership = Membership::new();
+        let mut node = Member {
+            node: ("node2".to_string(), "127.0.0.1".to_string(), "2254".to_string()),
+            incarnation: 1,
+            state: NodeState::Alive,
+        };
+        membership.apply(&node, Instant::now());
+        node.state = NodeState::Dead;
+        membership.apply(&node, Instant::now());
+        let manager = Arc::new(RwLock::new(Manager::new().with_membership(membership)));
+        let mut test_vm = VM::new().with_connection_manager(manager);
+        test_vm.heap = vec![0; 12];
+        test_vm.program = vec![53, 0, 11, 0, 53, 0, 11, 0, 53, 0, 11, 0];
+        test_vm.registers[2] = 12;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 12);
+        assert_eq!(&test_vm.heap, b"joined node2");
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 10);
+        assert_eq!(&test_vm.heap[..10], b"died node2");
+        // Nothing has happened since
+        test_vm.run_once();
+        assert_eq!(test_vm.registers[0], 0);
+    }
+
+    #[test]
+    fn test_syscall_errors() {
+        let mut test_vm = VM::new().with_policy(Policy::deny_all().allow(Capability::Syscall(Syscall::Argc)));
+        test_vm.program = vec![53, 0, 9, 0, 53, 0, 7, 0];
+        test_vm.run_once();
+        assert!(test_vm.error().is_none());
+        test_vm.run_once();
+        assert_eq!(
+            test_vm.error(),
+            Some(&VMError::CapabilityDenied {
+                capability: Capability::Syscall(Syscall::Random)
+            })
+        );
+
+        let mut test_vm = VM::new();
+        test_vm.program = vec![53, 1, 0, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.error(), Some(&VMError::UnknownSyscall { number: 256 }));
+    }
+
+    #[test]
+    fn test_calln_opcode() {
+        let mut test_vm = VM::new();
+        test_vm.register_native("divmod", 2, |args| Ok(vec![args[0] / args[1], args[0] % args[1]]));
+        test_vm.registers[3] = 17;
+        test_vm.registers[4] = 5;
+        let mut program = Assembler::new().assemble(".data\n.code\ncalln $3 @divmod\nhlt").unwrap();
+        test_vm.add_bytes(program.clone());
+        let events = test_vm.run();
+        assert_eq!(events[1].event.stop_code(), 0);
+        assert_eq!(test_vm.registers[3], 3);
+        assert_eq!(test_vm.registers[4], 2);
+        assert!(test_vm.error().is_none());
+
+        // A program that doesn't use it can still be run with the native registered
+        program = Assembler::new().assemble(".data\n.code\nload $0 #1\nhlt").unwrap();
+        let mut test_vm = VM::new();
+        test_vm.register_native("divmod", 2, |_| Ok(vec![]));
+        test_vm.add_bytes(program);
+        assert_eq!(test_vm.run()[1].event.stop_code(), 0);
+    }
+
+    #[test]
+    fn test_calln_errors() {
+        let program = Assembler::new().assemble(".data\n.code\ncalln $31 @check\nhlt").unwrap();
+
+        let mut test_vm = VM::new();
+        test_vm.add_bytes(program.clone());
+        let events = test_vm.run();
+        assert_eq!(events[1].event.stop_code(), 2);
+        assert_eq!(test_vm.error(), Some(&VMError::UnknownNative { name: "check".to_string() }));
+
+        let mut test_vm = VM::new();
+        test_vm.register_native("check", 1, |_| Err("check failed".to_string()));
+        test_vm.add_bytes(program.clone());
+        let events = test_vm.run();
+        match events[1].event {
+            VMEventType::Crash { code } => assert_eq!(code, 5),
+            _ => panic!("Expected the VM to crash"),
+        }
+        assert_eq!(
+            test_vm.error(),
+            Some(&VMError::NativeFailed {
+                name: "check".to_string(),
+                message: "check failed".to_string(),
+            })
+        );
+
+        let mut test_vm = VM::new();
+        test_vm.register_native("check", 2, |_| Ok(vec![]));
+        test_vm.add_bytes(program);
+        test_vm.run();
+        assert_eq!(test_vm.error(), Some(&VMError::NativeRegistersOutOfRange { name: "check".to_string() }));
+    }
 }
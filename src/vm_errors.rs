@@ -0,0 +1,104 @@
+use std::error::Error;
+use std::fmt;
+
+use policy::Capability;
+
+/// Errors that stop the VM. Each one has its own crash code, reported in the `Crash` event.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VMError {
+    InvalidHeader,
+    UnknownNative { name: String },
+    UnknownNativeIndex { index: u16 },
+    NativeRegistersOutOfRange { name: String },
+    NativeFailed { name: String, message: String },
+    InstructionLimitExceeded { limit: u64 },
+    HeapLimitExceeded { limit: usize },
+    UnknownSyscall { number: u16 },
+    CapabilityDenied { capability: Capability },
+    Interrupted,
+    JumpOutOfRange { offset: usize },
+    StackUnderflow,
+    HeapOffsetOutOfRange { offset: i32 },
+    ReadOnlyOffsetOutOfRange { offset: usize },
+    InvalidRegister { register: u8 },
+    DivisionByZero,
+    InvalidString { offset: usize },
+}
+
+impl VMError {
+    /// The code the VM crashes with, analogous to a process exit code
+    pub fn code(&self) -> u32 {
+        match self {
+            VMError::InvalidHeader => 1,
+            VMError::UnknownNative { .. } => 2,
+            VMError::UnknownNativeIndex { .. } => 3,
+            VMError::NativeRegistersOutOfRange { .. } => 4,
+            VMError::NativeFailed { .. } => 5,
+            VMError::InstructionLimitExceeded { .. } => 6,
+            VMError::HeapLimitExceeded { .. } => 7,
+            VMError::UnknownSyscall { .. } => 8,
+            VMError::CapabilityDenied { .. } => 9,
+            VMError::Interrupted => 10,
+            VMError::JumpOutOfRange { .. } => 11,
+            VMError::StackUnderflow => 12,
+            VMError::HeapOffsetOutOfRange { .. } => 13,
+            VMError::ReadOnlyOffsetOutOfRange { .. } => 14,
+            VMError::InvalidRegister { .. } => 15,
+            VMError::DivisionByZero => 16,
+            VMError::InvalidString { .. } => 17,
+        }
+    }
+}
+
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VMError::InvalidHeader => f.write_str("The program does not start with a valid header"),
+            VMError::UnknownNative { ref name } => f.write_str(&format!("The program imports {}, which is not a registered native function", name)),
+            VMError::UnknownNativeIndex { index } => f.write_str(&format!("CALLN refers to native function #{}, which the program does not import", index)),
+            VMError::NativeRegistersOutOfRange { ref name } => {
+                f.write_str(&format!("The arguments or results of the native function {} do not fit in the registers", name))
+            }
+            VMError::NativeFailed { ref name, ref message } => f.write_str(&format!("The native function {} failed: {}", name, message)),
+            VMError::InstructionLimitExceeded { limit } => f.write_str(&format!("The program did not finish within {} instructions", limit)),
+            VMError::HeapLimitExceeded { limit } => f.write_str(&format!("The program tried to grow the heap past {} bytes", limit)),
+            VMError::UnknownSyscall { number } => f.write_str(&format!("There is no system call #{}", number)),
+            VMError::CapabilityDenied { capability } => f.write_str(&format!("The program's policy does not allow {}", capability)),
+            VMError::Interrupted => f.write_str("The program was stopped by the host"),
+            VMError::JumpOutOfRange { offset } => f.write_str(&format!("The program jumped back {} bytes, before its start", offset)),
+            VMError::StackUnderflow => f.write_str("The program popped from an empty stack"),
+            VMError::HeapOffsetOutOfRange { offset } => f.write_str(&format!("The program used heap offset {}, which is outside the heap", offset)),
+            VMError::ReadOnlyOffsetOutOfRange { offset } => f.write_str(&format!(
+                "The program read a string at offset {}, which is outside the read-only section",
+                offset
+            )),
+            VMError::InvalidRegister { register } => f.write_str(&format!("There is no register ${}", register)),
+            VMError::DivisionByZero => f.write_str("The program divided by zero"),
+            VMError::InvalidString { offset } => f.write_str(&format!("The string at offset {} in the read-only section is not valid UTF-8", offset)),
+        }
+    }
+}
+
+impl Error for VMError {
+    fn description(&self) -> &str {
+        match self {
+            VMError::InvalidHeader => "The program does not start with a valid header",
+            VMError::UnknownNative { .. } => "The program imports a native function that is not registered",
+            VMError::UnknownNativeIndex { .. } => "CALLN refers to a native function the program does not import",
+            VMError::NativeRegistersOutOfRange { .. } => "The arguments or results of a native function do not fit in the registers",
+            VMError::NativeFailed { .. } => "A native function failed",
+            VMError::InstructionLimitExceeded { .. } => "The program did not finish within its instruction limit",
+            VMError::HeapLimitExceeded { .. } => "The program tried to grow the heap past its limit",
+            VMError::UnknownSyscall { .. } => "The program made a system call that does not exist",
+            VMError::CapabilityDenied { .. } => "The program's policy does not allow something it tried to do",
+            VMError::Interrupted => "The program was stopped by the host",
+            VMError::JumpOutOfRange { .. } => "The program jumped before its start",
+            VMError::StackUnderflow => "The program popped from an empty stack",
+            VMError::HeapOffsetOutOfRange { .. } => "The program used an offset outside the heap",
+            VMError::ReadOnlyOffsetOutOfRange { .. } => "The program read a string outside the read-only section",
+            VMError::InvalidRegister { .. } => "The program named a register that does not exist",
+            VMError::DivisionByZero => "The program divided by zero",
+            VMError::InvalidString { .. } => "A string in the read-only section is not valid UTF-8",
+        }
+    }
+}
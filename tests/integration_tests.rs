@@ -2,18 +2,18 @@ extern crate iridium;
 
 mod commons;
 
+use iridium::runtime::Runtime;
+
 #[test]
 fn create_vm() {
     commons::setup();
     let vm = iridium::vm::VM::new();
-    assert_eq!(vm.registers.len(), 32);
+    assert_eq!(vm.registers().len(), 32);
 }
 
 #[test]
 fn test_call_return() {
     commons::setup();
-    let mut vm = iridium::vm::VM::new();
-    let mut asm = iridium::assembler::Assembler::new();
     let code = r"
     .data
     .code
@@ -22,17 +22,13 @@ fn test_call_return() {
     HLT
     test: LOAD $0 #500
     RET";
-    let program = asm.assemble(code);
-    vm.add_bytes(program.unwrap());
-    let events = vm.run();
-    assert_eq!(events[1].event.stop_code(), 0);
+    let result = Runtime::new().run_source(code).unwrap();
+    assert_eq!(result.events[1].event.stop_code(), 0);
 }
 
 #[test]
 fn test_inc_loop() {
     commons::setup();
-    let mut vm = iridium::vm::VM::new();
-    let mut asm = iridium::assembler::Assembler::new();
     let code = r"
     .data
     .code
@@ -41,33 +37,25 @@ fn test_inc_loop() {
     test: inc $1
     loop @test
     hlt";
-    let program = asm.assemble(code);
-    vm.add_bytes(program.unwrap());
-    let events = vm.run();
-    assert_eq!(events[1].event.stop_code(), 0);
-    assert_eq!(vm.registers[1], 31);
+    let result = Runtime::new().run_source(code).unwrap();
+    assert_eq!(result.exit_code, 0);
+    assert_eq!(result.registers[1], 31);
 }
 
 #[test]
 fn test_hlt() {
     commons::setup();
-    let mut vm = iridium::vm::VM::new();
-    let mut asm = iridium::assembler::Assembler::new();
     let code = r"
     .data
     .code
     hlt";
-    let program = asm.assemble(code);
-    vm.add_bytes(program.unwrap());
-    let events = vm.run();
-    assert_eq!(events[1].event.stop_code(), 0);
+    let result = Runtime::new().run_source(code).unwrap();
+    assert_eq!(result.exit_code, 0);
 }
 
 #[test]
 fn test_function_call() {
     commons::setup();
-    let mut vm = iridium::vm::VM::new();
-    let mut asm = iridium::assembler::Assembler::new();
     let code = r"
     .data
     .code
@@ -76,9 +64,17 @@ fn test_function_call() {
     test:
     load $31 #1
     ret";
-    let program = asm.assemble(code);
-    vm.add_bytes(program.unwrap());
-    let events = vm.run();
-    assert_eq!(vm.registers[31], 1);
-    assert_eq!(events[1].event.stop_code(), 0);
+    let result = Runtime::new().run_source(code).unwrap();
+    assert_eq!(result.registers[31], 1);
+    assert_eq!(result.exit_code, 0);
+}
+
+#[test]
+fn test_precompiled_program() {
+    commons::setup();
+    let mut asm = iridium::assembler::Assembler::new();
+    let program = asm.assemble(".data\nhi: .asciiz 'Hi'\n.code\nprts @hi\nhlt").unwrap();
+    let result = Runtime::new().with_captured_output().run_bytes(program).unwrap();
+    assert_eq!(result.output, "Hi");
+    assert_eq!(result.exit_code, 0);
 }